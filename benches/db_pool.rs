@@ -0,0 +1,75 @@
+//! Per-request database latency: opening a fresh `AnyPool` for every request (how
+//! handlers used to get a connection) against acquiring from one shared pool.
+//!
+//!     cargo bench --bench db_pool
+//!     DATABASE_URL=postgres://... cargo bench --bench db_pool
+
+use sqlx::any::AnyPoolOptions;
+use sqlx::{AnyPool, Row};
+use std::time::{Duration, Instant};
+
+const REQUESTS: usize = 500;
+
+#[tokio::main]
+async fn main() {
+    sqlx::any::install_default_drivers();
+
+    let temp_db = std::env::temp_dir().join(format!("gurtpay-bench-{}.db", std::process::id()));
+    let url = std::env::var("DATABASE_URL")
+        .unwrap_or_else(|_| format!("sqlite:{}?mode=rwc", temp_db.display()));
+
+    let shared = AnyPoolOptions::new().max_connections(10).connect(&url).await
+        .expect("failed to connect");
+    sqlx::query("CREATE TABLE IF NOT EXISTS bench_users (id TEXT PRIMARY KEY, wallet_balance BIGINT NOT NULL)")
+        .execute(&shared).await.unwrap();
+    sqlx::query("DELETE FROM bench_users").execute(&shared).await.unwrap();
+    sqlx::query("INSERT INTO bench_users (id, wallet_balance) VALUES ('bench', 500000)")
+        .execute(&shared).await.unwrap();
+
+    println!("{} requests against {}", REQUESTS, url.split('@').next_back().unwrap_or(&url));
+
+    let per_request = measure(|| async {
+        let pool = AnyPool::connect(&url).await.unwrap();
+        lookup(&pool).await;
+    }).await;
+    report("connect per request", &per_request);
+
+    let pooled = measure(|| async { lookup(&shared).await }).await;
+    report("shared pool", &pooled);
+
+    sqlx::query("DROP TABLE bench_users").execute(&shared).await.unwrap();
+    shared.close().await;
+    let _ = std::fs::remove_file(&temp_db);
+}
+
+// What a balance request does once it has a pool.
+async fn lookup(pool: &AnyPool) {
+    let row = sqlx::query("SELECT wallet_balance FROM bench_users WHERE id = $1")
+        .bind("bench")
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    assert_eq!(row.get::<i64, _>("wallet_balance"), 500000);
+}
+
+async fn measure<F, Fut>(request: F) -> Vec<Duration>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    request().await;
+    let mut samples = Vec::with_capacity(REQUESTS);
+    for _ in 0..REQUESTS {
+        let start = Instant::now();
+        request().await;
+        samples.push(start.elapsed());
+    }
+    samples.sort();
+    samples
+}
+
+fn report(label: &str, samples: &[Duration]) {
+    let mean = samples.iter().sum::<Duration>() / samples.len() as u32;
+    let p = |q: usize| samples[(samples.len() * q / 100).min(samples.len() - 1)];
+    println!("{:<20} mean {:>9.3?}  p50 {:>9.3?}  p95 {:>9.3?}  p99 {:>9.3?}", label, mean, p(50), p(95), p(99));
+}
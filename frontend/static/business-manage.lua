@@ -44,9 +44,10 @@ local function load_business_info()
         if current_business then
             local info_text = ""
             info_text = info_text .. "Business Name: " .. current_business.business_name .. "\n"
-            info_text = info_text .. "Balance: " .. string.format("%.2f", current_business.balance) .. " GC\n"
+            info_text = info_text .. "Balance: " .. string.format("%.2f", tonumber(current_business.balance) or 0) .. " GC\n"
             info_text = info_text .. "Merchant ID: " .. (current_business.id or business_id) .. "\n"
             info_text = info_text .. "API Key: " .. current_business.api_key .. "\n"
+            info_text = info_text .. "Return Secret: " .. (current_business.return_secret or "N/A") .. "\n"
             if current_business.website_url and current_business.website_url ~= "" then
                 info_text = info_text .. "Website: " .. current_business.website_url .. "\n"
             end
@@ -49,6 +49,12 @@ ui(function()
     local logout_btn = gurt.select('#logout')
     if logout_btn then
         logout_btn:on('click', function()
+            pcall(function()
+                fetch('/api/auth/logout', {
+                    method = 'POST',
+                    headers = { ['Authorization'] = 'Bearer ' .. session_token }
+                })
+            end)
             gurt.crumbs.delete('gurtpay_session')
             setTimeout(function()
                 gurt.location.goto('/login')
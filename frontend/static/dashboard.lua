@@ -16,6 +16,39 @@ end
 
 local session_token = session.session_token
 
+-- Access tokens only last a few minutes, so trade the refresh token for a
+-- fresh pair whenever the dashboard loads
+if session.refresh_token then
+    local ok_fetch, response = pcall(function()
+        return fetch('/api/auth/refresh', {
+            method = 'POST',
+            headers = { ['Content-Type'] = 'application/json' },
+            body = JSON.stringify({ refresh_token = session.refresh_token })
+        })
+    end)
+    if ok_fetch and response:ok() then
+        local ok_json, data = pcall(function() return response:json() end)
+        if ok_json and data and data.session_token then
+            session.session_token = data.session_token
+            session.refresh_token = data.refresh_token
+            session_token = data.session_token
+            pcall(function()
+                gurt.crumbs.set({
+                    name = 'gurtpay_session',
+                    value = JSON.stringify(session),
+                    lifetime = 2592000
+                })
+            end)
+        end
+    elseif ok_fetch and response.status == 401 then
+        gurt.crumbs.delete('gurtpay_session')
+        setTimeout(function()
+            gurt.location.goto("/login")
+        end, 50)
+        return
+    end
+end
+
 local wallet_info = {}
 
 local function ui(fn)
@@ -39,7 +72,10 @@ end
 local function render_wallet()
     local text_content = ""
     text_content = text_content .. "Address: " .. (wallet_info.address or "N/A") .. "\n\n"
-    text_content = text_content .. "Balance: " .. tostring(wallet_info.balance or 0) .. " GC" .. "\n\n"
+    text_content = text_content .. "Balance: " .. tostring(wallet_info.available_balance or wallet_info.balance or 0) .. " GC" .. "\n\n"
+    if (tonumber(wallet_info.pending_balance) or 0) > 0 then
+        text_content = text_content .. "Pending: " .. tostring(wallet_info.pending_balance) .. " GC (held by card authorizations)" .. "\n\n"
+    end
     text_content = text_content .. "Total Sent: " .. tostring(wallet_info.total_sent or 0) .. " GC" .. "\n\n"
     text_content = text_content .. "Total Received: " .. tostring(wallet_info.total_received or 0) .. " GC"
     ui(function()
@@ -97,7 +133,7 @@ local function render_transactions()
             if party_label ~= "" then
                 text_content = text_content .. "  (" .. party_label .. ")"
             end
-            text_content = text_content .. "  " .. prefix .. string.format("%.2f", tx.amount or 0) .. " GC"
+            text_content = text_content .. "  " .. prefix .. string.format("%.2f", tonumber(tx.amount) or 0) .. " GC"
             text_content = text_content .. "  " .. (tx.created_at or "Unknown"):sub(1, 16):gsub("T", " ") .. "\n\n"
         end
     end
@@ -164,7 +200,7 @@ local function render_businesses()
         })
         
         local balance = gurt.create('p', {
-            text = '💰 Balance: ' .. string.format("%.2f", biz.balance or 0) .. ' GC',
+            text = '💰 Balance: ' .. string.format("%.2f", tonumber(biz.balance) or 0) .. ' GC',
             style = 'text-sm text-slate-600 font-medium'
         })
         
@@ -238,10 +274,182 @@ local function fetch_businesses()
     end
 end
 
+local incoming_requests = {}
+local outgoing_requests = {}
+
+local function error_message(response, fallback)
+    local ok_parse, error_data = pcall(function() return response:json() end)
+    if ok_parse and error_data and error_data.error then
+        return error_data.error
+    end
+    return fallback
+end
+
+local fetch_requests
+
+local function respond_to_request(action, request_id)
+    local response = fetch('/api/wallet/requests/' .. action, {
+        method = 'POST',
+        headers = {
+            ['Authorization'] = 'Bearer ' .. session_token,
+            ['Content-Type'] = 'application/json'
+        },
+        body = JSON.stringify({ request_id = request_id })
+    })
+
+    if response:ok() then
+        fetch_requests()
+        if action == 'pay' then
+            fetch_wallet()
+            fetch_transactions()
+        end
+    else
+        if handle_auth_error(response) then
+            return
+        end
+        alert('Could not ' .. action .. ' request: ' .. error_message(response, 'Unknown error'))
+    end
+end
+
+local function request_row(req, incoming)
+    local row = gurt.create('div', {
+        style = 'flex flex-row justify-between items-center gap-3 bg-[#f9fafb] p-3 rounded border'
+    })
+
+    local party = incoming and ('from ' .. (req.to_username or req.to_address or '?')) or ('to ' .. (req.from_username or req.from_address or '?'))
+    local info = gurt.create('div', { style = 'flex flex-col' })
+    info:append(gurt.create('p', {
+        text = string.format('%.2f', tonumber(req.amount) or 0) .. ' GC ' .. party,
+        style = 'text-sm font-bold text-slate-900'
+    }))
+    info:append(gurt.create('p', {
+        text = (req.description or '') .. '  ' .. (req.created_at or ''):sub(1, 16):gsub('T', ' '),
+        style = 'text-xs text-slate-500'
+    }))
+    row:append(info)
+
+    local actions = gurt.create('div', { style = 'flex flex-row gap-2' })
+    if req.status ~= 'pending' then
+        actions:append(gurt.create('span', { text = req.status:upper(), style = 'text-xs text-slate-500' }))
+    elseif incoming then
+        local pay_btn = gurt.create('button', { text = 'Pay', style = 'bg-[#0b5cab] text-white px-3 py-1 rounded text-sm hover:bg-[#094b97]' })
+        pay_btn:on('click', function() respond_to_request('pay', req.id) end)
+        local decline_btn = gurt.create('button', { text = 'Decline', style = 'bg-white text-slate-600 px-3 py-1 rounded border border-slate-300 text-sm' })
+        decline_btn:on('click', function() respond_to_request('decline', req.id) end)
+        actions:append(pay_btn)
+        actions:append(decline_btn)
+    else
+        local cancel_btn = gurt.create('button', { text = 'Cancel', style = 'bg-white text-slate-600 px-3 py-1 rounded border border-slate-300 text-sm' })
+        cancel_btn:on('click', function() respond_to_request('cancel', req.id) end)
+        actions:append(cancel_btn)
+    end
+    row:append(actions)
+
+    return row
+end
+
+local function render_requests()
+    local list_el = gurt.select('#requests-list')
+    if not list_el then return end
+
+    local children = list_el.children
+    for i = #children, 1, -1 do
+        children[i]:remove()
+    end
+
+    if #incoming_requests == 0 and #outgoing_requests == 0 then
+        list_el:append(gurt.create('p', {
+            text = 'No money requests.',
+            style = 'text-sm text-slate-700 bg-[#f9fafb] p-4 rounded border'
+        }))
+        return
+    end
+
+    if #incoming_requests > 0 then
+        list_el:append(gurt.create('p', { text = 'Asked of you', style = 'text-sm font-semibold text-slate-700' }))
+        for i = 1, math.min(#incoming_requests, 10) do
+            list_el:append(request_row(incoming_requests[i], true))
+        end
+    end
+    if #outgoing_requests > 0 then
+        list_el:append(gurt.create('p', { text = 'Your requests', style = 'text-sm font-semibold text-slate-700' }))
+        for i = 1, math.min(#outgoing_requests, 10) do
+            list_el:append(request_row(outgoing_requests[i], false))
+        end
+    end
+end
+
+fetch_requests = function()
+    local headers = { ['Authorization'] = 'Bearer ' .. session_token }
+
+    local incoming = fetch('/api/wallet/requests/incoming', { headers = headers })
+    local outgoing = fetch('/api/wallet/requests/outgoing', { headers = headers })
+
+    if incoming:ok() and outgoing:ok() then
+        incoming_requests = incoming:json().requests or {}
+        outgoing_requests = outgoing:json().requests or {}
+        ui(render_requests)
+    else
+        if handle_auth_error(incoming:ok() and outgoing or incoming) then
+            return
+        end
+        ui(function()
+            local list_el = gurt.select('#requests-list')
+            if list_el then
+                list_el.text = "Failed to load money requests"
+            end
+        end)
+    end
+end
+
+local function request_money()
+    local address = prompt("Wallet address to request money from:")
+    if not address or address:trim() == "" then return end
+    local amount = prompt("Amount to request (GC):")
+    if not amount or not tonumber(amount) or tonumber(amount) <= 0 then return end
+    local description = prompt("What is it for?") or ""
+
+    local response = fetch('/api/wallet/request', {
+        method = 'POST',
+        headers = {
+            ['Authorization'] = 'Bearer ' .. session_token,
+            ['Content-Type'] = 'application/json'
+        },
+        body = JSON.stringify({
+            from_address = address:trim(),
+            amount = string.format('%.2f', tonumber(amount)),
+            description = description
+        })
+    })
+
+    if response:ok() then
+        alert("Request sent!")
+        fetch_requests()
+    else
+        if handle_auth_error(response) then
+            return
+        end
+        alert("Request failed: " .. error_message(response, "Unknown error"))
+    end
+end
+
+ui(function()
+    local request_btn = gurt.select('#request-money')
+    if request_btn then
+        request_btn:on('click', request_money)
+    end
+end)
+
 ui(function()
     local logout_btn = gurt.select('#logout')
     if logout_btn then
         logout_btn:on('click', function()
+            pcall(function()
+                fetch('/api/auth/logout', {
+                    method = 'POST',
+                    headers = { ['Authorization'] = 'Bearer ' .. session_token }
+                })
+            end)
             gurt.crumbs.delete('gurtpay_session')
             gurt.location.goto('/login')
         end)
@@ -290,5 +498,6 @@ end
 setTimeout(function()
     fetch_wallet()
     fetch_transactions()
+    fetch_requests()
     fetch_businesses()
 end, 0)
\ No newline at end of file
@@ -65,12 +65,43 @@ if login_btn then
                 return response:json()
             end)
             
+            -- Accounts with two-factor get a challenge instead of a session
+            if ok_parse and data and data.two_factor_required then
+                local code = prompt('Enter the code from your authenticator app, or a recovery code:')
+                if not code or code:trim() == '' then
+                    set_status('Two-factor code required', true)
+                    return
+                end
+                set_status('Verifying code...')
+                local ok_fetch, verify_response = pcall(function()
+                    return fetch('/api/auth/login/2fa', {
+                        method = 'POST',
+                        headers = { ['Content-Type'] = 'application/json' },
+                        body = JSON.stringify({
+                            challenge_token = data.challenge_token,
+                            code = code:trim()
+                        })
+                    })
+                end)
+                if not ok_fetch then
+                    set_status('Network error. Please try again.', true)
+                    return
+                end
+                ok_parse, data = pcall(function()
+                    return verify_response:json()
+                end)
+                if not verify_response:ok() then
+                    set_status((ok_parse and data and data.error) or 'Invalid two-factor code', true)
+                    return
+                end
+            end
+            
             if ok_parse and data then
                 local ok_set = pcall(function()
                     gurt.crumbs.set({ 
                         name = 'gurtpay_session', 
                         value = JSON.stringify(data), 
-                        lifetime = 86400 
+                        lifetime = 2592000 
                     })
                 end)
                 
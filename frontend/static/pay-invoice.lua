@@ -2,6 +2,9 @@
 local session_token = nil
 local session_user = nil
 local current_invoice = nil
+-- Signed links back to the merchant, from /api/invoice/status
+local redirect_url = nil
+local cancel_url = nil
 -- Forward declaration so renderers can reference the handler before it's defined
 local perform_payment
 
@@ -95,9 +98,29 @@ local function render_invoice(invoice, business)
     desc_block:append(gurt.create('p', { id = 'invoice-description', text = invoice.description or 'No description', style = 'text-slate-900' }))
     details:append(desc_block)
 
+    if invoice.line_items and #invoice.line_items > 0 then
+        local items_block = gurt.create('div', { id = 'line-items', style = 'flex flex-col gap-1' })
+        items_block:append(gurt.create('p', { text = 'Items', style = 'text-slate-500 text-sm' }))
+        for _, line in ipairs(invoice.line_items) do
+            local label = line.name
+            if (tonumber(line.quantity) or 1) > 1 then
+                label = tostring(line.quantity) .. ' × ' .. label
+            end
+            local amount = string.format('%.2f GC', tonumber(line.amount) or 0)
+            if line.kind == 'discount' then
+                amount = '-' .. amount
+            end
+            local row = gurt.create('div', { style = 'flex justify-between' })
+            row:append(gurt.create('p', { text = label, style = 'text-slate-700' }))
+            row:append(gurt.create('p', { text = amount, style = 'text-slate-900' }))
+            items_block:append(row)
+        end
+        details:append(items_block)
+    end
+
     local amt_block = gurt.create('div', {})
     amt_block:append(gurt.create('p', { text = 'Amount', style = 'text-slate-500 text-sm' }))
-    amt_block:append(gurt.create('p', { id = 'invoice-amount', text = string.format('%.2f GC', invoice.amount or 0), style = 'text-2xl font-bold text-[#0b5cab]' }))
+    amt_block:append(gurt.create('p', { id = 'invoice-amount', text = string.format('%.2f GC', tonumber(invoice.amount) or 0), style = 'text-2xl font-bold text-[#0b5cab]' }))
     details:append(amt_block)
 
     if invoice.customer_name or invoice.customer_email then
@@ -124,6 +147,17 @@ local function render_invoice(invoice, business)
     local button = gurt.create('button', { id = 'pay-button', text = 'Pay Invoice', style = 'w-full mt-2 px-5 py-3 bg-[#0b5cab] text-white rounded-md font-bold hover:bg-[#094b97]' })
     content:append(button)
 
+    local merchant_name = (business and business.business_name) or 'merchant'
+    if invoice.status == 'paid' and redirect_url then
+        local back = gurt.create('button', { id = 'return-button', text = 'Return to ' .. merchant_name, style = 'w-full mt-2 px-5 py-3 bg-white text-[#0b5cab] border border-[#0b5cab] rounded-md font-bold' })
+        back:on('click', function() gurt.location.goto(redirect_url) end)
+        content:append(back)
+    elseif invoice.status ~= 'paid' and cancel_url then
+        local cancel = gurt.create('button', { id = 'cancel-button', text = 'Cancel and return to ' .. merchant_name, style = 'w-full mt-2 px-5 py-3 bg-white text-slate-600 border border-slate-300 rounded-md' })
+        cancel:on('click', function() gurt.location.goto(cancel_url) end)
+        content:append(cancel)
+    end
+
     container:append(content)
 
     root:append(container)
@@ -182,6 +216,8 @@ local function fetch_invoice()
         local ok_parse, data = pcall(function() return response:json() end)
         if ok_parse and data then
             current_invoice = data.invoice
+            redirect_url = data.redirect_url
+            cancel_url = data.cancel_url
             
             if current_invoice.status == "expired" then
                 render_error("This invoice has expired")
@@ -224,13 +260,31 @@ perform_payment = function()
     
     show_status("Processing payment...", false)
     
-    local response = fetch('/api/invoice/pay/' .. current_invoice.id, {
-        method = 'POST',
-        headers = {
-            ['Authorization'] = "Bearer " .. session_token,
-            ['Content-Type'] = "application/json"
-        }
-    })
+    local payload = {}
+    local function pay_request()
+        return fetch('/api/invoice/pay/' .. current_invoice.id, {
+            method = 'POST',
+            headers = {
+                ['Authorization'] = "Bearer " .. session_token,
+                ['Content-Type'] = "application/json"
+            },
+            body = JSON.stringify(payload)
+        })
+    end
+    
+    local response = pay_request()
+    
+    -- Large payments need a two-factor code when the user has set a threshold
+    if response.status == 403 then
+        local ok_parse, parsed = pcall(function() return response:json() end)
+        if ok_parse and parsed and parsed.code == "two_factor_required" then
+            local code = prompt(parsed.error .. "\nEnter the code from your authenticator app:")
+            if code and code:trim() ~= "" then
+                payload.two_factor_code = code:trim()
+                response = pay_request()
+            end
+        end
+    end
     
     if response:ok() then
         -- Re-fetch invoice to reflect definitive status and render accordingly
@@ -239,6 +293,15 @@ perform_payment = function()
             pay_button.text = 'Invoice Paid'
             pay_button.disabled = true
         end
+        local ok_parse, result = pcall(function() return response:json() end)
+        if ok_parse and result and result.redirect_url then
+            -- Send the customer back to the merchant's success page
+            show_status("Payment successful! Returning to the merchant...", false)
+            setTimeout(function()
+                gurt.location.goto(result.redirect_url)
+            end, 1500)
+            return
+        end
         -- Best-effort refresh
         setTimeout(function()
             fetch_invoice()
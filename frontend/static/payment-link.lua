@@ -0,0 +1,140 @@
+-- Reusable payment link: opens a fresh invoice for this customer, then hands
+-- off to the regular /pay/* page
+local current_link = nil
+
+local function get_link_id()
+    local href = gurt.location and gurt.location.href or ""
+    if type(href) ~= "string" then href = tostring(href or "") end
+    return href:match("/link/([0-9a-fA-F%-]+)") or href:match("/link/([^/?#]+)")
+end
+
+local function get_root()
+    return gurt.select('#link-root')
+end
+
+local function clear_children(el)
+    if not el then return end
+    local kids = el.children
+    for i = #kids, 1, -1 do
+        kids[i]:remove()
+    end
+end
+
+local function render_error(message)
+    local root = get_root()
+    if not root then return end
+    clear_children(root)
+    root:append(gurt.create('h2', { text = 'Error', style = 'text-xl font-bold text-red-900 mb-2' }))
+    root:append(gurt.create('p', { id = 'error-message', text = message or 'Unknown error', style = 'text-red-700' }))
+end
+
+local function show_status(message, is_error)
+    local status_el = gurt.select('#link-status')
+    if not status_el then return end
+    status_el.text = message or ''
+    status_el.classList:remove('text-red-600')
+    status_el.classList:remove('text-slate-600')
+    status_el.classList:add(is_error and 'text-red-600' or 'text-slate-600')
+end
+
+local function open_invoice()
+    if not current_link then return end
+
+    local payload = {}
+    if not current_link.amount then
+        local amount = tonumber(gurt.select('#link-amount').value)
+        if not amount or amount <= 0 then
+            show_status('Please enter an amount', true)
+            return
+        end
+        payload.amount = string.format('%.2f', amount)
+    end
+    local name = gurt.select('#link-customer-name').value:trim()
+    if name ~= '' then
+        payload.customer_name = name
+    end
+
+    local button = gurt.select('#continue-button')
+    if button then
+        button.text = 'Opening invoice...'
+        button.disabled = true
+    end
+
+    local response = fetch('/api/links/open/' .. current_link.link_id, {
+        method = 'POST',
+        headers = { ['Content-Type'] = 'application/json' },
+        body = JSON.stringify(payload)
+    })
+
+    local ok_parse, data = pcall(function() return response:json() end)
+    if response:ok() and ok_parse and data and data.invoice_id then
+        gurt.location.goto('/pay/' .. data.invoice_id)
+        return
+    end
+
+    show_status((ok_parse and data and data.error) or 'Could not open an invoice', true)
+    if button then
+        button.text = 'Continue to Payment'
+        button.disabled = false
+    end
+end
+
+local function render_link(link)
+    local root = get_root()
+    if not root then return end
+    clear_children(root)
+
+    local container = gurt.create('div', { style = 'flex flex-col gap-4' })
+    container:append(gurt.create('h2', { text = link.business_name or 'Payment', style = 'text-xl font-bold text-slate-900' }))
+
+    local desc_block = gurt.create('div', {})
+    desc_block:append(gurt.create('p', { text = 'Description', style = 'text-slate-500 text-sm' }))
+    desc_block:append(gurt.create('p', { text = link.description or '', style = 'text-slate-900' }))
+    container:append(desc_block)
+
+    local amt_block = gurt.create('div', {})
+    amt_block:append(gurt.create('p', { text = 'Amount', style = 'text-slate-500 text-sm' }))
+    if link.amount then
+        amt_block:append(gurt.create('p', { text = string.format('%.2f GC', tonumber(link.amount) or 0), style = 'text-2xl font-bold text-[#0b5cab]' }))
+    else
+        amt_block:append(gurt.create('input', { id = 'link-amount', type = 'number', placeholder = '0.00', min = '0.01', step = '0.01', style = 'w-full border border-slate-300 rounded px-4 py-3 bg-white text-2xl font-bold text-center text-[#0b5cab]' }))
+    end
+    container:append(amt_block)
+
+    local name_block = gurt.create('div', {})
+    name_block:append(gurt.create('p', { text = 'Your name (optional)', style = 'text-slate-500 text-sm' }))
+    name_block:append(gurt.create('input', { id = 'link-customer-name', type = 'text', maxlength = '100', style = 'w-full border border-slate-300 rounded px-4 py-3 bg-white' }))
+    container:append(name_block)
+
+    container:append(gurt.create('p', { id = 'link-status', text = '', style = 'text-center text-sm text-slate-600' }))
+
+    local button = gurt.create('button', { id = 'continue-button', text = 'Continue to Payment', style = 'w-full mt-2 px-5 py-3 bg-[#0b5cab] text-white rounded-md font-bold hover:bg-[#094b97]' })
+    button:on('click', open_invoice)
+    container:append(button)
+
+    root:append(container)
+end
+
+local function fetch_link()
+    local link_id = get_link_id()
+    if not link_id then
+        render_error('Invalid payment link')
+        return
+    end
+
+    local response = fetch('/api/links/status/' .. link_id, { method = 'GET' })
+    local ok_parse, data = pcall(function() return response:json() end)
+    if not response:ok() or not ok_parse or not data then
+        render_error((ok_parse and data and data.error) or 'Payment link not found')
+        return
+    end
+    if not data.available then
+        render_error('This payment link is no longer available')
+        return
+    end
+
+    current_link = data
+    render_link(data)
+end
+
+fetch_link()
@@ -103,7 +103,7 @@ if register_btn then
                     gurt.crumbs.set({ 
                         name = 'gurtpay_session', 
                         value = JSON.stringify(data), 
-                        lifetime = 86400 
+                        lifetime = 2592000 
                     })
                 end)
                 
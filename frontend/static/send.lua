@@ -19,8 +19,9 @@ local function load_balance()
     
     if response:ok() then
         local wallet_data = response:json()
-        current_balance = wallet_data.balance
-        gurt.select("#current-balance").text = current_balance .. " GC"
+        local available = wallet_data.available_balance or wallet_data.balance
+        current_balance = tonumber(available) or 0
+        gurt.select("#current-balance").text = available .. " GC"
     else
         show_status("Failed to load balance", false)
     end
@@ -75,18 +76,35 @@ local function perform_send()
     gurt.select("#send-btn").text = "Sending..."
     gurt.select("#send-btn").disabled = true
     
-    local response = fetch("/api/wallet/send", {
-        method = "POST",
-        headers = {
-            ["Authorization"] = "Bearer " .. session_token,
-            ["Content-Type"] = "application/json"
-        },
-        body = JSON.stringify({
-            to_address = recipient,
-            amount = amount,
-            description = description
+    local payload = {
+        to_address = recipient,
+        amount = amount,
+        description = description
+    }
+    local function send_request()
+        return fetch("/api/wallet/send", {
+            method = "POST",
+            headers = {
+                ["Authorization"] = "Bearer " .. session_token,
+                ["Content-Type"] = "application/json"
+            },
+            body = JSON.stringify(payload)
         })
-    })
+    end
+    
+    local response = send_request()
+    
+    -- Large sends need a two-factor code when the user has set a threshold
+    if response.status == 403 then
+        local ok_parse, parsed = pcall(function() return response:json() end)
+        if ok_parse and parsed and parsed.code == "two_factor_required" then
+            local code = prompt(parsed.error .. "\nEnter the code from your authenticator app:")
+            if code and code:trim() ~= "" then
+                payload.two_factor_code = code:trim()
+                response = send_request()
+            end
+        end
+    end
     
     if response:ok() then
         show_status(string.format("Successfully sent %.2f GC!", amount), true)
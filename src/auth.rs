@@ -1,18 +1,13 @@
 use crate::models::*;
+use crate::error::{ApiError, ApiResult};
 use gurtlib::Result;
 use serde::{Deserialize, Serialize};
-use jsonwebtoken::{encode, decode, Header, Validation, EncodingKey, DecodingKey, Algorithm};
+use crate::jwt_keys;
 use chrono::{Duration, Utc};
-use once_cell::sync::Lazy;
-use sqlx::{AnyPool, Row};
+use sqlx::{AnyConnection, AnyPool, Row};
 use uuid::Uuid;
 use bcrypt::{hash, verify, DEFAULT_COST};
-
-static JWT_SECRET: Lazy<String> = Lazy::new(|| {
-    std::env::var("JWT_SECRET").unwrap_or_else(|_| {
-        "gurtpay_super_secret_key_change_in_production_2024".to_string()
-    })
-});
+use once_cell::sync::Lazy;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct SessionClaims {
@@ -23,11 +18,33 @@ struct SessionClaims {
     session_id: String,  // Unique session identifier
 }
 
+/// Access tokens are short-lived; clients renew them with the refresh token.
+const ACCESS_TOKEN_MINUTES: i64 = 15;
+/// A login lasts this long however often it is refreshed.
+const SESSION_DAYS: i64 = 30;
+/// `last_used_at` is only rewritten when it is older than this, so a burst of
+/// requests doesn't write the session row on every one.
+const LAST_USED_RESOLUTION_SECS: i64 = 60;
+const CLEANUP_INTERVAL_SECS: u64 = 3600;
+
 #[derive(Debug)]
 pub struct SessionToken {
     pub jwt: String,
+    pub refresh_token: String,
+    pub session_id: String,
+    /// When `jwt` stops working; refresh before then.
+    pub expires_at: chrono::DateTime<Utc>,
+}
+
+/// One of a user's logins, as shown in their session list.
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
     pub session_id: String,
+    pub created_at: chrono::DateTime<Utc>,
     pub expires_at: chrono::DateTime<Utc>,
+    pub last_used_at: Option<chrono::DateTime<Utc>>,
+    /// Whether this is the session making the request.
+    pub current: bool,
 }
 
 pub fn hash_password(plain: &str) -> Result<String> {
@@ -42,56 +59,123 @@ pub fn verify_password(plain: &str, hashed: &str) -> Result<bool> {
     Ok(ok)
 }
 
+/// Stands in for the hash of a user who doesn't exist, at the same cost as a
+/// real one.
+static DUMMY_PASSWORD_HASH: Lazy<String> =
+    Lazy::new(|| hash("gurtpay-no-such-user", DEFAULT_COST).expect("bcrypt hashes a fixed password"));
+
+/// Like `verify_password`, but with no stored hash it still runs bcrypt against
+/// a dummy one before returning false, so an unknown username takes as long to
+/// reject as a wrong password and doesn't show which usernames exist.
+pub fn verify_password_or_dummy(plain: &str, hashed: Option<&str>) -> Result<bool> {
+    match hashed {
+        Some(hashed) => verify_password(plain, hashed),
+        None => verify_password(plain, &DUMMY_PASSWORD_HASH).map(|_| false),
+    }
+}
+
+/// Starts a new session for the user and returns its first access and refresh
+/// tokens.
 pub async fn generate_session_token(pool: &AnyPool, user: &User) -> Result<SessionToken> {
     let session_id = Uuid::new_v4().to_string();
     let now = Utc::now();
-    let expires_at = now + Duration::hours(24);
-    
-    let claims = SessionClaims {
-        sub: user.id.to_string(),
-        username: user.username.clone(),
-        exp: expires_at.timestamp(),
-        iat: now.timestamp(),
-        session_id: session_id.clone(),
-    };
-    
-    let jwt = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(JWT_SECRET.as_bytes()),
-    ).map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to generate JWT: {}", e)))?;
+    let (jwt, expires_at) = issue_access_token(&user.id.to_string(), &user.username, &session_id)?;
+    let refresh_token = generate_refresh_token();
     
     sqlx::query(
-        "INSERT INTO user_sessions (id, user_id, jwt_token, created_at, expires_at, active) 
-         VALUES ($1, $2, $3, $4, $5, TRUE)"
+        "INSERT INTO user_sessions (id, user_id, jwt_token, refresh_token_hash, created_at, expires_at, last_used_at, active) 
+         VALUES ($1, $2, $3, $4, $5, $6, $7, TRUE)"
     )
     .bind(&session_id)
     .bind(user.id.to_string())
     .bind(&jwt)
+    .bind(hash_refresh_token(&refresh_token))
+    .bind(now.to_rfc3339())
+    .bind((now + Duration::days(SESSION_DAYS)).to_rfc3339())
     .bind(now.to_rfc3339())
-    .bind(expires_at.to_rfc3339())
     .execute(pool)
     .await
     .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to store session: {}", e)))?;
     
     Ok(SessionToken {
         jwt,
+        refresh_token,
         session_id,
         expires_at,
     })
 }
 
-pub async fn validate_session_token(pool: &AnyPool, token: &str) -> Result<User> {
-    let token_data = decode::<SessionClaims>(
-        token,
-        &DecodingKey::from_secret(JWT_SECRET.as_bytes()),
-        &Validation::new(Algorithm::HS256),
-    ).map_err(|e| gurtlib::GurtError::invalid_message(format!("Invalid JWT token: {}", e)))?;
+/// Trades a refresh token for a new access token and a new refresh token. Each
+/// refresh token works once: presenting one that was already rotated out means
+/// it leaked, so the whole session is revoked.
+pub async fn refresh_session(pool: &AnyPool, refresh_token: &str) -> ApiResult<SessionToken> {
+    let token_hash = hash_refresh_token(refresh_token);
     
-    let claims = token_data.claims;
+    let row = sqlx::query(
+        "SELECT s.id, s.user_id, s.expires_at, u.username FROM user_sessions s 
+         JOIN users u ON u.id = s.user_id 
+         WHERE s.refresh_token_hash = $1 AND s.active = TRUE"
+    )
+    .bind(&token_hash)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to get session: {}", e)))?;
+    
+    let Some(row) = row else {
+        let revoked = sqlx::query("UPDATE user_sessions SET active = FALSE WHERE previous_refresh_token_hash = $1 AND active = TRUE")
+            .bind(&token_hash)
+            .execute(pool)
+            .await
+            .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to revoke session: {}", e)))?;
+        if revoked.rows_affected() > 0 {
+            tracing::warn!("Refresh token reused; revoked its session");
+            return Err(ApiError::unauthorized("Refresh token was already used; session revoked"));
+        }
+        return Err(ApiError::unauthorized("Invalid refresh token"));
+    };
+    
+    let session_id: String = row.get("id");
+    let expires_at = chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("expires_at"))
+        .map_err(|_| gurtlib::GurtError::invalid_message("Invalid expiration date".to_string()))?
+        .with_timezone(&Utc);
+    if expires_at < Utc::now() {
+        return Err(ApiError::unauthorized("Session expired"));
+    }
+    
+    let (jwt, access_expires_at) = issue_access_token(&row.get::<String, _>("user_id"), &row.get::<String, _>("username"), &session_id)?;
+    let new_refresh_token = generate_refresh_token();
+    
+    // Compare-and-set on the old hash, so two refreshes with one token can't both win
+    let rotated = sqlx::query(
+        "UPDATE user_sessions SET jwt_token = $1, refresh_token_hash = $2, previous_refresh_token_hash = $3, last_used_at = $4 
+         WHERE id = $5 AND refresh_token_hash = $6 AND active = TRUE"
+    )
+    .bind(&jwt)
+    .bind(hash_refresh_token(&new_refresh_token))
+    .bind(&token_hash)
+    .bind(Utc::now().to_rfc3339())
+    .bind(&session_id)
+    .bind(&token_hash)
+    .execute(pool)
+    .await
+    .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to rotate refresh token: {}", e)))?;
+    if rotated.rows_affected() == 0 {
+        return Err(ApiError::unauthorized("Invalid refresh token"));
+    }
+    
+    Ok(SessionToken {
+        jwt,
+        refresh_token: new_refresh_token,
+        session_id,
+        expires_at: access_expires_at,
+    })
+}
+
+pub async fn validate_session_token(pool: &AnyPool, token: &str) -> ApiResult<User> {
+    let claims = decode_claims(token)?;
     
     let session_row = sqlx::query(
-        "SELECT user_id, active, expires_at FROM user_sessions 
+        "SELECT user_id, expires_at FROM user_sessions 
          WHERE id = $1 AND jwt_token = $2 AND active = TRUE"
     )
     .bind(&claims.session_id)
@@ -114,14 +198,23 @@ pub async fn validate_session_token(pool: &AnyPool, token: &str) -> Result<User>
                     .await
                     .ok();
                 
-                return Err(gurtlib::GurtError::invalid_message("Session expired".to_string()));
+                return Err(ApiError::unauthorized("Session expired"));
             }
             
+            let now = Utc::now();
+            sqlx::query("UPDATE user_sessions SET last_used_at = $1 WHERE id = $2 AND (last_used_at IS NULL OR last_used_at < $3)")
+                .bind(now.to_rfc3339())
+                .bind(&claims.session_id)
+                .bind((now - Duration::seconds(LAST_USED_RESOLUTION_SECS)).to_rfc3339())
+                .execute(pool)
+                .await
+                .ok();
+            
             let user_id = Uuid::parse_str(&claims.sub)
-                .map_err(|_| gurtlib::GurtError::invalid_message("Invalid user ID in token".to_string()))?;
+                .map_err(|_| ApiError::unauthorized("Invalid user ID in token"))?;
             
             let user_row = sqlx::query(
-                "SELECT id, arsonflare_id, username, wallet_balance, wallet_address, created_at, is_admin 
+                "SELECT id, arsonflare_id, username, wallet_balance, wallet_address, created_at, CASE WHEN is_admin THEN 1 ELSE 0 END AS is_admin 
                  FROM users WHERE id = $1"
             )
             .bind(user_id.to_string())
@@ -135,28 +228,101 @@ pub async fn validate_session_token(pool: &AnyPool, token: &str) -> Result<User>
                         id: Uuid::parse_str(&row.get::<String, _>("id")).unwrap(),
                         arsonflare_id: row.get("arsonflare_id"),
                         username: row.get("username"),
-                        wallet_balance: row.get("wallet_balance"),
+                        wallet_balance: crate::money::Money::from_minor(row.get("wallet_balance")),
                         wallet_address: row.get("wallet_address"),
                         created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at")).unwrap().with_timezone(&Utc),
-                        is_admin: row.get("is_admin"),
+                        is_admin: row.get::<i64, _>("is_admin") != 0,
                     })
                 }
-                None => Err(gurtlib::GurtError::invalid_message("User not found".to_string()))
+                None => Err(ApiError::unauthorized("User not found"))
             }
         }
-        None => Err(gurtlib::GurtError::invalid_message("Invalid or expired session".to_string()))
+        None => Err(ApiError::unauthorized("Invalid or expired session"))
+    }
+}
+
+/// Who a route lets in. A route lists every kind it accepts; admins are users
+/// whose account has `is_admin` set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    User,
+    Admin,
+    Business,
+}
+
+/// The authenticated caller of a request: a user holding a session token, or a
+/// business holding its API key.
+#[derive(Debug)]
+pub enum Principal {
+    User(User),
+    Business(Business),
+}
+
+impl Principal {
+    pub fn into_user(self) -> ApiResult<User> {
+        match self {
+            Principal::User(user) => Ok(user),
+            Principal::Business(_) => Err(ApiError::forbidden("This endpoint requires a user session")),
+        }
+    }
+
+    pub fn into_business(self) -> ApiResult<Business> {
+        match self {
+            Principal::Business(business) => Ok(business),
+            Principal::User(_) => Err(ApiError::forbidden("This endpoint requires a business API key")),
+        }
     }
 }
 
-pub async fn invalidate_session(pool: &AnyPool, token: &str) -> Result<()> {
-    let token_data = decode::<SessionClaims>(
-        token,
-        &DecodingKey::from_secret(JWT_SECRET.as_bytes()),
-        &Validation::new(Algorithm::HS256),
-    ).map_err(|e| gurtlib::GurtError::invalid_message(format!("Invalid JWT token: {}", e)))?;
+/// Resolves the `authorization` header to a principal the route accepts. The
+/// bearer credential is tried as an API key when the route takes businesses and
+/// as a session token when it takes users or admins.
+pub async fn authenticate(pool: &AnyPool, auth_header: Option<&str>, accepts: &[Access]) -> ApiResult<Principal> {
+    let token = bearer_token(auth_header)?;
+
+    let accepts_business = accepts.contains(&Access::Business);
+    let accepts_user = accepts.contains(&Access::User);
+    let accepts_admin = accepts.contains(&Access::Admin);
+
+    if accepts_business {
+        if let Some(business) = crate::database::get_business_by_api_key(pool, token).await? {
+            return Ok(Principal::Business(business));
+        }
+        if !accepts_user && !accepts_admin {
+            return Err(ApiError::unauthorized("Invalid API key"));
+        }
+    }
+
+    let user = validate_session_token(pool, token).await?;
+    if accepts_user || (accepts_admin && user.is_admin) {
+        Ok(Principal::User(user))
+    } else if accepts_admin {
+        Err(ApiError::forbidden("Admin access required"))
+    } else {
+        Err(ApiError::forbidden("This endpoint requires a business API key"))
+    }
+}
+
+/// The credential in an `authorization: Bearer <token>` header.
+pub fn bearer_token(auth_header: Option<&str>) -> ApiResult<&str> {
+    let auth_header = auth_header
+        .ok_or_else(|| ApiError::unauthorized("Missing authorization header"))?;
+
+    auth_header.strip_prefix("Bearer ")
+        .ok_or_else(|| ApiError::unauthorized("Invalid authorization header format"))
+}
+
+/// The session an access token belongs to.
+pub fn session_id_of(token: &str) -> ApiResult<String> {
+    Ok(decode_claims(token)?.session_id)
+}
+
+/// Logs the session holding `token` out.
+pub async fn invalidate_session(pool: &AnyPool, token: &str) -> ApiResult<()> {
+    let claims = decode_claims(token)?;
     
     sqlx::query("UPDATE user_sessions SET active = FALSE WHERE id = $1")
-        .bind(&token_data.claims.session_id)
+        .bind(&claims.session_id)
         .execute(pool)
         .await
         .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to invalidate session: {}", e)))?;
@@ -164,15 +330,138 @@ pub async fn invalidate_session(pool: &AnyPool, token: &str) -> Result<()> {
     Ok(())
 }
 
-pub async fn cleanup_expired_sessions(pool: &AnyPool) -> Result<()> {
+/// The user's sessions that can still be used, newest first.
+pub async fn list_sessions(pool: &AnyPool, user_id: Uuid, current_session_id: &str) -> Result<Vec<SessionInfo>> {
+    let rows = sqlx::query(
+        "SELECT id, created_at, expires_at, last_used_at FROM user_sessions 
+         WHERE user_id = $1 AND active = TRUE AND expires_at > $2 ORDER BY created_at DESC"
+    )
+    .bind(user_id.to_string())
+    .bind(Utc::now().to_rfc3339())
+    .fetch_all(pool)
+    .await
+    .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to list sessions: {}", e)))?;
+    
+    let parse_time = |value: String| {
+        chrono::DateTime::parse_from_rfc3339(&value)
+            .map(|t| t.with_timezone(&Utc))
+            .map_err(|e| gurtlib::GurtError::invalid_message(format!("Invalid timestamp {}: {}", value, e)))
+    };
+    rows.iter().map(|row| {
+        let session_id: String = row.get("id");
+        Ok(SessionInfo {
+            current: session_id == current_session_id,
+            session_id,
+            created_at: parse_time(row.get("created_at"))?,
+            expires_at: parse_time(row.get("expires_at"))?,
+            last_used_at: row.get::<Option<String>, _>("last_used_at").map(parse_time).transpose()?,
+        })
+    }).collect()
+}
+
+/// Logs out one of the user's sessions.
+pub async fn revoke_session(pool: &AnyPool, user_id: Uuid, session_id: &str) -> ApiResult<()> {
+    let result = sqlx::query("UPDATE user_sessions SET active = FALSE WHERE id = $1 AND user_id = $2 AND active = TRUE")
+        .bind(session_id)
+        .bind(user_id.to_string())
+        .execute(pool)
+        .await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to revoke session: {}", e)))?;
+    
+    if result.rows_affected() == 0 {
+        return Err(ApiError::not_found("Session not found"));
+    }
+    Ok(())
+}
+
+/// Logs out every one of the user's sessions except `keep_session_id`.
+/// Returns how many were revoked.
+pub async fn revoke_other_sessions(pool: &AnyPool, user_id: Uuid, keep_session_id: &str) -> Result<u64> {
+    let mut conn = pool.acquire().await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to get connection: {}", e)))?;
+    revoke_sessions_on(&mut conn, user_id, Some(keep_session_id)).await
+}
+
+/// Logs out the user's sessions, all of them or all but `keep_session_id`.
+/// Returns how many were revoked.
+pub(crate) async fn revoke_sessions_on(conn: &mut AnyConnection, user_id: Uuid, keep_session_id: Option<&str>) -> Result<u64> {
+    let result = match keep_session_id {
+        Some(keep) => sqlx::query("UPDATE user_sessions SET active = FALSE WHERE user_id = $1 AND id <> $2 AND active = TRUE")
+            .bind(user_id.to_string())
+            .bind(keep)
+            .execute(&mut *conn)
+            .await,
+        None => sqlx::query("UPDATE user_sessions SET active = FALSE WHERE user_id = $1 AND active = TRUE")
+            .bind(user_id.to_string())
+            .execute(&mut *conn)
+            .await,
+    }
+    .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to revoke sessions: {}", e)))?;
+    
+    Ok(result.rows_affected())
+}
+
+/// Marks sessions past their expiry inactive. Returns how many were.
+pub async fn cleanup_expired_sessions(pool: &AnyPool) -> Result<u64> {
     let now = Utc::now();
-    sqlx::query("UPDATE user_sessions SET active = FALSE WHERE expires_at < ? AND active = TRUE")
+    let result = sqlx::query("UPDATE user_sessions SET active = FALSE WHERE expires_at < $1 AND active = TRUE")
         .bind(now.to_rfc3339())
         .execute(pool)
         .await
         .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to cleanup sessions: {}", e)))?;
     
-    Ok(())
+    Ok(result.rows_affected())
+}
+
+/// Starts the background task that retires expired sessions.
+pub fn spawn_session_cleanup(pool: AnyPool) {
+    tokio::spawn(async move {
+        loop {
+            match cleanup_expired_sessions(&pool).await {
+                Ok(0) => {}
+                Ok(count) => tracing::info!("Expired {} sessions", count),
+                Err(e) => tracing::warn!("Session cleanup failed: {}", e),
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(CLEANUP_INTERVAL_SECS)).await;
+        }
+    });
+}
+
+fn issue_access_token(user_id: &str, username: &str, session_id: &str) -> Result<(String, chrono::DateTime<Utc>)> {
+    let now = Utc::now();
+    let expires_at = now + Duration::minutes(ACCESS_TOKEN_MINUTES);
+    
+    let claims = SessionClaims {
+        sub: user_id.to_string(),
+        username: username.to_string(),
+        exp: expires_at.timestamp(),
+        iat: now.timestamp(),
+        session_id: session_id.to_string(),
+    };
+    
+    let jwt = jwt_keys::sign(&claims)?;
+    
+    Ok((jwt, expires_at))
+}
+
+fn decode_claims(token: &str) -> ApiResult<SessionClaims> {
+    jwt_keys::verify(token)
+}
+
+fn generate_refresh_token() -> String {
+    use rand::Rng;
+    let token: String = rand::thread_rng()
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect();
+    format!("rt_{}", token)
+}
+
+/// Refresh tokens are stored hashed, so a leaked database can't be used to log in.
+fn hash_refresh_token(token: &str) -> String {
+    use sha2::{Digest, Sha256};
+    format!("{:x}", Sha256::digest(token.as_bytes()))
 }
 
 pub fn generate_code() -> String {
@@ -190,3 +479,21 @@ pub fn generate_code() -> String {
     
     format!("GC-{}-{}", letters.to_uppercase(), numbers)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_against_the_stored_hash() {
+        let stored = hash_password("correct horse").unwrap();
+        assert!(verify_password_or_dummy("correct horse", Some(&stored)).unwrap());
+        assert!(!verify_password_or_dummy("wrong horse", Some(&stored)).unwrap());
+    }
+
+    #[test]
+    fn rejects_unknown_users_even_with_the_dummy_password() {
+        assert!(!verify_password_or_dummy("anything", None).unwrap());
+        assert!(!verify_password_or_dummy("gurtpay-no-such-user", None).unwrap());
+    }
+}
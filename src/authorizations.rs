@@ -0,0 +1,461 @@
+use crate::database::record_business_payment;
+use crate::error::{ApiError, ApiResult};
+use crate::limits;
+use crate::models::Transaction;
+use crate::money::Money;
+use crate::webhooks::{self, Event};
+use chrono::{DateTime, Utc};
+use gurtlib::{GurtError, Result};
+use serde::Serialize;
+use sqlx::{AnyConnection, AnyPool, Row};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How long a hold lasts when the merchant doesn't ask for something else.
+pub const DEFAULT_EXPIRY_HOURS: i64 = 7 * 24;
+pub const MAX_EXPIRY_HOURS: i64 = 30 * 24;
+const SWEEP_INTERVAL_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthorizationStatus {
+    Authorized,
+    Captured,
+    Voided,
+    Expired,
+}
+
+impl AuthorizationStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuthorizationStatus::Authorized => "authorized",
+            AuthorizationStatus::Captured => "captured",
+            AuthorizationStatus::Voided => "voided",
+            AuthorizationStatus::Expired => "expired",
+        }
+    }
+
+    fn parse(s: &str) -> Option<AuthorizationStatus> {
+        match s {
+            "authorized" => Some(AuthorizationStatus::Authorized),
+            "captured" => Some(AuthorizationStatus::Captured),
+            "voided" => Some(AuthorizationStatus::Voided),
+            "expired" => Some(AuthorizationStatus::Expired),
+            _ => None,
+        }
+    }
+}
+
+/// Funds reserved on a cardholder's wallet for a business. While `authorized`
+/// the amount counts against the user's available balance through
+/// `users.held_balance`; capturing, voiding or expiring releases it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Authorization {
+    pub id: Uuid,
+    pub business_id: Uuid,
+    pub amount: Money,
+    pub captured_amount: Option<Money>,
+    pub status: AuthorizationStatus,
+    pub description: String,
+    pub transaction_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Places a hold of `amount` on the user's wallet. Fails with insufficient
+/// funds if that is more than the user has available.
+pub async fn authorize(
+    pool: &AnyPool,
+    business_id: Uuid,
+    user_id: Uuid,
+    card_id: Uuid,
+    amount: Money,
+    description: &str,
+    expires_in_hours: i64,
+) -> ApiResult<Authorization> {
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    // Guarded like a ledger debit, so concurrent holds can't oversubscribe the wallet
+    let reserved = sqlx::query(
+        "UPDATE users SET held_balance = held_balance + $1 WHERE id = $2 AND wallet_balance - held_balance >= $3"
+    )
+    .bind(amount.minor())
+    .bind(user_id.to_string())
+    .bind(amount.minor())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to reserve funds: {}", e)))?;
+    if reserved.rows_affected() == 0 {
+        return Err(ApiError::insufficient_funds("Insufficient balance"));
+    }
+    // Checked again at capture; this stops a hold the limits would never let through
+    limits::enforce(&mut tx, user_id, amount, None).await?;
+
+    let created_at = Utc::now();
+    let authorization = Authorization {
+        id: Uuid::new_v4(),
+        business_id,
+        amount,
+        captured_amount: None,
+        status: AuthorizationStatus::Authorized,
+        description: description.to_string(),
+        transaction_id: None,
+        created_at,
+        expires_at: created_at + chrono::Duration::hours(expires_in_hours),
+    };
+
+    sqlx::query(
+        "INSERT INTO card_authorizations (id, business_id, user_id, card_id, amount, status, description, created_at, expires_at) \
+         VALUES ($1, $2, $3, $4, $5, 'authorized', $6, $7, $8)"
+    )
+    .bind(authorization.id.to_string())
+    .bind(business_id.to_string())
+    .bind(user_id.to_string())
+    .bind(card_id.to_string())
+    .bind(amount.minor())
+    .bind(description)
+    .bind(created_at.to_rfc3339())
+    .bind(authorization.expires_at.to_rfc3339())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to create authorization: {}", e)))?;
+
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit authorization: {}", e)))?;
+
+    Ok(authorization)
+}
+
+/// Charges up to the authorized amount and releases the rest of the hold.
+/// `amount` defaults to the full authorization; an authorization can only be
+/// captured once.
+pub async fn capture(pool: &AnyPool, business_id: Uuid, authorization_id: Uuid, amount: Option<Money>) -> ApiResult<(Authorization, Transaction)> {
+    let (mut authorization, user_id) = open_authorization(pool, business_id, authorization_id).await?;
+
+    let amount = amount.unwrap_or(authorization.amount);
+    if !amount.is_positive() {
+        return Err(ApiError::validation("Capture amount must be positive"));
+    }
+    if amount > authorization.amount {
+        return Err(ApiError::validation(format!("Capture amount exceeds the authorized {} GC", authorization.amount)));
+    }
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    let now = Utc::now();
+    let transaction_id = Uuid::new_v4();
+    if !close(&mut tx, &authorization, user_id, AuthorizationStatus::Captured, Some((amount, transaction_id)), now).await? {
+        return Err(ApiError::conflict("Authorization is no longer open"));
+    }
+
+    // The hold is already released, so the capture spends from the available balance it freed up
+    let transaction = record_business_payment(&mut tx, transaction_id, &user_id, &business_id, amount, &authorization.description).await?;
+    limits::enforce(&mut tx, user_id, amount, Some(transaction_id)).await?;
+
+    webhooks::enqueue(&mut tx, business_id, Event::PaymentSucceeded, serde_json::json!({
+        "transaction_id": transaction.id,
+        "authorization_id": authorization.id,
+        "amount": amount,
+        "description": authorization.description,
+        "created_at": transaction.created_at.to_rfc3339(),
+    })).await?;
+
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit capture: {}", e)))?;
+
+    authorization.status = AuthorizationStatus::Captured;
+    authorization.captured_amount = Some(amount);
+    authorization.transaction_id = Some(transaction.id);
+    Ok((authorization, transaction))
+}
+
+/// Cancels the authorization and gives the held funds back to the user.
+pub async fn void(pool: &AnyPool, business_id: Uuid, authorization_id: Uuid) -> ApiResult<Authorization> {
+    let (mut authorization, user_id) = open_authorization(pool, business_id, authorization_id).await?;
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+    if !close(&mut tx, &authorization, user_id, AuthorizationStatus::Voided, None, Utc::now()).await? {
+        return Err(ApiError::conflict("Authorization is no longer open"));
+    }
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit void: {}", e)))?;
+
+    authorization.status = AuthorizationStatus::Voided;
+    Ok(authorization)
+}
+
+/// Releases every hold whose expiry has passed. Returns how many were expired.
+pub async fn expire_due(pool: &AnyPool) -> Result<u64> {
+    let now = Utc::now();
+    let rows = sqlx::query(&format!("{} WHERE status = 'authorized' AND expires_at <= $1", SELECT_AUTHORIZATION))
+        .bind(now.to_rfc3339())
+        .fetch_all(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to find expired authorizations: {}", e)))?;
+
+    let mut expired = 0;
+    for row in rows {
+        let (authorization, user_id) = from_row(&row)?;
+        let mut tx = pool.begin().await
+            .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+        if close(&mut tx, &authorization, user_id, AuthorizationStatus::Expired, None, now).await? {
+            expired += 1;
+        }
+        tx.commit().await
+            .map_err(|e| GurtError::invalid_message(format!("Failed to commit expiry: {}", e)))?;
+    }
+    Ok(expired)
+}
+
+/// Starts the background task that releases expired holds.
+pub fn spawn_expiry_sweeper(pool: AnyPool) {
+    tokio::spawn(async move {
+        loop {
+            match expire_due(&pool).await {
+                Ok(0) => {}
+                Ok(count) => tracing::info!("Expired {} card authorizations", count),
+                Err(e) => tracing::warn!("Authorization expiry sweep failed: {}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(SWEEP_INTERVAL_SECS)).await;
+        }
+    });
+}
+
+const SELECT_AUTHORIZATION: &str =
+    "SELECT id, business_id, user_id, amount, captured_amount, status, description, transaction_id, created_at, expires_at \
+     FROM card_authorizations";
+
+/// Loads an authorization the business can still capture or void. One that has
+/// passed its expiry but not been swept yet is expired on the spot.
+async fn open_authorization(pool: &AnyPool, business_id: Uuid, authorization_id: Uuid) -> ApiResult<(Authorization, Uuid)> {
+    let row = sqlx::query(&format!("{} WHERE id = $1 AND business_id = $2", SELECT_AUTHORIZATION))
+        .bind(authorization_id.to_string())
+        .bind(business_id.to_string())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to get authorization: {}", e)))?
+        .ok_or_else(|| ApiError::not_found("Authorization not found"))?;
+    let (authorization, user_id) = from_row(&row)?;
+
+    if authorization.status != AuthorizationStatus::Authorized {
+        return Err(ApiError::conflict(format!("Authorization is already {}", authorization.status.as_str())));
+    }
+
+    let now = Utc::now();
+    if authorization.expires_at <= now {
+        let mut tx = pool.begin().await
+            .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+        close(&mut tx, &authorization, user_id, AuthorizationStatus::Expired, None, now).await?;
+        tx.commit().await
+            .map_err(|e| GurtError::invalid_message(format!("Failed to commit expiry: {}", e)))?;
+        return Err(ApiError::conflict("Authorization has expired"));
+    }
+
+    Ok((authorization, user_id))
+}
+
+/// Moves an open authorization to `status` and releases its hold. The status
+/// change is a compare-and-set, so only one of several racing closes wins;
+/// returns false for the losers.
+async fn close(
+    conn: &mut AnyConnection,
+    authorization: &Authorization,
+    user_id: Uuid,
+    status: AuthorizationStatus,
+    capture: Option<(Money, Uuid)>,
+    now: DateTime<Utc>,
+) -> Result<bool> {
+    // Captures and voids must land before the expiry; the sweeper only after it
+    let expiry_guard = match status {
+        AuthorizationStatus::Expired => "expires_at <= $6",
+        _ => "expires_at > $6",
+    };
+    let closed = sqlx::query(&format!(
+        "UPDATE card_authorizations SET status = $1, captured_amount = $2, transaction_id = $3, closed_at = $4 \
+         WHERE id = $5 AND status = 'authorized' AND {}",
+        expiry_guard
+    ))
+    .bind(status.as_str())
+    .bind(capture.map(|(amount, _)| amount.minor()))
+    .bind(capture.map(|(_, transaction_id)| transaction_id.to_string()))
+    .bind(now.to_rfc3339())
+    .bind(authorization.id.to_string())
+    .bind(now.to_rfc3339())
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to update authorization: {}", e)))?;
+    if closed.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    sqlx::query("UPDATE users SET held_balance = held_balance - $1 WHERE id = $2")
+        .bind(authorization.amount.minor())
+        .bind(user_id.to_string())
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to release held funds: {}", e)))?;
+
+    Ok(true)
+}
+
+fn from_row(row: &sqlx::any::AnyRow) -> Result<(Authorization, Uuid)> {
+    let parse_uuid = |column: &str| {
+        Uuid::parse_str(&row.get::<String, _>(column))
+            .map_err(|e| GurtError::invalid_message(format!("Invalid {} on authorization: {}", column, e)))
+    };
+    let parse_time = |column: &str| {
+        DateTime::parse_from_rfc3339(&row.get::<String, _>(column))
+            .map(|t| t.with_timezone(&Utc))
+            .map_err(|e| GurtError::invalid_message(format!("Invalid {} on authorization: {}", column, e)))
+    };
+    let status = row.get::<String, _>("status");
+
+    let authorization = Authorization {
+        id: parse_uuid("id")?,
+        business_id: parse_uuid("business_id")?,
+        amount: Money::from_minor(row.get("amount")),
+        captured_amount: row.get::<Option<i64>, _>("captured_amount").map(Money::from_minor),
+        status: AuthorizationStatus::parse(&status)
+            .ok_or_else(|| GurtError::invalid_message(format!("Unknown authorization status: {}", status)))?,
+        description: row.get("description"),
+        transaction_id: row.get::<Option<String>, _>("transaction_id").and_then(|id| Uuid::parse_str(&id).ok()),
+        created_at: parse_time("created_at")?,
+        expires_at: parse_time("expires_at")?,
+    };
+    Ok((authorization, parse_uuid("user_id")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Principal;
+    use crate::database::{create_user_with_password, get_user_by_id, test_business, test_pool, WELCOME_BONUS};
+    use crate::handlers::handle_get_balance;
+    use crate::models::User;
+    use crate::AppState;
+    use gurtlib::{GurtMethod, GurtRequest, ServerContext};
+
+    async fn cardholder(pool: &AnyPool) -> User {
+        create_user_with_password(pool, &format!("cardholder-{}", Uuid::new_v4()), "unused").await.unwrap()
+    }
+
+    async fn hold(pool: &AnyPool, business_id: Uuid, user_id: Uuid, amount: Money) -> Authorization {
+        authorize(pool, business_id, user_id, Uuid::new_v4(), amount, "Hotel deposit", DEFAULT_EXPIRY_HOURS).await.unwrap()
+    }
+
+    async fn held(pool: &AnyPool, user_id: Uuid) -> Money {
+        Money::from_minor(
+            sqlx::query("SELECT held_balance FROM users WHERE id = $1")
+                .bind(user_id.to_string())
+                .fetch_one(pool)
+                .await
+                .unwrap()
+                .get("held_balance"),
+        )
+    }
+
+    async fn wallet(pool: &AnyPool, user_id: Uuid) -> Money {
+        get_user_by_id(pool, user_id).await.unwrap().unwrap().wallet_balance
+    }
+
+    /// `available_balance` as the balance endpoint reports it.
+    async fn available(pool: &AnyPool, user_id: Uuid) -> Money {
+        let user = get_user_by_id(pool, user_id).await.unwrap().unwrap();
+        let ctx = ServerContext {
+            remote_addr: "127.0.0.1:4878".parse().unwrap(),
+            request: GurtRequest::new(GurtMethod::GET, "/api/wallet/balance".to_string()),
+        };
+        let response = handle_get_balance(&AppState { db: pool.clone() }, &ctx, Principal::User(user)).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        serde_json::from_value(body["available_balance"].clone()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn holds_reserve_funds_until_voided() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user = cardholder(&pool).await;
+
+        let authorization = hold(&pool, business_id, user.id, Money::from_major(1200)).await;
+        assert_eq!(held(&pool, user.id).await, Money::from_major(1200));
+        assert_eq!(available(&pool, user.id).await, Money::from_major(3800));
+        assert_eq!(wallet(&pool, user.id).await, WELCOME_BONUS);
+
+        let voided = void(&pool, business_id, authorization.id).await.unwrap();
+        assert_eq!(voided.status, AuthorizationStatus::Voided);
+        assert_eq!(held(&pool, user.id).await, Money::ZERO);
+        assert_eq!(available(&pool, user.id).await, WELCOME_BONUS);
+    }
+
+    #[tokio::test]
+    async fn holds_cant_exceed_the_available_balance() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user = cardholder(&pool).await;
+        hold(&pool, business_id, user.id, Money::from_major(3000)).await;
+
+        let second = authorize(&pool, business_id, user.id, Uuid::new_v4(), Money::from_major(2001), "Second", DEFAULT_EXPIRY_HOURS).await;
+
+        assert!(matches!(second, Err(ApiError::InsufficientFunds(_))));
+        assert_eq!(held(&pool, user.id).await, Money::from_major(3000));
+    }
+
+    #[tokio::test]
+    async fn partial_capture_charges_and_releases_the_whole_hold() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user = cardholder(&pool).await;
+        let authorization = hold(&pool, business_id, user.id, Money::from_major(100)).await;
+
+        let (captured, transaction) = capture(&pool, business_id, authorization.id, Some(Money::from_major(60))).await.unwrap();
+
+        assert_eq!(captured.status, AuthorizationStatus::Captured);
+        assert_eq!(captured.captured_amount, Some(Money::from_major(60)));
+        assert_eq!(transaction.amount, Money::from_major(60));
+        assert_eq!(held(&pool, user.id).await, Money::ZERO);
+        assert_eq!(wallet(&pool, user.id).await, Money::from_major(4940));
+        assert!(matches!(void(&pool, business_id, authorization.id).await, Err(ApiError::Conflict(_))));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn capture_racing_void_closes_once() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user = cardholder(&pool).await;
+        let authorization = hold(&pool, business_id, user.id, Money::from_major(100)).await;
+
+        let (captured, voided) = tokio::join!(
+            tokio::spawn({
+                let pool = pool.clone();
+                async move { capture(&pool, business_id, authorization.id, None).await }
+            }),
+            tokio::spawn({
+                let pool = pool.clone();
+                async move { void(&pool, business_id, authorization.id).await }
+            }),
+        );
+        let (captured, voided) = (captured.unwrap(), voided.unwrap());
+
+        assert_ne!(captured.is_ok(), voided.is_ok());
+        assert!(matches!(captured, Ok(_) | Err(ApiError::Conflict(_))));
+        assert!(matches!(voided, Ok(_) | Err(ApiError::Conflict(_))));
+        assert_eq!(held(&pool, user.id).await, Money::ZERO);
+        let charged = if captured.is_ok() { Money::from_major(100) } else { Money::ZERO };
+        assert_eq!(wallet(&pool, user.id).await, WELCOME_BONUS.checked_sub(charged).unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_holds_are_released() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user = cardholder(&pool).await;
+        let authorization = authorize(&pool, business_id, user.id, Uuid::new_v4(), Money::from_major(100), "Short", 0).await.unwrap();
+
+        assert_eq!(expire_due(&pool).await.unwrap(), 1);
+        assert_eq!(held(&pool, user.id).await, Money::ZERO);
+        assert!(matches!(capture(&pool, business_id, authorization.id, None).await, Err(ApiError::Conflict(_))));
+    }
+}
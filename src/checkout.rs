@@ -0,0 +1,151 @@
+use crate::error::{ApiError, ApiResult};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use url::Url;
+use uuid::Uuid;
+
+/// Query parameters appended to a return URL. The signature covers
+/// `"<invoice_id>.<status>"` and is keyed with the business's return secret, so
+/// only GurtPay and the merchant can produce it.
+///
+/// `paid` and `cancelled` are only signed once the invoice really is paid, or
+/// cancelled or expired. A customer who walks away from an invoice that is
+/// still pending is sent back with `abandoned` and no signature, since the
+/// invoice can still be paid after they leave.
+const INVOICE_PARAM: &str = "gurtpay_invoice_id";
+const STATUS_PARAM: &str = "gurtpay_status";
+const SIGNATURE_PARAM: &str = "gurtpay_signature";
+const ABANDONED_STATUS: &str = "abandoned";
+
+/// Checks that `url` points at the business's own site: the host of its
+/// `website_url` or a subdomain of it. Returns the normalized URL.
+pub fn validate_return_url(field: &str, url: &str, website_url: &str) -> ApiResult<String> {
+    let parsed = Url::parse(url.trim())
+        .map_err(|e| ApiError::validation(format!("Invalid {}: {}", field, e)))?;
+    if !matches!(parsed.scheme(), "gurt" | "http" | "https") {
+        return Err(ApiError::validation(format!("{} must be a gurt://, http:// or https:// URL", field)));
+    }
+
+    let site_host = Url::parse(website_url.trim()).ok()
+        .and_then(|site| site.host_str().map(str::to_lowercase))
+        .ok_or_else(|| ApiError::validation(format!("Your website_url has no domain to check {} against", field)))?;
+    let host = parsed.host_str().map(str::to_lowercase).unwrap_or_default();
+
+    if host != site_host && !host.ends_with(&format!(".{}", site_host)) {
+        return Err(ApiError::validation(format!("{} must be on {} or one of its subdomains", field, site_host)));
+    }
+    Ok(parsed.to_string())
+}
+
+/// `url` with the invoice id, status and signature appended to its query.
+pub fn signed_return_url(url: &str, invoice_id: Uuid, status: &str, return_secret: &str) -> ApiResult<String> {
+    let mut url = return_url(url, invoice_id, status)?;
+    url.query_pairs_mut().append_pair(SIGNATURE_PARAM, &signature(invoice_id, status, return_secret));
+    Ok(url.to_string())
+}
+
+/// `url` for a customer leaving a pending invoice: the invoice id and an
+/// unsigned `abandoned` status.
+pub fn abandoned_return_url(url: &str, invoice_id: Uuid) -> ApiResult<String> {
+    Ok(return_url(url, invoice_id, ABANDONED_STATUS)?.to_string())
+}
+
+fn return_url(url: &str, invoice_id: Uuid, status: &str) -> ApiResult<Url> {
+    let mut url = Url::parse(url)
+        .map_err(|e| ApiError::internal(format!("Stored return URL is invalid: {}", e)))?;
+    url.query_pairs_mut()
+        .append_pair(INVOICE_PARAM, &invoice_id.to_string())
+        .append_pair(STATUS_PARAM, status);
+    Ok(url)
+}
+
+/// A new business's return secret. Kept apart from the API key, which goes out
+/// with every API call and would let anyone who saw it forge a paid return.
+pub fn generate_return_secret() -> String {
+    let secret: String = rand::thread_rng()
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    format!("rsec_{}", secret)
+}
+
+fn signature(invoice_id: Uuid, status: &str, return_secret: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(return_secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(invoice_id.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(status.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(url: &str) -> Vec<(String, String)> {
+        Url::parse(url).unwrap().query_pairs().into_owned().collect()
+    }
+
+    #[test]
+    fn signs_return_urls_with_the_return_secret() {
+        let invoice_id = Uuid::new_v4();
+        let secret = generate_return_secret();
+        let url = signed_return_url("gurt://shop.real/thanks?order=7", invoice_id, "paid", &secret).unwrap();
+
+        let mut expected = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        expected.update(format!("{}.paid", invoice_id).as_bytes());
+        assert_eq!(query(&url), vec![
+            ("order".to_string(), "7".to_string()),
+            (INVOICE_PARAM.to_string(), invoice_id.to_string()),
+            (STATUS_PARAM.to_string(), "paid".to_string()),
+            (SIGNATURE_PARAM.to_string(), format!("{:x}", expected.finalize().into_bytes())),
+        ]);
+    }
+
+    #[test]
+    fn abandoned_return_urls_are_unsigned() {
+        let invoice_id = Uuid::new_v4();
+        let url = abandoned_return_url("gurt://shop.real/cart", invoice_id).unwrap();
+
+        assert_eq!(query(&url), vec![
+            (INVOICE_PARAM.to_string(), invoice_id.to_string()),
+            (STATUS_PARAM.to_string(), "abandoned".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn signatures_depend_on_secret_invoice_and_status() {
+        let invoice_id = Uuid::new_v4();
+        let paid = signature(invoice_id, "paid", "rsec_a");
+        assert_eq!(paid, signature(invoice_id, "paid", "rsec_a"));
+        assert_ne!(paid, signature(invoice_id, "paid", "rsec_b"));
+        assert_ne!(paid, signature(invoice_id, "cancelled", "rsec_a"));
+        assert_ne!(paid, signature(Uuid::new_v4(), "paid", "rsec_a"));
+    }
+
+    #[test]
+    fn generates_distinct_secrets() {
+        let secret = generate_return_secret();
+        assert!(secret.starts_with("rsec_"));
+        assert_eq!(secret.len(), 37);
+        assert_ne!(secret, generate_return_secret());
+    }
+
+    #[test]
+    fn accepts_return_urls_on_the_business_site() {
+        let site = "https://Shop.real/";
+        assert!(validate_return_url("success_url", "gurt://shop.real/thanks", site).is_ok());
+        assert!(validate_return_url("success_url", "https://pay.shop.real/done", site).is_ok());
+    }
+
+    #[test]
+    fn rejects_return_urls_elsewhere() {
+        let site = "https://shop.real";
+        for url in ["gurt://evil.real/", "gurt://evilshop.real/", "gurt://shop.real.evil.real/", "javascript:alert(1)", "not a url"] {
+            assert!(matches!(validate_return_url("success_url", url, site), Err(ApiError::Validation(_))), "{}", url);
+        }
+        assert!(validate_return_url("success_url", "gurt://shop.real/", "").is_err());
+    }
+}
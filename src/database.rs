@@ -1,9 +1,18 @@
-use sqlx::{AnyPool, Row};
+use sqlx::{any::AnyPoolOptions, AnyPool, Row};
+use std::time::Duration;
 use uuid::Uuid;
 use chrono::{Utc, Datelike};
 use crate::models::*;
+use crate::money::Money;
+use crate::ledger::{self, Account, JournalEntry};
+use crate::{limits, migrations, payment_links};
+use crate::webhooks::{self, Event};
+use crate::error::{ApiError, ApiResult};
 use gurtlib::Result;
 
+pub const WELCOME_BONUS: Money = Money::from_major(5000);
+const INVOICE_SWEEP_INTERVAL_SECS: u64 = 60;
+
 pub async fn get_database_pool() -> Result<AnyPool> {
     let db_url = std::env::var("DATABASE_URL")
         .or_else(|_| std::env::var("DATABASE_PATH"))
@@ -22,12 +31,67 @@ pub async fn get_database_pool() -> Result<AnyPool> {
         format!("sqlite:{}", db_url)
     };
 
-    let pool = AnyPool::connect(&conn_string).await
+    // Built once at startup and shared by every handler through AppState
+    let pool = AnyPoolOptions::new()
+        .max_connections(env_or("DB_MAX_CONNECTIONS", 10))
+        .acquire_timeout(Duration::from_secs(env_or("DB_ACQUIRE_TIMEOUT_SECS", 30)))
+        .idle_timeout(Duration::from_secs(env_or("DB_IDLE_TIMEOUT_SECS", 600)))
+        .connect(&conn_string)
+        .await
         .map_err(|e| gurtlib::GurtError::invalid_message(format!("Database connection failed: {}", e)))?;
     Ok(pool)
 }
 
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
 pub async fn init_database() -> Result<AnyPool> {
+    let pool = open_database().await?;
+    
+    migrations::migrate(&pool).await?;
+
+    Ok(pool)
+}
+
+/// A fresh, migrated in-memory SQLite database for tests. The connections share
+/// one named database, which lives as long as the pool keeps one open.
+#[cfg(test)]
+pub async fn test_pool() -> AnyPool {
+    sqlx::any::install_default_drivers();
+    let pool = AnyPoolOptions::new()
+        .max_connections(4)
+        .min_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect(&format!("sqlite:file:gurtpay-test-{}?mode=memory&cache=shared", Uuid::new_v4()))
+        .await
+        .expect("test database");
+    migrations::migrate(&pool).await.expect("test migrations");
+    pool
+}
+
+/// A new user owning a new business, for tests that need one. Returns the
+/// business id.
+#[cfg(test)]
+pub async fn test_business(pool: &AnyPool) -> Uuid {
+    let user = create_user_with_password(pool, &format!("owner-{}", Uuid::new_v4()), "unused").await.expect("test user");
+    let business_id = Uuid::new_v4();
+    sqlx::query("INSERT INTO businesses (id, user_id, business_name, api_key, return_secret, created_at) VALUES ($1, $2, $3, $4, $5, $6)")
+        .bind(business_id.to_string())
+        .bind(user.id.to_string())
+        .bind("Test Shop")
+        .bind(format!("gp_{}", business_id.simple()))
+        .bind(crate::checkout::generate_return_secret())
+        .bind(Utc::now().to_rfc3339())
+        .execute(pool)
+        .await
+        .expect("test business");
+    business_id
+}
+
+/// Connects without migrating, creating the default SQLite file if needed.
+pub async fn open_database() -> Result<AnyPool> {
     if std::env::var("DATABASE_URL").is_err() && std::env::var("DATABASE_PATH").is_err() {
         let db_path = std::env::current_dir()
             .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to get current directory: {}", e)))?
@@ -40,237 +104,28 @@ pub async fn init_database() -> Result<AnyPool> {
         }
     }
 
-    let pool = get_database_pool().await?;
-    
-    // Create tables
-    sqlx::query(r#"
-        CREATE TABLE IF NOT EXISTS users (
-            id TEXT PRIMARY KEY,
-            arsonflare_id TEXT UNIQUE NOT NULL,
-            username TEXT NOT NULL,
-            wallet_balance DOUBLE PRECISION DEFAULT 0.0,
-            wallet_address TEXT UNIQUE NOT NULL,
-            created_at TEXT NOT NULL,
-            is_admin BOOLEAN DEFAULT FALSE
-        )
-    "#).execute(&pool).await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create users table: {}", e)))?;
-
-    sqlx::query(r#"
-        CREATE TABLE IF NOT EXISTS user_credentials (
-            user_id TEXT PRIMARY KEY,
-            password_hash TEXT NOT NULL,
-            FOREIGN KEY (user_id) REFERENCES users (id)
-        )
-    "#).execute(&pool).await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create user_credentials table: {}", e)))?;
-
-    sqlx::query(r#"
-        CREATE TABLE IF NOT EXISTS businesses (
-            id TEXT PRIMARY KEY,
-            user_id TEXT NOT NULL,
-            business_name TEXT NOT NULL,
-            website_url TEXT,
-            api_key TEXT UNIQUE NOT NULL,
-            verified BOOLEAN DEFAULT TRUE,
-            balance DOUBLE PRECISION DEFAULT 0.0,
-            created_at TEXT NOT NULL,
-            FOREIGN KEY (user_id) REFERENCES users (id)
-        )
-    "#).execute(&pool).await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create businesses table: {}", e)))?;
-
-    sqlx::query(r#"
-        CREATE TABLE IF NOT EXISTS transactions (
-            id TEXT PRIMARY KEY,
-            transaction_type TEXT NOT NULL,
-            from_user_id TEXT,
-            to_user_id TEXT,
-            business_id TEXT,
-            amount DOUBLE PRECISION NOT NULL,
-            platform_fee DOUBLE PRECISION DEFAULT 0.0,
-            status TEXT DEFAULT 'completed',
-            description TEXT NOT NULL,
-            created_at TEXT NOT NULL,
-            completed_at TEXT,
-            FOREIGN KEY (from_user_id) REFERENCES users (id),
-            FOREIGN KEY (to_user_id) REFERENCES users (id),
-            FOREIGN KEY (business_id) REFERENCES businesses (id)
-        )
-    "#).execute(&pool).await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create transactions table: {}", e)))?;
-
-    sqlx::query(r#"
-        CREATE TABLE IF NOT EXISTS redemption_codes (
-            id TEXT PRIMARY KEY,
-            code TEXT UNIQUE NOT NULL,
-            amount DOUBLE PRECISION NOT NULL,
-            max_uses INTEGER,
-            current_uses INTEGER DEFAULT 0,
-            created_by TEXT NOT NULL,
-            created_at TEXT NOT NULL,
-            expires_at TEXT,
-            active BOOLEAN DEFAULT TRUE,
-            FOREIGN KEY (created_by) REFERENCES users (id)
-        )
-    "#).execute(&pool).await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create redemption_codes table: {}", e)))?;
-
-    sqlx::query(r#"
-        CREATE TABLE IF NOT EXISTS code_redemptions (
-            id TEXT PRIMARY KEY,
-            code_id TEXT NOT NULL,
-            user_id TEXT NOT NULL,
-            amount_received DOUBLE PRECISION NOT NULL,
-            redeemed_at TEXT NOT NULL,
-            FOREIGN KEY (code_id) REFERENCES redemption_codes (id),
-            FOREIGN KEY (user_id) REFERENCES users (id),
-            UNIQUE(code_id, user_id)
-        )
-    "#).execute(&pool).await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create code_redemptions table: {}", e)))?;
-
-    sqlx::query(r#"
-        CREATE TABLE IF NOT EXISTS invoices (
-            id TEXT PRIMARY KEY,
-            business_id TEXT NOT NULL,
-            amount DOUBLE PRECISION NOT NULL,
-            description TEXT NOT NULL,
-            customer_name TEXT,
-            status TEXT DEFAULT 'pending',
-            paid_at TEXT,
-            created_at TEXT NOT NULL,
-            expires_at TEXT,
-            FOREIGN KEY (business_id) REFERENCES businesses (id)
-        )
-    "#).execute(&pool).await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create invoices table: {}", e)))?;
-
-    sqlx::query(r#"
-        CREATE TABLE IF NOT EXISTS user_sessions (
-            id TEXT PRIMARY KEY,
-            user_id TEXT NOT NULL,
-            jwt_token TEXT NOT NULL,
-            created_at TEXT NOT NULL,
-            expires_at TEXT NOT NULL,
-            active BOOLEAN DEFAULT TRUE,
-            FOREIGN KEY (user_id) REFERENCES users (id)
-        )
-    "#).execute(&pool).await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create user_sessions table: {}", e)))?;
-
-    sqlx::query(r#"
-        CREATE TABLE IF NOT EXISTS money_requests (
-            id TEXT PRIMARY KEY,
-            from_user_id TEXT NOT NULL,
-            to_user_id TEXT NOT NULL,
-            amount DOUBLE PRECISION NOT NULL,
-            description TEXT NOT NULL,
-            status TEXT DEFAULT 'pending',
-            created_at TEXT NOT NULL,
-            responded_at TEXT,
-            FOREIGN KEY (from_user_id) REFERENCES users (id),
-            FOREIGN KEY (to_user_id) REFERENCES users (id)
-        )
-    "#).execute(&pool).await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create money_requests table: {}", e)))?;
-
-    sqlx::query(r#"
-        CREATE TABLE IF NOT EXISTS debit_cards (
-            id TEXT PRIMARY KEY,
-            user_id TEXT NOT NULL,
-            card_number TEXT NOT NULL UNIQUE,
-            cvv TEXT NOT NULL,
-            expiration_month INTEGER NOT NULL,
-            expiration_year INTEGER NOT NULL,
-            is_active BOOLEAN DEFAULT TRUE,
-            created_at TEXT NOT NULL,
-            FOREIGN KEY (user_id) REFERENCES users (id)
-        )
-    "#).execute(&pool).await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create debit_cards table: {}", e)))?;
-
-    Ok(pool)
+    get_database_pool().await
 }
 
-pub async fn create_user(pool: &AnyPool, arsonflare_id: &str, username: &str) -> Result<User> {
+/// Appends to the audit log on the caller's connection, so the entry commits
+/// together with the change it describes.
+pub async fn record_audit(conn: &mut sqlx::AnyConnection, actor_user_id: Option<Uuid>, action: &str, details: &serde_json::Value) -> Result<Uuid> {
     let id = Uuid::new_v4();
-    let wallet_address = generate_wallet_address();
-    let created_at = Utc::now();
-    
-    sqlx::query(
-        "INSERT INTO users (id, arsonflare_id, username, wallet_balance, wallet_address, created_at) 
-         VALUES (?, ?, ?, 0.0, ?, ?)"
-    )
-    .bind(id.to_string())
-    .bind(arsonflare_id)
-    .bind(username)
-    .bind(&wallet_address)
-    .bind(created_at.to_rfc3339())
-    .execute(pool)
-    .await
-    .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create user: {}", e)))?;
-    
-    // Create welcome transaction and credit balance via ledger + wallet_balance
-    let transaction_id = Uuid::new_v4();
-    sqlx::query(
-        "INSERT INTO transactions (id, transaction_type, to_user_id, amount, status, description, created_at, completed_at)
-         VALUES (?, 'welcome', ?, 5000.0, 'completed', 'Welcome to GurtPay!', ?, ?)"
-    )
-    .bind(transaction_id.to_string())
-    .bind(id.to_string())
-    .bind(created_at.to_rfc3339())
-    .bind(created_at.to_rfc3339())
-    .execute(pool)
-    .await
-    .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create welcome transaction: {}", e)))?;
-    
-    sqlx::query("UPDATE users SET wallet_balance = wallet_balance + 5000.0 WHERE id = $1")
+    sqlx::query("INSERT INTO audit_log (id, actor_user_id, action, details, created_at) VALUES ($1, $2, $3, $4, $5)")
         .bind(id.to_string())
-        .execute(pool)
+        .bind(actor_user_id.map(|id| id.to_string()))
+        .bind(action)
+        .bind(details.to_string())
+        .bind(Utc::now().to_rfc3339())
+        .execute(conn)
         .await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to credit welcome balance: {}", e)))?;
-    
-    Ok(User {
-        id,
-        arsonflare_id: arsonflare_id.to_string(),
-        username: username.to_string(),
-        wallet_balance: 0.0,
-        wallet_address,
-        created_at,
-        is_admin: false,
-    })
-}
-
-pub async fn get_user_by_arsonflare_id(pool: &AnyPool, arsonflare_id: &str) -> Result<Option<User>> {
-    let row = sqlx::query(
-        "SELECT id, arsonflare_id, username, wallet_balance, wallet_address, created_at, is_admin 
-         FROM users WHERE arsonflare_id = ?"
-    )
-    .bind(arsonflare_id)
-    .fetch_optional(pool)
-    .await
-    .map_err(|e| gurtlib::GurtError::invalid_message(format!("Database query failed: {}", e)))?;
-    
-    match row {
-        Some(row) => {
-            Ok(Some(User {
-                id: Uuid::parse_str(&row.get::<String, _>("id")).unwrap(),
-                arsonflare_id: row.get("arsonflare_id"),
-                username: row.get("username"),
-                wallet_balance: row.get("wallet_balance"),
-                wallet_address: row.get("wallet_address"),
-                created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at")).unwrap().with_timezone(&Utc),
-                is_admin: row.get("is_admin"),
-            }))
-        }
-        None => Ok(None),
-    }
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to write audit log: {}", e)))?;
+    Ok(id)
 }
 
 pub async fn get_user_by_wallet_address(pool: &AnyPool, wallet_address: &str) -> Result<Option<User>> {
     let row = sqlx::query(
-        "SELECT id, arsonflare_id, username, wallet_balance, wallet_address, created_at, is_admin 
+        "SELECT id, arsonflare_id, username, wallet_balance, wallet_address, created_at, CASE WHEN is_admin THEN 1 ELSE 0 END AS is_admin 
          FROM users WHERE wallet_address = ?"
     )
     .bind(wallet_address)
@@ -284,10 +139,10 @@ pub async fn get_user_by_wallet_address(pool: &AnyPool, wallet_address: &str) ->
                 id: Uuid::parse_str(&row.get::<String, _>("id")).unwrap(),
                 arsonflare_id: row.get("arsonflare_id"),
                 username: row.get("username"),
-                wallet_balance: row.get("wallet_balance"),
+                wallet_balance: Money::from_minor(row.get("wallet_balance")),
                 wallet_address: row.get("wallet_address"),
                 created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at")).unwrap().with_timezone(&Utc),
-                is_admin: row.get("is_admin"),
+                is_admin: row.get::<i64, _>("is_admin") != 0,
             }))
         }
         None => Ok(None),
@@ -296,7 +151,7 @@ pub async fn get_user_by_wallet_address(pool: &AnyPool, wallet_address: &str) ->
 
 pub async fn get_user_by_id(pool: &AnyPool, user_id: Uuid) -> Result<Option<User>> {
     let row = sqlx::query(
-        "SELECT id, arsonflare_id, username, wallet_balance, wallet_address, created_at, is_admin 
+        "SELECT id, arsonflare_id, username, wallet_balance, wallet_address, created_at, CASE WHEN is_admin THEN 1 ELSE 0 END AS is_admin 
          FROM users WHERE id = ?"
     )
     .bind(user_id.to_string())
@@ -310,67 +165,63 @@ pub async fn get_user_by_id(pool: &AnyPool, user_id: Uuid) -> Result<Option<User
                 id: Uuid::parse_str(&row.get::<String, _>("id")).unwrap(),
                 arsonflare_id: row.get("arsonflare_id"),
                 username: row.get("username"),
-                wallet_balance: row.get("wallet_balance"),
+                wallet_balance: Money::from_minor(row.get("wallet_balance")),
                 wallet_address: row.get("wallet_address"),
                 created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at")).unwrap().with_timezone(&Utc),
-                is_admin: row.get("is_admin"),
+                is_admin: row.get::<i64, _>("is_admin") != 0,
             }))
         }
         None => Ok(None),
     }
 }
 
-pub async fn transfer_funds(pool: &AnyPool, from_user_id: &Uuid, to_user_id: &Uuid, amount: f64, description: &str) -> Result<Transaction> {
+pub async fn transfer_funds(pool: &AnyPool, from_user_id: &Uuid, to_user_id: &Uuid, amount: Money, description: &str) -> ApiResult<Transaction> {
     let mut tx = pool.begin().await
         .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
     
-    // Check sender balance
-    let sender_balance: f64 = sqlx::query_scalar("SELECT wallet_balance FROM users WHERE id = $1")
-        .bind(from_user_id.to_string())
-        .fetch_one(&mut *tx)
-        .await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to get sender balance: {}", e)))?;
-    
-    if sender_balance < amount {
-        return Err(gurtlib::GurtError::invalid_message("Insufficient funds".to_string()));
-    }
-    
-    // Update balances
-    sqlx::query("UPDATE users SET wallet_balance = wallet_balance - $1 WHERE id = $2")
-        .bind(amount)
-        .bind(from_user_id.to_string())
-        .execute(&mut *tx)
-        .await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to debit sender: {}", e)))?;
+    let transaction = record_transfer(&mut tx, Uuid::new_v4(), from_user_id, to_user_id, amount, description).await?;
     
-    sqlx::query("UPDATE users SET wallet_balance = wallet_balance + $1 WHERE id = $2")
-        .bind(amount)
-        .bind(to_user_id.to_string())
-        .execute(&mut *tx)
-        .await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to credit recipient: {}", e)))?;
+    tx.commit().await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to commit transaction: {}", e)))?;
     
-    // Create transaction record
-    let transaction_id = Uuid::new_v4();
+    Ok(transaction)
+}
+
+pub(crate) async fn record_transfer(
+    conn: &mut sqlx::AnyConnection,
+    transaction_id: Uuid,
+    from_user_id: &Uuid,
+    to_user_id: &Uuid,
+    amount: Money,
+    description: &str,
+) -> ApiResult<Transaction> {
     let created_at = Utc::now();
     
     sqlx::query(
         "INSERT INTO transactions (id, transaction_type, from_user_id, to_user_id, amount, status, description, created_at, completed_at)
-         VALUES (?, 'transfer', ?, ?, ?, 'completed', ?, ?, ?)"
+         VALUES ($1, 'transfer', $2, $3, $4, 'completed', $5, $6, $7)"
     )
     .bind(transaction_id.to_string())
     .bind(from_user_id.to_string())
     .bind(to_user_id.to_string())
-    .bind(amount)
+    .bind(amount.minor())
     .bind(description)
     .bind(created_at.to_rfc3339())
     .bind(created_at.to_rfc3339())
-    .execute(&mut *tx)
+    .execute(&mut *conn)
     .await
     .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create transaction: {}", e)))?;
     
-    tx.commit().await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to commit transaction: {}", e)))?;
+    // Move the funds; fails on insufficient sender balance
+    let entry = JournalEntry::transfer(
+        transaction_id,
+        Account::UserWallet(*from_user_id),
+        Account::UserWallet(*to_user_id),
+        amount,
+        description,
+    );
+    ledger::post(conn, &entry).await?;
+    limits::enforce(conn, *from_user_id, amount, Some(transaction_id)).await?;
     
     Ok(Transaction {
         id: transaction_id,
@@ -379,11 +230,12 @@ pub async fn transfer_funds(pool: &AnyPool, from_user_id: &Uuid, to_user_id: &Uu
         to_user_id: Some(*to_user_id),
         business_id: None,
         amount,
-        platform_fee: 0.0,
+        platform_fee: Money::ZERO,
         status: TransactionStatus::Completed,
         description: description.to_string(),
         created_at,
         completed_at: Some(created_at),
+        refunded_amount: Money::ZERO,
     })
 }
 
@@ -430,28 +282,31 @@ pub fn generate_expiration() -> (i32, i32) {
 }
 
 // Invoice functions
-pub async fn create_invoice(
-    pool: &AnyPool,
-    business_id: Uuid,
-    amount: f64,
-    description: &str,
-    customer_name: Option<&str>,
-    expires_at: Option<chrono::DateTime<Utc>>,
-) -> Result<Invoice> {
+pub async fn create_invoice(pool: &AnyPool, business_id: Uuid, new: NewInvoice) -> Result<Invoice> {
     let invoice_id = Uuid::new_v4();
     let now = Utc::now();
     
+    let line_items = serde_json::to_string(&new.line_items)
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to encode line items: {}", e)))?;
+    let metadata = serde_json::to_string(&new.metadata)
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to encode metadata: {}", e)))?;
+    
     sqlx::query(r#"
-        INSERT INTO invoices (id, business_id, amount, description, customer_name, status, created_at, expires_at)
-        VALUES (?, ?, ?, ?, ?, 'pending', ?, ?)
+        INSERT INTO invoices (id, business_id, amount, description, customer_name, status, created_at, expires_at, line_items, metadata, success_url, cancel_url, payment_link_id)
+        VALUES ($1, $2, $3, $4, $5, 'pending', $6, $7, $8, $9, $10, $11, $12)
     "#)
     .bind(invoice_id.to_string())
     .bind(business_id.to_string())
-    .bind(amount)
-    .bind(description)
-    .bind(customer_name)
+    .bind(new.amount.minor())
+    .bind(&new.description)
+    .bind(new.customer_name.as_deref())
     .bind(now.to_rfc3339())
-    .bind(expires_at.map(|t| t.to_rfc3339()))
+    .bind(new.expires_at.map(|t| t.to_rfc3339()))
+    .bind(line_items)
+    .bind(metadata)
+    .bind(new.success_url.as_deref())
+    .bind(new.cancel_url.as_deref())
+    .bind(new.payment_link_id.map(|id| id.to_string()))
     .execute(pool)
     .await
     .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create invoice: {}", e)))?;
@@ -459,70 +314,293 @@ pub async fn create_invoice(
     Ok(Invoice {
         id: invoice_id,
         business_id,
-        amount,
-        description: description.to_string(),
-        customer_name: customer_name.map(|s| s.to_string()),
+        amount: new.amount,
+        description: new.description,
+        customer_name: new.customer_name,
         status: InvoiceStatus::Pending,
         paid_at: None,
+        transaction_id: None,
         created_at: now,
-        expires_at,
+        expires_at: new.expires_at,
+        line_items: new.line_items,
+        metadata: new.metadata,
+        success_url: new.success_url,
+        cancel_url: new.cancel_url,
+        payment_link_id: new.payment_link_id,
     })
 }
 
+const SELECT_INVOICE: &str =
+    "SELECT id, business_id, amount, description, customer_name, status, paid_at, transaction_id, created_at, expires_at, line_items, metadata, success_url, cancel_url, payment_link_id FROM invoices";
+
+fn invoice_from_row(row: &sqlx::any::AnyRow) -> Invoice {
+    let status = match row.get::<String, _>("status").as_str() {
+        "pending" => InvoiceStatus::Pending,
+        "paid" => InvoiceStatus::Paid,
+        "expired" => InvoiceStatus::Expired,
+        "cancelled" => InvoiceStatus::Cancelled,
+        _ => InvoiceStatus::Pending,
+    };
+    
+    Invoice {
+        id: Uuid::parse_str(&row.get::<String, _>("id")).unwrap(),
+        business_id: Uuid::parse_str(&row.get::<String, _>("business_id")).unwrap(),
+        amount: Money::from_minor(row.get("amount")),
+        description: row.get("description"),
+        customer_name: row.get("customer_name"),
+        status,
+        paid_at: row.get::<Option<String>, _>("paid_at").map(|s| chrono::DateTime::parse_from_rfc3339(&s).unwrap().with_timezone(&Utc)),
+        transaction_id: row.get::<Option<String>, _>("transaction_id").and_then(|s| Uuid::parse_str(&s).ok()),
+        created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at")).unwrap().with_timezone(&Utc),
+        expires_at: row.get::<Option<String>, _>("expires_at").map(|s| chrono::DateTime::parse_from_rfc3339(&s).unwrap().with_timezone(&Utc)),
+        line_items: row.get::<Option<String>, _>("line_items").and_then(|s| serde_json::from_str(&s).ok()).unwrap_or_default(),
+        metadata: row.get::<Option<String>, _>("metadata").and_then(|s| serde_json::from_str(&s).ok()).unwrap_or_default(),
+        success_url: row.get("success_url"),
+        cancel_url: row.get("cancel_url"),
+        payment_link_id: row.get::<Option<String>, _>("payment_link_id").and_then(|s| Uuid::parse_str(&s).ok()),
+    }
+}
+
 pub async fn get_invoice(pool: &AnyPool, invoice_id: Uuid) -> Result<Option<Invoice>> {
-    let row = sqlx::query(r#"
-        SELECT id, business_id, amount, description, customer_name, status, paid_at, created_at, expires_at
-        FROM invoices WHERE id = ?
-    "#)
-    .bind(invoice_id.to_string())
-    .fetch_optional(pool)
-    .await
-    .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to get invoice: {}", e)))?;
+    let row = sqlx::query(&format!("{} WHERE id = $1", SELECT_INVOICE))
+        .bind(invoice_id.to_string())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to get invoice: {}", e)))?;
     
-    match row {
-        Some(row) => {
-            let status = match row.get::<String, _>("status").as_str() {
-                "pending" => InvoiceStatus::Pending,
-                "paid" => InvoiceStatus::Paid,
-                "expired" => InvoiceStatus::Expired,
-                "cancelled" => InvoiceStatus::Cancelled,
-                _ => InvoiceStatus::Pending,
-            };
-            
-            Ok(Some(Invoice {
-                id: Uuid::parse_str(&row.get::<String, _>("id")).unwrap(),
-                business_id: Uuid::parse_str(&row.get::<String, _>("business_id")).unwrap(),
-                amount: row.get("amount"),
-                description: row.get("description"),
-                customer_name: row.get("customer_name"),
-                status,
-                paid_at: row.get::<Option<String>, _>("paid_at").map(|s| chrono::DateTime::parse_from_rfc3339(&s).unwrap().with_timezone(&Utc)),
-                created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at")).unwrap().with_timezone(&Utc),
-                expires_at: row.get::<Option<String>, _>("expires_at").map(|s| chrono::DateTime::parse_from_rfc3339(&s).unwrap().with_timezone(&Utc)),
-            }))
-        }
-        None => Ok(None),
+    Ok(row.as_ref().map(invoice_from_row))
+}
+
+/// One page of the business's invoices matching `filter`, newest first, and
+/// how many match in total.
+pub async fn list_invoices(pool: &AnyPool, business_id: Uuid, filter: &InvoiceListQuery, limit: i64, offset: i64) -> Result<(Vec<Invoice>, i64)> {
+    enum Arg {
+        Text(String),
+        Int(i64),
+    }
+    
+    let mut conditions = vec!["business_id = $1".to_string()];
+    let mut args = vec![Arg::Text(business_id.to_string())];
+    let mut push = |condition: &str, arg: Arg| {
+        args.push(arg);
+        conditions.push(condition.replace('?', &format!("${}", args.len())));
+    };
+    
+    if let Some(status) = &filter.status {
+        let status = serde_json::to_value(status).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default();
+        push("status = ?", Arg::Text(status));
+    }
+    if let Some(from) = filter.created_from {
+        push("created_at >= ?", Arg::Text(from.to_rfc3339()));
+    }
+    if let Some(to) = filter.created_to {
+        push("created_at < ?", Arg::Text(to.to_rfc3339()));
+    }
+    if let Some(name) = filter.customer_name.as_deref().map(str::trim).filter(|n| !n.is_empty()) {
+        let escaped = name.to_lowercase().replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+        push("LOWER(customer_name) LIKE ? ESCAPE '\\'", Arg::Text(format!("%{}%", escaped)));
+    }
+    if let Some(min) = filter.min_amount {
+        push("amount >= ?", Arg::Int(min.minor()));
+    }
+    if let Some(max) = filter.max_amount {
+        push("amount <= ?", Arg::Int(max.minor()));
+    }
+    
+    let where_clause = conditions.join(" AND ");
+    let count_sql = format!("SELECT COUNT(*) AS count FROM invoices WHERE {}", where_clause);
+    let page_sql = format!(
+        "{} WHERE {} ORDER BY created_at DESC, id LIMIT ${} OFFSET ${}",
+        SELECT_INVOICE, where_clause, args.len() + 1, args.len() + 2
+    );
+    
+    let mut count_query = sqlx::query(&count_sql);
+    let mut page_query = sqlx::query(&page_sql);
+    for arg in &args {
+        (count_query, page_query) = match arg {
+            Arg::Text(value) => (count_query.bind(value.clone()), page_query.bind(value.clone())),
+            Arg::Int(value) => (count_query.bind(*value), page_query.bind(*value)),
+        };
     }
+    
+    let total = count_query
+        .fetch_one(pool)
+        .await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to count invoices: {}", e)))?
+        .get::<i64, _>("count");
+    
+    let rows = page_query
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to list invoices: {}", e)))?;
+    
+    Ok((rows.iter().map(invoice_from_row).collect(), total))
 }
 
-pub async fn mark_invoice_paid(pool: &AnyPool, invoice_id: Uuid) -> Result<()> {
+/// Cancels one of the business's invoices. Only pending invoices can be
+/// cancelled; the compare-and-set means a payment racing the cancel wins or
+/// loses cleanly.
+pub async fn cancel_invoice(pool: &AnyPool, business_id: Uuid, invoice_id: Uuid) -> ApiResult<Invoice> {
+    let cancelled = sqlx::query("UPDATE invoices SET status = 'cancelled' WHERE id = $1 AND business_id = $2 AND status = 'pending'")
+        .bind(invoice_id.to_string())
+        .bind(business_id.to_string())
+        .execute(pool)
+        .await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to cancel invoice: {}", e)))?;
+    
+    let invoice = get_invoice(pool, invoice_id).await?
+        .filter(|invoice| invoice.business_id == business_id)
+        .ok_or_else(|| ApiError::not_found("Invoice not found"))?;
+    
+    if cancelled.rows_affected() == 0 {
+        return Err(ApiError::conflict(match invoice.status {
+            InvoiceStatus::Paid => "Invoice is already paid",
+            InvoiceStatus::Expired => "Invoice has expired",
+            InvoiceStatus::Cancelled => "Invoice is already cancelled",
+            InvoiceStatus::Pending => "Invoice could not be cancelled",
+        }));
+    }
+    Ok(invoice)
+}
+
+/// Pays a pending invoice from the user's wallet in one database transaction.
+/// The status flip is a compare-and-set that runs first, so it also takes the
+/// row (Postgres) or database (SQLite) write lock: of two concurrent payers one
+/// gets `Paid` and the other waits, then sees `AlreadyPaid`. Insufficient funds
+/// rolls the whole thing back and leaves the invoice pending.
+pub async fn pay_invoice(pool: &AnyPool, invoice_id: Uuid, user_id: Uuid) -> ApiResult<InvoicePayment> {
+    let mut tx = pool.begin().await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+    
+    let transaction_id = Uuid::new_v4();
     let now = Utc::now();
     
-    sqlx::query(r#"
-        UPDATE invoices SET status = 'paid', paid_at = ? WHERE id = ?
-    "#)
+    let claimed = sqlx::query(
+        "UPDATE invoices SET status = 'paid', paid_at = $1, paid_by_user_id = $2, transaction_id = $3 \
+         WHERE id = $4 AND status = 'pending' AND (expires_at IS NULL OR expires_at > $5)"
+    )
     .bind(now.to_rfc3339())
+    .bind(user_id.to_string())
+    .bind(transaction_id.to_string())
     .bind(invoice_id.to_string())
-    .execute(pool)
+    .bind(now.to_rfc3339())
+    .execute(&mut *tx)
     .await
-    .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to mark invoice paid: {}", e)))?;
+    .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to claim invoice: {}", e)))?;
     
-    Ok(())
+    if claimed.rows_affected() == 0 {
+        drop(tx);
+        return Ok(match get_invoice(pool, invoice_id).await? {
+            None => InvoicePayment::NotFound,
+            Some(invoice) => match invoice.status {
+                InvoiceStatus::Paid => InvoicePayment::AlreadyPaid,
+                InvoiceStatus::Pending => {
+                    expire_invoice(pool, &invoice).await?;
+                    InvoicePayment::NotPayable(InvoiceStatus::Expired)
+                }
+                status => InvoicePayment::NotPayable(status),
+            },
+        });
+    }
+    
+    let row = sqlx::query("SELECT business_id, amount, description, payment_link_id FROM invoices WHERE id = $1")
+        .bind(invoice_id.to_string())
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to get invoice: {}", e)))?;
+    let business_id = Uuid::parse_str(&row.get::<String, _>("business_id"))
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Invalid invoice business: {}", e)))?;
+    let amount = Money::from_minor(row.get("amount"));
+    let description = format!("Payment for invoice: {}", row.get::<String, _>("description"));
+    let payment_link_id = row.get::<Option<String>, _>("payment_link_id");
+    
+    // A payment link's uses are counted here rather than when the invoice is
+    // opened, so customers who walk away don't use it up
+    if let Some(link_id) = &payment_link_id {
+        payment_links::record_use(&mut tx, link_id).await?;
+    }
+    
+    let transaction = record_business_payment(&mut tx, transaction_id, &user_id, &business_id, amount, &description).await?;
+    limits::enforce(&mut tx, user_id, amount, Some(transaction_id)).await?;
+    
+    webhooks::enqueue(&mut tx, business_id, Event::InvoicePaid, serde_json::json!({
+        "invoice_id": invoice_id,
+        "amount": amount,
+        "transaction_id": transaction_id,
+        "payment_link_id": payment_link_id,
+        "paid_at": now.to_rfc3339(),
+    })).await?;
+    
+    tx.commit().await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to commit invoice payment: {}", e)))?;
+    
+    Ok(InvoicePayment::Paid(transaction))
+}
+
+/// Marks a pending invoice that is past its expiry as expired and tells the
+/// business. Does nothing if the invoice was paid or expired in the meantime.
+pub async fn expire_invoice(pool: &AnyPool, invoice: &Invoice) -> Result<bool> {
+    let mut tx = pool.begin().await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+    
+    let expired = sqlx::query("UPDATE invoices SET status = 'expired' WHERE id = $1 AND status = 'pending' AND expires_at <= $2")
+        .bind(invoice.id.to_string())
+        .bind(Utc::now().to_rfc3339())
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to expire invoice: {}", e)))?;
+    if expired.rows_affected() == 0 {
+        return Ok(false);
+    }
+    
+    webhooks::enqueue(&mut tx, invoice.business_id, Event::InvoiceExpired, serde_json::json!({
+        "invoice_id": invoice.id,
+        "amount": invoice.amount,
+        "expires_at": invoice.expires_at.map(|at| at.to_rfc3339()),
+    })).await?;
+    
+    tx.commit().await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to commit invoice expiry: {}", e)))?;
+    Ok(true)
+}
+
+/// Expires every pending invoice whose `expires_at` has passed. Returns how
+/// many were expired.
+pub async fn expire_due_invoices(pool: &AnyPool) -> Result<u64> {
+    let rows = sqlx::query(&format!("{} WHERE status = 'pending' AND expires_at <= $1", SELECT_INVOICE))
+        .bind(Utc::now().to_rfc3339())
+        .fetch_all(pool)
+        .await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to find expired invoices: {}", e)))?;
+    
+    let mut expired = 0;
+    for row in &rows {
+        if expire_invoice(pool, &invoice_from_row(row)).await? {
+            expired += 1;
+        }
+    }
+    Ok(expired)
+}
+
+/// Starts the background task that expires overdue invoices.
+pub fn spawn_invoice_sweeper(pool: AnyPool) {
+    tokio::spawn(async move {
+        loop {
+            match expire_due_invoices(&pool).await {
+                Ok(0) => {}
+                Ok(count) => tracing::info!("Expired {} invoices", count),
+                Err(e) => tracing::warn!("Invoice expiry sweep failed: {}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(INVOICE_SWEEP_INTERVAL_SECS)).await;
+        }
+    });
 }
 
 pub async fn get_business_by_api_key(pool: &AnyPool, api_key: &str) -> Result<Option<Business>> {
     let row = sqlx::query(r#"
-        SELECT id, user_id, business_name, website_url, api_key, verified, balance, created_at
+        SELECT id, user_id, business_name, website_url, api_key, return_secret, CASE WHEN verified THEN 1 ELSE 0 END AS verified, balance, created_at
         FROM businesses WHERE api_key = ?
     "#)
     .bind(api_key)
@@ -538,8 +616,9 @@ pub async fn get_business_by_api_key(pool: &AnyPool, api_key: &str) -> Result<Op
                 business_name: row.get("business_name"),
                 website_url: row.get("website_url"),
                 api_key: row.get("api_key"),
-                verified: row.get("verified"),
-                balance: row.get("balance"),
+                return_secret: row.get("return_secret"),
+                verified: row.get::<i64, _>("verified") != 0,
+                balance: Money::from_minor(row.get("balance")),
                 created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at")).unwrap().with_timezone(&Utc),
             }))
         }
@@ -549,7 +628,7 @@ pub async fn get_business_by_api_key(pool: &AnyPool, api_key: &str) -> Result<Op
 
 pub async fn get_business_by_id(pool: &AnyPool, business_id: Uuid) -> Result<Option<Business>> {
     let row = sqlx::query(r#"
-        SELECT id, user_id, business_name, website_url, api_key, verified, balance, created_at
+        SELECT id, user_id, business_name, website_url, api_key, return_secret, CASE WHEN verified THEN 1 ELSE 0 END AS verified, balance, created_at
         FROM businesses WHERE id = ?
     "#)
     .bind(business_id.to_string())
@@ -565,8 +644,9 @@ pub async fn get_business_by_id(pool: &AnyPool, business_id: Uuid) -> Result<Opt
                 business_name: row.get("business_name"),
                 website_url: row.get("website_url"),
                 api_key: row.get("api_key"),
-                verified: row.get("verified"),
-                balance: row.get("balance"),
+                return_secret: row.get("return_secret"),
+                verified: row.get::<i64, _>("verified") != 0,
+                balance: Money::from_minor(row.get("balance")),
                 created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at")).unwrap().with_timezone(&Utc),
             }))
         }
@@ -578,55 +658,61 @@ pub async fn transfer_to_business(
     pool: &AnyPool,
     from_user_id: &Uuid,
     business_id: &Uuid,
-    amount: f64,
+    amount: Money,
     description: &str,
-) -> Result<Transaction> {
+) -> ApiResult<Transaction> {
     let mut tx = pool.begin().await
         .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
     
-    let user_balance: f64 = sqlx::query_scalar("SELECT wallet_balance FROM users WHERE id = $1")
-        .bind(from_user_id.to_string())
-        .fetch_one(&mut *tx)
-        .await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to get user balance: {}", e)))?;
+    let transaction = record_business_payment(&mut tx, Uuid::new_v4(), from_user_id, business_id, amount, description).await?;
+    // Card payments count against the payer's transfer limits
+    limits::enforce(&mut tx, *from_user_id, amount, Some(transaction.id)).await?;
     
-    if user_balance < amount {
-        return Err(gurtlib::GurtError::invalid_message("Insufficient funds".to_string()));
-    }
+    webhooks::enqueue(&mut tx, *business_id, Event::PaymentSucceeded, serde_json::json!({
+        "transaction_id": transaction.id,
+        "amount": amount,
+        "description": description,
+        "created_at": transaction.created_at.to_rfc3339(),
+    })).await?;
     
-    sqlx::query("UPDATE users SET wallet_balance = wallet_balance - $1 WHERE id = $2")
-        .bind(amount)
-        .bind(from_user_id.to_string())
-        .execute(&mut *tx)
-        .await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to debit user: {}", e)))?;
-    
-    sqlx::query("UPDATE businesses SET balance = balance + $1 WHERE id = $2")
-        .bind(amount)
-        .bind(business_id.to_string())
-        .execute(&mut *tx)
-        .await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to credit business: {}", e)))?;
+    tx.commit().await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to commit transaction: {}", e)))?;
     
-    let transaction_id = Uuid::new_v4();
+    Ok(transaction)
+}
+
+pub(crate) async fn record_business_payment(
+    conn: &mut sqlx::AnyConnection,
+    transaction_id: Uuid,
+    from_user_id: &Uuid,
+    business_id: &Uuid,
+    amount: Money,
+    description: &str,
+) -> ApiResult<Transaction> {
     let created_at = Utc::now();
     sqlx::query(
         "INSERT INTO transactions (id, transaction_type, from_user_id, to_user_id, business_id, amount, platform_fee, status, description, created_at, completed_at) \
-         VALUES ($1, 'business_payment', $2, NULL, $3, $4, 0.0, 'completed', $5, $6, $7)"
+         VALUES ($1, 'business_payment', $2, NULL, $3, $4, 0, 'completed', $5, $6, $7)"
     )
     .bind(transaction_id.to_string())
     .bind(from_user_id.to_string())
     .bind(business_id.to_string())
-    .bind(amount)
+    .bind(amount.minor())
     .bind(description)
     .bind(created_at.to_rfc3339())
     .bind(created_at.to_rfc3339())
-    .execute(&mut *tx)
+    .execute(&mut *conn)
     .await
     .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create transaction: {}", e)))?;
     
-    tx.commit().await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to commit transaction: {}", e)))?;
+    let entry = JournalEntry::transfer(
+        transaction_id,
+        Account::UserWallet(*from_user_id),
+        Account::Business(*business_id),
+        amount,
+        description,
+    );
+    ledger::post(conn, &entry).await?;
     
     Ok(Transaction {
         id: transaction_id,
@@ -635,41 +721,142 @@ pub async fn transfer_to_business(
         to_user_id: None,
         business_id: Some(*business_id),
         amount,
-        platform_fee: 0.0,
+        platform_fee: Money::ZERO,
         status: TransactionStatus::Completed,
         description: description.to_string(),
         created_at,
         completed_at: Some(created_at),
+        refunded_amount: Money::ZERO,
     })
 }
 
-pub async fn get_user_session(pool: &AnyPool, session_token: &str) -> Result<Option<crate::models::UserSession>> {
-    let row = sqlx::query(r#"
-        SELECT id, user_id, session_token, created_at, expires_at
-        FROM user_sessions WHERE session_token = ? AND expires_at > datetime('now')
-    "#)
-    .bind(session_token)
-    .fetch_optional(pool)
+/// Gives all or part of a business payment back to the customer who made it,
+/// out of the business balance. `amount` defaults to whatever hasn't been
+/// refunded yet. The original's `refunded_amount` is bumped with a guarded
+/// UPDATE, so concurrent refunds can never add up to more than was paid.
+pub async fn refund_business_payment(
+    pool: &AnyPool,
+    business_id: Uuid,
+    original_id: Uuid,
+    amount: Option<Money>,
+    reason: Option<&str>,
+) -> ApiResult<(Transaction, Money)> {
+    let mut tx = pool.begin().await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+    
+    let original = sqlx::query(
+        "SELECT transaction_type, from_user_id, amount, refunded_amount, status, description \
+         FROM transactions WHERE id = $1 AND business_id = $2"
+    )
+    .bind(original_id.to_string())
+    .bind(business_id.to_string())
+    .fetch_optional(&mut *tx)
     .await
-    .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to get user session: {}", e)))?;
+    .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to get transaction: {}", e)))?
+    .ok_or_else(|| ApiError::not_found("Transaction not found"))?;
     
-    match row {
-        Some(row) => {
-            Ok(Some(crate::models::UserSession {
-                id: Uuid::parse_str(&row.get::<String, _>("id")).unwrap(),
-                user_id: Uuid::parse_str(&row.get::<String, _>("user_id")).unwrap(),
-                session_token: row.get("session_token"),
-                created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at")).unwrap().with_timezone(&Utc),
-                expires_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("expires_at")).unwrap().with_timezone(&Utc),
-            }))
-        }
-        None => Ok(None),
+    if original.get::<String, _>("transaction_type") != "business_payment" || original.get::<String, _>("status") != "completed" {
+        return Err(ApiError::validation("Only completed business payments can be refunded"));
+    }
+    let payer_id = original.get::<Option<String>, _>("from_user_id")
+        .and_then(|id| Uuid::parse_str(&id).ok())
+        .ok_or_else(|| ApiError::internal(format!("Business payment {} has no payer", original_id)))?;
+    let paid = Money::from_minor(original.get("amount"));
+    let remaining = paid.checked_sub(Money::from_minor(original.get("refunded_amount")))
+        .ok_or_else(|| ApiError::internal(format!("Refunded amount of {} is out of range", original_id)))?;
+    
+    let amount = amount.unwrap_or(remaining);
+    if !amount.is_positive() {
+        return Err(if remaining.is_positive() {
+            ApiError::validation("Refund amount must be positive")
+        } else {
+            ApiError::conflict("Transaction is already fully refunded")
+        });
     }
+    
+    let claimed = sqlx::query(
+        "UPDATE transactions SET refunded_amount = refunded_amount + $1 WHERE id = $2 AND refunded_amount + $3 <= amount"
+    )
+    .bind(amount.minor())
+    .bind(original_id.to_string())
+    .bind(amount.minor())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to update refunded amount: {}", e)))?;
+    if claimed.rows_affected() == 0 {
+        return Err(ApiError::conflict(format!("Refund exceeds the refundable amount of {} GC", remaining)));
+    }
+    
+    // Read back rather than subtracting from the earlier read, which a
+    // concurrent refund may have made stale
+    let refundable = Money::from_minor(
+        sqlx::query("SELECT amount - refunded_amount AS refundable FROM transactions WHERE id = $1")
+            .bind(original_id.to_string())
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to read refunded amount: {}", e)))?
+            .get("refundable"),
+    );
+    
+    let refund_id = Uuid::new_v4();
+    let created_at = Utc::now();
+    let description = match reason.map(str::trim).filter(|r| !r.is_empty()) {
+        Some(reason) => format!("Refund: {}", reason),
+        None => format!("Refund: {}", original.get::<String, _>("description")),
+    };
+    
+    sqlx::query(
+        "INSERT INTO transactions (id, transaction_type, from_user_id, to_user_id, business_id, amount, platform_fee, status, description, created_at, completed_at, refund_of) \
+         VALUES ($1, 'refund', NULL, $2, $3, $4, 0, 'completed', $5, $6, $7, $8)"
+    )
+    .bind(refund_id.to_string())
+    .bind(payer_id.to_string())
+    .bind(business_id.to_string())
+    .bind(amount.minor())
+    .bind(&description)
+    .bind(created_at.to_rfc3339())
+    .bind(created_at.to_rfc3339())
+    .bind(original_id.to_string())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create refund transaction: {}", e)))?;
+    
+    // Fails with insufficient funds if the business has already withdrawn the money
+    let entry = JournalEntry::transfer(refund_id, Account::Business(business_id), Account::UserWallet(payer_id), amount, &description);
+    ledger::post(&mut tx, &entry).await?;
+    
+    webhooks::enqueue(&mut tx, business_id, Event::RefundCreated, serde_json::json!({
+        "refund_id": refund_id,
+        "transaction_id": original_id,
+        "amount": amount,
+        "refundable_amount": refundable,
+        "description": description,
+        "created_at": created_at.to_rfc3339(),
+    })).await?;
+    
+    tx.commit().await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to commit refund: {}", e)))?;
+    
+    let refund = Transaction {
+        id: refund_id,
+        transaction_type: TransactionType::Refund,
+        from_user_id: None,
+        to_user_id: Some(payer_id),
+        business_id: Some(business_id),
+        amount,
+        platform_fee: Money::ZERO,
+        status: TransactionStatus::Completed,
+        description,
+        created_at,
+        completed_at: Some(created_at),
+        refunded_amount: Money::ZERO,
+    };
+    Ok((refund, refundable))
 }
 
 pub async fn get_user_by_username(pool: &AnyPool, username: &str) -> Result<Option<User>> {
     let row = sqlx::query(
-        "SELECT id, arsonflare_id, username, wallet_balance, wallet_address, created_at, is_admin \
+        "SELECT id, arsonflare_id, username, wallet_balance, wallet_address, created_at, CASE WHEN is_admin THEN 1 ELSE 0 END AS is_admin \
          FROM users WHERE LOWER(username) = LOWER($1) OR LOWER(arsonflare_id) = LOWER($2)"
     )
     .bind(username)
@@ -682,22 +869,22 @@ pub async fn get_user_by_username(pool: &AnyPool, username: &str) -> Result<Opti
             id: Uuid::parse_str(&row.get::<String, _>("id")).unwrap(),
             arsonflare_id: row.get("arsonflare_id"),
             username: row.get("username"),
-            wallet_balance: row.get("wallet_balance"),
+            wallet_balance: Money::from_minor(row.get("wallet_balance")),
             wallet_address: row.get("wallet_address"),
             created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at")).unwrap().with_timezone(&Utc),
-            is_admin: row.get("is_admin"),
+            is_admin: row.get::<i64, _>("is_admin") != 0,
         })),
         None => Ok(None),
     }
 }
 
-pub async fn set_user_password_hash(pool: &AnyPool, user_id: &Uuid, password_hash: &str) -> Result<()> {
+pub async fn set_user_password_hash(conn: &mut sqlx::AnyConnection, user_id: &Uuid, password_hash: &str) -> Result<()> {
     sqlx::query(
         "INSERT INTO user_credentials (user_id, password_hash) VALUES ($1, $2)\n         ON CONFLICT(user_id) DO UPDATE SET password_hash = excluded.password_hash"
     )
     .bind(user_id.to_string())
     .bind(password_hash)
-    .execute(pool)
+    .execute(&mut *conn)
     .await
     .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to set credentials: {}", e)))?;
     Ok(())
@@ -717,46 +904,52 @@ pub async fn create_user_with_password(pool: &AnyPool, username: &str, password_
     let wallet_address = generate_wallet_address();
     let created_at = Utc::now();
 
+    // The user, their password and the welcome bonus commit together, so a
+    // failure part way can't leave a password-less user holding the username
+    let mut tx = pool.begin().await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
     sqlx::query(
         "INSERT INTO users (id, arsonflare_id, username, wallet_balance, wallet_address, created_at) \
-         VALUES ($1, $2, $3, 0.0, $4, $5)"
+         VALUES ($1, $2, $3, 0, $4, $5)"
     )
     .bind(id.to_string())
     .bind(username)
     .bind(username)
     .bind(&wallet_address)
     .bind(created_at.to_rfc3339())
-    .execute(pool)
+    .execute(&mut *tx)
     .await
     .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create user: {}", e)))?;
 
-    set_user_password_hash(pool, &id, password_hash).await?;
+    set_user_password_hash(&mut tx, &id, password_hash).await?;
 
-    // Welcome transaction and credit
+    // Welcome transaction, minted into the new wallet
     let transaction_id = Uuid::new_v4();
     sqlx::query(
         "INSERT INTO transactions (id, transaction_type, to_user_id, amount, status, description, created_at, completed_at) \
-         VALUES ($1, 'welcome', $2, 5000.0, 'completed', 'Welcome to GurtPay!', $3, $4)"
+         VALUES ($1, 'welcome', $2, $3, 'completed', 'Welcome to GurtPay!', $4, $5)"
     )
     .bind(transaction_id.to_string())
     .bind(id.to_string())
+    .bind(WELCOME_BONUS.minor())
     .bind(created_at.to_rfc3339())
     .bind(created_at.to_rfc3339())
-    .execute(pool)
+    .execute(&mut *tx)
     .await
     .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to create welcome transaction: {}", e)))?;
 
-    sqlx::query("UPDATE users SET wallet_balance = wallet_balance + 5000.0 WHERE id = $1")
-        .bind(id.to_string())
-        .execute(pool)
-        .await
-        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to credit welcome balance: {}", e)))?;
+    let entry = JournalEntry::transfer(transaction_id, Account::Mint, Account::UserWallet(id), WELCOME_BONUS, "Welcome to GurtPay!");
+    ledger::post(&mut tx, &entry).await?;
+
+    tx.commit().await
+        .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to commit welcome bonus: {}", e)))?;
 
     Ok(User {
         id,
         arsonflare_id: username.to_string(),
         username: username.to_string(),
-        wallet_balance: 0.0,
+        wallet_balance: WELCOME_BONUS,
         wallet_address,
         created_at,
         is_admin: false,
@@ -764,7 +957,7 @@ pub async fn create_user_with_password(pool: &AnyPool, username: &str, password_
 }
 
 // Debit card functions
-pub async fn create_debit_card(pool: &AnyPool, user_id: Uuid) -> Result<serde_json::Value> {
+pub async fn create_debit_card(pool: &AnyPool, user_id: Uuid) -> ApiResult<serde_json::Value> {
     // Check if user already has an active card
     let existing_card_count = sqlx::query("SELECT COUNT(*) as count FROM debit_cards WHERE user_id = $1 AND is_active = TRUE")
         .bind(user_id.to_string())
@@ -774,7 +967,7 @@ pub async fn create_debit_card(pool: &AnyPool, user_id: Uuid) -> Result<serde_js
     
     let count: i64 = existing_card_count.get("count");
     if count > 0 {
-        return Err(gurtlib::GurtError::invalid_message("User already has an active debit card. Use regenerate instead.".to_string()));
+        return Err(ApiError::conflict("User already has an active debit card. Use regenerate instead."));
     }
     
     let card_id = Uuid::new_v4();
@@ -807,7 +1000,7 @@ pub async fn create_debit_card(pool: &AnyPool, user_id: Uuid) -> Result<serde_js
 }
 
 pub async fn get_user_debit_cards(pool: &AnyPool, user_id: Uuid) -> Result<Vec<serde_json::Value>> {
-    let rows = sqlx::query("SELECT id, card_number, cvv, expiration_month, expiration_year, is_active, created_at FROM debit_cards WHERE user_id = $1 AND is_active = TRUE ORDER BY created_at DESC")
+    let rows = sqlx::query("SELECT id, card_number, cvv, expiration_month, expiration_year, CASE WHEN is_active THEN 1 ELSE 0 END AS is_active, created_at FROM debit_cards WHERE user_id = $1 AND is_active = TRUE ORDER BY created_at DESC")
         .bind(user_id.to_string())
         .fetch_all(pool)
         .await
@@ -823,7 +1016,7 @@ pub async fn get_user_debit_cards(pool: &AnyPool, user_id: Uuid) -> Result<Vec<s
             "cvv": row.get::<String, _>("cvv"),
             "expiration_month": row.get::<i32, _>("expiration_month"),
             "expiration_year": row.get::<i32, _>("expiration_year"),
-            "is_active": row.get::<bool, _>("is_active"),
+            "is_active": row.get::<i64, _>("is_active") != 0,
             "created_at": row.get::<String, _>("created_at")
         }));
     }
@@ -869,14 +1062,17 @@ pub async fn regenerate_debit_card(pool: &AnyPool, user_id: Uuid) -> Result<serd
     }))
 }
 
-pub async fn deactivate_debit_card(pool: &AnyPool, user_id: Uuid, card_id: Uuid) -> Result<()> {
-    sqlx::query("UPDATE debit_cards SET is_active = FALSE WHERE id = $1 AND user_id = $2")
+pub async fn deactivate_debit_card(pool: &AnyPool, user_id: Uuid, card_id: Uuid) -> ApiResult<()> {
+    let result = sqlx::query("UPDATE debit_cards SET is_active = FALSE WHERE id = $1 AND user_id = $2")
         .bind(card_id.to_string())
         .bind(user_id.to_string())
         .execute(pool)
         .await
         .map_err(|e| gurtlib::GurtError::invalid_message(format!("Failed to deactivate card: {}", e)))?;
 
+    if result.rows_affected() == 0 {
+        return Err(ApiError::not_found("Card not found"));
+    }
     Ok(())
 }
 
@@ -911,3 +1107,278 @@ pub async fn verify_card_details(pool: &AnyPool, card_number: &str, cvv: &str, e
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn payer(pool: &AnyPool) -> User {
+        create_user_with_password(pool, &format!("payer-{}", Uuid::new_v4()), "unused").await.unwrap()
+    }
+
+    fn new_invoice(amount: Money, expires_at: Option<chrono::DateTime<Utc>>) -> NewInvoice {
+        NewInvoice {
+            amount,
+            description: "Order 1".to_string(),
+            customer_name: None,
+            expires_at,
+            line_items: Vec::new(),
+            metadata: Default::default(),
+            success_url: None,
+            cancel_url: None,
+            payment_link_id: None,
+        }
+    }
+
+    async fn invoice(pool: &AnyPool, business_id: Uuid, amount: Money, expires_at: Option<chrono::DateTime<Utc>>) -> Invoice {
+        create_invoice(pool, business_id, new_invoice(amount, expires_at)).await.unwrap()
+    }
+
+    async fn wallet(pool: &AnyPool, user_id: Uuid) -> Money {
+        get_user_by_id(pool, user_id).await.unwrap().unwrap().wallet_balance
+    }
+
+    async fn business_balance(pool: &AnyPool, business_id: Uuid) -> Money {
+        get_business_by_id(pool, business_id).await.unwrap().unwrap().balance
+    }
+
+    async fn status(pool: &AnyPool, invoice_id: Uuid) -> InvoiceStatus {
+        get_invoice(pool, invoice_id).await.unwrap().unwrap().status
+    }
+
+    /// Pays a fresh invoice for `amount` and returns the business payment.
+    async fn paid(pool: &AnyPool, business_id: Uuid, user_id: Uuid, amount: Money) -> Uuid {
+        let invoice = invoice(pool, business_id, amount, None).await;
+        match pay_invoice(pool, invoice.id, user_id).await.unwrap() {
+            InvoicePayment::Paid(transaction) => transaction.id,
+            _ => panic!("invoice was not paid"),
+        }
+    }
+
+    async fn refunded(pool: &AnyPool, transaction_id: Uuid) -> Money {
+        Money::from_minor(
+            sqlx::query("SELECT refunded_amount FROM transactions WHERE id = $1")
+                .bind(transaction_id.to_string())
+                .fetch_one(pool)
+                .await
+                .unwrap()
+                .get("refunded_amount"),
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_payments_charge_once() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user = payer(&pool).await;
+        let invoice = invoice(&pool, business_id, Money::from_major(40), None).await;
+
+        let (first, second) = tokio::join!(
+            tokio::spawn({
+                let pool = pool.clone();
+                async move { pay_invoice(&pool, invoice.id, user.id).await }
+            }),
+            tokio::spawn({
+                let pool = pool.clone();
+                async move { pay_invoice(&pool, invoice.id, user.id).await }
+            }),
+        );
+        let outcomes = [first.unwrap().unwrap(), second.unwrap().unwrap()];
+
+        assert_eq!(outcomes.iter().filter(|o| matches!(o, InvoicePayment::Paid(_))).count(), 1);
+        assert_eq!(outcomes.iter().filter(|o| matches!(o, InvoicePayment::AlreadyPaid)).count(), 1);
+        assert_eq!(wallet(&pool, user.id).await, Money::from_major(4960));
+        assert_eq!(business_balance(&pool, business_id).await, Money::from_major(40));
+        assert!(matches!(status(&pool, invoice.id).await, InvoiceStatus::Paid));
+    }
+
+    #[tokio::test]
+    async fn insufficient_funds_leaves_the_invoice_pending() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user = payer(&pool).await;
+        let invoice = invoice(&pool, business_id, WELCOME_BONUS.checked_add(Money::from_minor(1)).unwrap(), None).await;
+
+        let result = pay_invoice(&pool, invoice.id, user.id).await;
+
+        assert!(matches!(result, Err(ApiError::InsufficientFunds(_))));
+        let after = get_invoice(&pool, invoice.id).await.unwrap().unwrap();
+        assert!(matches!(after.status, InvoiceStatus::Pending));
+        assert!(after.transaction_id.is_none());
+        assert_eq!(wallet(&pool, user.id).await, WELCOME_BONUS);
+        assert_eq!(business_balance(&pool, business_id).await, Money::ZERO);
+    }
+
+    #[tokio::test]
+    async fn expired_invoices_are_not_payable() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user = payer(&pool).await;
+        let invoice = invoice(&pool, business_id, Money::from_major(40), Some(Utc::now() - chrono::Duration::minutes(1))).await;
+
+        let result = pay_invoice(&pool, invoice.id, user.id).await.unwrap();
+
+        assert!(matches!(result, InvoicePayment::NotPayable(InvoiceStatus::Expired)));
+        assert!(matches!(status(&pool, invoice.id).await, InvoiceStatus::Expired));
+        assert_eq!(wallet(&pool, user.id).await, WELCOME_BONUS);
+    }
+
+    #[tokio::test]
+    async fn unknown_invoices_are_not_found() {
+        let pool = test_pool().await;
+        let user = payer(&pool).await;
+        assert!(matches!(pay_invoice(&pool, Uuid::new_v4(), user.id).await.unwrap(), InvoicePayment::NotFound));
+    }
+
+    #[tokio::test]
+    async fn partial_then_full_refunds_restore_the_payer() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user = payer(&pool).await;
+        let payment = paid(&pool, business_id, user.id, Money::from_major(40)).await;
+
+        let (first, refundable) = refund_business_payment(&pool, business_id, payment, Some(Money::from_major(15)), Some("damaged")).await.unwrap();
+        assert_eq!(first.amount, Money::from_major(15));
+        assert_eq!(first.description, "Refund: damaged");
+        assert_eq!(refundable, Money::from_major(25));
+
+        let (rest, refundable) = refund_business_payment(&pool, business_id, payment, None, None).await.unwrap();
+        assert_eq!(rest.amount, Money::from_major(25));
+        assert_eq!(refundable, Money::ZERO);
+
+        assert_eq!(refunded(&pool, payment).await, Money::from_major(40));
+        assert_eq!(wallet(&pool, user.id).await, WELCOME_BONUS);
+        assert_eq!(business_balance(&pool, business_id).await, Money::ZERO);
+
+        let again = refund_business_payment(&pool, business_id, payment, None, None).await;
+        assert!(matches!(again, Err(ApiError::Conflict(_))));
+    }
+
+    #[tokio::test]
+    async fn refunds_beyond_the_payment_conflict() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user = payer(&pool).await;
+        let payment = paid(&pool, business_id, user.id, Money::from_major(40)).await;
+        refund_business_payment(&pool, business_id, payment, Some(Money::from_major(30)), None).await.unwrap();
+
+        let over = refund_business_payment(&pool, business_id, payment, Some(Money::from_major(11)), None).await;
+
+        assert!(matches!(over, Err(ApiError::Conflict(_))));
+        assert_eq!(refunded(&pool, payment).await, Money::from_major(30));
+        assert_eq!(wallet(&pool, user.id).await, WELCOME_BONUS.checked_sub(Money::from_major(10)).unwrap());
+    }
+
+    #[tokio::test]
+    async fn refunds_after_a_withdrawal_need_funds() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user = payer(&pool).await;
+        let payment = paid(&pool, business_id, user.id, Money::from_major(40)).await;
+        let withdrawal = JournalEntry::new(None, "withdrawal")
+            .debit(Account::Business(business_id), Money::from_major(35))
+            .credit(Account::Mint, Money::from_major(35));
+        ledger::post(&mut pool.acquire().await.unwrap(), &withdrawal).await.unwrap();
+
+        let result = refund_business_payment(&pool, business_id, payment, Some(Money::from_major(10)), None).await;
+
+        assert!(matches!(result, Err(ApiError::InsufficientFunds(_))));
+        assert_eq!(refunded(&pool, payment).await, Money::ZERO);
+        assert_eq!(wallet(&pool, user.id).await, WELCOME_BONUS.checked_sub(Money::from_major(40)).unwrap());
+        assert_eq!(business_balance(&pool, business_id).await, Money::from_major(5));
+    }
+
+    #[tokio::test]
+    async fn only_pending_invoices_can_be_cancelled() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user = payer(&pool).await;
+        let pending = invoice(&pool, business_id, Money::from_major(10), None).await;
+        let settled = invoice(&pool, business_id, Money::from_major(10), None).await;
+        pay_invoice(&pool, settled.id, user.id).await.unwrap();
+
+        let cancelled = cancel_invoice(&pool, business_id, pending.id).await.unwrap();
+        assert!(matches!(cancelled.status, InvoiceStatus::Cancelled));
+        assert!(matches!(cancel_invoice(&pool, business_id, pending.id).await, Err(ApiError::Conflict(_))));
+        assert!(matches!(cancel_invoice(&pool, business_id, settled.id).await, Err(ApiError::Conflict(_))));
+        assert!(matches!(status(&pool, settled.id).await, InvoiceStatus::Paid));
+
+        let other_business = test_business(&pool).await;
+        let theirs = invoice(&pool, other_business, Money::from_major(10), None).await;
+        assert!(matches!(cancel_invoice(&pool, business_id, theirs.id).await, Err(ApiError::NotFound(_))));
+        assert!(matches!(status(&pool, theirs.id).await, InvoiceStatus::Pending));
+    }
+
+    #[tokio::test]
+    async fn sweeper_expires_only_overdue_pending_invoices() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        webhooks::create_endpoint(&pool, business_id, "http://127.0.0.1:9/hooks", &[]).await.unwrap();
+        let user = payer(&pool).await;
+        let past = Utc::now() - chrono::Duration::minutes(1);
+        let future = Utc::now() + chrono::Duration::hours(1);
+
+        let overdue = [
+            invoice(&pool, business_id, Money::from_major(10), Some(past)).await,
+            invoice(&pool, business_id, Money::from_major(20), Some(past)).await,
+        ];
+        let upcoming = invoice(&pool, business_id, Money::from_major(10), Some(future)).await;
+        let open_ended = invoice(&pool, business_id, Money::from_major(10), None).await;
+        let cancelled = invoice(&pool, business_id, Money::from_major(10), Some(past)).await;
+        cancel_invoice(&pool, business_id, cancelled.id).await.unwrap();
+        let settled = invoice(&pool, business_id, Money::from_major(10), Some(future)).await;
+        pay_invoice(&pool, settled.id, user.id).await.unwrap();
+        sqlx::query("UPDATE invoices SET expires_at = $1 WHERE id = $2")
+            .bind(past.to_rfc3339())
+            .bind(settled.id.to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(expire_due_invoices(&pool).await.unwrap(), 2);
+        assert_eq!(expire_due_invoices(&pool).await.unwrap(), 0);
+
+        for invoice in &overdue {
+            assert!(matches!(status(&pool, invoice.id).await, InvoiceStatus::Expired));
+        }
+        assert!(matches!(status(&pool, upcoming.id).await, InvoiceStatus::Pending));
+        assert!(matches!(status(&pool, open_ended.id).await, InvoiceStatus::Pending));
+        assert!(matches!(status(&pool, cancelled.id).await, InvoiceStatus::Cancelled));
+        assert!(matches!(status(&pool, settled.id).await, InvoiceStatus::Paid));
+
+        let expiries = webhooks::list_deliveries(&pool, business_id).await.unwrap()
+            .into_iter()
+            .filter(|delivery| delivery.event_type == Event::InvoiceExpired.as_str())
+            .count();
+        assert_eq!(expiries, 2);
+    }
+
+    #[tokio::test]
+    async fn customer_name_filter_treats_wildcards_literally() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        for name in ["50% Off Club", "500 Offers", "a_b Ltd", "axb Ltd"] {
+            create_invoice(&pool, business_id, NewInvoice {
+                customer_name: Some(name.to_string()),
+                ..new_invoice(Money::from_major(10), None)
+            })
+            .await
+            .unwrap();
+        }
+
+        let names = |customer_name: &str| {
+            let pool = pool.clone();
+            let filter: InvoiceListQuery = serde_json::from_value(serde_json::json!({ "customer_name": customer_name })).unwrap();
+            async move {
+                let (invoices, total) = list_invoices(&pool, business_id, &filter, 50, 0).await.unwrap();
+                assert_eq!(total, invoices.len() as i64);
+                invoices.into_iter().filter_map(|invoice| invoice.customer_name).collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(names("50%").await, vec!["50% Off Club"]);
+        assert_eq!(names("A_B").await, vec!["a_b Ltd"]);
+        assert_eq!(names("%").await, vec!["50% Off Club"]);
+        assert_eq!(names("off").await.len(), 2);
+    }
+}
@@ -0,0 +1,151 @@
+use gurtlib::prelude::*;
+use gurtlib::GurtStatusCode;
+use serde_json::json;
+use std::fmt;
+
+/// Errors a handler can return to a client. Each variant maps to one status
+/// code and a stable `code` string, and is rendered as
+/// `{"error": "<message>", "code": "<code>"}` so clients can branch on the
+/// code without parsing messages.
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    InsufficientFunds(String),
+    /// The payment would go past one of the user's transfer limits.
+    #[error("{0}")]
+    LimitExceeded(String),
+    /// The action needs a current two-factor code the request didn't carry.
+    #[error("{0}")]
+    TwoFactorRequired(String),
+    /// Too many recent failed logins or card checks for this account or source.
+    #[error("{0}")]
+    TooManyAttempts(String),
+    #[error("{0}")]
+    Validation(String),
+    /// Anything the client can't fix. The message is logged, never sent.
+    #[error("{0}")]
+    Internal(String),
+}
+
+pub type ApiResult<T> = std::result::Result<T, ApiError>;
+
+impl ApiError {
+    pub fn unauthorized<T: fmt::Display>(msg: T) -> Self {
+        ApiError::Unauthorized(msg.to_string())
+    }
+
+    pub fn forbidden<T: fmt::Display>(msg: T) -> Self {
+        ApiError::Forbidden(msg.to_string())
+    }
+
+    pub fn not_found<T: fmt::Display>(msg: T) -> Self {
+        ApiError::NotFound(msg.to_string())
+    }
+
+    pub fn conflict<T: fmt::Display>(msg: T) -> Self {
+        ApiError::Conflict(msg.to_string())
+    }
+
+    pub fn insufficient_funds<T: fmt::Display>(msg: T) -> Self {
+        ApiError::InsufficientFunds(msg.to_string())
+    }
+
+    pub fn limit_exceeded<T: fmt::Display>(msg: T) -> Self {
+        ApiError::LimitExceeded(msg.to_string())
+    }
+
+    pub fn two_factor_required<T: fmt::Display>(msg: T) -> Self {
+        ApiError::TwoFactorRequired(msg.to_string())
+    }
+
+    pub fn too_many_attempts<T: fmt::Display>(msg: T) -> Self {
+        ApiError::TooManyAttempts(msg.to_string())
+    }
+
+    pub fn validation<T: fmt::Display>(msg: T) -> Self {
+        ApiError::Validation(msg.to_string())
+    }
+
+    pub fn internal<T: fmt::Display>(msg: T) -> Self {
+        ApiError::Internal(msg.to_string())
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::InsufficientFunds(_) => "insufficient_funds",
+            ApiError::LimitExceeded(_) => "limit_exceeded",
+            ApiError::TwoFactorRequired(_) => "two_factor_required",
+            ApiError::TooManyAttempts(_) => "too_many_attempts",
+            ApiError::Validation(_) => "validation_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn status_code(&self) -> u16 {
+        match self {
+            ApiError::Unauthorized(_) => 401,
+            ApiError::Forbidden(_) => 403,
+            ApiError::NotFound(_) => 404,
+            ApiError::Conflict(_) => 409,
+            ApiError::InsufficientFunds(_) => 402,
+            ApiError::LimitExceeded(_) => 403,
+            ApiError::TwoFactorRequired(_) => 403,
+            ApiError::TooManyAttempts(_) => 429,
+            ApiError::Validation(_) => 400,
+            ApiError::Internal(_) => 500,
+        }
+    }
+
+    pub fn into_response(self) -> GurtResponse {
+        let message = match &self {
+            ApiError::Internal(detail) => {
+                tracing::error!("Internal error: {}", detail);
+                "Internal server error".to_string()
+            }
+            other => other.to_string(),
+        };
+
+        let body = json!({"error": message, "code": self.code()});
+        let mut response = GurtResponse::new(GurtStatusCode::BadRequest)
+            .with_header("content-type", "application/json")
+            .with_string_body(body.to_string());
+        response.status_code = self.status_code();
+        response.status_message = status_message(response.status_code).to_string();
+        response
+    }
+}
+
+// Anything that still fails with a plain GurtError (a query, serialization)
+// is a server-side problem.
+impl From<GurtError> for ApiError {
+    fn from(e: GurtError) -> Self {
+        ApiError::Internal(e.to_string())
+    }
+}
+
+impl From<ApiError> for GurtError {
+    fn from(e: ApiError) -> Self {
+        GurtError::invalid_message(e)
+    }
+}
+
+// gurtlib has no constants for 402 or 409, so their status lines are filled in by hand.
+pub(crate) fn status_message(status_code: u16) -> &'static str {
+    match status_code {
+        402 => "PAYMENT_REQUIRED",
+        409 => "CONFLICT",
+        code => GurtStatusCode::from_u16(code).map(|c| c.message()).unwrap_or("UNKNOWN"),
+    }
+}
@@ -1,155 +1,371 @@
-use crate::{models::*, auth::*, database::*};
+use crate::{models::*, auth::*, database::*, money::Money, AppState};
+use crate::ledger::{self, Account, JournalEntry};
+use crate::{authorizations, checkout, idempotency, limits, lockout, money_requests, passwords, payment_links, reconcile, scheduled_transfers, subscriptions, two_factor, webhooks};
+use crate::error::{ApiError, ApiResult};
 use gurtlib::prelude::*;
-use gurtlib::GurtStatusCode;
 use serde_json::json;
 use uuid::Uuid;
 use chrono::Utc;
 use sqlx::Row;
 
+const INVOICE_PAGE_SIZE: i64 = 20;
+const MAX_INVOICE_PAGE_SIZE: i64 = 100;
+const MAX_LINE_ITEMS: usize = 100;
+const MAX_LINE_NAME_LEN: usize = 200;
+const MAX_LINE_QUANTITY: i64 = 1_000_000;
+const MAX_METADATA_KEYS: usize = 20;
+const MAX_METADATA_KEY_LEN: usize = 40;
+const MAX_METADATA_VALUE_LEN: usize = 500;
+
 #[derive(serde::Deserialize)]
 pub struct RegisterRequest { pub username: String, pub password: String }
 #[derive(serde::Deserialize)]
 pub struct LoginRequest { pub username: String, pub password: String }
 
-pub fn handle_register_local(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
+pub fn handle_register_local(state: &AppState, ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     let body = ctx.text().unwrap_or_default();
     Box::pin(async move {
         let req: RegisterRequest = serde_json::from_str(&body)
-            .map_err(|_| GurtError::invalid_message("Invalid JSON".to_string()))?;
-        if req.username.trim().is_empty() || req.password.len() < 6 {
-            return GurtResponse::bad_request().with_json_body(&json!({"error": "Username and 6+ char password required"}));
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        if req.username.trim().is_empty() {
+            return Err(ApiError::validation("Username is required"));
         }
-        let pool = get_database_pool().await?;
-        if let Some(_) = get_user_by_username(&pool, &req.username).await? {
-            return GurtResponse::bad_request().with_json_body(&json!({"error": "Username already exists"}));
+        passwords::validate(&req.password)?;
+        if get_user_by_username(&pool, &req.username).await?.is_some() {
+            return Err(ApiError::conflict("Username already exists"));
         }
         let ph = hash_password(&req.password)?;
         let user = create_user_with_password(&pool, &req.username, &ph).await?;
         let token = generate_session_token(&pool, &user).await?;
-        GurtResponse::ok().with_json_body(&json!({"user": user, "session_token": token.jwt}))
+        Ok(GurtResponse::ok().with_json_body(&session_json(Some(&user), &token))?)
     })
 }
 
-pub fn handle_login_local(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
+pub fn handle_login_local(state: &AppState, ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     let body = ctx.text().unwrap_or_default();
+    let source = ctx.client_ip().to_string();
     Box::pin(async move {
         let req: LoginRequest = serde_json::from_str(&body)
-            .map_err(|_| GurtError::invalid_message("Invalid JSON".to_string()))?;
-        let pool = get_database_pool().await?;
-        let user = match get_user_by_username(&pool, &req.username).await? {
-            Some(u) => u, None => return GurtResponse::new(GurtStatusCode::Unauthorized).with_json_body(&json!({"error": "Invalid credentials"}))
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        let keys = [lockout::Key::login_account(&req.username), lockout::Key::login_source(&source)];
+        lockout::check(&pool, &keys).await?;
+        let user = get_user_by_username(&pool, &req.username).await?;
+        let stored = match &user { Some(u) => get_password_hash(&pool, &u.id).await?, None => None };
+        let ok = verify_password_or_dummy(&req.password, stored.as_deref())?;
+        let user = match user {
+            Some(u) if ok => u,
+            _ => {
+                lockout::record_failure(&pool, &keys).await?;
+                return Err(ApiError::unauthorized("Invalid credentials"));
+            }
+        };
+        // Only the account's count is forgiven. The address keeps its failures
+        // until they age out of the window, so someone guessing at many
+        // accounts can't wipe the count by signing in to their own in between.
+        lockout::clear(&pool, &keys[0]).await?;
+        if two_factor::is_enabled(&pool, user.id).await? {
+            let challenge = two_factor::start_login(&pool, user.id).await?;
+            return Ok(GurtResponse::ok().with_json_body(&json!({
+                "two_factor_required": true,
+                "challenge_token": challenge.challenge_token,
+                "expires_at": challenge.expires_at.to_rfc3339(),
+            }))?);
+        }
+        let token = generate_session_token(&pool, &user).await?;
+        Ok(GurtResponse::ok().with_json_body(&session_json(Some(&user), &token))?)
+    })
+}
+
+/// Second login step: trades the challenge from `/api/auth/login` and a
+/// two-factor code for a session.
+pub fn handle_login_two_factor(state: &AppState, ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    let source = ctx.client_ip().to_string();
+    Box::pin(async move {
+        let req: LoginTwoFactorRequest = serde_json::from_str(&body)
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        let keys = [lockout::Key::login_source(&source)];
+        lockout::check(&pool, &keys).await?;
+        let user_id = match two_factor::complete_login(&pool, &req.challenge_token, &req.code).await {
+            Ok(user_id) => user_id,
+            Err(e @ ApiError::Unauthorized(_)) => {
+                lockout::record_failure(&pool, &keys).await?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
         };
-        let stored = get_password_hash(&pool, &user.id).await?;
-        let ok = match stored { Some(h) => verify_password(&req.password, &h)?, None => false };
-        if !ok { return GurtResponse::new(GurtStatusCode::Unauthorized).with_json_body(&json!({"error": "Invalid credentials"})); }
+        let user = get_user_by_id(&pool, user_id).await?
+            .ok_or_else(|| ApiError::unauthorized("User not found"))?;
         let token = generate_session_token(&pool, &user).await?;
-        GurtResponse::ok().with_json_body(&json!({"user": user, "session_token": token.jwt}))
+        Ok(GurtResponse::ok().with_json_body(&session_json(Some(&user), &token))?)
+    })
+}
+
+/// Swaps a refresh token for a fresh access token and refresh token.
+pub fn handle_refresh_session(state: &AppState, ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    Box::pin(async move {
+        let req: RefreshSessionRequest = serde_json::from_str(&body)
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        let token = refresh_session(&pool, &req.refresh_token).await?;
+        Ok(GurtResponse::ok().with_json_body(&session_json(None, &token))?)
+    })
+}
+
+pub fn handle_logout(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let auth_header = ctx.header("authorization").cloned();
+    Box::pin(async move {
+        principal.into_user()?;
+        let token = bearer_token(auth_header.as_deref())?;
+        invalidate_session(&pool, token).await?;
+        Ok(GurtResponse::ok().with_json_body(&json!({ "success": true }))?)
+    })
+}
+
+pub fn handle_list_sessions(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let auth_header = ctx.header("authorization").cloned();
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let current = session_id_of(bearer_token(auth_header.as_deref())?)?;
+        let sessions = list_sessions(&pool, user.id, &current).await?;
+        Ok(GurtResponse::ok().with_json_body(&json!({ "sessions": sessions }))?)
+    })
+}
+
+pub fn handle_revoke_session(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let req: RevokeSessionRequest = serde_json::from_str(&body)
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        revoke_session(&pool, user.id, &req.session_id).await?;
+        Ok(GurtResponse::ok().with_json_body(&json!({ "success": true }))?)
+    })
+}
+
+/// Signs the user out everywhere except the session making the request.
+pub fn handle_revoke_other_sessions(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let auth_header = ctx.header("authorization").cloned();
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let current = session_id_of(bearer_token(auth_header.as_deref())?)?;
+        let revoked = revoke_other_sessions(&pool, user.id, &current).await?;
+        Ok(GurtResponse::ok().with_json_body(&json!({ "success": true, "revoked": revoked }))?)
+    })
+}
+
+/// Changes the password and logs out the user's other sessions. Wrong current
+/// passwords count towards the account's login lockout.
+pub fn handle_change_password(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    let auth_header = ctx.header("authorization").cloned();
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let current = session_id_of(bearer_token(auth_header.as_deref())?)?;
+        let req: ChangePasswordRequest = serde_json::from_str(&body)
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        passwords::validate(&req.new_password)?;
+        let keys = [lockout::Key::login_account(&user.username)];
+        lockout::check(&pool, &keys).await?;
+        let stored = get_password_hash(&pool, &user.id).await?
+            .ok_or_else(|| ApiError::validation("This account has no password yet; ask an admin for a reset token"))?;
+        if !verify_password(&req.current_password, &stored)? {
+            lockout::record_failure(&pool, &keys).await?;
+            return Err(ApiError::forbidden("Current password is incorrect"));
+        }
+        lockout::clear(&pool, &keys[0]).await?;
+        let revoked = passwords::change(&pool, user.id, &current, &req.new_password).await?;
+        Ok(GurtResponse::ok().with_json_body(&json!({ "success": true, "sessions_revoked": revoked }))?)
+    })
+}
+
+/// Sets a new password with a token from an admin. The user signs in again
+/// afterwards.
+pub fn handle_reset_password(state: &AppState, ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    let source = ctx.client_ip().to_string();
+    Box::pin(async move {
+        let req: ResetPasswordRequest = serde_json::from_str(&body)
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        let keys = [lockout::Key::login_source(&source)];
+        lockout::check(&pool, &keys).await?;
+        match passwords::reset(&pool, &req.reset_token, &req.new_password).await {
+            Ok(_) => {}
+            Err(e @ ApiError::Unauthorized(_)) => {
+                lockout::record_failure(&pool, &keys).await?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(GurtResponse::ok().with_json_body(&json!({ "success": true }))?)
+    })
+}
+
+fn session_json(user: Option<&User>, token: &SessionToken) -> serde_json::Value {
+    let mut body = json!({
+        "session_token": token.jwt,
+        "refresh_token": token.refresh_token,
+        "session_id": token.session_id,
+        "expires_at": token.expires_at.to_rfc3339(),
+    });
+    if let Some(user) = user {
+        body["user"] = json!(user);
+    }
+    body
+}
+
+pub fn handle_two_factor_status(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let status = two_factor::status(&pool, user.id).await?;
+        Ok(GurtResponse::ok().with_json_body(&json!(status))?)
+    })
+}
+
+pub fn handle_two_factor_setup(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let enrollment = two_factor::begin_setup(&pool, &user).await?;
+        Ok(GurtResponse::ok().with_json_body(&json!(enrollment))?)
+    })
+}
+
+pub fn handle_two_factor_enable(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let req: TwoFactorCodeRequest = serde_json::from_str(&body)
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        let recovery_codes = two_factor::enable(&pool, user.id, &req.code).await?;
+        Ok(GurtResponse::ok().with_json_body(&json!({ "success": true, "recovery_codes": recovery_codes }))?)
+    })
+}
+
+pub fn handle_two_factor_disable(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let req: TwoFactorCodeRequest = serde_json::from_str(&body)
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        two_factor::disable(&pool, user.id, &req.code).await?;
+        Ok(GurtResponse::ok().with_json_body(&json!({ "success": true }))?)
+    })
+}
+
+pub fn handle_regenerate_recovery_codes(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let req: TwoFactorCodeRequest = serde_json::from_str(&body)
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        let recovery_codes = two_factor::regenerate_recovery_codes(&pool, user.id, &req.code).await?;
+        Ok(GurtResponse::ok().with_json_body(&json!({ "success": true, "recovery_codes": recovery_codes }))?)
+    })
+}
+
+pub fn handle_set_send_threshold(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let req: SetSendThresholdRequest = serde_json::from_str(&body)
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        two_factor::set_send_threshold(&pool, user.id, &req.code, req.threshold).await?;
+        Ok(GurtResponse::ok().with_json_body(&json!({ "success": true, "send_threshold": req.threshold }))?)
     })
 }
 
 pub fn handle_auth_verify(_ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
     Box::pin(async move {
-        GurtResponse::new(GurtStatusCode::BadRequest).with_json_body(&json!({"error":"OAuth removed; use /api/auth/register and /api/auth/login"}))
+        Ok(ApiError::validation("OAuth removed; use /api/auth/register and /api/auth/login").into_response())
     })
 }
 
 pub fn handle_user_register(_ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
     Box::pin(async move {
-        GurtResponse::new(GurtStatusCode::BadRequest).with_json_body(&json!({"error":"Use /api/auth/register"}))
+        Ok(ApiError::validation("Use /api/auth/register").into_response())
     })
 }
 
-pub fn handle_get_profile(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
-    
+pub fn handle_get_profile(_state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
     Box::pin(async move {
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("Missing authorization header".to_string()))?;
-        
-        let token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
-        
-        let pool = get_database_pool().await?;
+        let user = principal.into_user()?;
         
-        let user = validate_session_token(&pool, token).await?;
-        
-        GurtResponse::ok().with_json_body(&user)
+        Ok(GurtResponse::ok().with_json_body(&user)?)
     })
 }
 
-pub fn handle_get_balance(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
+pub fn handle_get_balance(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     
     Box::pin(async move {
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("Missing authorization header".to_string()))?;
-        
-        let token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
-        
-        let pool = get_database_pool().await?;
-        
-        let user = validate_session_token(&pool, token).await?;
+        let user = principal.into_user()?;
         
-        let balance_row = sqlx::query("SELECT SUM(CASE 
-                WHEN from_user_id = $1 THEN -amount 
-                WHEN to_user_id = $2 THEN amount 
-                ELSE 0 
-            END) as balance FROM transactions WHERE from_user_id = $3 OR to_user_id = $4")
-            .bind(&user.id.to_string())
-            .bind(&user.id.to_string())
-            .bind(&user.id.to_string())
-            .bind(&user.id.to_string())
-            .fetch_one(&pool)
-            .await
-            .map_err(|e| GurtError::invalid_message(format!("Failed to get balance: {}", e)))?;
-        
-        let sent_row = sqlx::query("SELECT CAST(COALESCE(SUM(amount), 0) AS DOUBLE PRECISION) as total_sent FROM transactions WHERE from_user_id = $1")
-            .bind(&user.id.to_string())
+        let sent_row = sqlx::query("SELECT CAST(COALESCE(SUM(amount), 0) AS BIGINT) as total_sent FROM transactions WHERE from_user_id = $1")
+            .bind(user.id.to_string())
             .fetch_one(&pool)
             .await
             .map_err(|e| GurtError::invalid_message(format!("Failed to get sent total: {}", e)))?;
             
-        let received_row = sqlx::query("SELECT CAST(COALESCE(SUM(amount), 0) AS DOUBLE PRECISION) as total_received FROM transactions WHERE to_user_id = $1")
-            .bind(&user.id.to_string())
+        let received_row = sqlx::query("SELECT CAST(COALESCE(SUM(amount), 0) AS BIGINT) as total_received FROM transactions WHERE to_user_id = $1")
+            .bind(user.id.to_string())
             .fetch_one(&pool)
             .await
             .map_err(|e| GurtError::invalid_message(format!("Failed to get received total: {}", e)))?;
         
-        let transaction_balance: f64 = balance_row.get("balance");
-        let balance = 0.0 + transaction_balance;
-        let total_sent: f64 = sent_row.get("total_sent");
-        let total_received: f64 = received_row.get("total_received");
+        let held_row = sqlx::query("SELECT held_balance FROM users WHERE id = $1")
+            .bind(user.id.to_string())
+            .fetch_one(&pool)
+            .await
+            .map_err(|e| GurtError::invalid_message(format!("Failed to get held balance: {}", e)))?;
+        
+        // The cached wallet balance is maintained by the ledger on every posting;
+        // card authorizations hold part of it back until they are captured or released
+        let balance = user.wallet_balance;
+        let pending_balance = Money::from_minor(held_row.get("held_balance"));
+        let available_balance = balance.checked_sub(pending_balance)
+            .ok_or_else(|| ApiError::internal("Available balance out of range"))?;
+        let total_sent = Money::from_minor(sent_row.get("total_sent"));
+        let total_received = Money::from_minor(received_row.get("total_received"));
         
         let response = json!({
             "balance": balance,
+            "available_balance": available_balance,
+            "pending_balance": pending_balance,
             "currency": "GC",
             "address": user.wallet_address,
             "total_sent": total_sent,
             "total_received": total_received
         });
         
-        GurtResponse::ok().with_json_body(&response)
+        Ok(GurtResponse::ok().with_json_body(&response)?)
     })
 }
 
-pub fn handle_get_transactions(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
+pub fn handle_get_transactions(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     
     Box::pin(async move {
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("Missing authorization header".to_string()))?;
-        
-        let token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
-        
-        let pool = get_database_pool().await?;
-        
-        let user = validate_session_token(&pool, token).await?;
+        let user = principal.into_user()?;
         
         let rows = sqlx::query(
             "SELECT t.id, t.transaction_type, t.from_user_id, t.to_user_id, t.business_id,
-                    t.amount, t.platform_fee, t.status, t.description, t.created_at, t.completed_at,
+                    t.amount, t.platform_fee, t.refunded_amount, t.status, t.description, t.created_at, t.completed_at,
                     fu.username AS from_username, tu.username AS to_username,
                     fu.wallet_address AS from_address, tu.wallet_address AS to_address,
                     b.business_name
@@ -161,8 +377,8 @@ pub fn handle_get_transactions(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std
              ORDER BY t.created_at DESC
              LIMIT 50"
         )
-        .bind(&user.id.to_string())
-        .bind(&user.id.to_string())
+        .bind(user.id.to_string())
+        .bind(user.id.to_string())
         .fetch_all(&pool)
         .await
         .map_err(|e| GurtError::invalid_message(format!("Failed to get transactions: {}", e)))?;
@@ -199,7 +415,8 @@ pub fn handle_get_transactions(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std
                 json!({
                     "id": row.get::<String, _>("id"),
                     "transaction_type": row.get::<String, _>("transaction_type"),
-                    "amount": row.get::<f64, _>("amount"),
+                    "amount": Money::from_minor(row.get("amount")),
+                    "refunded_amount": Money::from_minor(row.get("refunded_amount")),
                     "description": row.get::<String, _>("description"),
                     "status": row.get::<String, _>("status"),
                     "created_at": row.get::<String, _>("created_at"),
@@ -210,82 +427,57 @@ pub fn handle_get_transactions(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std
             })
             .collect();
         
-        GurtResponse::ok().with_json_body(&transactions)
+        Ok(GurtResponse::ok().with_json_body(&transactions)?)
     })
 }
 
-pub fn handle_send_money(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
+pub fn handle_send_money(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let idempotency_key = ctx.header("idempotency-key").map(|s| s.to_string());
     let body = ctx.text().unwrap_or_default();
     
     Box::pin(async move {
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("Missing authorization header".to_string()))?;
-        
-        let token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
+        let user = principal.into_user()?;
         
-        let pool = get_database_pool().await?;
-        
-        let user = validate_session_token(&pool, token).await?;
-        
-        let request: SendMoneyRequest = serde_json::from_str(&body)
-            .map_err(|_| GurtError::invalid_message("Invalid JSON".to_string()))?;
-        
-        if request.amount <= 0.0 {
-            return GurtResponse::bad_request()
-                .with_json_body(&json!({"error": "Amount must be positive"}));
-        }
-        
-        if request.amount > 10000.0 {
-            return GurtResponse::bad_request()
-                .with_json_body(&json!({"error": "Amount exceeds daily limit of 10,000 GC"}));
-        }
-        
-        let recipient = get_user_by_wallet_address(&pool, &request.to_address).await?;
-        
-        match recipient {
-            Some(recipient) => {
-                if recipient.id == user.id {
-                    return GurtResponse::bad_request()
-                        .with_json_body(&json!({"error": "Cannot send money to yourself"}));
-                }
+        idempotency::run(&pool, idempotency_key.as_deref(), &format!("user:{}", user.id), "/api/wallet/send", &body, async {
+            let request: SendMoneyRequest = serde_json::from_str(&body)
+                .map_err(|_| ApiError::validation("Invalid JSON"))?;
+            
+            if !request.amount.is_positive() {
+                return Err(ApiError::validation("Amount must be positive"));
+            }
+            
+            two_factor::require_for_send(&pool, user.id, request.amount, request.two_factor_code.as_deref()).await?;
+            
+            let recipient = get_user_by_wallet_address(&pool, &request.to_address).await?;
+            
+            match recipient {
+                Some(recipient) => {
+                    if recipient.id == user.id {
+                        return Err(ApiError::validation("Cannot send money to yourself"));
+                    }
                 
-                let transaction = transfer_funds(&pool, &user.id, &recipient.id, request.amount, &request.description).await?;
-                GurtResponse::ok().with_json_body(&transaction)
+                    let transaction = transfer_funds(&pool, &user.id, &recipient.id, request.amount, &request.description).await?;
+                    Ok(GurtResponse::ok().with_json_body(&transaction)?)
+                }
+                None => Err(ApiError::not_found("Recipient wallet address not found"))
             }
-            None => GurtResponse::bad_request()
-                .with_json_body(&json!({"error": "Recipient wallet address not found"}))
-        }
+        }).await
     })
 }
 
-pub fn handle_request_money(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
+pub fn handle_request_money(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     let body = ctx.text().unwrap_or_default();
     
     Box::pin(async move {
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("Missing authorization header".to_string()))?;
-        
-        let token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
-        
-        let pool = get_database_pool().await?;
-        
-        let user = validate_session_token(&pool, token).await?;
+        let user = principal.into_user()?;
         
         let request: RequestMoneyRequest = serde_json::from_str(&body)
-            .map_err(|_| GurtError::invalid_message("Invalid JSON".to_string()))?;
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
         
-        if request.amount <= 0.0 {
-            return GurtResponse::bad_request()
-                .with_json_body(&json!({"error": "Amount must be positive"}));
-        }
-        
-        if request.amount > 10000.0 {
-            return GurtResponse::bad_request()
-                .with_json_body(&json!({"error": "Amount exceeds daily limit of 10,000 GC"}));
+        if !request.amount.is_positive() {
+            return Err(ApiError::validation("Amount must be positive"));
         }
         
         let from_user = get_user_by_wallet_address(&pool, &request.from_address).await?;
@@ -293,8 +485,7 @@ pub fn handle_request_money(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::f
         match from_user {
             Some(from_user) => {
                 if from_user.id == user.id {
-                    return GurtResponse::bad_request()
-                        .with_json_body(&json!({"error": "Cannot request money from yourself"}));
+                    return Err(ApiError::validation("Cannot request money from yourself"));
                 }
                 
                 let request_id = Uuid::new_v4();
@@ -302,12 +493,12 @@ pub fn handle_request_money(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::f
                 
                 sqlx::query(
                     "INSERT INTO money_requests (id, from_user_id, to_user_id, amount, description, status, created_at) 
-                     VALUES (?, ?, ?, ?, ?, 'pending', ?)"
+                     VALUES ($1, $2, $3, $4, $5, 'pending', $6)"
                 )
                 .bind(request_id.to_string())
                 .bind(from_user.id.to_string())
                 .bind(user.id.to_string())
-                .bind(request.amount)
+                .bind(request.amount.minor())
                 .bind(&request.description)
                 .bind(created_at.to_rfc3339())
                 .execute(&pool)
@@ -321,48 +512,238 @@ pub fn handle_request_money(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::f
                     "amount": request.amount,
                     "description": request.description,
                     "status": "pending",
-                    "created_at": created_at.to_rfc3339()
+                    "created_at": created_at.to_rfc3339(),
+                    "expires_at": (created_at + chrono::Duration::days(money_requests::EXPIRY_DAYS)).to_rfc3339()
                 });
                 
-                GurtResponse::ok().with_json_body(&response)
+                Ok(GurtResponse::ok().with_json_body(&response)?)
             }
-            None => GurtResponse::bad_request()
-                .with_json_body(&json!({"error": "User wallet address not found"}))
+            None => Err(ApiError::not_found("User wallet address not found"))
         }
     })
 }
 
-pub fn handle_register_business(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
+pub fn handle_list_incoming_requests(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let requests = money_requests::list(&pool, user.id, money_requests::Direction::Incoming).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({ "requests": requests }))?)
+    })
+}
+
+pub fn handle_list_outgoing_requests(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let requests = money_requests::list(&pool, user.id, money_requests::Direction::Outgoing).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({ "requests": requests }))?)
+    })
+}
+
+pub fn handle_pay_money_request(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let request: RespondMoneyRequest = serde_json::from_str(&body)
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        
+        let asked = money_requests::get_incoming(&pool, user.id, request.request_id).await?;
+        two_factor::require_for_send(&pool, user.id, asked.amount, request.two_factor_code.as_deref()).await?;
+        
+        let (money_request, transaction) = money_requests::pay(&pool, user.id, request.request_id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "request": money_request,
+            "transaction": transaction
+        }))?)
+    })
+}
+
+pub fn handle_decline_money_request(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let request: RespondMoneyRequest = serde_json::from_str(&body)
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        
+        let money_request = money_requests::decline(&pool, user.id, request.request_id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "request": money_request
+        }))?)
+    })
+}
+
+pub fn handle_cancel_money_request(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let request: RespondMoneyRequest = serde_json::from_str(&body)
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        
+        let money_request = money_requests::cancel(&pool, user.id, request.request_id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "request": money_request
+        }))?)
+    })
+}
+
+pub fn handle_create_scheduled_transfer(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let request: CreateScheduledTransferRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        
+        // Approving the schedule approves every run, so each run's amount is what is checked
+        two_factor::require_for_send(&pool, user.id, request.amount, request.two_factor_code.as_deref()).await?;
+        
+        let schedule = scheduled_transfers::create(&pool, user.id, &request).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "scheduled_transfer": schedule
+        }))?)
+    })
+}
+
+pub fn handle_list_scheduled_transfers(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let schedules = scheduled_transfers::list(&pool, user.id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({ "scheduled_transfers": schedules }))?)
+    })
+}
+
+pub fn handle_list_scheduled_transfer_runs(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let path = ctx.path().to_string();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let schedule_id_str = path.strip_prefix("/api/transfers/scheduled/runs/")
+            .ok_or_else(|| ApiError::validation("Missing scheduled transfer ID in path"))?;
+        
+        let schedule_id = Uuid::parse_str(schedule_id_str)
+            .map_err(|_| ApiError::validation("Invalid scheduled transfer ID format"))?;
+        
+        let runs = scheduled_transfers::list_runs(&pool, user.id, schedule_id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({ "runs": runs }))?)
+    })
+}
+
+pub fn handle_pause_scheduled_transfer(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let request: ScheduledTransferActionRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        
+        let schedule = scheduled_transfers::pause(&pool, user.id, request.scheduled_transfer_id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "scheduled_transfer": schedule
+        }))?)
+    })
+}
+
+pub fn handle_resume_scheduled_transfer(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     let body = ctx.text().unwrap_or_default();
     
     Box::pin(async move {
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("Missing authorization header".to_string()))?;
+        let user = principal.into_user()?;
+        let request: ScheduledTransferActionRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
         
-        let token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
+        let schedule = scheduled_transfers::resume(&pool, user.id, request.scheduled_transfer_id).await?;
         
-        let pool = get_database_pool().await?;
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "scheduled_transfer": schedule
+        }))?)
+    })
+}
+
+pub fn handle_cancel_scheduled_transfer(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let request: ScheduledTransferActionRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        
+        let schedule = scheduled_transfers::cancel(&pool, user.id, request.scheduled_transfer_id).await?;
         
-        let user = validate_session_token(&pool, token).await?;
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "scheduled_transfer": schedule
+        }))?)
+    })
+}
+
+/// The user's transfer limits and how much of each they have left.
+pub fn handle_get_limits(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let allowance = limits::allowance(&pool, user.id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!(allowance))?)
+    })
+}
+
+pub fn handle_register_business(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
         
         let request: BusinessRegisterRequest = serde_json::from_str(&body)
-            .map_err(|_| GurtError::invalid_message("Invalid JSON".to_string()))?;
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
         
         let business_id = Uuid::new_v4();
         let api_key = format!("gp_{}", generate_code().replace("-", "").to_lowercase());
+        let return_secret = checkout::generate_return_secret();
         let created_at = Utc::now();
         
         sqlx::query(
-            "INSERT INTO businesses (id, user_id, business_name, website_url, api_key, created_at) 
-             VALUES ($1, $2, $3, $4, $5, $6)"
+            "INSERT INTO businesses (id, user_id, business_name, website_url, api_key, return_secret, created_at) 
+             VALUES ($1, $2, $3, $4, $5, $6, $7)"
         )
         .bind(business_id.to_string())
         .bind(user.id.to_string())
         .bind(&request.business_name)
         .bind(&request.website_url)
         .bind(&api_key)
+        .bind(&return_secret)
         .bind(created_at.to_rfc3339())
         .execute(&pool)
         .await
@@ -374,38 +755,31 @@ pub fn handle_register_business(ctx: &ServerContext) -> std::pin::Pin<Box<dyn st
             business_name: request.business_name,
             website_url: request.website_url,
             api_key,
+            return_secret,
             verified: true,
-            balance: 0.0,
+            balance: Money::ZERO,
             created_at,
         };
         
-        GurtResponse::ok().with_json_body(&business)
+        Ok(GurtResponse::ok().with_json_body(&business)?)
     })
 }
 
-pub fn handle_redeem_code(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
+pub fn handle_redeem_code(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     let body = ctx.text().unwrap_or_default();
     
     Box::pin(async move {
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("Missing authorization header".to_string()))?;
-        
-        let token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
-        
-        let pool = get_database_pool().await?;
-        
-        let user = validate_session_token(&pool, token).await?;
+        let user = principal.into_user()?;
         
         let request: RedeemCodeRequest = serde_json::from_str(&body)
-            .map_err(|_| GurtError::invalid_message("Invalid JSON".to_string()))?;
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
         
         let mut tx = pool.begin().await
             .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
         
         let code_row = sqlx::query(
-            "SELECT id, amount, expires_at, max_uses, current_uses, active FROM redemption_codes WHERE code = $1"
+            "SELECT id, amount, expires_at, max_uses, current_uses, CASE WHEN active THEN 1 ELSE 0 END AS active FROM redemption_codes WHERE code = $1"
         )
         .bind(&request.code)
         .fetch_optional(&mut *tx)
@@ -415,30 +789,27 @@ pub fn handle_redeem_code(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::fut
         match code_row {
             Some(row) => {
                 let code_id: String = row.get("id");
-                let amount: f64 = row.get("amount");
+                let amount = Money::from_minor(row.get("amount"));
                 let expires_at: Option<String> = row.get("expires_at");
                 let max_uses: Option<i32> = row.get("max_uses");
                 let current_uses: i32 = row.get("current_uses");
-                let active: bool = row.get("active");
+                let active = row.get::<i64, _>("active") != 0;
                 
                 if !active {
-                    return GurtResponse::bad_request()
-                        .with_json_body(&json!({"error": "Code is not active"}));
+                    return Err(ApiError::validation("Code is not active"));
                 }
                 
                 if let Some(expires_str) = expires_at {
                     let expires = chrono::DateTime::parse_from_rfc3339(&expires_str)
                         .map_err(|_| GurtError::invalid_message("Invalid expiration date".to_string()))?;
                     if Utc::now() > expires {
-                        return GurtResponse::bad_request()
-                            .with_json_body(&json!({"error": "Code has expired"}));
+                        return Err(ApiError::validation("Code has expired"));
                     }
                 }
                 
                 if let Some(max) = max_uses {
                     if current_uses >= max {
-                        return GurtResponse::bad_request()
-                            .with_json_body(&json!({"error": "Code has reached maximum uses"}));
+                        return Err(ApiError::validation("Code has reached maximum uses"));
                     }
                 }
                 
@@ -458,8 +829,8 @@ pub fn handle_redeem_code(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::fut
                 )
                 .bind(redemption_id.to_string())
                 .bind(&code_id)
-                .bind(&user.id.to_string())
-                .bind(amount)
+                .bind(user.id.to_string())
+                .bind(amount.minor())
                 .bind(redeemed_at.to_rfc3339())
                 .execute(&mut *tx)
                 .await
@@ -468,73 +839,197 @@ pub fn handle_redeem_code(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::fut
                 let transaction_id = Uuid::new_v4();
                 sqlx::query(
                     "INSERT INTO transactions (id, transaction_type, from_user_id, to_user_id, amount, platform_fee, status, description, created_at) 
-                     VALUES (?, 'code_redemption', NULL, ?, ?, 0.0, 'completed', ?, ?)"
+                     VALUES (?, 'code_redemption', NULL, ?, ?, 0, 'completed', ?, ?)"
                 )
                 .bind(transaction_id.to_string())
-                .bind(&user.id.to_string())
-                .bind(amount)
+                .bind(user.id.to_string())
+                .bind(amount.minor())
                 .bind(format!("Redeemed code: {}", request.code))
                 .bind(redeemed_at.to_rfc3339())
                 .execute(&mut *tx)
                 .await
                 .map_err(|e| GurtError::invalid_message(format!("Failed to create transaction: {}", e)))?;
                 
+                let entry = JournalEntry::transfer(
+                    transaction_id,
+                    Account::Mint,
+                    Account::UserWallet(user.id),
+                    amount,
+                    &format!("Redeemed code: {}", request.code),
+                );
+                ledger::post(&mut tx, &entry).await?;
+                
                 tx.commit().await
                     .map_err(|e| GurtError::invalid_message(format!("Failed to commit transaction: {}", e)))?;
                 
-                GurtResponse::ok().with_json_body(&json!({
+                Ok(GurtResponse::ok().with_json_body(&json!({
                     "message": "Code redeemed successfully",
                     "amount": amount,
                     "code": request.code
-                }))
+                }))?)
             }
-            None => GurtResponse::bad_request()
-                .with_json_body(&json!({"error": "Invalid or expired code"}))
+            None => Err(ApiError::not_found("Invalid or expired code"))
         }
     })
 }
 
-pub fn handle_create_code(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
+pub fn handle_list_limit_tiers(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    
+    Box::pin(async move {
+        principal.into_user()?;
+        let tiers = limits::list_tiers(&pool).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({ "tiers": tiers }))?)
+    })
+}
+
+pub fn handle_set_limit_tier(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     let body = ctx.text().unwrap_or_default();
     
     Box::pin(async move {
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("Missing authorization header".to_string()))?;
+        let admin = principal.into_user()?;
+        let request: SetTierLimitsRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
         
-        let token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
+        let tier = limits::set_tier(&pool, admin.id, &request).await?;
         
-        let pool = get_database_pool().await?;
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "tier": tier
+        }))?)
+    })
+}
+
+/// Moves a user to another tier and/or overrides their limits. Each call
+/// replaces the user's previous overrides.
+pub fn handle_set_user_limits(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let admin = principal.into_user()?;
+        let request: SetUserLimitsRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
         
-        let user = validate_session_token(&pool, token).await?;
+        get_user_by_id(&pool, request.user_id).await?
+            .ok_or_else(|| ApiError::not_found("User not found"))?;
         
-        if !user.is_admin {
-            return GurtResponse::new(GurtStatusCode::Forbidden)
-                .with_json_body(&json!({"error": "Admin access required"}));
-        }
+        limits::set_user(&pool, admin.id, &request).await?;
+        let allowance = limits::allowance(&pool, request.user_id).await?;
         
-        let request: CreateCodeRequest = serde_json::from_str(&body)
-            .map_err(|_| GurtError::invalid_message("Invalid JSON".to_string()))?;
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "limits": allowance
+        }))?)
+    })
+}
+
+pub fn handle_get_user_limits(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let path = ctx.path().to_string();
+    
+    Box::pin(async move {
+        principal.into_user()?;
+        let user_id_str = path.strip_prefix("/api/admin/limits/user/")
+            .ok_or_else(|| ApiError::validation("Missing user ID in path"))?;
         
-        let code_id = Uuid::new_v4();
-        let code = generate_code();
-        let created_at = Utc::now();
-        let expires_at = request.expires_in_hours.map(|hours| created_at + chrono::Duration::hours(hours as i64));
+        let user_id = Uuid::parse_str(user_id_str)
+            .map_err(|_| ApiError::validation("Invalid user ID format"))?;
         
-        sqlx::query(
-            "INSERT INTO redemption_codes (id, code, amount, max_uses, current_uses, created_by, created_at, expires_at, active) 
-             VALUES (?, ?, ?, ?, 0, ?, ?, ?, true)"
-        )
-        .bind(code_id.to_string())
-        .bind(&code)
-        .bind(request.amount)
-        .bind(request.max_uses)
-        .bind(&user.id.to_string())
-        .bind(created_at.to_rfc3339())
-        .bind(expires_at.map(|dt| dt.to_rfc3339()))
-        .execute(&pool)
-        .await
+        get_user_by_id(&pool, user_id).await?
+            .ok_or_else(|| ApiError::not_found("User not found"))?;
+        
+        let allowance = limits::allowance(&pool, user_id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!(allowance))?)
+    })
+}
+
+pub fn handle_list_lockouts(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    
+    Box::pin(async move {
+        principal.into_user()?;
+        let lockouts = lockout::list_active(&pool).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({ "lockouts": lockouts }))?)
+    })
+}
+
+pub fn handle_unlock_account(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let admin = principal.into_user()?;
+        let request: UnlockAccountRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        
+        let cleared = lockout::unlock_account(&pool, admin.id, &request.username).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "cleared": cleared
+        }))?)
+    })
+}
+
+pub fn handle_issue_password_reset(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let admin = principal.into_user()?;
+        let request: IssuePasswordResetRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        
+        let user = get_user_by_username(&pool, &request.username).await?
+            .ok_or_else(|| ApiError::not_found("User not found"))?;
+        let reset = passwords::issue_reset_token(&pool, admin.id, user.id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "username": user.username,
+            "reset_token": reset.reset_token,
+            "expires_at": reset.expires_at.to_rfc3339()
+        }))?)
+    })
+}
+
+pub fn handle_create_code(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        
+        let request: CreateCodeRequest = serde_json::from_str(&body)
+            .map_err(|_| ApiError::validation("Invalid JSON"))?;
+        
+        if !request.amount.is_positive() {
+            return Err(ApiError::validation("Amount must be positive"));
+        }
+        
+        let code_id = Uuid::new_v4();
+        let code = generate_code();
+        let created_at = Utc::now();
+        let expires_at = request.expires_in_hours.map(|hours| created_at + chrono::Duration::hours(hours as i64));
+        
+        sqlx::query(
+            "INSERT INTO redemption_codes (id, code, amount, max_uses, current_uses, created_by, created_at, expires_at, active) 
+             VALUES (?, ?, ?, ?, 0, ?, ?, ?, true)"
+        )
+        .bind(code_id.to_string())
+        .bind(&code)
+        .bind(request.amount.minor())
+        .bind(request.max_uses)
+        .bind(user.id.to_string())
+        .bind(created_at.to_rfc3339())
+        .bind(expires_at.map(|dt| dt.to_rfc3339()))
+        .execute(&pool)
+        .await
         .map_err(|e| GurtError::invalid_message(format!("Failed to create code: {}", e)))?;
         
         let redemption_code = RedemptionCode {
@@ -549,29 +1044,41 @@ pub fn handle_create_code(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::fut
             active: true,
         };
         
-        GurtResponse::ok().with_json_body(&redemption_code)
+        Ok(GurtResponse::ok().with_json_body(&redemption_code)?)
     })
 }
 
-pub fn handle_get_businesses(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
+pub fn handle_reconcile_balances(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
     
     Box::pin(async move {
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("Missing authorization header".to_string()))?;
-        
-        let token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
-        
-        let pool = get_database_pool().await?;
+        let user = principal.into_user()?;
+        
+        // An empty body is a dry run
+        let request: ReconcileRequest = if body.trim().is_empty() {
+            ReconcileRequest::default()
+        } else {
+            serde_json::from_str(&body)
+                .map_err(|_| ApiError::validation("Invalid JSON"))?
+        };
         
-        let user = validate_session_token(&pool, token).await?;
+        let report = reconcile::reconcile(&pool, request.repair, Some(user.id)).await?;
+        Ok(GurtResponse::ok().with_json_body(&report)?)
+    })
+}
+
+pub fn handle_get_businesses(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
         
         let rows = sqlx::query(
-            "SELECT id, user_id, business_name, website_url, api_key, verified, balance, created_at 
+            "SELECT id, user_id, business_name, website_url, api_key, return_secret, CASE WHEN verified THEN 1 ELSE 0 END AS verified, balance, created_at 
              FROM businesses WHERE user_id = $1 ORDER BY created_at DESC"
         )
-        .bind(&user.id.to_string())
+        .bind(user.id.to_string())
         .fetch_all(&pool)
         .await
         .map_err(|e| GurtError::invalid_message(format!("Failed to get businesses: {}", e)))?;
@@ -584,8 +1091,9 @@ pub fn handle_get_businesses(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::
                 business_name: row.get("business_name"),
                 website_url: row.get("website_url"),
                 api_key: row.get("api_key"),
-                verified: row.get("verified"),
-                balance: row.get("balance"),
+                return_secret: row.get("return_secret"),
+                verified: row.get::<i64, _>("verified") != 0,
+                balance: Money::from_minor(row.get("balance")),
                 created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at")).unwrap().with_timezone(&Utc),
             })
             .collect();
@@ -593,7 +1101,7 @@ pub fn handle_get_businesses(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::
         for business in &businesses {
             if !business.verified {
                 let _ = sqlx::query("UPDATE businesses SET verified = TRUE WHERE id = $1")
-                    .bind(&business.id.to_string())
+                    .bind(business.id.to_string())
                     .execute(&pool)
                     .await;
             }
@@ -607,177 +1115,157 @@ pub fn handle_get_businesses(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::
             })
             .collect();
 
-        GurtResponse::ok().with_json_body(&updated_businesses)
+        Ok(GurtResponse::ok().with_json_body(&updated_businesses)?)
     })
 }
 
-pub fn handle_business_transfer(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
+pub fn handle_business_transfer(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let idempotency_key = ctx.header("idempotency-key").map(|s| s.to_string());
     let body = ctx.text().unwrap_or_default();
     
     Box::pin(async move {
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("Missing authorization header".to_string()))?;
-        
-        let token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
-        
-        let pool = get_database_pool().await?;
-        
-        let user = validate_session_token(&pool, token).await?;
-        
-        let request: BusinessTransferRequest = serde_json::from_str(&body)
-            .map_err(|_| GurtError::invalid_message("Invalid JSON".to_string()))?;
-        
-        if request.amount <= 0.0 {
-            return GurtResponse::bad_request()
-                .with_json_body(&json!({"error": "Amount must be positive"}));
-        }
-        
-        let business_row = sqlx::query(
-            "SELECT id, user_id, business_name, balance FROM businesses WHERE id = $1 AND user_id = $2"
-        )
-        .bind(&request.business_id)
-        .bind(&user.id.to_string())
-        .fetch_optional(&pool)
-        .await
-        .map_err(|e| GurtError::invalid_message(format!("Failed to get business: {}", e)))?;
-        
-        let business = match business_row {
-            Some(row) => row,
-            None => return GurtResponse::bad_request()
-                .with_json_body(&json!({"error": "Business not found or access denied"}))
-        };
-        
-        let business_name: String = business.get("business_name");
-        let current_business_balance: f64 = business.get("balance");
-        
-        let mut tx = pool.begin().await
-            .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
-        
-        let (transaction_type, from_user_id, to_user_id, business_id_for_tx) = match request.direction.as_str() {
-            "deposit" => {
-                let user_balance: f64 = sqlx::query_scalar("SELECT wallet_balance FROM users WHERE id = $1")
-                    .bind(&user.id.to_string())
-                    .fetch_one(&mut *tx)
-                    .await
-                    .map_err(|e| GurtError::invalid_message(format!("Failed to get user balance: {}", e)))?;
-                
-                if user_balance < request.amount {
-                    return GurtResponse::bad_request()
-                        .with_json_body(&json!({"error": "Insufficient personal funds"}));
-                }
-                
-                sqlx::query("UPDATE users SET wallet_balance = wallet_balance - $1 WHERE id = $2")
-                    .bind(request.amount)
-                    .bind(&user.id.to_string())
-                    .execute(&mut *tx)
-                    .await
-                    .map_err(|e| GurtError::invalid_message(format!("Failed to debit user: {}", e)))?;
-                
-                sqlx::query("UPDATE businesses SET balance = balance + $1 WHERE id = $2")
-                    .bind(request.amount)
-                    .bind(&request.business_id)
-                    .execute(&mut *tx)
-                    .await
-                    .map_err(|e| GurtError::invalid_message(format!("Failed to credit business: {}", e)))?;
-                
-                ("business_deposit", Some(user.id), None, Some(Uuid::parse_str(&request.business_id).unwrap()))
-            },
-            "withdraw" => {
-                if current_business_balance < request.amount {
-                    return GurtResponse::bad_request()
-                        .with_json_body(&json!({"error": "Insufficient business funds"}));
-                }
-                
-                sqlx::query("UPDATE businesses SET balance = balance - $1 WHERE id = $2")
-                    .bind(request.amount)
-                    .bind(&request.business_id)
-                    .execute(&mut *tx)
-                    .await
-                    .map_err(|e| GurtError::invalid_message(format!("Failed to debit business: {}", e)))?;
-                
-                sqlx::query("UPDATE users SET wallet_balance = wallet_balance + $1 WHERE id = $2")
-                    .bind(request.amount)
-                    .bind(&user.id.to_string())
-                    .execute(&mut *tx)
-                    .await
-                    .map_err(|e| GurtError::invalid_message(format!("Failed to credit user: {}", e)))?;
-                
-                ("business_withdraw", None, Some(user.id), Some(Uuid::parse_str(&request.business_id).unwrap()))
-            },
-            _ => return GurtResponse::bad_request()
-                .with_json_body(&json!({"error": "Invalid direction. Must be 'deposit' or 'withdraw'"}))
-        };
-        
-        let transaction_id = Uuid::new_v4();
-        let created_at = Utc::now();
-        
-        sqlx::query(
-            "INSERT INTO transactions (id, transaction_type, from_user_id, to_user_id, business_id, amount, platform_fee, status, description, created_at, completed_at) 
-             VALUES (?, ?, ?, ?, ?, ?, 0.0, 'completed', ?, ?, ?)"
-        )
-        .bind(transaction_id.to_string())
-        .bind(transaction_type)
-        .bind(from_user_id.map(|id| id.to_string()))
-        .bind(to_user_id.map(|id| id.to_string()))
-        .bind(business_id_for_tx.map(|id| id.to_string()))
-        .bind(request.amount)
-        .bind(&format!("{} - {}", request.description, business_name))
-        .bind(created_at.to_rfc3339())
-        .bind(created_at.to_rfc3339())
-        .execute(&mut *tx)
-        .await
-        .map_err(|e| GurtError::invalid_message(format!("Failed to create transaction: {}", e)))?;
+        let user = principal.into_user()?;
         
-        tx.commit().await
-            .map_err(|e| GurtError::invalid_message(format!("Failed to commit transaction: {}", e)))?;
-        
-        GurtResponse::ok().with_json_body(&json!({
-            "message": format!("Successfully {} {} GC {}", 
-                              if request.direction == "deposit" { "deposited" } else { "withdrew" },
-                              request.amount,
-                              if request.direction == "deposit" { "to" } else { "from" }),
-            "transaction_id": transaction_id,
-            "amount": request.amount,
-            "direction": request.direction
-        }))
+        idempotency::run(&pool, idempotency_key.as_deref(), &format!("user:{}", user.id), "/api/business/transfer", &body, async {
+            let request: BusinessTransferRequest = serde_json::from_str(&body)
+                .map_err(|_| ApiError::validation("Invalid JSON"))?;
+            
+            if !request.amount.is_positive() {
+                return Err(ApiError::validation("Amount must be positive"));
+            }
+            
+            let business_row = sqlx::query(
+                "SELECT id, user_id, business_name, balance FROM businesses WHERE id = $1 AND user_id = $2"
+            )
+            .bind(&request.business_id)
+            .bind(user.id.to_string())
+            .fetch_optional(&pool)
+            .await
+            .map_err(|e| GurtError::invalid_message(format!("Failed to get business: {}", e)))?;
+            
+            let business = match business_row {
+                Some(row) => row,
+                None => return Err(ApiError::not_found("Business not found or access denied"))
+            };
+            
+            let business_name: String = business.get("business_name");
+            let current_business_balance = Money::from_minor(business.get("balance"));
+            
+            let business_id = Uuid::parse_str(&business.get::<String, _>("id"))
+                .map_err(|e| GurtError::invalid_message(format!("Invalid business id: {}", e)))?;
+            
+            let (transaction_type, from_user_id, to_user_id, from_account, to_account) = match request.direction.as_str() {
+                "deposit" => {
+                    if user.wallet_balance < request.amount {
+                        return Err(ApiError::insufficient_funds("Insufficient personal funds"));
+                    }
+                    ("business_deposit", Some(user.id), None, Account::UserWallet(user.id), Account::Business(business_id))
+                },
+                "withdraw" => {
+                    if current_business_balance < request.amount {
+                        return Err(ApiError::insufficient_funds("Insufficient business funds"));
+                    }
+                    ("business_withdraw", None, Some(user.id), Account::Business(business_id), Account::UserWallet(user.id))
+                },
+                _ => return Err(ApiError::validation("Invalid direction. Must be 'deposit' or 'withdraw'"))
+            };
+            
+            let mut tx = pool.begin().await
+                .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+            
+            let transaction_id = Uuid::new_v4();
+            let created_at = Utc::now();
+            let description = format!("{} - {}", request.description, business_name);
+            
+            sqlx::query(
+                "INSERT INTO transactions (id, transaction_type, from_user_id, to_user_id, business_id, amount, platform_fee, status, description, created_at, completed_at) 
+                 VALUES ($1, $2, $3, $4, $5, $6, 0, 'completed', $7, $8, $9)"
+            )
+            .bind(transaction_id.to_string())
+            .bind(transaction_type)
+            .bind(from_user_id.map(|id| id.to_string()))
+            .bind(to_user_id.map(|id| id.to_string()))
+            .bind(business_id.to_string())
+            .bind(request.amount.minor())
+            .bind(&description)
+            .bind(created_at.to_rfc3339())
+            .bind(created_at.to_rfc3339())
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| GurtError::invalid_message(format!("Failed to create transaction: {}", e)))?;
+            
+            let entry = JournalEntry::transfer(transaction_id, from_account, to_account, request.amount, &description);
+            ledger::post(&mut tx, &entry).await?;
+            
+            tx.commit().await
+                .map_err(|e| GurtError::invalid_message(format!("Failed to commit transaction: {}", e)))?;
+            
+            Ok(GurtResponse::ok().with_json_body(&json!({
+                "message": format!("Successfully {} {} GC {}", 
+                                  if request.direction == "deposit" { "deposited" } else { "withdrew" },
+                                  request.amount,
+                                  if request.direction == "deposit" { "to" } else { "from" }),
+                "transaction_id": transaction_id,
+                "amount": request.amount,
+                "direction": request.direction
+            }))?)
+        }).await
     })
 }
 
-pub fn handle_create_invoice(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
+pub fn handle_create_invoice(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     let body = ctx.text().unwrap_or_default();
-    let headers = ctx.headers().clone();
     
     Box::pin(async move {
-        let auth_header = headers.get("authorization")
-            .ok_or_else(|| GurtError::invalid_message("Missing Authorization header"))?;
-        
-        let api_key = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid Authorization header format"))?;
-        
-        let pool = get_database_pool().await?;
-        
-        let business = get_business_by_api_key(&pool, api_key).await?
-            .ok_or_else(|| GurtError::invalid_message("Invalid API key"))?;
-        
-        let req: CreateInvoiceRequest = serde_json::from_str(&body)
-            .map_err(|e| GurtError::invalid_message(format!("Invalid JSON: {}", e)))?;
+        let business = principal.into_business()?;
+        
+        let mut req: CreateInvoiceRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        
+        let (amount, description) = if req.line_items.is_empty() {
+            let amount = req.amount.ok_or_else(|| ApiError::validation("Missing amount"))?;
+            let description = req.description.ok_or_else(|| ApiError::validation("Missing description"))?;
+            (amount, description)
+        } else {
+            let total = price_line_items(&mut req.line_items, req.amount)?;
+            let description = req.description.unwrap_or_else(|| {
+                req.line_items.iter()
+                    .filter(|line| line.kind == LineItemKind::Item)
+                    .map(|line| line.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            });
+            (total, description)
+        };
         
-        if req.amount <= 0.0 {
-            return Err(GurtError::invalid_message("Amount must be greater than 0"));
+        if !amount.is_positive() {
+            return Err(ApiError::validation("Amount must be greater than 0"));
         }
+        validate_metadata(&req.metadata)?;
+        
+        let website_url = business.website_url.as_deref().unwrap_or_default();
+        let success_url = req.success_url.as_deref()
+            .map(|url| checkout::validate_return_url("success_url", url, website_url))
+            .transpose()?;
+        let cancel_url = req.cancel_url.as_deref()
+            .map(|url| checkout::validate_return_url("cancel_url", url, website_url))
+            .transpose()?;
         
         let expires_at = Some(Utc::now() + chrono::Duration::hours(req.expires_in_hours.unwrap_or(24) as i64));
         
-        let invoice = create_invoice(
-            &pool,
-            business.id,
-            req.amount,
-            &req.description,
-            req.customer_name.as_deref(),
+        let invoice = create_invoice(&pool, business.id, NewInvoice {
+            amount,
+            description,
+            customer_name: req.customer_name,
             expires_at,
-        ).await?;
+            line_items: req.line_items,
+            metadata: req.metadata,
+            success_url,
+            cancel_url,
+            payment_link_id: None,
+        }).await?;
         
         let payment_url = format!("gurt://gurtpay.dev/pay/{}", invoice.id);
         
@@ -788,206 +1276,392 @@ pub fn handle_create_invoice(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::
             description: invoice.description,
             status: invoice.status,
             expires_at: invoice.expires_at,
+            line_items: invoice.line_items,
+            metadata: invoice.metadata,
+            success_url: invoice.success_url,
+            cancel_url: invoice.cancel_url,
         };
         
-        GurtResponse::ok().with_json_body(&json!(response))
+        Ok(GurtResponse::ok().with_json_body(&json!(response))?)
     })
 }
 
-pub fn handle_verify_invoice(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
+/// Fills in each line's amount and returns what the invoice comes to: items
+/// and tax minus discounts. An `amount` the caller also sent must match it.
+fn price_line_items(lines: &mut [InvoiceLineItem], amount: Option<Money>) -> ApiResult<Money> {
+    if lines.len() > MAX_LINE_ITEMS {
+        return Err(ApiError::validation(format!("An invoice can have at most {} line items", MAX_LINE_ITEMS)));
+    }
+    if !lines.iter().any(|line| line.kind == LineItemKind::Item) {
+        return Err(ApiError::validation("Line items must include at least one item"));
+    }
+    
+    let out_of_range = || ApiError::validation("Line item amounts out of range");
+    let mut total: i64 = 0;
+    for line in lines.iter_mut() {
+        line.name = line.name.trim().to_string();
+        if line.name.is_empty() || line.name.len() > MAX_LINE_NAME_LEN {
+            return Err(ApiError::validation(format!("Line item names must be 1 to {} characters", MAX_LINE_NAME_LEN)));
+        }
+        if !(1..=MAX_LINE_QUANTITY).contains(&line.quantity) {
+            return Err(ApiError::validation(format!("Line item quantity must be between 1 and {}", MAX_LINE_QUANTITY)));
+        }
+        if !line.unit_price.is_positive() {
+            return Err(ApiError::validation("Line item unit_price must be greater than 0"));
+        }
+        
+        let amount = line.unit_price.minor().checked_mul(line.quantity).ok_or_else(out_of_range)?;
+        line.amount = Money::from_minor(amount);
+        total = match line.kind {
+            LineItemKind::Item | LineItemKind::Tax => total.checked_add(amount),
+            LineItemKind::Discount => total.checked_sub(amount),
+        }.ok_or_else(out_of_range)?;
+    }
+    
+    let total = Money::from_minor(total);
+    if let Some(amount) = amount.filter(|&amount| amount != total) {
+        return Err(ApiError::validation(format!("Amount {} does not match the line item total of {}", amount, total)));
+    }
+    Ok(total)
+}
+
+fn validate_metadata(metadata: &std::collections::BTreeMap<String, String>) -> ApiResult<()> {
+    if metadata.len() > MAX_METADATA_KEYS {
+        return Err(ApiError::validation(format!("metadata can have at most {} keys", MAX_METADATA_KEYS)));
+    }
+    for (key, value) in metadata {
+        if key.is_empty() || key.len() > MAX_METADATA_KEY_LEN {
+            return Err(ApiError::validation(format!("metadata keys must be 1 to {} characters", MAX_METADATA_KEY_LEN)));
+        }
+        if value.len() > MAX_METADATA_VALUE_LEN {
+            return Err(ApiError::validation(format!("metadata values must be at most {} characters", MAX_METADATA_VALUE_LEN)));
+        }
+    }
+    Ok(())
+}
+
+pub fn handle_verify_invoice(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     let path = ctx.path().to_string();
-    let headers = ctx.headers().clone();
     
     Box::pin(async move {
         let invoice_id_str = path.strip_prefix("/api/invoice/verify/")
-            .ok_or_else(|| GurtError::invalid_message("Missing invoice ID in path"))?;
+            .ok_or_else(|| ApiError::validation("Missing invoice ID in path"))?;
         
         let invoice_id = Uuid::parse_str(invoice_id_str)
-            .map_err(|_| GurtError::invalid_message("Invalid invoice ID format"))?;
-        
-        let auth_header = headers.get("authorization")
-            .ok_or_else(|| GurtError::invalid_message("Missing Authorization header"))?;
+            .map_err(|_| ApiError::validation("Invalid invoice ID format"))?;
         
-        let api_key = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid Authorization header format"))?;
-        
-        let pool = get_database_pool().await?;
-        
-        let business = get_business_by_api_key(&pool, api_key).await?
-            .ok_or_else(|| GurtError::invalid_message("Invalid API key"))?;
+        let business = principal.into_business()?;
         
         let invoice = get_invoice(&pool, invoice_id).await?
-            .ok_or_else(|| GurtError::invalid_message("Invoice not found"))?;
+            .ok_or_else(|| ApiError::not_found("Invoice not found"))?;
         
         if invoice.business_id != business.id {
-            return Err(GurtError::invalid_message("Invoice does not belong to this business"));
+            return Err(ApiError::forbidden("Invoice does not belong to this business"));
         }
         
-        GurtResponse::ok().with_json_body(&json!(invoice))
+        Ok(GurtResponse::ok().with_json_body(&json!(invoice))?)
     })
 }
 
-pub fn handle_pay_invoice(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
+pub fn handle_cancel_invoice(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     let path = ctx.path().to_string();
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
     
     Box::pin(async move {
-        let invoice_id_str = path.strip_prefix("/api/invoice/pay/")
-            .ok_or_else(|| GurtError::invalid_message("Missing invoice ID in path"))?;
+        let invoice_id_str = path.strip_prefix("/api/invoice/cancel/")
+            .ok_or_else(|| ApiError::validation("Missing invoice ID in path"))?;
         
         let invoice_id = Uuid::parse_str(invoice_id_str)
-            .map_err(|_| GurtError::invalid_message("Invalid invoice ID format"))?;
+            .map_err(|_| ApiError::validation("Invalid invoice ID format"))?;
         
-        let pool = get_database_pool().await?;
+        let business = principal.into_business()?;
         
-        // Validate user via JWT (stored as jwt_token in user_sessions)
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("User not authenticated".to_string()))?;
-        let session_token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
+        let invoice = cancel_invoice(&pool, business.id, invoice_id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!(invoice))?)
+    })
+}
 
-        let user = validate_session_token(&pool, session_token).await?;
-        let user_id = user.id;
+pub fn handle_list_invoices(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let path = ctx.path().to_string();
+    
+    Box::pin(async move {
+        let business = principal.into_business()?;
         
-        let invoice = get_invoice(&pool, invoice_id).await?
-            .ok_or_else(|| GurtError::invalid_message("Invoice not found"))?;
+        let query = path.split_once('?').map(|(_, query)| query).unwrap_or("");
+        let filter: InvoiceListQuery = serde_urlencoded::from_str(query)
+            .map_err(|e| ApiError::validation(format!("Invalid query: {}", e)))?;
         
-        if matches!(invoice.status, InvoiceStatus::Paid) {
-            return Err(GurtError::invalid_message("Invoice is already paid"));
+        let limit = filter.limit.unwrap_or(INVOICE_PAGE_SIZE);
+        if !(1..=MAX_INVOICE_PAGE_SIZE).contains(&limit) {
+            return Err(ApiError::validation(format!("limit must be between 1 and {}", MAX_INVOICE_PAGE_SIZE)));
         }
-        
-        if let Some(expires_at) = invoice.expires_at {
-            if Utc::now() > expires_at {
-                return Err(GurtError::invalid_message("Invoice has expired"));
-            }
+        let offset = filter.offset.unwrap_or(0);
+        if offset < 0 {
+            return Err(ApiError::validation("offset must not be negative"));
         }
         
-        let user = get_user_by_id(&pool, user_id).await?
-            .ok_or_else(|| GurtError::invalid_message("User not found"))?;
+        let (invoices, total) = list_invoices(&pool, business.id, &filter, limit, offset).await?;
         
-        if user.wallet_balance < invoice.amount {
-            return Err(GurtError::invalid_message("Insufficient balance"));
-        }
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "invoices": invoices,
+            "total": total,
+            "limit": limit,
+            "offset": offset
+        }))?)
+    })
+}
+
+pub fn handle_pay_invoice(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let idempotency_key = ctx.header("idempotency-key").map(|s| s.to_string());
+    let path = ctx.path().to_string();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let invoice_id_str = path.strip_prefix("/api/invoice/pay/")
+            .ok_or_else(|| ApiError::validation("Missing invoice ID in path"))?;
         
-        transfer_to_business(&pool, &user.id, &invoice.business_id, invoice.amount,
-                             &format!("Payment for invoice: {}", invoice.description)).await?;
+        let invoice_id = Uuid::parse_str(invoice_id_str)
+            .map_err(|_| ApiError::validation("Invalid invoice ID format"))?;
         
-        mark_invoice_paid(&pool, invoice.id).await?;
+        let user_id = principal.into_user()?.id;
         
-        GurtResponse::ok().with_json_body(&json!({"status": "paid", "message": "Payment successful"}))
+        idempotency::run(&pool, idempotency_key.as_deref(), &format!("user:{}", user_id), &path, &body, async {
+            let request: PayInvoiceRequest = if body.trim().is_empty() {
+                PayInvoiceRequest::default()
+            } else {
+                serde_json::from_str(&body).map_err(|_| ApiError::validation("Invalid JSON"))?
+            };
+            
+            let invoice = get_invoice(&pool, invoice_id).await?
+                .ok_or_else(|| ApiError::not_found("Invoice not found"))?;
+            
+            let user = get_user_by_id(&pool, user_id).await?
+                .ok_or_else(|| ApiError::not_found("User not found"))?;
+            
+            if matches!(invoice.status, InvoiceStatus::Pending) {
+                if user.wallet_balance < invoice.amount {
+                    return Err(ApiError::insufficient_funds("Insufficient balance"));
+                }
+                two_factor::require_for_send(&pool, user.id, invoice.amount, request.two_factor_code.as_deref()).await?;
+            }
+            
+            let transaction = match pay_invoice(&pool, invoice.id, user.id).await? {
+                InvoicePayment::Paid(transaction) => transaction,
+                InvoicePayment::AlreadyPaid => return Err(ApiError::conflict("Invoice is already paid")),
+                InvoicePayment::NotPayable(InvoiceStatus::Expired) => return Err(ApiError::conflict("Invoice has expired")),
+                InvoicePayment::NotPayable(InvoiceStatus::Cancelled) => return Err(ApiError::conflict("Invoice has been cancelled")),
+                InvoicePayment::NotPayable(_) => return Err(ApiError::conflict("Invoice is no longer payable")),
+                InvoicePayment::NotFound => return Err(ApiError::not_found("Invoice not found")),
+            };
+            
+            let redirect_url = match &invoice.success_url {
+                Some(url) => {
+                    let business = get_business_by_id(&pool, invoice.business_id).await?
+                        .ok_or_else(|| ApiError::not_found("Business not found"))?;
+                    Some(checkout::signed_return_url(url, invoice.id, "paid", &business.return_secret)?)
+                }
+                None => None,
+            };
+            
+            Ok(GurtResponse::ok().with_json_body(&json!({
+                "status": "paid",
+                "message": "Payment successful",
+                "transaction_id": transaction.id,
+                "redirect_url": redirect_url
+            }))?)
+        }).await
     })
 }
 
-pub fn handle_get_invoice_status(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
+pub fn handle_get_invoice_status(state: &AppState, ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     let path = ctx.path().to_string();
     
     Box::pin(async move {
         let invoice_id_str = path.strip_prefix("/api/invoice/status/")
-            .ok_or_else(|| GurtError::invalid_message("Missing invoice ID in path"))?;
+            .ok_or_else(|| ApiError::validation("Missing invoice ID in path"))?;
         
         let invoice_id = Uuid::parse_str(invoice_id_str)
-            .map_err(|_| GurtError::invalid_message("Invalid invoice ID format"))?;
-        
-        let pool = get_database_pool().await?;
+            .map_err(|_| ApiError::validation("Invalid invoice ID format"))?;
         
         let invoice = get_invoice(&pool, invoice_id).await?
-            .ok_or_else(|| GurtError::invalid_message("Invoice not found"))?;
+            .ok_or_else(|| ApiError::not_found("Invoice not found"))?;
         
         let business = get_business_by_id(&pool, invoice.business_id).await?
-            .ok_or_else(|| GurtError::invalid_message("Business not found"))?;
+            .ok_or_else(|| ApiError::not_found("Business not found"))?;
+        
+        // Links back to the merchant for the pay page. Only a status the invoice
+        // has actually reached is signed; walking away from a pending invoice
+        // isn't final, since it can still be paid
+        let redirect_url = match (&invoice.status, &invoice.success_url) {
+            (InvoiceStatus::Paid, Some(url)) => Some(checkout::signed_return_url(url, invoice.id, "paid", &business.return_secret)?),
+            _ => None,
+        };
+        let cancel_url = match (&invoice.status, &invoice.cancel_url) {
+            (InvoiceStatus::Paid, _) | (_, None) => None,
+            (InvoiceStatus::Cancelled | InvoiceStatus::Expired, Some(url)) => {
+                Some(checkout::signed_return_url(url, invoice.id, "cancelled", &business.return_secret)?)
+            }
+            (InvoiceStatus::Pending, Some(url)) => Some(checkout::abandoned_return_url(url, invoice.id)?),
+        };
         
-        GurtResponse::ok().with_json_body(&json!({
+        Ok(GurtResponse::ok().with_json_body(&json!({
             "invoice": invoice,
+            "redirect_url": redirect_url,
+            "cancel_url": cancel_url,
             "business": {
                 "business_name": business.business_name,
                 "website_url": business.website_url
             }
-        }))
+        }))?)
     })
 }
 
-// Debit card endpoints
-pub fn handle_create_debit_card(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
+pub fn handle_create_refund(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let idempotency_key = ctx.header("idempotency-key").map(|s| s.to_string());
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let business = principal.into_business()?;
+        
+        idempotency::run(&pool, idempotency_key.as_deref(), &format!("business:{}", business.id), "/api/refunds/create", &body, async {
+            let request: RefundRequest = serde_json::from_str(&body)
+                .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+            
+            let (refund, refundable_amount) = refund_business_payment(
+                &pool,
+                business.id,
+                request.transaction_id,
+                request.amount,
+                request.reason.as_deref(),
+            ).await?;
+            
+            Ok(GurtResponse::ok().with_json_body(&json!({
+                "refund": refund,
+                "original_transaction_id": request.transaction_id,
+                "refundable_amount": refundable_amount
+            }))?)
+        }).await
+    })
+}
+
+// Webhook endpoints
+pub fn handle_create_webhook(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
     
     Box::pin(async move {
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("Missing authorization header".to_string()))?;
+        let business = principal.into_business()?;
         
-        let token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
+        let request: CreateWebhookRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
         
-        let pool = get_database_pool().await?;
-        let user = validate_session_token(&pool, token).await?;
-        let card = create_debit_card(&pool, user.id).await?;
+        let endpoint = webhooks::create_endpoint(&pool, business.id, &request.url, &request.events).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&endpoint)?)
+    })
+}
+
+pub fn handle_list_webhooks(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    
+    Box::pin(async move {
+        let business = principal.into_business()?;
+        let endpoints = webhooks::list_endpoints(&pool, business.id).await?;
         
-        GurtResponse::ok().with_json_body(&card)
+        Ok(GurtResponse::ok().with_json_body(&json!({"endpoints": endpoints}))?)
     })
 }
 
-pub fn handle_list_debit_cards(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
+pub fn handle_delete_webhook(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
     
     Box::pin(async move {
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("Missing authorization header".to_string()))?;
+        let business = principal.into_business()?;
         
-        let token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
+        let request: DeleteWebhookRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
         
-        let pool = get_database_pool().await?;
-        let user = validate_session_token(&pool, token).await?;
+        webhooks::disable_endpoint(&pool, business.id, request.endpoint_id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "message": "Webhook endpoint deleted"
+        }))?)
+    })
+}
+
+pub fn handle_list_webhook_deliveries(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    
+    Box::pin(async move {
+        let business = principal.into_business()?;
+        let deliveries = webhooks::list_deliveries(&pool, business.id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({"deliveries": deliveries}))?)
+    })
+}
+
+// Debit card endpoints
+pub fn handle_create_debit_card(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let card = create_debit_card(&pool, user.id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&card)?)
+    })
+}
+
+pub fn handle_list_debit_cards(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
         let cards = get_user_debit_cards(&pool, user.id).await?;
         
         let response = serde_json::json!({
             "cards": cards
         });
         
-        GurtResponse::ok().with_json_body(&response)
+        Ok(GurtResponse::ok().with_json_body(&response)?)
     })
 }
 
-pub fn handle_regenerate_debit_card(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
+pub fn handle_regenerate_debit_card(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     
     Box::pin(async move {
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("Missing authorization header".to_string()))?;
-        
-        let token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
-        
-        let pool = get_database_pool().await?;
-        let user = validate_session_token(&pool, token).await?;
+        let user = principal.into_user()?;
         let card = regenerate_debit_card(&pool, user.id).await?;
         
-        GurtResponse::ok().with_json_body(&card)
+        Ok(GurtResponse::ok().with_json_body(&card)?)
     })
 }
 
-pub fn handle_deactivate_debit_card(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let auth_header = ctx.header("authorization").map(|s| s.to_string());
+pub fn handle_deactivate_debit_card(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     let body = ctx.text().unwrap_or_default();
     
     Box::pin(async move {
-        let auth_header = auth_header
-            .ok_or_else(|| GurtError::invalid_message("Missing authorization header".to_string()))?;
-        
-        let token = auth_header.strip_prefix("Bearer ")
-            .ok_or_else(|| GurtError::invalid_message("Invalid authorization header format".to_string()))?;
-        
-        let pool = get_database_pool().await?;
-        let user = validate_session_token(&pool, token).await?;
+        let user = principal.into_user()?;
         
         let request_data: serde_json::Value = serde_json::from_str(&body)
-            .map_err(|e| GurtError::invalid_message(format!("Invalid JSON: {}", e)))?;
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
         
         let card_id_str = request_data["card_id"]
             .as_str()
-            .ok_or_else(|| GurtError::invalid_message("Missing card_id".to_string()))?;
+            .ok_or_else(|| ApiError::validation("Missing card_id"))?;
         
         let card_id = uuid::Uuid::parse_str(card_id_str)
-            .map_err(|e| GurtError::invalid_message(format!("Invalid card_id: {}", e)))?;
+            .map_err(|e| ApiError::validation(format!("Invalid card_id: {}", e)))?;
         
         deactivate_debit_card(&pool, user.id, card_id).await?;
         
@@ -996,26 +1670,30 @@ pub fn handle_deactivate_debit_card(ctx: &ServerContext) -> std::pin::Pin<Box<dy
             "message": "Card deactivated successfully"
         });
         
-        GurtResponse::ok().with_json_body(&response)
+        Ok(GurtResponse::ok().with_json_body(&response)?)
     })
 }
 
-pub fn handle_process_payment(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
-    let body = ctx.text().unwrap_or_default();
-    
-    Box::pin(async move {
-        let pool = get_database_pool().await?;
-        
-        let request_data: serde_json::Value = serde_json::from_str(&body)
-            .map_err(|e| GurtError::invalid_message(format!("Invalid JSON: {}", e)))?;
-    
+/// Card fields shared by the payment and authorization endpoints.
+struct CardPayment {
+    card_number: String,
+    cvv: String,
+    exp_month: i32,
+    exp_year: i32,
+    cardholder_username: String,
+    amount: Money,
+    merchant_id: String,
+    description: String,
+}
+
+fn parse_card_payment(request_data: &serde_json::Value) -> ApiResult<CardPayment> {
     let card_number = request_data["card_number"]
         .as_str()
-        .ok_or_else(|| GurtError::invalid_message("Missing card_number".to_string()))?;
+        .ok_or_else(|| ApiError::validation("Missing card_number"))?;
     
     let cvv = request_data["cvv"]
         .as_str()
-        .ok_or_else(|| GurtError::invalid_message("Missing cvv".to_string()))?;
+        .ok_or_else(|| ApiError::validation("Missing cvv"))?;
     
     let exp_month_val = &request_data["expiration_month"];
     let exp_month: i32 = if let Some(v) = exp_month_val.as_i64() {
@@ -1023,9 +1701,9 @@ pub fn handle_process_payment(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std:
     } else if let Some(v) = exp_month_val.as_f64() {
         v as i32
     } else if let Some(s) = exp_month_val.as_str() {
-        s.parse::<i32>().map_err(|_| GurtError::invalid_message("Missing expiration_month".to_string()))?
+        s.parse::<i32>().map_err(|_| ApiError::validation("Missing expiration_month"))?
     } else {
-        return Err(GurtError::invalid_message("Missing expiration_month".to_string()));
+        return Err(ApiError::validation("Missing expiration_month"));
     };
     
     let exp_year_val = &request_data["expiration_year"];
@@ -1034,96 +1712,512 @@ pub fn handle_process_payment(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std:
     } else if let Some(v) = exp_year_val.as_f64() {
         v as i32
     } else if let Some(s) = exp_year_val.as_str() {
-        s.parse::<i32>().map_err(|_| GurtError::invalid_message("Missing expiration_year".to_string()))?
+        s.parse::<i32>().map_err(|_| ApiError::validation("Missing expiration_year"))?
     } else {
-        return Err(GurtError::invalid_message("Missing expiration_year".to_string()));
+        return Err(ApiError::validation("Missing expiration_year"));
     };
     
     let cardholder_username = request_data["cardholder_username"]
         .as_str()
-        .ok_or_else(|| GurtError::invalid_message("Missing cardholder_username".to_string()))?;
+        .ok_or_else(|| ApiError::validation("Missing cardholder_username"))?;
     
-    let amount = request_data["amount"]
-        .as_f64()
-        .ok_or_else(|| GurtError::invalid_message("Missing amount".to_string()))?;
+    let amount: Money = serde_json::from_value(request_data["amount"].clone())
+        .map_err(|_| ApiError::validation("Missing amount"))?;
     
     let merchant_id = request_data["merchant_id"]
         .as_str()
-        .ok_or_else(|| GurtError::invalid_message("Missing merchant_id".to_string()))?;
+        .ok_or_else(|| ApiError::validation("Missing merchant_id"))?;
     
     let description = request_data["description"]
         .as_str()
         .unwrap_or("Payment");
     
-        // Validate payment amount
-        if amount <= 0.0 {
-            return GurtResponse::bad_request().with_json_body(&serde_json::json!({
-                "success": false,
-                "error": "Invalid amount"
-            }));
-        }
+    Ok(CardPayment {
+        card_number: card_number.to_string(),
+        cvv: cvv.to_string(),
+        exp_month,
+        exp_year,
+        cardholder_username: cardholder_username.to_string(),
+        amount,
+        merchant_id: merchant_id.to_string(),
+        description: description.to_string(),
+    })
+}
+
+/// Checks the card and merchant and returns (card_id, cardholder user_id,
+/// business_id, business_name). Wrong card details count towards a lockout of
+/// the cardholder and of `source`.
+async fn verify_card_payment(pool: &sqlx::AnyPool, payment: &CardPayment, source: &str) -> ApiResult<(Uuid, Uuid, Uuid, String)> {
+    // Validate payment amount
+    if !payment.amount.is_positive() {
+        return Err(ApiError::validation("Invalid amount"));
+    }
+    
+    let keys = [lockout::Key::card_account(&payment.cardholder_username), lockout::Key::card_source(source)];
+    lockout::check(pool, &keys).await?;
     
     // Verify card details
-    let card_verification = verify_card_details(&pool, card_number, cvv, exp_month, exp_year, cardholder_username).await?;
-    
-        let (_card_id, user_id) = match card_verification {
-            Some((card_id, user_id)) => (card_id, user_id),
-            None => {
-                return GurtResponse::bad_request().with_json_body(&serde_json::json!({
-                    "success": false,
-                    "error": "Invalid card details"
-                }));
-            }
-        };
+    let card_verification = verify_card_details(pool, &payment.card_number, &payment.cvv, payment.exp_month, payment.exp_year, &payment.cardholder_username).await?;
+    
+    let (card_id, user_id) = match card_verification {
+        Some((card_id, user_id)) => (card_id, user_id),
+        None => {
+            lockout::record_failure(pool, &keys).await?;
+            return Err(ApiError::validation("Invalid card details"));
+        }
+    };
+    lockout::clear(pool, &keys[0]).await?;
     
     // Parse merchant ID as business UUID
-    let business_id = uuid::Uuid::parse_str(merchant_id)
-        .map_err(|e| GurtError::invalid_message(format!("Invalid merchant_id: {}", e)))?;
+    let business_id = uuid::Uuid::parse_str(&payment.merchant_id)
+        .map_err(|e| ApiError::validation(format!("Invalid merchant_id: {}", e)))?;
     
     // Check if business exists
     let business = sqlx::query("SELECT business_name FROM businesses WHERE id = $1")
         .bind(business_id.to_string())
-        .fetch_optional(&pool)
+        .fetch_optional(pool)
         .await
         .map_err(|e| GurtError::invalid_message(format!("Failed to check business: {}", e)))?;
     
-        let business_name = match business {
-            Some(row) => row.get::<String, _>("business_name"),
-            None => {
-                return GurtResponse::bad_request().with_json_body(&serde_json::json!({
-                    "success": false,
-                    "error": "Invalid merchant"
-                }));
+    let business_name = match business {
+        Some(row) => row.get::<String, _>("business_name"),
+        None => {
+            return Err(ApiError::not_found("Invalid merchant"));
+        }
+    };
+    
+    Ok((card_id, user_id, business_id, business_name))
+}
+
+pub fn handle_process_payment(state: &AppState, ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let idempotency_key = ctx.header("idempotency-key").map(|s| s.to_string());
+    let body = ctx.text().unwrap_or_default();
+    let source = ctx.client_ip().to_string();
+    
+    Box::pin(async move {
+        let request_data: serde_json::Value = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        let payment = parse_card_payment(&request_data)?;
+    
+        // Keys are scoped to the card the caller just proved they hold, so nobody
+        // can claim keys in a merchant's name with only its public id
+        let (card_id, user_id, business_id, business_name) = verify_card_payment(&pool, &payment, &source).await?;
+        
+        idempotency::run(&pool, idempotency_key.as_deref(), &format!("card:{}:{}", card_id, business_id), "/api/payments/process", &body, async {
+            // Check user balance, less anything held by open authorizations
+            let user_balance_row = sqlx::query("SELECT wallet_balance - held_balance AS available FROM users WHERE id = $1")
+                .bind(user_id.to_string())
+                .fetch_one(&pool)
+                .await
+                .map_err(|e| GurtError::invalid_message(format!("Failed to get user balance: {}", e)))?;
+            
+            let current_balance = Money::from_minor(user_balance_row.get("available"));
+            
+            if current_balance < payment.amount {
+                return Err(ApiError::insufficient_funds("Insufficient balance"));
             }
+            
+            // Process the payment using existing transfer_to_business function
+            let transaction = transfer_to_business(&pool, &user_id, &business_id, payment.amount, &payment.description).await?;
+            
+            let response = serde_json::json!({
+                "success": true,
+                "transaction_id": transaction.id,
+                "amount": payment.amount,
+                "merchant_name": business_name,
+                "message": "Payment processed successfully"
+            });
+            
+            Ok(GurtResponse::ok().with_json_body(&response)?)
+        }).await
+    })
+}
+
+pub fn handle_authorize_payment(state: &AppState, ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let idempotency_key = ctx.header("idempotency-key").map(|s| s.to_string());
+    let body = ctx.text().unwrap_or_default();
+    let source = ctx.client_ip().to_string();
+    
+    Box::pin(async move {
+        let request_data: serde_json::Value = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        let payment = parse_card_payment(&request_data)?;
+        
+        let expires_in_hours = match &request_data["expires_in_hours"] {
+            serde_json::Value::Null => authorizations::DEFAULT_EXPIRY_HOURS,
+            value => value.as_i64()
+                .filter(|hours| (1..=authorizations::MAX_EXPIRY_HOURS).contains(hours))
+                .ok_or_else(|| ApiError::validation(format!("expires_in_hours must be between 1 and {}", authorizations::MAX_EXPIRY_HOURS)))?,
         };
+        
+        let (card_id, user_id, business_id, business_name) = verify_card_payment(&pool, &payment, &source).await?;
+        
+        idempotency::run(&pool, idempotency_key.as_deref(), &format!("card:{}:{}", card_id, business_id), "/api/payments/authorize", &body, async {
+            let authorization = authorizations::authorize(
+                &pool,
+                business_id,
+                user_id,
+                card_id,
+                payment.amount,
+                &payment.description,
+                expires_in_hours,
+            ).await?;
+            
+            Ok(GurtResponse::ok().with_json_body(&json!({
+                "success": true,
+                "authorization": authorization,
+                "merchant_name": business_name,
+                "message": "Payment authorized"
+            }))?)
+        }).await
+    })
+}
+
+pub fn handle_capture_payment(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let idempotency_key = ctx.header("idempotency-key").map(|s| s.to_string());
+    let body = ctx.text().unwrap_or_default();
     
-    // Check user balance
-    let user_balance_row = sqlx::query("SELECT wallet_balance FROM users WHERE id = $1")
-        .bind(user_id.to_string())
-        .fetch_one(&pool)
-        .await
-        .map_err(|e| GurtError::invalid_message(format!("Failed to get user balance: {}", e)))?;
+    Box::pin(async move {
+        let business = principal.into_business()?;
+        
+        idempotency::run(&pool, idempotency_key.as_deref(), &format!("business:{}", business.id), "/api/payments/capture", &body, async {
+            let request: CaptureRequest = serde_json::from_str(&body)
+                .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+            
+            let (authorization, transaction) = authorizations::capture(&pool, business.id, request.authorization_id, request.amount).await?;
+            
+            Ok(GurtResponse::ok().with_json_body(&json!({
+                "success": true,
+                "authorization": authorization,
+                "transaction": transaction
+            }))?)
+        }).await
+    })
+}
+
+pub fn handle_void_payment(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
     
-    let current_balance: f64 = user_balance_row.get("wallet_balance");
+    Box::pin(async move {
+        let business = principal.into_business()?;
+        let request: VoidRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        
+        let authorization = authorizations::void(&pool, business.id, request.authorization_id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "authorization": authorization
+        }))?)
+    })
+}
+
+pub fn handle_create_plan(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
     
-        if current_balance < amount {
-            return GurtResponse::bad_request().with_json_body(&serde_json::json!({
-                "success": false,
-                "error": "Insufficient balance"
-            }));
-        }
+    Box::pin(async move {
+        let business = principal.into_business()?;
+        let request: CreatePlanRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        
+        let plan = subscriptions::create_plan(&pool, business.id, &request).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "plan": plan
+        }))?)
+    })
+}
+
+pub fn handle_list_plans(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
     
-        // Process the payment using existing transfer_to_business function
-        let transaction = transfer_to_business(&pool, &user_id, &business_id, amount, description).await?;
+    Box::pin(async move {
+        let business = principal.into_business()?;
+        let plans = subscriptions::list_plans(&pool, business.id).await?;
         
-        let response = serde_json::json!({
+        Ok(GurtResponse::ok().with_json_body(&json!({ "plans": plans }))?)
+    })
+}
+
+pub fn handle_deactivate_plan(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let business = principal.into_business()?;
+        let request: DeactivatePlanRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        
+        subscriptions::deactivate_plan(&pool, business.id, request.plan_id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({ "success": true }))?)
+    })
+}
+
+/// Public view of a plan so a user can see what they are agreeing to before
+/// subscribing.
+pub fn handle_get_plan(state: &AppState, ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let path = ctx.path().to_string();
+    
+    Box::pin(async move {
+        let plan_id_str = path.strip_prefix("/api/subscriptions/plan/")
+            .ok_or_else(|| ApiError::validation("Missing plan ID in path"))?;
+        
+        let plan_id = Uuid::parse_str(plan_id_str)
+            .map_err(|_| ApiError::validation("Invalid plan ID format"))?;
+        
+        let plan = subscriptions::get_plan(&pool, plan_id).await?
+            .ok_or_else(|| ApiError::not_found("Plan not found"))?;
+        
+        let business = get_business_by_id(&pool, plan.business_id).await?
+            .ok_or_else(|| ApiError::not_found("Business not found"))?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "plan_id": plan.id,
+            "business_name": business.business_name,
+            "name": plan.name,
+            "amount": plan.amount,
+            "interval": plan.interval,
+            "interval_count": plan.interval_count,
+            "trial_days": plan.trial_days,
+            "active": plan.active
+        }))?)
+    })
+}
+
+pub fn handle_subscribe(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let request: SubscribeRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        
+        let subscription = subscriptions::subscribe(&pool, user.id, request.plan_id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
             "success": true,
-            "transaction_id": transaction.id,
-            "amount": amount,
-            "merchant_name": business_name,
-            "message": "Payment processed successfully"
-        });
+            "subscription": subscription
+        }))?)
+    })
+}
+
+pub fn handle_list_my_subscriptions(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    
+    Box::pin(async move {
+        let user = principal.into_user()?;
+        let subscriptions = subscriptions::list_for_user(&pool, user.id).await?;
         
-        GurtResponse::ok().with_json_body(&response)
+        Ok(GurtResponse::ok().with_json_body(&json!({ "subscriptions": subscriptions }))?)
     })
-}
\ No newline at end of file
+}
+
+pub fn handle_list_business_subscriptions(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    
+    Box::pin(async move {
+        let business = principal.into_business()?;
+        let subscriptions = subscriptions::list_for_business(&pool, business.id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({ "subscriptions": subscriptions }))?)
+    })
+}
+
+/// Either side can end a subscription: the subscriber with their session, or
+/// the business with its API key.
+pub fn handle_cancel_subscription(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let request: CancelSubscriptionRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        
+        let owner = match principal {
+            Principal::User(user) => subscriptions::Owner::User(user.id),
+            Principal::Business(business) => subscriptions::Owner::Business(business.id),
+        };
+        let subscription = subscriptions::cancel(&pool, owner, request.subscription_id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "subscription": subscription
+        }))?)
+    })
+}
+
+pub fn handle_create_payment_link(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let business = principal.into_business()?;
+        let request: CreatePaymentLinkRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        
+        let link = payment_links::create(&pool, business.id, &request).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "success": true,
+            "url": format!("gurt://gurtpay.dev/link/{}", link.id),
+            "link": link
+        }))?)
+    })
+}
+
+pub fn handle_list_payment_links(state: &AppState, _ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    
+    Box::pin(async move {
+        let business = principal.into_business()?;
+        let links = payment_links::list(&pool, business.id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({ "links": links }))?)
+    })
+}
+
+pub fn handle_deactivate_payment_link(state: &AppState, ctx: &ServerContext, principal: Principal) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let business = principal.into_business()?;
+        let request: DeactivatePaymentLinkRequest = serde_json::from_str(&body)
+            .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?;
+        
+        payment_links::deactivate(&pool, business.id, request.link_id).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({ "success": true }))?)
+    })
+}
+
+/// Public view of a payment link for the `/link/*` page.
+pub fn handle_get_payment_link(state: &AppState, ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let path = ctx.path().to_string();
+    
+    Box::pin(async move {
+        let link_id_str = path.strip_prefix("/api/links/status/")
+            .ok_or_else(|| ApiError::validation("Missing link ID in path"))?;
+        
+        let link_id = Uuid::parse_str(link_id_str)
+            .map_err(|_| ApiError::validation("Invalid link ID format"))?;
+        
+        let link = payment_links::get(&pool, link_id).await?
+            .ok_or_else(|| ApiError::not_found("Payment link not found"))?;
+        
+        let business = get_business_by_id(&pool, link.business_id).await?
+            .ok_or_else(|| ApiError::not_found("Business not found"))?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "link_id": link.id,
+            "business_name": business.business_name,
+            "description": link.description,
+            "amount": link.amount,
+            "available": link.is_open()
+        }))?)
+    })
+}
+
+/// Opens a fresh invoice from a payment link. Anyone with the link can call
+/// this; the customer then pays the invoice on the `/pay/*` page.
+pub fn handle_open_payment_link(state: &AppState, ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = ApiResult<GurtResponse>> + Send + 'static>> {
+    let pool = state.db.clone();
+    let path = ctx.path().to_string();
+    let body = ctx.text().unwrap_or_default();
+    
+    Box::pin(async move {
+        let link_id_str = path.strip_prefix("/api/links/open/")
+            .ok_or_else(|| ApiError::validation("Missing link ID in path"))?;
+        
+        let link_id = Uuid::parse_str(link_id_str)
+            .map_err(|_| ApiError::validation("Invalid link ID format"))?;
+        
+        let request: OpenPaymentLinkRequest = if body.trim().is_empty() {
+            OpenPaymentLinkRequest::default()
+        } else {
+            serde_json::from_str(&body)
+                .map_err(|e| ApiError::validation(format!("Invalid JSON: {}", e)))?
+        };
+        
+        let invoice = payment_links::open_invoice(&pool, link_id, request).await?;
+        
+        Ok(GurtResponse::ok().with_json_body(&json!({
+            "invoice_id": invoice.id,
+            "payment_url": format!("gurt://gurtpay.dev/pay/{}", invoice.id),
+            "amount": invoice.amount,
+            "expires_at": invoice.expires_at
+        }))?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(value: serde_json::Value) -> Vec<InvoiceLineItem> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn prices_items_and_tax_less_discounts() {
+        let mut items = lines(json!([
+            { "name": " Widget ", "quantity": 3, "unit_price": "4.50" },
+            { "name": "Gadget", "unit_price": 10 },
+            { "name": "VAT", "kind": "tax", "unit_price": "4.70" },
+            { "name": "Loyalty", "kind": "discount", "unit_price": "2.20" },
+        ]));
+
+        let total = price_line_items(&mut items, None).unwrap();
+
+        assert_eq!(total, "26.00".parse().unwrap());
+        assert_eq!(items[0].name, "Widget");
+        assert_eq!(items[0].amount, "13.50".parse().unwrap());
+        assert_eq!(items[1].amount, Money::from_major(10));
+        assert_eq!(items[3].amount, "2.20".parse().unwrap());
+    }
+
+    #[test]
+    fn prices_in_exact_minor_units() {
+        // 3 × 0.1 as a double is 0.30000000000000004
+        let mut items = lines(json!([{ "name": "Sticker", "quantity": 3, "unit_price": 0.1 }]));
+        assert_eq!(price_line_items(&mut items, None).unwrap(), Money::from_minor(30));
+
+        let too_precise = serde_json::from_value::<Vec<InvoiceLineItem>>(json!([{ "name": "Sticker", "unit_price": "0.125" }]));
+        assert!(too_precise.is_err());
+    }
+
+    #[test]
+    fn amount_must_match_the_line_items() {
+        let items = lines(json!([
+            { "name": "Widget", "quantity": 2, "unit_price": "5.00" },
+            { "name": "Tax", "kind": "tax", "unit_price": "1.00" },
+        ]));
+
+        assert_eq!(price_line_items(&mut items.clone(), Some(Money::from_major(11))).unwrap(), Money::from_major(11));
+        assert!(matches!(price_line_items(&mut items.clone(), Some(Money::from_major(10))), Err(ApiError::Validation(_))));
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        let invalid = [
+            json!([{ "name": "Tax", "kind": "tax", "unit_price": 1 }]),
+            json!([{ "name": "  ", "unit_price": 1 }]),
+            json!([{ "name": "Widget", "quantity": 0, "unit_price": 1 }]),
+            json!([{ "name": "Widget", "unit_price": 0 }]),
+            json!([{ "name": "Widget", "quantity": MAX_LINE_QUANTITY, "unit_price": i64::MAX / 100 }]),
+        ];
+        for value in invalid {
+            assert!(matches!(price_line_items(&mut lines(value.clone()), None), Err(ApiError::Validation(_))), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use crate::error::{self, ApiError, ApiResult};
+use gurtlib::prelude::*;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use sqlx::{AnyPool, Row};
+use std::future::Future;
+
+/// How long a key is remembered. A retry after this is treated as a new request.
+const KEY_TTL_HOURS: i64 = 24;
+const MAX_KEY_LEN: usize = 255;
+
+/// Runs `handler` at most once per `(caller, key)`. The first request with a
+/// key claims it before doing any work; once the handler returns, its response
+/// is stored and every later request with the same key and body gets that
+/// response back instead of moving money again. Reusing a key for a different
+/// request, or while the first one is still running, is a 409.
+///
+/// Without a key the handler just runs. Client errors are stored and replayed
+/// like any other response; if the handler fails on our side the claim is
+/// released so the client can retry with the same key.
+pub async fn run<F>(pool: &AnyPool, key: Option<&str>, caller: &str, path: &str, body: &str, handler: F) -> ApiResult<GurtResponse>
+where
+    F: Future<Output = ApiResult<GurtResponse>>,
+{
+    let Some(key) = key.map(str::trim).filter(|k| !k.is_empty()) else {
+        return handler.await;
+    };
+
+    if key.len() > MAX_KEY_LEN {
+        return Err(ApiError::validation(format!("Idempotency-Key must be at most {} characters", MAX_KEY_LEN)));
+    }
+
+    let request_hash = format!("{:x}", Sha256::new().chain_update(path).chain_update([0]).chain_update(body).finalize());
+    let now = Utc::now();
+
+    sqlx::query("DELETE FROM idempotency_keys WHERE created_at < $1")
+        .bind((now - chrono::Duration::hours(KEY_TTL_HOURS)).to_rfc3339())
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to expire idempotency keys: {}", e)))?;
+
+    let claimed = sqlx::query(
+        "INSERT INTO idempotency_keys (caller, idempotency_key, request_hash, created_at) VALUES ($1, $2, $3, $4) \
+         ON CONFLICT (caller, idempotency_key) DO NOTHING"
+    )
+    .bind(caller)
+    .bind(key)
+    .bind(&request_hash)
+    .bind(now.to_rfc3339())
+    .execute(pool)
+    .await
+    .map_err(|e| ApiError::internal(format!("Failed to claim idempotency key: {}", e)))?;
+
+    if claimed.rows_affected() == 0 {
+        return replay(pool, key, caller, &request_hash).await;
+    }
+
+    let response = match handler.await {
+        Ok(response) => response,
+        Err(e @ ApiError::Internal(_)) => {
+            release(pool, key, caller).await?;
+            return Err(e);
+        }
+        Err(e) => e.into_response(),
+    };
+
+    if response.is_server_error() {
+        release(pool, key, caller).await?;
+        return Ok(response);
+    }
+
+    sqlx::query(
+        "UPDATE idempotency_keys SET status_code = $1, content_type = $2, response_body = $3 \
+         WHERE caller = $4 AND idempotency_key = $5"
+    )
+    .bind(response.status_code as i32)
+    .bind(response.header("content-type").cloned())
+    .bind(String::from_utf8_lossy(&response.body).into_owned())
+    .bind(caller)
+    .bind(key)
+    .execute(pool)
+    .await
+    .map_err(|e| ApiError::internal(format!("Failed to store idempotent response: {}", e)))?;
+
+    Ok(response)
+}
+
+async fn replay(pool: &AnyPool, key: &str, caller: &str, request_hash: &str) -> ApiResult<GurtResponse> {
+    let row = sqlx::query(
+        "SELECT request_hash, status_code, content_type, response_body FROM idempotency_keys \
+         WHERE caller = $1 AND idempotency_key = $2"
+    )
+    .bind(caller)
+    .bind(key)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| ApiError::internal(format!("Failed to look up idempotency key: {}", e)))?;
+
+    // Released between our insert and this read; the client can just retry.
+    let Some(row) = row else {
+        return Err(ApiError::conflict("A request with this Idempotency-Key is still being processed"));
+    };
+
+    if row.get::<String, _>("request_hash") != request_hash {
+        return Err(ApiError::conflict("Idempotency-Key was already used for a different request"));
+    }
+
+    let Some(status_code) = row.get::<Option<i32>, _>("status_code") else {
+        return Err(ApiError::conflict("A request with this Idempotency-Key is still being processed"));
+    };
+
+    let mut response = GurtResponse::ok()
+        .with_header("idempotent-replayed", "true")
+        .with_string_body(row.get::<Option<String>, _>("response_body").unwrap_or_default());
+    response.status_code = status_code as u16;
+    response.status_message = error::status_message(response.status_code).to_string();
+    if let Some(content_type) = row.get::<Option<String>, _>("content_type") {
+        response = response.with_header("content-type", content_type);
+    }
+    Ok(response)
+}
+
+async fn release(pool: &AnyPool, key: &str, caller: &str) -> ApiResult<()> {
+    sqlx::query("DELETE FROM idempotency_keys WHERE caller = $1 AND idempotency_key = $2")
+        .bind(caller)
+        .bind(key)
+        .execute(pool)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to release idempotency key: {}", e)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_pool;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const PATH: &str = "/api/wallet/send";
+    const BODY: &str = r#"{"amount":"5.00"}"#;
+
+    async fn counted(calls: &AtomicUsize, result: ApiResult<GurtResponse>) -> ApiResult<GurtResponse> {
+        calls.fetch_add(1, Ordering::SeqCst);
+        result
+    }
+
+    fn created(n: u32) -> ApiResult<GurtResponse> {
+        Ok(GurtResponse::ok().with_json_body(&serde_json::json!({ "transaction": n }))?)
+    }
+
+    #[tokio::test]
+    async fn replays_the_stored_response_without_running_again() {
+        let pool = test_pool().await;
+        let calls = AtomicUsize::new(0);
+
+        let first = run(&pool, Some("k1"), "user:a", PATH, BODY, counted(&calls, created(1))).await.unwrap();
+        let second = run(&pool, Some("k1"), "user:a", PATH, BODY, counted(&calls, created(2))).await.unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(second.status_code, first.status_code);
+        assert_eq!(second.body, first.body);
+        assert_eq!(second.header("idempotent-replayed").map(String::as_str), Some("true"));
+        assert_eq!(second.header("content-type"), first.header("content-type"));
+    }
+
+    #[tokio::test]
+    async fn rejects_the_same_key_with_a_different_body() {
+        let pool = test_pool().await;
+        let calls = AtomicUsize::new(0);
+
+        run(&pool, Some("k1"), "user:a", PATH, BODY, counted(&calls, created(1))).await.unwrap();
+        let reused = run(&pool, Some("k1"), "user:a", PATH, r#"{"amount":"50.00"}"#, counted(&calls, created(2))).await;
+        let other_path = run(&pool, Some("k1"), "user:a", "/api/business/transfer", BODY, counted(&calls, created(3))).await;
+
+        assert!(matches!(reused, Err(ApiError::Conflict(_))));
+        assert!(matches!(other_path, Err(ApiError::Conflict(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn keys_are_scoped_to_the_caller() {
+        let pool = test_pool().await;
+        let calls = AtomicUsize::new(0);
+
+        run(&pool, Some("k1"), "user:a", PATH, BODY, counted(&calls, created(1))).await.unwrap();
+        let other = run(&pool, Some("k1"), "user:b", PATH, BODY, counted(&calls, created(2))).await.unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(other.header("idempotent-replayed").is_none());
+    }
+
+    #[tokio::test]
+    async fn replays_client_errors() {
+        let pool = test_pool().await;
+        let calls = AtomicUsize::new(0);
+
+        let first = run(&pool, Some("k1"), "user:a", PATH, BODY, counted(&calls, Err(ApiError::insufficient_funds("Insufficient balance")))).await.unwrap();
+        let second = run(&pool, Some("k1"), "user:a", PATH, BODY, counted(&calls, created(1))).await.unwrap();
+
+        assert_eq!(first.status_code, 402);
+        assert_eq!(second.status_code, 402);
+        assert_eq!(second.body, first.body);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn releases_the_key_after_an_internal_error() {
+        let pool = test_pool().await;
+        let calls = AtomicUsize::new(0);
+
+        let failed = run(&pool, Some("k1"), "user:a", PATH, BODY, counted(&calls, Err(ApiError::internal("database went away")))).await;
+        assert!(matches!(failed, Err(ApiError::Internal(_))));
+
+        let retried = run(&pool, Some("k1"), "user:a", PATH, BODY, counted(&calls, created(1))).await.unwrap();
+        assert_eq!(retried.status_code, 200);
+        assert!(retried.header("idempotent-replayed").is_none());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn releases_the_key_after_a_5xx_response() {
+        let pool = test_pool().await;
+        let calls = AtomicUsize::new(0);
+
+        let failed = run(&pool, Some("k1"), "user:a", PATH, BODY, counted(&calls, Ok(ApiError::internal("boom").into_response()))).await.unwrap();
+        assert_eq!(failed.status_code, 500);
+
+        let retried = run(&pool, Some("k1"), "user:a", PATH, BODY, counted(&calls, created(1))).await.unwrap();
+        assert_eq!(retried.status_code, 200);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn runs_every_time_without_a_key() {
+        let pool = test_pool().await;
+        let calls = AtomicUsize::new(0);
+
+        run(&pool, None, "user:a", PATH, BODY, counted(&calls, created(1))).await.unwrap();
+        run(&pool, Some("  "), "user:a", PATH, BODY, counted(&calls, created(2))).await.unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_overlong_keys() {
+        let pool = test_pool().await;
+        let calls = AtomicUsize::new(0);
+
+        let key = "k".repeat(MAX_KEY_LEN + 1);
+        let result = run(&pool, Some(&key), "user:a", PATH, BODY, counted(&calls, created(1))).await;
+
+        assert!(matches!(result, Err(ApiError::Validation(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,362 @@
+use crate::money::Money;
+use crate::error::{ApiError, ApiResult};
+use chrono::{DateTime, Utc};
+use gurtlib::{GurtError, Result};
+use sqlx::{AnyConnection, Row};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// A balance the ledger keeps books for. User wallets and business balances are
+/// what the platform owes its customers; the mint is the platform's own side of
+/// every GC it issues (welcome bonuses, redemption codes), so it is the one
+/// account allowed to run negative.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Account {
+    UserWallet(Uuid),
+    Business(Uuid),
+    Mint,
+}
+
+impl Account {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Account::UserWallet(_) => "user_wallet",
+            Account::Business(_) => "business",
+            Account::Mint => "mint",
+        }
+    }
+
+    pub fn owner_id(&self) -> Option<Uuid> {
+        match self {
+            Account::UserWallet(id) | Account::Business(id) => Some(*id),
+            Account::Mint => None,
+        }
+    }
+}
+
+/// One balanced set of postings. Debits take money out of an account and
+/// credits put it in; `post` refuses entries where the two sides differ.
+#[derive(Debug, Clone)]
+pub struct JournalEntry {
+    pub transaction_id: Option<Uuid>,
+    pub description: String,
+    pub created_at: DateTime<Utc>,
+    postings: Vec<(Account, Money)>,
+}
+
+impl JournalEntry {
+    pub fn new(transaction_id: Option<Uuid>, description: &str) -> Self {
+        Self {
+            transaction_id,
+            description: description.to_string(),
+            created_at: Utc::now(),
+            postings: Vec::new(),
+        }
+    }
+
+    /// The common case: `amount` leaves `from` and lands in `to`.
+    pub fn transfer(transaction_id: Uuid, from: Account, to: Account, amount: Money, description: &str) -> Self {
+        Self::new(Some(transaction_id), description)
+            .debit(from, amount)
+            .credit(to, amount)
+    }
+
+    pub fn debit(mut self, account: Account, amount: Money) -> Self {
+        self.postings.push((account, Money::from_minor(-amount.minor())));
+        self
+    }
+
+    pub fn credit(mut self, account: Account, amount: Money) -> Self {
+        self.postings.push((account, amount));
+        self
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.postings.len() < 2 {
+            return Err(GurtError::invalid_message("Journal entry needs at least two postings"));
+        }
+
+        let mut total = Money::ZERO;
+        for (_, amount) in &self.postings {
+            if amount.minor() == 0 {
+                return Err(GurtError::invalid_message("Journal entry contains a zero posting"));
+            }
+            total = total.checked_add(*amount)
+                .ok_or_else(|| GurtError::invalid_message("Journal entry amount out of range"))?;
+        }
+
+        if total != Money::ZERO {
+            return Err(GurtError::invalid_message(format!("Unbalanced journal entry (off by {})", total)));
+        }
+        Ok(())
+    }
+}
+
+/// Records `entry` and applies it to the cached `wallet_balance`/`balance`
+/// columns. Must run inside the caller's database transaction so the entry and
+/// whatever business record it belongs to commit or roll back together. Fails
+/// with `ApiError::InsufficientFunds` if a debit would take a customer account
+/// below zero.
+pub async fn post(conn: &mut AnyConnection, entry: &JournalEntry) -> ApiResult<Uuid> {
+    entry.validate()?;
+
+    for (account, amount) in &entry.postings {
+        apply_to_cached_balance(conn, account, *amount).await?;
+    }
+
+    Ok(record(conn, entry).await?)
+}
+
+/// Writes the entry without touching cached balances. Only for rebuilding
+/// history that the cached columns already reflect.
+pub(crate) async fn record(conn: &mut AnyConnection, entry: &JournalEntry) -> Result<Uuid> {
+    entry.validate()?;
+
+    let entry_id = Uuid::new_v4();
+    let created_at = entry.created_at.to_rfc3339();
+
+    sqlx::query("INSERT INTO ledger_entries (id, transaction_id, description, created_at) VALUES ($1, $2, $3, $4)")
+        .bind(entry_id.to_string())
+        .bind(entry.transaction_id.map(|id| id.to_string()))
+        .bind(&entry.description)
+        .bind(&created_at)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to record journal entry: {}", e)))?;
+
+    for (account, amount) in &entry.postings {
+        sqlx::query(
+            "INSERT INTO ledger_postings (id, entry_id, account_type, account_id, amount, created_at) \
+             VALUES ($1, $2, $3, $4, $5, $6)"
+        )
+        .bind(Uuid::new_v4().to_string())
+        .bind(entry_id.to_string())
+        .bind(account.kind())
+        .bind(account.owner_id().map(|id| id.to_string()))
+        .bind(amount.minor())
+        .bind(&created_at)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to record posting: {}", e)))?;
+    }
+
+    Ok(entry_id)
+}
+
+async fn apply_to_cached_balance(conn: &mut AnyConnection, account: &Account, amount: Money) -> ApiResult<()> {
+    // Card authorizations reserve part of a wallet without moving it, so a
+    // debit may only spend what isn't held.
+    let (table, column, reserved) = match account {
+        Account::UserWallet(_) => ("users", "wallet_balance", "held_balance"),
+        Account::Business(_) => ("businesses", "balance", "0"),
+        Account::Mint => return Ok(()),
+    };
+    let owner_id = account.owner_id().map(|id| id.to_string());
+
+    // The balance guard lives in the UPDATE itself so two concurrent debits
+    // can't both pass a read-then-write check. The mint has no cached column,
+    // which is also why it is free to go negative.
+    let sql = format!("UPDATE {table} SET {column} = {column} + $1 WHERE id = $2 AND {column} + $3 >= {reserved}");
+
+    let result = sqlx::query(&sql)
+        .bind(amount.minor())
+        .bind(&owner_id)
+        .bind(amount.minor())
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to update {} balance: {}", account.kind(), e)))?;
+
+    if result.rows_affected() == 0 {
+        let exists = sqlx::query(&format!("SELECT id FROM {table} WHERE id = $1"))
+            .bind(&owner_id)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to look up {} account: {}", account.kind(), e)))?;
+        return Err(match exists {
+            Some(_) => ApiError::insufficient_funds("Insufficient funds"),
+            None => ApiError::not_found(format!("Unknown {} account", account.kind())),
+        });
+    }
+    Ok(())
+}
+
+/// Current balance of every customer account that has ever been posted to,
+/// summed straight from the postings rather than the cached columns.
+pub async fn balances(conn: &mut AnyConnection) -> Result<HashMap<Account, Money>> {
+    let rows = sqlx::query(
+        "SELECT account_type, account_id, CAST(COALESCE(SUM(amount), 0) AS BIGINT) AS total \
+         FROM ledger_postings WHERE account_id IS NOT NULL GROUP BY account_type, account_id"
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to sum ledger postings: {}", e)))?;
+
+    let mut balances = HashMap::new();
+    for row in rows {
+        let Some(id) = row.get::<Option<String>, _>("account_id").and_then(|s| Uuid::parse_str(&s).ok()) else { continue };
+        let account = match row.get::<String, _>("account_type").as_str() {
+            "user_wallet" => Account::UserWallet(id),
+            "business" => Account::Business(id),
+            _ => continue,
+        };
+        balances.insert(account, Money::from_minor(row.get("total")));
+    }
+    Ok(balances)
+}
+
+/// Which accounts a row from the legacy `transactions` table moved money between.
+pub fn legacy_accounts(from_user_id: Option<Uuid>, to_user_id: Option<Uuid>, business_id: Option<Uuid>) -> Option<(Account, Account)> {
+    let from = match (from_user_id, business_id) {
+        (Some(user), _) => Account::UserWallet(user),
+        (None, Some(business)) if to_user_id.is_some() => Account::Business(business),
+        (None, _) => Account::Mint,
+    };
+    let to = match (to_user_id, business_id) {
+        (Some(user), _) => Account::UserWallet(user),
+        (None, Some(business)) => Account::Business(business),
+        (None, None) => return None,
+    };
+    Some((from, to))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{create_user_with_password, get_user_by_id, test_pool, WELCOME_BONUS};
+    use sqlx::AnyPool;
+
+    /// `amount` from one account to another, outside any transaction row.
+    fn movement(from: Account, to: Account, amount: Money, description: &str) -> JournalEntry {
+        JournalEntry::new(None, description).debit(from, amount).credit(to, amount)
+    }
+
+    async fn user(pool: &AnyPool) -> Uuid {
+        create_user_with_password(pool, &format!("ledger-{}", Uuid::new_v4()), "unused").await.unwrap().id
+    }
+
+    async fn wallet(pool: &AnyPool, user_id: Uuid) -> Money {
+        get_user_by_id(pool, user_id).await.unwrap().unwrap().wallet_balance
+    }
+
+    async fn posting_count(pool: &AnyPool) -> i64 {
+        sqlx::query("SELECT COUNT(*) AS count FROM ledger_postings").fetch_one(pool).await.unwrap().get("count")
+    }
+
+    #[test]
+    fn rejects_unbalanced_entries() {
+        let a = Account::UserWallet(Uuid::new_v4());
+        let b = Account::UserWallet(Uuid::new_v4());
+
+        let unbalanced = JournalEntry::new(None, "off").debit(a, Money::from_minor(500)).credit(b, Money::from_minor(499));
+        assert!(unbalanced.validate().unwrap_err().to_string().contains("Unbalanced"));
+        assert!(JournalEntry::new(None, "one").credit(a, Money::from_minor(1)).validate().is_err());
+        assert!(JournalEntry::transfer(Uuid::new_v4(), a, b, Money::ZERO, "zero").validate().is_err());
+        assert!(JournalEntry::new(None, "huge").credit(a, Money::from_minor(i64::MAX)).credit(b, Money::from_minor(1)).validate().is_err());
+
+        let split = JournalEntry::new(None, "split")
+            .debit(a, Money::from_minor(300))
+            .credit(b, Money::from_minor(200))
+            .credit(Account::Mint, Money::from_minor(100));
+        split.validate().unwrap();
+    }
+
+    #[tokio::test]
+    async fn post_refuses_unbalanced_entries_without_writing() {
+        let pool = test_pool().await;
+        let (a, b) = (user(&pool).await, user(&pool).await);
+        let before = posting_count(&pool).await;
+
+        let entry = JournalEntry::new(None, "off").debit(Account::UserWallet(a), Money::from_minor(500)).credit(Account::UserWallet(b), Money::from_minor(400));
+        let mut conn = pool.acquire().await.unwrap();
+        assert!(matches!(post(&mut conn, &entry).await, Err(ApiError::Internal(_))));
+        drop(conn);
+
+        assert_eq!(posting_count(&pool).await, before);
+        assert_eq!(wallet(&pool, a).await, WELCOME_BONUS);
+        assert_eq!(wallet(&pool, b).await, WELCOME_BONUS);
+    }
+
+    #[tokio::test]
+    async fn posts_transfers_to_cached_balances_and_postings() {
+        let pool = test_pool().await;
+        let (a, b) = (user(&pool).await, user(&pool).await);
+
+        let mut tx = pool.begin().await.unwrap();
+        let entry = movement(Account::UserWallet(a), Account::UserWallet(b), Money::from_major(20), "Lunch");
+        post(&mut tx, &entry).await.unwrap();
+        let ledger = balances(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(wallet(&pool, a).await, Money::from_major(4980));
+        assert_eq!(wallet(&pool, b).await, Money::from_major(5020));
+        assert_eq!(ledger[&Account::UserWallet(a)], Money::from_major(4980));
+        assert_eq!(ledger[&Account::UserWallet(b)], Money::from_major(5020));
+    }
+
+    #[tokio::test]
+    async fn insufficient_funds_leaves_balances_and_postings_unchanged() {
+        let pool = test_pool().await;
+        let (a, b) = (user(&pool).await, user(&pool).await);
+        let before = posting_count(&pool).await;
+        let too_much = WELCOME_BONUS.checked_add(Money::from_minor(1)).unwrap();
+
+        // Credit first, so the failing debit comes after a posting was applied
+        let entry = JournalEntry::new(None, "Too much")
+            .credit(Account::UserWallet(b), too_much)
+            .debit(Account::UserWallet(a), too_much);
+        let mut tx = pool.begin().await.unwrap();
+        assert!(matches!(post(&mut tx, &entry).await, Err(ApiError::InsufficientFunds(_))));
+        tx.rollback().await.unwrap();
+
+        assert_eq!(posting_count(&pool).await, before);
+        assert_eq!(wallet(&pool, a).await, WELCOME_BONUS);
+        assert_eq!(wallet(&pool, b).await, WELCOME_BONUS);
+    }
+
+    #[tokio::test]
+    async fn held_funds_cant_be_spent() {
+        let pool = test_pool().await;
+        let (a, b) = (user(&pool).await, user(&pool).await);
+        sqlx::query("UPDATE users SET held_balance = $1 WHERE id = $2")
+            .bind(Money::from_major(4990).minor())
+            .bind(a.to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let mut conn = pool.acquire().await.unwrap();
+        let over = movement(Account::UserWallet(a), Account::UserWallet(b), Money::from_major(11), "Over");
+        assert!(matches!(post(&mut conn, &over).await, Err(ApiError::InsufficientFunds(_))));
+        let within = movement(Account::UserWallet(a), Account::UserWallet(b), Money::from_major(10), "Within");
+        post(&mut conn, &within).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn mint_may_go_negative() {
+        let pool = test_pool().await;
+        let a = user(&pool).await;
+
+        let mut conn = pool.acquire().await.unwrap();
+        let entry = movement(Account::Mint, Account::UserWallet(a), Money::from_major(1_000_000), "Redeem");
+        post(&mut conn, &entry).await.unwrap();
+
+        let mint: i64 = sqlx::query("SELECT CAST(SUM(amount) AS BIGINT) AS total FROM ledger_postings WHERE account_type = 'mint'")
+            .fetch_one(&mut *conn)
+            .await
+            .unwrap()
+            .get("total");
+        assert!(Money::from_minor(mint).is_negative());
+        drop(conn);
+        assert_eq!(wallet(&pool, a).await, WELCOME_BONUS.checked_add(Money::from_major(1_000_000)).unwrap());
+    }
+
+    #[tokio::test]
+    async fn unknown_accounts_are_not_found() {
+        let pool = test_pool().await;
+        let a = user(&pool).await;
+
+        let mut conn = pool.acquire().await.unwrap();
+        let entry = movement(Account::UserWallet(a), Account::Business(Uuid::new_v4()), Money::from_major(1), "Nobody");
+        assert!(matches!(post(&mut conn, &entry).await, Err(ApiError::NotFound(_))));
+    }
+}
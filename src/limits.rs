@@ -0,0 +1,356 @@
+use crate::database::record_audit;
+use crate::error::{ApiError, ApiResult};
+use crate::models::{SetTierLimitsRequest, SetUserLimitsRequest};
+use crate::money::Money;
+use chrono::{DateTime, Utc};
+use gurtlib::{GurtError, Result};
+use serde::Serialize;
+use sqlx::{AnyConnection, AnyPool, Row};
+use uuid::Uuid;
+
+/// Tier for users nobody has assigned one to.
+pub const DEFAULT_TIER: &str = "standard";
+const DAY_HOURS: i64 = 24;
+/// The monthly limit is a rolling window, like the daily one.
+const MONTH_DAYS: i64 = 30;
+const MAX_TIER_NAME_LEN: usize = 32;
+
+/// Caps on what a user can send out of their wallet. `None` is unlimited.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Limits {
+    pub per_transaction: Option<Money>,
+    pub daily: Option<Money>,
+    pub monthly: Option<Money>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Tier {
+    pub name: String,
+    #[serde(flatten)]
+    pub limits: Limits,
+}
+
+/// A user's effective limits and how much of each window they have used.
+#[derive(Debug, Clone, Serialize)]
+pub struct Allowance {
+    pub tier: String,
+    pub limits: Limits,
+    pub spent_last_24h: Money,
+    pub spent_last_30d: Money,
+    pub remaining_daily: Option<Money>,
+    pub remaining_monthly: Option<Money>,
+}
+
+pub async fn list_tiers(pool: &AnyPool) -> Result<Vec<Tier>> {
+    let rows = sqlx::query("SELECT name, per_transaction, daily, monthly FROM limit_tiers ORDER BY name")
+        .fetch_all(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to list limit tiers: {}", e)))?;
+    Ok(rows.iter().map(|row| Tier { name: row.get("name"), limits: limits_from_row(row) }).collect())
+}
+
+/// Creates the tier or replaces its limits.
+pub async fn set_tier(pool: &AnyPool, actor: Uuid, request: &SetTierLimitsRequest) -> ApiResult<Tier> {
+    let name = request.name.trim().to_lowercase();
+    if name.is_empty() || name.len() > MAX_TIER_NAME_LEN || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(ApiError::validation(format!("Tier names must be 1 to {} letters, digits, '-' or '_'", MAX_TIER_NAME_LEN)));
+    }
+    let limits = validate(request.per_transaction, request.daily, request.monthly)?;
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    sqlx::query(
+        "INSERT INTO limit_tiers (name, per_transaction, daily, monthly, updated_at) VALUES ($1, $2, $3, $4, $5) \
+         ON CONFLICT (name) DO UPDATE SET per_transaction = excluded.per_transaction, daily = excluded.daily, \
+         monthly = excluded.monthly, updated_at = excluded.updated_at"
+    )
+    .bind(&name)
+    .bind(limits.per_transaction.map(Money::minor))
+    .bind(limits.daily.map(Money::minor))
+    .bind(limits.monthly.map(Money::minor))
+    .bind(Utc::now().to_rfc3339())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to save limit tier: {}", e)))?;
+
+    let tier = Tier { name, limits };
+    record_audit(&mut tx, Some(actor), "limit_tier_set", &serde_json::json!(tier)).await?;
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit limit tier: {}", e)))?;
+
+    Ok(tier)
+}
+
+/// Puts the user on a tier and overrides any of its limits for them alone.
+/// Leaving a limit out uses the tier's.
+pub async fn set_user(pool: &AnyPool, actor: Uuid, request: &SetUserLimitsRequest) -> ApiResult<()> {
+    let overrides = validate(request.per_transaction, request.daily, request.monthly)?;
+    let tier = request.tier.as_deref().map(|tier| tier.trim().to_lowercase());
+    if let Some(tier) = &tier {
+        let exists = sqlx::query("SELECT name FROM limit_tiers WHERE name = $1")
+            .bind(tier)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| GurtError::invalid_message(format!("Failed to get limit tier: {}", e)))?;
+        if exists.is_none() {
+            return Err(ApiError::not_found(format!("Limit tier '{}' not found", tier)));
+        }
+    }
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    sqlx::query(
+        "INSERT INTO user_limits (user_id, tier, per_transaction, daily, monthly, updated_at) VALUES ($1, $2, $3, $4, $5, $6) \
+         ON CONFLICT (user_id) DO UPDATE SET tier = excluded.tier, per_transaction = excluded.per_transaction, \
+         daily = excluded.daily, monthly = excluded.monthly, updated_at = excluded.updated_at"
+    )
+    .bind(request.user_id.to_string())
+    .bind(&tier)
+    .bind(overrides.per_transaction.map(Money::minor))
+    .bind(overrides.daily.map(Money::minor))
+    .bind(overrides.monthly.map(Money::minor))
+    .bind(Utc::now().to_rfc3339())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to save user limits: {}", e)))?;
+
+    record_audit(&mut tx, Some(actor), "user_limits_set", &serde_json::json!({
+        "user_id": request.user_id,
+        "tier": tier,
+        "overrides": overrides,
+    })).await?;
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit user limits: {}", e)))?;
+
+    Ok(())
+}
+
+pub async fn allowance(pool: &AnyPool, user_id: Uuid) -> Result<Allowance> {
+    let mut conn = pool.acquire().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to get connection: {}", e)))?;
+    allowance_on(&mut conn, user_id, None).await
+}
+
+/// Rejects `amount` if it would take the user past any of their limits.
+/// `recorded` is the payment's own transaction when it has already been
+/// written, so it isn't counted twice. Call this inside the payment's database
+/// transaction after the user's balance row has been updated: that write
+/// serializes the user's concurrent payments, so each one sees the others.
+pub(crate) async fn enforce(conn: &mut AnyConnection, user_id: Uuid, amount: Money, recorded: Option<Uuid>) -> ApiResult<()> {
+    let allowance = allowance_on(conn, user_id, recorded).await?;
+
+    if let Some(max) = allowance.limits.per_transaction {
+        if amount > max {
+            return Err(ApiError::limit_exceeded(format!("Amount exceeds your per-transaction limit of {} GC", max)));
+        }
+    }
+    if allowance.remaining_daily.is_some_and(|remaining| amount > remaining) {
+        return Err(ApiError::limit_exceeded(format!(
+            "Amount exceeds your 24-hour limit; {} GC remaining",
+            allowance.remaining_daily.unwrap_or(Money::ZERO)
+        )));
+    }
+    if allowance.remaining_monthly.is_some_and(|remaining| amount > remaining) {
+        return Err(ApiError::limit_exceeded(format!(
+            "Amount exceeds your 30-day limit; {} GC remaining",
+            allowance.remaining_monthly.unwrap_or(Money::ZERO)
+        )));
+    }
+    Ok(())
+}
+
+async fn allowance_on(conn: &mut AnyConnection, user_id: Uuid, exclude: Option<Uuid>) -> Result<Allowance> {
+    let (tier, limits) = effective_limits(conn, user_id).await?;
+
+    let now = Utc::now();
+    let (spent_day, spent_month) = spent_since(
+        conn,
+        user_id,
+        now - chrono::Duration::hours(DAY_HOURS),
+        now - chrono::Duration::days(MONTH_DAYS),
+        exclude,
+    ).await?;
+
+    let remaining = |limit: Option<Money>, spent: Money| {
+        limit.map(|limit| limit.checked_sub(spent).filter(|left| !left.is_negative()).unwrap_or(Money::ZERO))
+    };
+    Ok(Allowance {
+        tier,
+        remaining_daily: remaining(limits.daily, spent_day),
+        remaining_monthly: remaining(limits.monthly, spent_month),
+        limits,
+        spent_last_24h: spent_day,
+        spent_last_30d: spent_month,
+    })
+}
+
+/// The user's tier with their own overrides laid over it.
+async fn effective_limits(conn: &mut AnyConnection, user_id: Uuid) -> Result<(String, Limits)> {
+    let overrides = sqlx::query("SELECT tier, per_transaction, daily, monthly FROM user_limits WHERE user_id = $1")
+        .bind(user_id.to_string())
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to get user limits: {}", e)))?;
+
+    let tier = overrides.as_ref()
+        .and_then(|row| row.get::<Option<String>, _>("tier"))
+        .unwrap_or_else(|| DEFAULT_TIER.to_string());
+    let tier_limits = sqlx::query("SELECT per_transaction, daily, monthly FROM limit_tiers WHERE name = $1")
+        .bind(&tier)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to get limit tier: {}", e)))?
+        .map(|row| limits_from_row(&row))
+        .unwrap_or_default();
+
+    let Some(row) = overrides else { return Ok((tier, tier_limits)) };
+    let own = limits_from_row(&row);
+    Ok((tier, Limits {
+        per_transaction: own.per_transaction.or(tier_limits.per_transaction),
+        daily: own.daily.or(tier_limits.daily),
+        monthly: own.monthly.or(tier_limits.monthly),
+    }))
+}
+
+/// What the user sent out of their wallet, to people or businesses, since each
+/// cutoff, plus their open card holds, which count in both windows until they
+/// are released. Refunds don't give allowance back. A hold being captured is
+/// already closed when its payment is checked, and a new hold is checked before
+/// its row exists, so neither is counted on top of the amount being checked.
+async fn spent_since(conn: &mut AnyConnection, user_id: Uuid, day_start: DateTime<Utc>, month_start: DateTime<Utc>, exclude: Option<Uuid>) -> Result<(Money, Money)> {
+    let row = sqlx::query(
+        "SELECT CAST(COALESCE(SUM(CASE WHEN created_at > $1 THEN amount ELSE 0 END), 0) AS BIGINT) AS day, \
+                CAST(COALESCE(SUM(amount), 0) AS BIGINT) AS month \
+         FROM transactions \
+         WHERE from_user_id = $2 AND transaction_type IN ('transfer', 'business_payment') \
+           AND status = 'completed' AND created_at > $3 AND id <> $4"
+    )
+    .bind(day_start.to_rfc3339())
+    .bind(user_id.to_string())
+    .bind(month_start.to_rfc3339())
+    .bind(exclude.map(|id| id.to_string()).unwrap_or_default())
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to sum spending: {}", e)))?;
+
+    let held: i64 = sqlx::query(
+        "SELECT CAST(COALESCE(SUM(amount), 0) AS BIGINT) AS held FROM card_authorizations WHERE user_id = $1 AND status = 'authorized'"
+    )
+    .bind(user_id.to_string())
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to sum card holds: {}", e)))?
+    .get("held");
+
+    let with_holds = |spent: i64| spent.checked_add(held)
+        .map(Money::from_minor)
+        .ok_or_else(|| GurtError::invalid_message("Spending total out of range"));
+    Ok((with_holds(row.get("day"))?, with_holds(row.get("month"))?))
+}
+
+fn validate(per_transaction: Option<Money>, daily: Option<Money>, monthly: Option<Money>) -> ApiResult<Limits> {
+    if [per_transaction, daily, monthly].iter().flatten().any(|limit| !limit.is_positive()) {
+        return Err(ApiError::validation("Limits must be greater than 0"));
+    }
+    Ok(Limits { per_transaction, daily, monthly })
+}
+
+fn limits_from_row(row: &sqlx::any::AnyRow) -> Limits {
+    Limits {
+        per_transaction: row.get::<Option<i64>, _>("per_transaction").map(Money::from_minor),
+        daily: row.get::<Option<i64>, _>("daily").map(Money::from_minor),
+        monthly: row.get::<Option<i64>, _>("monthly").map(Money::from_minor),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorizations;
+    use crate::database::{create_invoice, create_user_with_password, get_invoice, get_user_by_id, pay_invoice, test_business, test_pool, transfer_to_business, WELCOME_BONUS};
+    use crate::models::{InvoicePayment, InvoiceStatus, NewInvoice};
+
+    /// A new user who can send at most `daily` GC a day.
+    async fn limited_user(pool: &AnyPool, daily: Money) -> Uuid {
+        let user_id = create_user_with_password(pool, &format!("limited-{}", Uuid::new_v4()), "unused").await.unwrap().id;
+        set_user(pool, Uuid::new_v4(), &SetUserLimitsRequest {
+            user_id,
+            tier: None,
+            per_transaction: None,
+            daily: Some(daily),
+            monthly: None,
+        })
+        .await
+        .unwrap();
+        user_id
+    }
+
+    async fn pay_new_invoice(pool: &AnyPool, business_id: Uuid, user_id: Uuid, amount: Money) -> (Uuid, ApiResult<InvoicePayment>) {
+        let invoice = create_invoice(pool, business_id, NewInvoice {
+            amount,
+            description: "Order".to_string(),
+            customer_name: None,
+            expires_at: None,
+            line_items: Vec::new(),
+            metadata: Default::default(),
+            success_url: None,
+            cancel_url: None,
+            payment_link_id: None,
+        })
+        .await
+        .unwrap();
+        (invoice.id, pay_invoice(pool, invoice.id, user_id).await)
+    }
+
+    #[tokio::test]
+    async fn invoice_payments_count_against_limits() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user_id = limited_user(&pool, Money::from_major(100)).await;
+
+        let (_, first) = pay_new_invoice(&pool, business_id, user_id, Money::from_major(60)).await;
+        assert!(matches!(first, Ok(InvoicePayment::Paid(_))));
+        let (second_id, second) = pay_new_invoice(&pool, business_id, user_id, Money::from_major(50)).await;
+
+        assert!(matches!(second, Err(ApiError::LimitExceeded(_))));
+        assert!(matches!(get_invoice(&pool, second_id).await.unwrap().unwrap().status, InvoiceStatus::Pending));
+        assert_eq!(get_user_by_id(&pool, user_id).await.unwrap().unwrap().wallet_balance, Money::from_major(4940));
+        assert_eq!(allowance(&pool, user_id).await.unwrap().spent_last_24h, Money::from_major(60));
+    }
+
+    #[tokio::test]
+    async fn open_holds_count_as_spent_until_captured() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user_id = limited_user(&pool, Money::from_major(100)).await;
+
+        let hold = authorizations::authorize(&pool, business_id, user_id, Uuid::new_v4(), Money::from_major(70), "Deposit", 24).await.unwrap();
+        let held = allowance(&pool, user_id).await.unwrap();
+        assert_eq!(held.spent_last_24h, Money::from_major(70));
+        assert_eq!(held.remaining_daily, Some(Money::from_major(30)));
+
+        let over = transfer_to_business(&pool, &user_id, &business_id, Money::from_major(40), "Card payment").await;
+        assert!(matches!(over, Err(ApiError::LimitExceeded(_))));
+
+        // Captured in full, the hold is counted once, as the payment
+        authorizations::capture(&pool, business_id, hold.id, None).await.unwrap();
+        let captured = allowance(&pool, user_id).await.unwrap();
+        assert_eq!(captured.spent_last_24h, Money::from_major(70));
+        assert_eq!(get_user_by_id(&pool, user_id).await.unwrap().unwrap().wallet_balance, WELCOME_BONUS.checked_sub(Money::from_major(70)).unwrap());
+    }
+
+    #[tokio::test]
+    async fn released_holds_give_the_allowance_back() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user_id = limited_user(&pool, Money::from_major(100)).await;
+        let hold = authorizations::authorize(&pool, business_id, user_id, Uuid::new_v4(), Money::from_major(70), "Deposit", 24).await.unwrap();
+
+        authorizations::void(&pool, business_id, hold.id).await.unwrap();
+
+        assert_eq!(allowance(&pool, user_id).await.unwrap().remaining_daily, Some(Money::from_major(100)));
+        assert!(transfer_to_business(&pool, &user_id, &business_id, Money::from_major(100), "Card payment").await.is_ok());
+    }
+}
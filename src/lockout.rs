@@ -0,0 +1,379 @@
+use crate::database::record_audit;
+use crate::error::{ApiError, ApiResult};
+use chrono::{DateTime, Utc};
+use gurtlib::{GurtError, Result};
+use serde::Serialize;
+use sqlx::{AnyPool, Row};
+use uuid::Uuid;
+
+/// Failures allowed before each further attempt has to wait.
+pub(crate) const FREE_ATTEMPTS: i32 = 3;
+/// The wait doubles with each failure past the free ones, up to this.
+const MAX_DELAY_SECS: i64 = 60;
+/// Failures older than this are forgotten.
+const WINDOW_MINUTES: i64 = 60;
+const LOCKOUT_MINUTES: i64 = 15;
+const ACCOUNT_MAX_FAILURES: i32 = 10;
+/// Higher than for an account, since many people can share an address.
+const SOURCE_MAX_FAILURES: i32 = 50;
+
+/// What failures are counted against: an account or a client address, for
+/// logins or for card checks.
+#[derive(Debug, Clone)]
+pub struct Key {
+    scope: &'static str,
+    subject: String,
+    max_failures: i32,
+}
+
+impl Key {
+    pub fn login_account(username: &str) -> Key {
+        Key { scope: "login_account", subject: normalize_username(username), max_failures: ACCOUNT_MAX_FAILURES }
+    }
+
+    /// Never cleared by a successful login; see `clear`.
+    pub fn login_source(source: &str) -> Key {
+        Key { scope: "login_source", subject: source.to_string(), max_failures: SOURCE_MAX_FAILURES }
+    }
+
+    pub fn card_account(username: &str) -> Key {
+        Key { scope: "card_account", subject: normalize_username(username), max_failures: ACCOUNT_MAX_FAILURES }
+    }
+
+    pub fn card_source(source: &str) -> Key {
+        Key { scope: "card_source", subject: source.to_string(), max_failures: SOURCE_MAX_FAILURES }
+    }
+
+    /// Codes entered by a signed-in user, for step-up checks and settings.
+    pub fn two_factor(user_id: Uuid) -> Key {
+        Key { scope: "two_factor", subject: user_id.to_string(), max_failures: ACCOUNT_MAX_FAILURES }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Lockout {
+    pub scope: String,
+    pub subject: String,
+    pub failures: i32,
+    pub locked_until: DateTime<Utc>,
+}
+
+/// Rejects the attempt if any key is locked out or still waiting out its
+/// delay. Call before checking the credentials, so a right guess during a
+/// lockout doesn't get through either.
+pub async fn check(pool: &AnyPool, keys: &[Key]) -> ApiResult<()> {
+    let now = Utc::now();
+    for key in keys {
+        let row = sqlx::query("SELECT failures, last_failure_at, locked_until FROM auth_failures WHERE scope = $1 AND subject = $2")
+            .bind(key.scope)
+            .bind(&key.subject)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| GurtError::invalid_message(format!("Failed to get failed attempts: {}", e)))?;
+        let Some(row) = row else { continue };
+
+        if let Some(locked_until) = row.get::<Option<String>, _>("locked_until").as_deref().map(parse_time).transpose()? {
+            if locked_until > now {
+                let minutes = (locked_until - now).num_minutes() + 1;
+                return Err(ApiError::too_many_attempts(format!("Too many failed attempts; try again in {} min", minutes)));
+            }
+            continue;
+        }
+
+        let failures = row.get::<i32, _>("failures");
+        let last_failure_at = parse_time(&row.get::<String, _>("last_failure_at"))?;
+        if failures < FREE_ATTEMPTS || last_failure_at < now - chrono::Duration::minutes(WINDOW_MINUTES) {
+            continue;
+        }
+        let retry_at = last_failure_at + chrono::Duration::seconds(delay_secs(failures));
+        if retry_at > now {
+            let seconds = (retry_at - now).num_seconds() + 1;
+            return Err(ApiError::too_many_attempts(format!("Too many failed attempts; try again in {}s", seconds)));
+        }
+    }
+    Ok(())
+}
+
+/// Counts a failed attempt against every key, locking out the ones that
+/// reached their limit. Each new lockout is written to the audit log.
+pub async fn record_failure(pool: &AnyPool, keys: &[Key]) -> Result<()> {
+    let now = Utc::now();
+    let window_start = now - chrono::Duration::minutes(WINDOW_MINUTES);
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    for key in keys {
+        // A failure after the window or after a served lockout starts the count again
+        sqlx::query(
+            "INSERT INTO auth_failures (scope, subject, failures, last_failure_at) VALUES ($1, $2, 1, $3) \
+             ON CONFLICT (scope, subject) DO UPDATE SET \
+                 failures = CASE WHEN auth_failures.last_failure_at < $4 OR auth_failures.locked_until < $5 \
+                                 THEN 1 ELSE auth_failures.failures + 1 END, \
+                 locked_until = CASE WHEN auth_failures.locked_until < $6 THEN NULL ELSE auth_failures.locked_until END, \
+                 last_failure_at = excluded.last_failure_at"
+        )
+        .bind(key.scope)
+        .bind(&key.subject)
+        .bind(now.to_rfc3339())
+        .bind(window_start.to_rfc3339())
+        .bind(now.to_rfc3339())
+        .bind(now.to_rfc3339())
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to record failed attempt: {}", e)))?;
+
+        let locked_until = now + chrono::Duration::minutes(LOCKOUT_MINUTES);
+        let locked = sqlx::query(
+            "UPDATE auth_failures SET locked_until = $1 WHERE scope = $2 AND subject = $3 AND failures >= $4 AND locked_until IS NULL"
+        )
+        .bind(locked_until.to_rfc3339())
+        .bind(key.scope)
+        .bind(&key.subject)
+        .bind(key.max_failures)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to lock out: {}", e)))?;
+
+        if locked.rows_affected() > 0 {
+            tracing::warn!("Locked out {} {} until {}", key.scope, key.subject, locked_until.to_rfc3339());
+            record_audit(&mut tx, None, "lockout", &serde_json::json!({
+                "scope": key.scope,
+                "subject": key.subject,
+                "failures": key.max_failures,
+                "locked_until": locked_until.to_rfc3339(),
+            })).await?;
+        }
+    }
+
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit failed attempt: {}", e)))?;
+    Ok(())
+}
+
+/// Forgets the key's failures after a successful attempt. Callers only clear
+/// account keys: source keys keep counting until their failures age out of
+/// `WINDOW_MINUTES`, since one success from an address says nothing about the
+/// other accounts it has been guessing at. A lockout in force is never cleared.
+pub async fn clear(pool: &AnyPool, key: &Key) -> Result<()> {
+    sqlx::query("DELETE FROM auth_failures WHERE scope = $1 AND subject = $2 AND locked_until IS NULL")
+        .bind(key.scope)
+        .bind(&key.subject)
+        .execute(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to clear failed attempts: {}", e)))?;
+    Ok(())
+}
+
+/// Lockouts that are still in force.
+pub async fn list_active(pool: &AnyPool) -> Result<Vec<Lockout>> {
+    let rows = sqlx::query(
+        "SELECT scope, subject, failures, locked_until FROM auth_failures WHERE locked_until > $1 ORDER BY locked_until DESC"
+    )
+    .bind(Utc::now().to_rfc3339())
+    .fetch_all(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to list lockouts: {}", e)))?;
+
+    rows.iter().map(|row| Ok(Lockout {
+        scope: row.get("scope"),
+        subject: row.get("subject"),
+        failures: row.get("failures"),
+        locked_until: parse_time(&row.get::<String, _>("locked_until"))?,
+    })).collect()
+}
+
+/// Lifts an account's login, card and two-factor lockouts and forgets its
+/// failures. Returns how many records were cleared.
+pub async fn unlock_account(pool: &AnyPool, actor: Uuid, username: &str) -> ApiResult<u64> {
+    let subject = normalize_username(username);
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    let cleared = sqlx::query(
+        "DELETE FROM auth_failures WHERE (scope IN ('login_account', 'card_account') AND subject = $1) \
+            OR (scope = 'two_factor' AND subject IN (SELECT id FROM users WHERE LOWER(username) = $2))"
+    )
+    .bind(&subject)
+    .bind(&subject)
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to unlock account: {}", e)))?
+    .rows_affected();
+
+    record_audit(&mut tx, Some(actor), "lockout_cleared", &serde_json::json!({
+        "username": subject,
+        "cleared": cleared,
+    })).await?;
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit unlock: {}", e)))?;
+
+    Ok(cleared)
+}
+
+fn delay_secs(failures: i32) -> i64 {
+    let exponent = (failures - FREE_ATTEMPTS).clamp(0, 6) as u32;
+    (1i64 << exponent).min(MAX_DELAY_SECS)
+}
+
+fn normalize_username(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| GurtError::invalid_message(format!("Invalid timestamp {}: {}", value, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_pool;
+
+    async fn fail(pool: &AnyPool, keys: &[Key], times: i32) {
+        for _ in 0..times {
+            record_failure(pool, keys).await.unwrap();
+        }
+    }
+
+    /// Moves the key's last failure `secs` into the past.
+    async fn backdate(pool: &AnyPool, key: &Key, secs: i64) {
+        sqlx::query("UPDATE auth_failures SET last_failure_at = $1 WHERE scope = $2 AND subject = $3")
+            .bind((Utc::now() - chrono::Duration::seconds(secs)).to_rfc3339())
+            .bind(key.scope)
+            .bind(&key.subject)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    fn is_too_many(result: ApiResult<()>) -> bool {
+        matches!(result, Err(ApiError::TooManyAttempts(_)))
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        assert_eq!(delay_secs(FREE_ATTEMPTS), 1);
+        assert_eq!(delay_secs(FREE_ATTEMPTS + 1), 2);
+        assert_eq!(delay_secs(FREE_ATTEMPTS + 5), 32);
+        assert_eq!(delay_secs(FREE_ATTEMPTS + 6), MAX_DELAY_SECS);
+        assert_eq!(delay_secs(i32::MAX), MAX_DELAY_SECS);
+    }
+
+    #[tokio::test]
+    async fn delays_attempts_after_the_free_ones() {
+        let pool = test_pool().await;
+        let keys = [Key::login_account("Alice"), Key::login_source("10.0.0.1")];
+
+        fail(&pool, &keys, FREE_ATTEMPTS - 1).await;
+        check(&pool, &keys).await.unwrap();
+
+        fail(&pool, &keys, 1).await;
+        assert!(is_too_many(check(&pool, &keys).await));
+        backdate(&pool, &keys[0], delay_secs(FREE_ATTEMPTS) + 1).await;
+        backdate(&pool, &keys[1], delay_secs(FREE_ATTEMPTS) + 1).await;
+        check(&pool, &keys).await.unwrap();
+
+        fail(&pool, &keys, 1).await;
+        let delay = delay_secs(FREE_ATTEMPTS + 1);
+        backdate(&pool, &keys[0], delay - 1).await;
+        backdate(&pool, &keys[1], delay - 1).await;
+        assert!(is_too_many(check(&pool, &keys).await));
+        backdate(&pool, &keys[0], delay + 1).await;
+        backdate(&pool, &keys[1], delay + 1).await;
+        check(&pool, &keys).await.unwrap();
+
+        // The username is matched however it is typed
+        fail(&pool, &keys, 1).await;
+        assert!(is_too_many(check(&pool, &[Key::login_account("  ALICE ")]).await));
+    }
+
+    #[tokio::test]
+    async fn forgets_failures_outside_the_window() {
+        let pool = test_pool().await;
+        let key = Key::login_account("bob");
+
+        fail(&pool, std::slice::from_ref(&key), FREE_ATTEMPTS + 2).await;
+        backdate(&pool, &key, WINDOW_MINUTES * 60 + 1).await;
+        check(&pool, std::slice::from_ref(&key)).await.unwrap();
+
+        fail(&pool, std::slice::from_ref(&key), 1).await;
+        let failures: i32 = sqlx::query("SELECT failures FROM auth_failures WHERE scope = $1 AND subject = $2")
+            .bind(key.scope)
+            .bind(&key.subject)
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("failures");
+        assert_eq!(failures, 1);
+    }
+
+    #[tokio::test]
+    async fn locks_out_at_the_limit_and_audits_it() {
+        let pool = test_pool().await;
+        let keys = [Key::login_account("carol")];
+
+        fail(&pool, &keys, ACCOUNT_MAX_FAILURES).await;
+        // Even with the delay long past, the lockout holds
+        backdate(&pool, &keys[0], MAX_DELAY_SECS + 1).await;
+        let locked = check(&pool, &keys).await;
+        assert!(matches!(&locked, Err(ApiError::TooManyAttempts(message)) if message.contains("min")));
+
+        let active = list_active(&pool).await.unwrap();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].scope, "login_account");
+        assert_eq!(active[0].subject, "carol");
+        assert!(active[0].locked_until > Utc::now() + chrono::Duration::minutes(LOCKOUT_MINUTES - 1));
+
+        // A success doesn't lift a lockout
+        clear(&pool, &keys[0]).await.unwrap();
+        assert!(is_too_many(check(&pool, &keys).await));
+
+        let audits: i64 = sqlx::query("SELECT COUNT(*) AS count FROM audit_log WHERE action = 'lockout'")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("count");
+        assert_eq!(audits, 1);
+    }
+
+    #[tokio::test]
+    async fn sources_lock_out_at_their_own_limit() {
+        let pool = test_pool().await;
+        let source = Key::login_source("10.0.0.2");
+
+        for n in 0..SOURCE_MAX_FAILURES {
+            // Many accounts from one address
+            fail(&pool, &[Key::login_account(&format!("user{}", n)), source.clone()], 1).await;
+        }
+        assert!(list_active(&pool).await.unwrap().iter().any(|l| l.scope == "login_source" && l.subject == "10.0.0.2"));
+        assert!(list_active(&pool).await.unwrap().iter().all(|l| l.scope != "login_account"));
+    }
+
+    #[tokio::test]
+    async fn admin_unlock_lifts_account_lockouts_only() {
+        let pool = test_pool().await;
+        let admin = Uuid::new_v4();
+        let account = Key::login_account("dave");
+        let card = Key::card_account("dave");
+        let source = Key::login_source("10.0.0.3");
+
+        fail(&pool, &[account.clone(), card.clone()], ACCOUNT_MAX_FAILURES).await;
+        fail(&pool, std::slice::from_ref(&source), FREE_ATTEMPTS).await;
+        assert!(is_too_many(check(&pool, std::slice::from_ref(&account)).await));
+        assert!(is_too_many(check(&pool, std::slice::from_ref(&card)).await));
+
+        assert_eq!(unlock_account(&pool, admin, " Dave").await.unwrap(), 2);
+        check(&pool, &[account, card]).await.unwrap();
+        assert!(is_too_many(check(&pool, &[source]).await));
+        assert!(list_active(&pool).await.unwrap().is_empty());
+
+        let actor: Option<String> = sqlx::query("SELECT actor_user_id FROM audit_log WHERE action = 'lockout_cleared'")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("actor_user_id");
+        assert_eq!(actor, Some(admin.to_string()));
+    }
+}
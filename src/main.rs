@@ -1,18 +1,80 @@
 use gurtlib::prelude::*;
 use gurtlib::GurtStatusCode;
 use sqlx::AnyPool;
-use rustls;
 
 mod models;
 mod auth;
+mod jwt_keys;
 mod handlers;
 mod database;
+mod money;
+mod ledger;
+mod reconcile;
+mod idempotency;
+mod migrations;
+mod error;
+mod webhooks;
+mod authorizations;
+mod checkout;
+mod subscriptions;
+mod payment_links;
+mod money_requests;
+mod limits;
+mod lockout;
+mod passwords;
+mod scheduled_transfers;
+mod two_factor;
 
 use handlers::*;
 use database::*;
+use auth::{Access, Principal};
+
+// Principals accepted by authenticated routes
+const USER: &[Access] = &[Access::User];
+const ADMIN: &[Access] = &[Access::Admin];
+const BUSINESS: &[Access] = &[Access::Business];
+const USER_OR_BUSINESS: &[Access] = &[Access::User, Access::Business];
 
 #[derive(Clone)]
-pub struct AppState { db: AnyPool }
+pub struct AppState { pub db: AnyPool }
+
+type HandlerFuture = std::pin::Pin<Box<dyn std::future::Future<Output = error::ApiResult<GurtResponse>> + Send + 'static>>;
+type RouteFuture = std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>>;
+
+// Adapts a handler that needs the shared state to the plain closure gurtlib routes take,
+// rendering any ApiError as its JSON error response.
+fn with_state<H>(state: &AppState, handler: H) -> impl Fn(&ServerContext) -> RouteFuture + Send + Sync + 'static
+where
+    H: Fn(&AppState, &ServerContext) -> HandlerFuture + Send + Sync + 'static,
+{
+    let state = state.clone();
+    move |ctx| {
+        let response = handler(&state, ctx);
+        Box::pin(async move { Ok(response.await.unwrap_or_else(error::ApiError::into_response)) })
+    }
+}
+
+// Like `with_state`, but authenticates the request first and only calls the handler
+// for a principal the route accepts.
+fn with_auth<H>(state: &AppState, accepts: &'static [Access], handler: H) -> impl Fn(&ServerContext) -> RouteFuture + Send + Sync + 'static
+where
+    H: Fn(&AppState, &ServerContext, Principal) -> HandlerFuture + Send + Sync + 'static,
+{
+    let state = state.clone();
+    let handler = std::sync::Arc::new(handler);
+    move |ctx| {
+        let state = state.clone();
+        let handler = handler.clone();
+        let ctx = ctx.clone();
+        Box::pin(async move {
+            let response = match auth::authenticate(&state.db, ctx.header("authorization").map(|s| s.as_str()), accepts).await {
+                Ok(principal) => handler(&state, &ctx, principal).await,
+                Err(e) => Err(e),
+            };
+            Ok(response.unwrap_or_else(error::ApiError::into_response))
+        })
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -21,8 +83,37 @@ async fn main() -> Result<()> {
     
     tracing_subscriber::fmt::init();
     
+    // --dry-run prints pending migrations; --migrate-only applies them and exits.
+    // --allow-dev-secret lets a local server run without configured JWT keys.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--dry-run") {
+        let db = open_database().await?;
+        return migrations::dry_run(&db).await;
+    }
+    
     let db = init_database().await?;
-    let _state = AppState { db };
+    if args.iter().any(|arg| arg == "--migrate-only") {
+        println!("✅ Database schema is up to date");
+        return Ok(());
+    }
+    
+    jwt_keys::load(args.iter().any(|arg| arg == "--allow-dev-secret"))?;
+    
+    let state = AppState { db };
+    
+    let report = reconcile::reconcile(&state.db, false, None).await?;
+    if !report.discrepancies.is_empty() {
+        println!("⚠️  {} of {} balances disagree with the ledger; POST /api/admin/reconcile to review",
+                 report.discrepancies.len(), report.accounts_checked);
+    }
+    
+    webhooks::spawn_dispatcher(state.db.clone());
+    auth::spawn_session_cleanup(state.db.clone());
+    authorizations::spawn_expiry_sweeper(state.db.clone());
+    database::spawn_invoice_sweeper(state.db.clone());
+    subscriptions::spawn_billing_scheduler(state.db.clone());
+    money_requests::spawn_expiry_sweeper(state.db.clone());
+    scheduled_transfers::spawn_worker(state.db.clone());
     
     // Get certificate paths from environment or use defaults
     let cert_path = std::env::var("CERT_PATH").unwrap_or_else(|_| ".".to_string());
@@ -40,37 +131,101 @@ async fn main() -> Result<()> {
         .get("/cards", serve_cards_page)
         .get("/wallet", serve_wallet_page)
         .get("/pay/*", serve_pay_invoice_page)
+        .get("/link/*", serve_payment_link_page)
         .get("/docs", serve_api_docs)
         .get("/api-docs", serve_api_docs)
         
-        .post("/api/auth/register", handle_register_local)
-        .post("/api/auth/login", handle_login_local)
+        .post("/api/auth/register", with_state(&state, handle_register_local))
+        .post("/api/auth/login", with_state(&state, handle_login_local))
+        .post("/api/auth/login/2fa", with_state(&state, handle_login_two_factor))
+        .post("/api/auth/refresh", with_state(&state, handle_refresh_session))
+        .post("/api/auth/password/change", with_auth(&state, USER, handle_change_password))
+        .post("/api/auth/password/reset", with_state(&state, handle_reset_password))
+        .post("/api/auth/logout", with_auth(&state, USER, handle_logout))
+        .get("/api/auth/sessions", with_auth(&state, USER, handle_list_sessions))
+        .post("/api/auth/sessions/revoke", with_auth(&state, USER, handle_revoke_session))
+        .post("/api/auth/sessions/revoke-all", with_auth(&state, USER, handle_revoke_other_sessions))
+        .get("/api/auth/2fa", with_auth(&state, USER, handle_two_factor_status))
+        .post("/api/auth/2fa/setup", with_auth(&state, USER, handle_two_factor_setup))
+        .post("/api/auth/2fa/enable", with_auth(&state, USER, handle_two_factor_enable))
+        .post("/api/auth/2fa/disable", with_auth(&state, USER, handle_two_factor_disable))
+        .post("/api/auth/2fa/recovery-codes", with_auth(&state, USER, handle_regenerate_recovery_codes))
+        .post("/api/auth/2fa/threshold", with_auth(&state, USER, handle_set_send_threshold))
         .post("/api/auth/verify", handle_auth_verify)
         .post("/api/user/register", handle_user_register)
-        .get("/api/user/profile", handle_get_profile)
-        .get("/api/wallet/balance", handle_get_balance)
-        .get("/api/wallet/transactions", handle_get_transactions)
-        .post("/api/wallet/send", handle_send_money)
-        .post("/api/wallet/request", handle_request_money)
-        .post("/api/business/register", handle_register_business)
-        .get("/api/business/list", handle_get_businesses)
-        .post("/api/business/transfer", handle_business_transfer)
-        .post("/api/codes/redeem", handle_redeem_code)
-        .post("/api/admin/codes/create", handle_create_code)
+        .get("/api/user/profile", with_auth(&state, USER, handle_get_profile))
+        .get("/api/wallet/balance", with_auth(&state, USER, handle_get_balance))
+        .get("/api/wallet/transactions", with_auth(&state, USER, handle_get_transactions))
+        .get("/api/wallet/limits", with_auth(&state, USER, handle_get_limits))
+        .post("/api/wallet/send", with_auth(&state, USER, handle_send_money))
+        .post("/api/wallet/request", with_auth(&state, USER, handle_request_money))
+        .get("/api/wallet/requests/incoming", with_auth(&state, USER, handle_list_incoming_requests))
+        .get("/api/wallet/requests/outgoing", with_auth(&state, USER, handle_list_outgoing_requests))
+        .post("/api/wallet/requests/pay", with_auth(&state, USER, handle_pay_money_request))
+        .post("/api/wallet/requests/decline", with_auth(&state, USER, handle_decline_money_request))
+        .post("/api/wallet/requests/cancel", with_auth(&state, USER, handle_cancel_money_request))
+        .post("/api/transfers/scheduled/create", with_auth(&state, USER, handle_create_scheduled_transfer))
+        .get("/api/transfers/scheduled/list", with_auth(&state, USER, handle_list_scheduled_transfers))
+        .get("/api/transfers/scheduled/runs/*", with_auth(&state, USER, handle_list_scheduled_transfer_runs))
+        .post("/api/transfers/scheduled/pause", with_auth(&state, USER, handle_pause_scheduled_transfer))
+        .post("/api/transfers/scheduled/resume", with_auth(&state, USER, handle_resume_scheduled_transfer))
+        .post("/api/transfers/scheduled/cancel", with_auth(&state, USER, handle_cancel_scheduled_transfer))
+        .post("/api/business/register", with_auth(&state, USER, handle_register_business))
+        .get("/api/business/list", with_auth(&state, USER, handle_get_businesses))
+        .post("/api/business/transfer", with_auth(&state, USER, handle_business_transfer))
+        .post("/api/codes/redeem", with_auth(&state, USER, handle_redeem_code))
+        .post("/api/admin/codes/create", with_auth(&state, ADMIN, handle_create_code))
+        .post("/api/admin/reconcile", with_auth(&state, ADMIN, handle_reconcile_balances))
+        .get("/api/admin/limits/tiers", with_auth(&state, ADMIN, handle_list_limit_tiers))
+        .post("/api/admin/limits/tiers", with_auth(&state, ADMIN, handle_set_limit_tier))
+        .post("/api/admin/limits/user", with_auth(&state, ADMIN, handle_set_user_limits))
+        .get("/api/admin/limits/user/*", with_auth(&state, ADMIN, handle_get_user_limits))
+        .get("/api/admin/lockouts", with_auth(&state, ADMIN, handle_list_lockouts))
+        .post("/api/admin/lockouts/unlock", with_auth(&state, ADMIN, handle_unlock_account))
+        .post("/api/admin/password-reset", with_auth(&state, ADMIN, handle_issue_password_reset))
         
         // Debit card endpoints
-        .post("/api/cards/create", handle_create_debit_card)
-        .get("/api/cards/list", handle_list_debit_cards)
-        .post("/api/cards/regenerate", handle_regenerate_debit_card)
-        .post("/api/cards/deactivate", handle_deactivate_debit_card)
+        .post("/api/cards/create", with_auth(&state, USER, handle_create_debit_card))
+        .get("/api/cards/list", with_auth(&state, USER, handle_list_debit_cards))
+        .post("/api/cards/regenerate", with_auth(&state, USER, handle_regenerate_debit_card))
+        .post("/api/cards/deactivate", with_auth(&state, USER, handle_deactivate_debit_card))
+        
+        // Webhooks for businesses
+        .post("/api/webhooks/create", with_auth(&state, BUSINESS, handle_create_webhook))
+        .get("/api/webhooks/list", with_auth(&state, BUSINESS, handle_list_webhooks))
+        .post("/api/webhooks/delete", with_auth(&state, BUSINESS, handle_delete_webhook))
+        .get("/api/webhooks/deliveries", with_auth(&state, BUSINESS, handle_list_webhook_deliveries))
         
         // Payment processing for external merchants
-        .post("/api/payments/process", handle_process_payment)
+        .post("/api/payments/process", with_state(&state, handle_process_payment))
+        .post("/api/payments/authorize", with_state(&state, handle_authorize_payment))
+        .post("/api/payments/capture", with_auth(&state, BUSINESS, handle_capture_payment))
+        .post("/api/payments/void", with_auth(&state, BUSINESS, handle_void_payment))
+        .post("/api/refunds/create", with_auth(&state, BUSINESS, handle_create_refund))
+        
+        .post("/api/invoice/create", with_auth(&state, BUSINESS, handle_create_invoice))
+        .get("/api/invoice/verify/*", with_auth(&state, BUSINESS, handle_verify_invoice))
+        .get("/api/invoice/status/*", with_state(&state, handle_get_invoice_status))
+        .post("/api/invoice/pay/*", with_auth(&state, USER, handle_pay_invoice))
+        .post("/api/invoice/cancel/*", with_auth(&state, BUSINESS, handle_cancel_invoice))
+        .get("/api/invoice/list", with_auth(&state, BUSINESS, handle_list_invoices))
         
-        .post("/api/invoice/create", handle_create_invoice)
-        .get("/api/invoice/verify/*", handle_verify_invoice)
-        .get("/api/invoice/status/*", handle_get_invoice_status)
-        .post("/api/invoice/pay/*", handle_pay_invoice)
+        // Reusable payment links that open a fresh invoice per payer
+        .post("/api/links/create", with_auth(&state, BUSINESS, handle_create_payment_link))
+        .get("/api/links/list", with_auth(&state, BUSINESS, handle_list_payment_links))
+        .post("/api/links/deactivate", with_auth(&state, BUSINESS, handle_deactivate_payment_link))
+        .get("/api/links/status/*", with_state(&state, handle_get_payment_link))
+        .post("/api/links/open/*", with_state(&state, handle_open_payment_link))
+
+        // Recurring billing: businesses define plans, users subscribe from their wallet
+        .post("/api/subscriptions/plans/create", with_auth(&state, BUSINESS, handle_create_plan))
+        .get("/api/subscriptions/plans/list", with_auth(&state, BUSINESS, handle_list_plans))
+        .post("/api/subscriptions/plans/deactivate", with_auth(&state, BUSINESS, handle_deactivate_plan))
+        .get("/api/subscriptions/plan/*", with_state(&state, handle_get_plan))
+        .post("/api/subscriptions/subscribe", with_auth(&state, USER, handle_subscribe))
+        .get("/api/subscriptions/mine", with_auth(&state, USER, handle_list_my_subscriptions))
+        .get("/api/subscriptions/list", with_auth(&state, BUSINESS, handle_list_business_subscriptions))
+        .post("/api/subscriptions/cancel", with_auth(&state, USER_OR_BUSINESS, handle_cancel_subscription))
 
         .get("/static/*", serve_static_files);
     
@@ -138,6 +293,15 @@ fn serve_pay_invoice_page(_ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::fu
     })
 }
 
+fn serve_payment_link_page(_ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
+    Box::pin(async move {
+        let html = include_str!("../frontend/payment-link.html");
+        Ok(GurtResponse::ok()
+            .with_header("content-type", "text/html")
+            .with_string_body(html))
+    })
+}
+
 fn serve_api_docs(_ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<GurtResponse>> + Send + 'static>> {
     Box::pin(async move {
         let html = include_str!("../frontend/api-docs.html");
@@ -202,14 +366,13 @@ fn serve_static_files(ctx: &ServerContext) -> std::pin::Pin<Box<dyn std::future:
             "send.lua" => include_str!("../frontend/static/send.lua"),
             "cards.lua" => include_str!("../frontend/static/cards.lua"),
             "pay-invoice.lua" => include_str!("../frontend/static/pay-invoice.lua"),
+            "payment-link.lua" => include_str!("../frontend/static/payment-link.lua"),
             "api-docs.lua" => include_str!("../frontend/static/api-docs.lua"),
             _ => return Ok(GurtResponse::not_found()),
         };
         
         let content_type = if path.ends_with(".css") {
             "text/css"
-        } else if path.ends_with(".lua") {
-            "text/plain"
         } else {
             "text/plain"
         };
@@ -0,0 +1,912 @@
+use crate::checkout;
+use crate::ledger::{self, JournalEntry};
+use crate::money::Money;
+use chrono::Utc;
+use gurtlib::{GurtError, Result};
+use sqlx::{AnyConnection, AnyPool, Row};
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::pin::Pin;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Sqlite,
+    Postgres,
+}
+
+type DataMigration = for<'c> fn(&'c mut AnyConnection) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'c>>;
+
+enum Up {
+    /// Same statements on every backend.
+    Sql(&'static [&'static str]),
+    /// Statements built per backend, for DDL that SQLite and Postgres spell differently.
+    PerBackend(fn(Backend) -> Vec<String>),
+    /// Data rewrites that need more than SQL.
+    Data(DataMigration),
+}
+
+struct Migration {
+    version: i64,
+    name: &'static str,
+    up: Up,
+}
+
+// Append only: never edit or reorder a migration once it has shipped, add a new one.
+// Table creation keeps IF NOT EXISTS so databases from before this table existed can be adopted.
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial_schema", up: Up::Sql(INITIAL_SCHEMA) },
+    Migration { version: 2, name: "money_minor_units", up: Up::PerBackend(money_minor_units) },
+    Migration { version: 3, name: "ledger", up: Up::Sql(LEDGER) },
+    Migration { version: 4, name: "ledger_backfill", up: Up::Data(ledger_backfill) },
+    Migration { version: 5, name: "audit_log", up: Up::Sql(AUDIT_LOG) },
+    Migration { version: 6, name: "idempotency_keys", up: Up::Sql(IDEMPOTENCY_KEYS) },
+    Migration { version: 7, name: "invoice_payer", up: Up::Sql(INVOICE_PAYER) },
+    Migration { version: 8, name: "webhooks", up: Up::Sql(WEBHOOKS) },
+    Migration { version: 9, name: "refunds", up: Up::Sql(REFUNDS) },
+    Migration { version: 10, name: "card_authorizations", up: Up::Sql(CARD_AUTHORIZATIONS) },
+    Migration { version: 11, name: "invoice_indexes", up: Up::Sql(INVOICE_INDEXES) },
+    Migration { version: 12, name: "invoice_line_items", up: Up::Sql(INVOICE_LINE_ITEMS) },
+    Migration { version: 13, name: "invoice_return_urls", up: Up::Sql(INVOICE_RETURN_URLS) },
+    Migration { version: 14, name: "subscriptions", up: Up::Sql(SUBSCRIPTIONS) },
+    Migration { version: 15, name: "payment_links", up: Up::Sql(PAYMENT_LINKS) },
+    Migration { version: 16, name: "money_request_responses", up: Up::Sql(MONEY_REQUEST_RESPONSES) },
+    Migration { version: 17, name: "transfer_limits", up: Up::Sql(TRANSFER_LIMITS) },
+    Migration { version: 18, name: "scheduled_transfers", up: Up::Sql(SCHEDULED_TRANSFERS) },
+    Migration { version: 19, name: "session_refresh_tokens", up: Up::Sql(SESSION_REFRESH_TOKENS) },
+    Migration { version: 20, name: "two_factor", up: Up::Sql(TWO_FACTOR) },
+    Migration { version: 21, name: "auth_failures", up: Up::Sql(AUTH_FAILURES) },
+    Migration { version: 22, name: "password_resets", up: Up::Sql(PASSWORD_RESETS) },
+    Migration { version: 23, name: "business_return_secrets", up: Up::Sql(&["ALTER TABLE businesses ADD COLUMN return_secret TEXT"]) },
+    Migration { version: 24, name: "return_secret_backfill", up: Up::Data(return_secret_backfill) },
+];
+
+const INITIAL_SCHEMA: &[&str] = &[
+    r#"
+        CREATE TABLE IF NOT EXISTS users (
+            id TEXT PRIMARY KEY,
+            arsonflare_id TEXT UNIQUE NOT NULL,
+            username TEXT NOT NULL,
+            wallet_balance DOUBLE PRECISION DEFAULT 0.0,
+            wallet_address TEXT UNIQUE NOT NULL,
+            created_at TEXT NOT NULL,
+            is_admin BOOLEAN DEFAULT FALSE
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS user_credentials (
+            user_id TEXT PRIMARY KEY,
+            password_hash TEXT NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS businesses (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            business_name TEXT NOT NULL,
+            website_url TEXT,
+            api_key TEXT UNIQUE NOT NULL,
+            verified BOOLEAN DEFAULT TRUE,
+            balance DOUBLE PRECISION DEFAULT 0.0,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS transactions (
+            id TEXT PRIMARY KEY,
+            transaction_type TEXT NOT NULL,
+            from_user_id TEXT,
+            to_user_id TEXT,
+            business_id TEXT,
+            amount DOUBLE PRECISION NOT NULL,
+            platform_fee DOUBLE PRECISION DEFAULT 0.0,
+            status TEXT DEFAULT 'completed',
+            description TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            completed_at TEXT,
+            FOREIGN KEY (from_user_id) REFERENCES users (id),
+            FOREIGN KEY (to_user_id) REFERENCES users (id),
+            FOREIGN KEY (business_id) REFERENCES businesses (id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS redemption_codes (
+            id TEXT PRIMARY KEY,
+            code TEXT UNIQUE NOT NULL,
+            amount DOUBLE PRECISION NOT NULL,
+            max_uses INTEGER,
+            current_uses INTEGER DEFAULT 0,
+            created_by TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            expires_at TEXT,
+            active BOOLEAN DEFAULT TRUE,
+            FOREIGN KEY (created_by) REFERENCES users (id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS code_redemptions (
+            id TEXT PRIMARY KEY,
+            code_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            amount_received DOUBLE PRECISION NOT NULL,
+            redeemed_at TEXT NOT NULL,
+            FOREIGN KEY (code_id) REFERENCES redemption_codes (id),
+            FOREIGN KEY (user_id) REFERENCES users (id),
+            UNIQUE(code_id, user_id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS invoices (
+            id TEXT PRIMARY KEY,
+            business_id TEXT NOT NULL,
+            amount DOUBLE PRECISION NOT NULL,
+            description TEXT NOT NULL,
+            customer_name TEXT,
+            status TEXT DEFAULT 'pending',
+            paid_at TEXT,
+            created_at TEXT NOT NULL,
+            expires_at TEXT,
+            FOREIGN KEY (business_id) REFERENCES businesses (id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS user_sessions (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            jwt_token TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            expires_at TEXT NOT NULL,
+            active BOOLEAN DEFAULT TRUE,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS money_requests (
+            id TEXT PRIMARY KEY,
+            from_user_id TEXT NOT NULL,
+            to_user_id TEXT NOT NULL,
+            amount DOUBLE PRECISION NOT NULL,
+            description TEXT NOT NULL,
+            status TEXT DEFAULT 'pending',
+            created_at TEXT NOT NULL,
+            responded_at TEXT,
+            FOREIGN KEY (from_user_id) REFERENCES users (id),
+            FOREIGN KEY (to_user_id) REFERENCES users (id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS debit_cards (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            card_number TEXT NOT NULL UNIQUE,
+            cvv TEXT NOT NULL,
+            expiration_month INTEGER NOT NULL,
+            expiration_year INTEGER NOT NULL,
+            is_active BOOLEAN DEFAULT TRUE,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+    "#,
+];
+
+// Every column that held GC as DOUBLE PRECISION before amounts moved to integer minor units.
+const MONEY_COLUMNS: &[(&str, &str)] = &[
+    ("users", "wallet_balance"),
+    ("businesses", "balance"),
+    ("transactions", "amount"),
+    ("transactions", "platform_fee"),
+    ("redemption_codes", "amount"),
+    ("code_redemptions", "amount_received"),
+    ("invoices", "amount"),
+    ("money_requests", "amount"),
+];
+
+// Postgres can retype a column in place. SQLite can't, so each column is copied into
+// a rounded integer column which then takes over the original name.
+fn money_minor_units(backend: Backend) -> Vec<String> {
+    let scale = Money::MINOR_PER_MAJOR;
+    MONEY_COLUMNS.iter()
+        .flat_map(|(table, column)| match backend {
+            Backend::Postgres => vec![
+                format!("ALTER TABLE {table} ALTER COLUMN {column} DROP DEFAULT"),
+                format!("ALTER TABLE {table} ALTER COLUMN {column} TYPE BIGINT USING CAST(ROUND(COALESCE({column}, 0) * {scale}) AS BIGINT)"),
+                format!("ALTER TABLE {table} ALTER COLUMN {column} SET DEFAULT 0"),
+                format!("ALTER TABLE {table} ALTER COLUMN {column} SET NOT NULL"),
+            ],
+            Backend::Sqlite => vec![
+                format!("ALTER TABLE {table} ADD COLUMN {column}_minor BIGINT NOT NULL DEFAULT 0"),
+                format!("UPDATE {table} SET {column}_minor = CAST(ROUND(COALESCE({column}, 0) * {scale}) AS BIGINT)"),
+                format!("ALTER TABLE {table} DROP COLUMN {column}"),
+                format!("ALTER TABLE {table} RENAME COLUMN {column}_minor TO {column}"),
+            ],
+        })
+        .collect()
+}
+
+const LEDGER: &[&str] = &[
+    r#"
+        CREATE TABLE IF NOT EXISTS ledger_entries (
+            id TEXT PRIMARY KEY,
+            transaction_id TEXT,
+            description TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY (transaction_id) REFERENCES transactions (id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS ledger_postings (
+            id TEXT PRIMARY KEY,
+            entry_id TEXT NOT NULL,
+            account_type TEXT NOT NULL,
+            account_id TEXT,
+            amount BIGINT NOT NULL,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY (entry_id) REFERENCES ledger_entries (id)
+        )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_ledger_postings_account ON ledger_postings (account_type, account_id)",
+];
+
+// Replays completed transactions into the ledger without touching cached balances;
+// any drift between the two is left for reconciliation to report.
+fn ledger_backfill(conn: &mut AnyConnection) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+    Box::pin(async move {
+        let rows = sqlx::query(
+            "SELECT id, from_user_id, to_user_id, business_id, amount, description, created_at \
+             FROM transactions WHERE status = 'completed' ORDER BY created_at"
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to read transactions for ledger backfill: {}", e)))?;
+
+        let parse_id = |value: Option<String>| value.and_then(|s| Uuid::parse_str(&s).ok());
+        for row in rows {
+            let amount = Money::from_minor(row.get("amount"));
+            if !amount.is_positive() {
+                continue;
+            }
+            let accounts = ledger::legacy_accounts(
+                parse_id(row.get("from_user_id")),
+                parse_id(row.get("to_user_id")),
+                parse_id(row.get("business_id")),
+            );
+            let Some((from, to)) = accounts else { continue };
+            let Ok(transaction_id) = Uuid::parse_str(&row.get::<String, _>("id")) else { continue };
+
+            let mut entry = JournalEntry::transfer(transaction_id, from, to, amount, &row.get::<String, _>("description"));
+            if let Ok(created_at) = chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at")) {
+                entry.created_at = created_at.with_timezone(&Utc);
+            }
+            ledger::record(&mut *conn, &entry).await?;
+        }
+        Ok(())
+    })
+}
+
+const AUDIT_LOG: &[&str] = &[r#"
+    CREATE TABLE IF NOT EXISTS audit_log (
+        id TEXT PRIMARY KEY,
+        actor_user_id TEXT,
+        action TEXT NOT NULL,
+        details TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    )
+"#];
+
+const IDEMPOTENCY_KEYS: &[&str] = &[r#"
+    CREATE TABLE IF NOT EXISTS idempotency_keys (
+        caller TEXT NOT NULL,
+        idempotency_key TEXT NOT NULL,
+        request_hash TEXT NOT NULL,
+        status_code INTEGER,
+        content_type TEXT,
+        response_body TEXT,
+        created_at TEXT NOT NULL,
+        PRIMARY KEY (caller, idempotency_key)
+    )
+"#];
+
+const INVOICE_PAYER: &[&str] = &[
+    "ALTER TABLE invoices ADD COLUMN paid_by_user_id TEXT",
+    "ALTER TABLE invoices ADD COLUMN transaction_id TEXT",
+];
+
+const WEBHOOKS: &[&str] = &[
+    r#"
+        CREATE TABLE IF NOT EXISTS webhook_endpoints (
+            id TEXT PRIMARY KEY,
+            business_id TEXT NOT NULL,
+            url TEXT NOT NULL,
+            secret TEXT NOT NULL,
+            events TEXT NOT NULL,
+            active BOOLEAN DEFAULT TRUE,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY (business_id) REFERENCES businesses (id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS webhook_deliveries (
+            id TEXT PRIMARY KEY,
+            endpoint_id TEXT NOT NULL,
+            business_id TEXT NOT NULL,
+            event_id TEXT NOT NULL,
+            event_type TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            status TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            next_attempt_at TEXT NOT NULL,
+            last_status_code INTEGER,
+            last_error TEXT,
+            created_at TEXT NOT NULL,
+            delivered_at TEXT,
+            FOREIGN KEY (endpoint_id) REFERENCES webhook_endpoints (id)
+        )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_due ON webhook_deliveries (status, next_attempt_at)",
+    "CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_business ON webhook_deliveries (business_id, created_at)",
+];
+
+const REFUNDS: &[&str] = &[
+    "ALTER TABLE transactions ADD COLUMN refunded_amount BIGINT NOT NULL DEFAULT 0",
+    "ALTER TABLE transactions ADD COLUMN refund_of TEXT",
+    "CREATE INDEX IF NOT EXISTS idx_transactions_refund_of ON transactions (refund_of)",
+];
+
+const CARD_AUTHORIZATIONS: &[&str] = &[
+    "ALTER TABLE users ADD COLUMN held_balance BIGINT NOT NULL DEFAULT 0",
+    r#"
+        CREATE TABLE IF NOT EXISTS card_authorizations (
+            id TEXT PRIMARY KEY,
+            business_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            card_id TEXT NOT NULL,
+            amount BIGINT NOT NULL,
+            captured_amount BIGINT,
+            status TEXT NOT NULL,
+            description TEXT NOT NULL,
+            transaction_id TEXT,
+            created_at TEXT NOT NULL,
+            expires_at TEXT NOT NULL,
+            closed_at TEXT,
+            FOREIGN KEY (business_id) REFERENCES businesses (id),
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_card_authorizations_due ON card_authorizations (status, expires_at)",
+    "CREATE INDEX IF NOT EXISTS idx_card_authorizations_business ON card_authorizations (business_id, created_at)",
+];
+
+const INVOICE_INDEXES: &[&str] = &[
+    "CREATE INDEX IF NOT EXISTS idx_invoices_business_created ON invoices (business_id, created_at)",
+    "CREATE INDEX IF NOT EXISTS idx_invoices_due ON invoices (status, expires_at)",
+];
+
+// Both hold JSON; NULL on invoices created before this migration.
+const INVOICE_LINE_ITEMS: &[&str] = &[
+    "ALTER TABLE invoices ADD COLUMN line_items TEXT",
+    "ALTER TABLE invoices ADD COLUMN metadata TEXT",
+];
+
+const INVOICE_RETURN_URLS: &[&str] = &[
+    "ALTER TABLE invoices ADD COLUMN success_url TEXT",
+    "ALTER TABLE invoices ADD COLUMN cancel_url TEXT",
+];
+
+const SUBSCRIPTIONS: &[&str] = &[
+    r#"
+        CREATE TABLE IF NOT EXISTS subscription_plans (
+            id TEXT PRIMARY KEY,
+            business_id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            amount BIGINT NOT NULL,
+            billing_interval TEXT NOT NULL,
+            interval_count INTEGER NOT NULL DEFAULT 1,
+            trial_days INTEGER NOT NULL DEFAULT 0,
+            active BOOLEAN DEFAULT TRUE,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY (business_id) REFERENCES businesses (id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS subscriptions (
+            id TEXT PRIMARY KEY,
+            plan_id TEXT NOT NULL,
+            business_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            status TEXT NOT NULL,
+            current_period_start TEXT NOT NULL,
+            current_period_end TEXT NOT NULL,
+            next_charge_at TEXT,
+            failed_attempts INTEGER NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL,
+            cancelled_at TEXT,
+            FOREIGN KEY (plan_id) REFERENCES subscription_plans (id),
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS subscription_charges (
+            id TEXT PRIMARY KEY,
+            subscription_id TEXT NOT NULL,
+            transaction_id TEXT NOT NULL,
+            amount BIGINT NOT NULL,
+            period_start TEXT NOT NULL,
+            period_end TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            UNIQUE (subscription_id, period_start),
+            FOREIGN KEY (subscription_id) REFERENCES subscriptions (id)
+        )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_subscriptions_due ON subscriptions (status, next_charge_at)",
+    "CREATE INDEX IF NOT EXISTS idx_subscriptions_user ON subscriptions (user_id)",
+    "CREATE UNIQUE INDEX IF NOT EXISTS idx_subscriptions_live ON subscriptions (user_id, plan_id) WHERE status <> 'cancelled'",
+    "CREATE INDEX IF NOT EXISTS idx_subscriptions_business ON subscriptions (business_id, created_at)",
+];
+
+const PAYMENT_LINKS: &[&str] = &[
+    r#"
+        CREATE TABLE IF NOT EXISTS payment_links (
+            id TEXT PRIMARY KEY,
+            business_id TEXT NOT NULL,
+            description TEXT NOT NULL,
+            amount BIGINT,
+            max_uses INTEGER,
+            use_count INTEGER NOT NULL DEFAULT 0,
+            expires_at TEXT,
+            active BOOLEAN DEFAULT TRUE,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY (business_id) REFERENCES businesses (id)
+        )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_payment_links_business ON payment_links (business_id, created_at)",
+    "ALTER TABLE invoices ADD COLUMN payment_link_id TEXT",
+    "CREATE INDEX IF NOT EXISTS idx_invoices_payment_link ON invoices (payment_link_id)",
+];
+
+const MONEY_REQUEST_RESPONSES: &[&str] = &[
+    "ALTER TABLE money_requests ADD COLUMN transaction_id TEXT",
+    "CREATE INDEX IF NOT EXISTS idx_money_requests_from ON money_requests (from_user_id, created_at)",
+    "CREATE INDEX IF NOT EXISTS idx_money_requests_to ON money_requests (to_user_id, created_at)",
+    "CREATE INDEX IF NOT EXISTS idx_money_requests_pending ON money_requests (status, created_at)",
+];
+
+// Limits are in minor units like every other amount; NULL means unlimited. The
+// standard tier keeps the old 10,000 GC single-transfer cap.
+const TRANSFER_LIMITS: &[&str] = &[
+    r#"
+        CREATE TABLE IF NOT EXISTS limit_tiers (
+            name TEXT PRIMARY KEY,
+            per_transaction BIGINT,
+            daily BIGINT,
+            monthly BIGINT,
+            updated_at TEXT NOT NULL
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS user_limits (
+            user_id TEXT PRIMARY KEY,
+            tier TEXT,
+            per_transaction BIGINT,
+            daily BIGINT,
+            monthly BIGINT,
+            updated_at TEXT NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+    "#,
+    "INSERT INTO limit_tiers (name, per_transaction, daily, monthly, updated_at) \
+     VALUES ('standard', 1000000, 2500000, 25000000, '1970-01-01T00:00:00+00:00')",
+    "CREATE INDEX IF NOT EXISTS idx_transactions_sender ON transactions (from_user_id, created_at)",
+];
+
+const SCHEDULED_TRANSFERS: &[&str] = &[
+    r#"
+        CREATE TABLE IF NOT EXISTS scheduled_transfers (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            to_user_id TEXT NOT NULL,
+            amount BIGINT NOT NULL,
+            description TEXT NOT NULL,
+            schedule_interval TEXT NOT NULL,
+            interval_count INTEGER NOT NULL DEFAULT 1,
+            next_run_at TEXT,
+            remaining_runs INTEGER,
+            status TEXT NOT NULL,
+            failed_attempts INTEGER NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users (id),
+            FOREIGN KEY (to_user_id) REFERENCES users (id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS scheduled_transfer_runs (
+            id TEXT PRIMARY KEY,
+            scheduled_transfer_id TEXT NOT NULL,
+            scheduled_for TEXT NOT NULL,
+            status TEXT NOT NULL,
+            transaction_id TEXT,
+            error TEXT,
+            created_at TEXT NOT NULL,
+            UNIQUE (scheduled_transfer_id, scheduled_for),
+            FOREIGN KEY (scheduled_transfer_id) REFERENCES scheduled_transfers (id)
+        )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_scheduled_transfers_due ON scheduled_transfers (status, next_run_at)",
+    "CREATE INDEX IF NOT EXISTS idx_scheduled_transfers_user ON scheduled_transfers (user_id, created_at)",
+];
+
+const SESSION_REFRESH_TOKENS: &[&str] = &[
+    "ALTER TABLE user_sessions ADD COLUMN refresh_token_hash TEXT",
+    "ALTER TABLE user_sessions ADD COLUMN previous_refresh_token_hash TEXT",
+    "ALTER TABLE user_sessions ADD COLUMN last_used_at TEXT",
+    "CREATE UNIQUE INDEX IF NOT EXISTS idx_user_sessions_refresh ON user_sessions (refresh_token_hash)",
+    "CREATE INDEX IF NOT EXISTS idx_user_sessions_previous_refresh ON user_sessions (previous_refresh_token_hash)",
+    "CREATE INDEX IF NOT EXISTS idx_user_sessions_user ON user_sessions (user_id, active)",
+];
+
+const TWO_FACTOR: &[&str] = &[
+    r#"
+        CREATE TABLE IF NOT EXISTS user_two_factor (
+            user_id TEXT PRIMARY KEY,
+            secret TEXT NOT NULL,
+            enabled BOOLEAN NOT NULL DEFAULT FALSE,
+            last_used_step BIGINT,
+            send_threshold BIGINT,
+            created_at TEXT NOT NULL,
+            enabled_at TEXT,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS two_factor_recovery_codes (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            code_hash TEXT NOT NULL,
+            used_at TEXT,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+    "#,
+    r#"
+        CREATE TABLE IF NOT EXISTS login_challenges (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            expires_at TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users (id)
+        )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_recovery_codes_user ON two_factor_recovery_codes (user_id, code_hash)",
+];
+
+const AUTH_FAILURES: &[&str] = &[
+    r#"
+        CREATE TABLE IF NOT EXISTS auth_failures (
+            scope TEXT NOT NULL,
+            subject TEXT NOT NULL,
+            failures INTEGER NOT NULL,
+            last_failure_at TEXT NOT NULL,
+            locked_until TEXT,
+            PRIMARY KEY (scope, subject)
+        )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_auth_failures_locked ON auth_failures (locked_until)",
+];
+
+const PASSWORD_RESETS: &[&str] = &[
+    r#"
+        CREATE TABLE IF NOT EXISTS password_resets (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            issued_by TEXT NOT NULL,
+            expires_at TEXT NOT NULL,
+            used_at TEXT,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users (id),
+            FOREIGN KEY (issued_by) REFERENCES users (id)
+        )
+    "#,
+    "CREATE INDEX IF NOT EXISTS idx_password_resets_user ON password_resets (user_id)",
+];
+
+// Businesses from before return secrets each get their own. Return URLs they
+// verified with the API key stop matching, which the API docs call out.
+fn return_secret_backfill(conn: &mut AnyConnection) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+    Box::pin(async move {
+        let ids: Vec<String> = sqlx::query("SELECT id FROM businesses WHERE return_secret IS NULL")
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(|e| GurtError::invalid_message(format!("Failed to read businesses for return secret backfill: {}", e)))?
+            .into_iter()
+            .map(|row| row.get("id"))
+            .collect();
+
+        for id in ids {
+            sqlx::query("UPDATE businesses SET return_secret = $1 WHERE id = $2")
+                .bind(checkout::generate_return_secret())
+                .bind(id)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| GurtError::invalid_message(format!("Failed to backfill return secret: {}", e)))?;
+        }
+        Ok(())
+    })
+}
+
+impl Migration {
+    fn statements(&self, backend: Backend) -> Option<Vec<String>> {
+        match &self.up {
+            Up::Sql(statements) => Some(statements.iter().map(|s| s.to_string()).collect()),
+            Up::PerBackend(build) => Some(build(backend)),
+            Up::Data(_) => None,
+        }
+    }
+
+    async fn apply(&self, pool: &AnyPool, backend: Backend) -> Result<()> {
+        let fail = |e: &dyn std::fmt::Display| {
+            GurtError::invalid_message(format!("Migration {} ({}) failed: {}", self.version, self.name, e))
+        };
+
+        let mut tx = pool.begin().await.map_err(|e| fail(&e))?;
+
+        match &self.up {
+            Up::Data(run) => run(&mut tx).await.map_err(|e| fail(&e))?,
+            _ => {
+                for statement in self.statements(backend).unwrap_or_default() {
+                    sqlx::query(&statement).execute(&mut *tx).await.map_err(|e| fail(&e))?;
+                }
+            }
+        }
+
+        record(&mut tx, self.version, self.name).await?;
+        tx.commit().await.map_err(|e| fail(&e))
+    }
+}
+
+/// What `migrate` would do: the migrations to run, in order.
+struct Plan {
+    backend: Backend,
+    current: i64,
+    pending: Vec<&'static Migration>,
+}
+
+async fn plan(pool: &AnyPool) -> Result<Plan> {
+    let backend = backend(pool).await?;
+
+    let tracked = probe(pool, "SELECT version FROM schema_migrations WHERE 1 = 0").await;
+    let applied: BTreeSet<i64> = if tracked {
+        let rows = sqlx::query("SELECT version FROM schema_migrations")
+            .fetch_all(pool)
+            .await
+            .map_err(|e| GurtError::invalid_message(format!("Failed to read schema_migrations: {}", e)))?;
+        rows.iter().map(|row| row.get::<i64, _>("version")).collect()
+    } else {
+        BTreeSet::new()
+    };
+
+    let latest = MIGRATIONS.last().map(|m| m.version).unwrap_or(0);
+    if let Some(&newest) = applied.iter().next_back().filter(|&&v| v > latest) {
+        return Err(GurtError::invalid_message(format!(
+            "Database schema is at version {} but this build only knows up to {}; refusing to start",
+            newest, latest
+        )));
+    }
+
+    Ok(Plan {
+        backend,
+        current: applied.iter().next_back().copied().unwrap_or(0),
+        pending: MIGRATIONS.iter().filter(|m| !applied.contains(&m.version)).collect(),
+    })
+}
+
+/// Brings the schema up to date. Each migration commits on its own together with
+/// its `schema_migrations` row, so a failure stops at the last good version.
+pub async fn migrate(pool: &AnyPool) -> Result<()> {
+    let plan = plan(pool).await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (version BIGINT PRIMARY KEY, name TEXT NOT NULL, applied_at TEXT NOT NULL)"
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to create schema_migrations table: {}", e)))?;
+
+    for migration in &plan.pending {
+        println!("🗄️  Applying migration {:04} {}", migration.version, migration.name);
+        migration.apply(pool, plan.backend).await?;
+    }
+    Ok(())
+}
+
+/// Prints the pending migrations and their SQL without changing anything.
+pub async fn dry_run(pool: &AnyPool) -> Result<()> {
+    let plan = plan(pool).await?;
+
+    println!("🗄️  {:?} database at schema version {}", plan.backend, plan.current);
+    if plan.pending.is_empty() {
+        println!("  Nothing to migrate");
+    }
+    for migration in &plan.pending {
+        match migration.statements(plan.backend) {
+            Some(statements) => {
+                println!("  {:04} {}", migration.version, migration.name);
+                for statement in statements {
+                    println!("      {};", statement.split_whitespace().collect::<Vec<_>>().join(" "));
+                }
+            }
+            None => println!("  {:04} {} (data migration)", migration.version, migration.name),
+        }
+    }
+    Ok(())
+}
+
+async fn backend(pool: &AnyPool) -> Result<Backend> {
+    let conn = pool.acquire().await
+        .map_err(|e| GurtError::invalid_message(format!("Database connection failed: {}", e)))?;
+    match conn.backend_name() {
+        "PostgreSQL" => Ok(Backend::Postgres),
+        "SQLite" => Ok(Backend::Sqlite),
+        other => Err(GurtError::invalid_message(format!("Unsupported database backend: {}", other))),
+    }
+}
+
+// Runs outside any transaction: on Postgres a failed statement poisons the transaction it is in.
+async fn probe(pool: &AnyPool, sql: &str) -> bool {
+    sqlx::query(sql).execute(pool).await.is_ok()
+}
+
+async fn record(conn: &mut AnyConnection, version: i64, name: &str) -> Result<()> {
+    sqlx::query("INSERT INTO schema_migrations (version, name, applied_at) VALUES ($1, $2, $3)")
+        .bind(version)
+        .bind(name)
+        .bind(Utc::now().to_rfc3339())
+        .execute(conn)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to record migration {}: {}", version, e)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reconcile::reconcile;
+    use sqlx::any::AnyPoolOptions;
+
+    /// A database with nothing in it, not even `schema_migrations`.
+    async fn empty_pool() -> AnyPool {
+        sqlx::any::install_default_drivers();
+        AnyPoolOptions::new()
+            .max_connections(4)
+            .min_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect(&format!("sqlite:file:gurtpay-migrations-{}?mode=memory&cache=shared", Uuid::new_v4()))
+            .await
+            .unwrap()
+    }
+
+    async fn applied_versions(pool: &AnyPool) -> Vec<i64> {
+        sqlx::query("SELECT version FROM schema_migrations ORDER BY version")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get("version"))
+            .collect()
+    }
+
+    async fn execute(pool: &AnyPool, sql: &str) {
+        sqlx::query(sql).execute(pool).await.unwrap();
+    }
+
+    #[test]
+    fn versions_are_consecutive_from_one() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i64 + 1, "{}", migration.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn migrates_a_fresh_database_to_the_latest_version() {
+        let pool = empty_pool().await;
+        migrate(&pool).await.unwrap();
+
+        let expected: Vec<i64> = MIGRATIONS.iter().map(|m| m.version).collect();
+        assert_eq!(applied_versions(&pool).await, expected);
+        assert_eq!(plan(&pool).await.unwrap().current, MIGRATIONS.last().unwrap().version);
+    }
+
+    #[tokio::test]
+    async fn running_again_applies_nothing() {
+        let pool = empty_pool().await;
+        migrate(&pool).await.unwrap();
+        let before = applied_versions(&pool).await;
+
+        assert!(plan(&pool).await.unwrap().pending.is_empty());
+        migrate(&pool).await.unwrap();
+        assert_eq!(applied_versions(&pool).await, before);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_database_newer_than_the_build() {
+        let pool = empty_pool().await;
+        migrate(&pool).await.unwrap();
+        execute(&pool, "INSERT INTO schema_migrations (version, name, applied_at) VALUES (9999, 'from_the_future', '2026-01-01T00:00:00Z')").await;
+
+        assert!(migrate(&pool).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn upgrades_a_float_schema_database() {
+        let pool = empty_pool().await;
+        for statement in INITIAL_SCHEMA {
+            execute(&pool, statement).await;
+        }
+
+        let (alice, bob, owner, business) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now().to_rfc3339();
+        // Alice was minted 10.10, paid the business 0.30 and sent Bob 0.20; her
+        // stored balance carries the float error those sums pick up.
+        for (id, balance) in [(alice, "9.599999999999998"), (bob, "0.2"), (owner, "0.0")] {
+            execute(&pool, &format!(
+                "INSERT INTO users (id, arsonflare_id, username, wallet_balance, wallet_address, created_at) \
+                 VALUES ('{id}', 'af-{id}', 'user-{id}', {balance}, 'wallet-{id}', '{now}')"
+            )).await;
+        }
+        execute(&pool, &format!(
+            "INSERT INTO businesses (id, user_id, business_name, api_key, balance, created_at) \
+             VALUES ('{business}', '{owner}', 'Shop', 'gp_{}', 0.3, '{now}')",
+            business.simple()
+        )).await;
+        for (from, to, business_id, amount) in [
+            ("NULL".to_string(), format!("'{alice}'"), "NULL".to_string(), "10.1"),
+            (format!("'{alice}'"), "NULL".to_string(), format!("'{business}'"), "0.3"),
+            (format!("'{alice}'"), format!("'{bob}'"), "NULL".to_string(), "0.2"),
+        ] {
+            execute(&pool, &format!(
+                "INSERT INTO transactions (id, transaction_type, from_user_id, to_user_id, business_id, amount, description, created_at) \
+                 VALUES ('{}', 'transfer', {from}, {to}, {business_id}, {amount}, 'legacy', '{now}')",
+                Uuid::new_v4()
+            )).await;
+        }
+
+        migrate(&pool).await.unwrap();
+
+        let balances = sqlx::query("SELECT id, typeof(wallet_balance) AS kind, wallet_balance FROM users")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        for row in &balances {
+            assert_eq!(row.get::<String, _>("kind"), "integer");
+        }
+        let wallet = |id: Uuid| balances.iter()
+            .find(|row| row.get::<String, _>("id") == id.to_string())
+            .map(|row| row.get::<i64, _>("wallet_balance"))
+            .unwrap();
+        assert_eq!(wallet(alice), 960);
+        assert_eq!(wallet(bob), 20);
+
+        let amounts: Vec<i64> = sqlx::query("SELECT amount FROM transactions ORDER BY amount")
+            .fetch_all(&pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get("amount"))
+            .collect();
+        assert_eq!(amounts, vec![20, 30, 1010]);
+
+        let postings: i64 = sqlx::query("SELECT COUNT(*) AS n FROM ledger_postings")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("n");
+        assert_eq!(postings, 6);
+
+        let report = reconcile(&pool, false, None).await.unwrap();
+        assert!(report.discrepancies.is_empty(), "{:?}", report.discrepancies);
+    }
+}
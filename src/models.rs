@@ -1,13 +1,15 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
+use crate::money::Money;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: Uuid,
     pub arsonflare_id: String,
     pub username: String,
-    pub wallet_balance: f64,
+    pub wallet_balance: Money,
     pub wallet_address: String,
     pub created_at: DateTime<Utc>,
     pub is_admin: bool,
@@ -20,8 +22,11 @@ pub struct Business {
     pub business_name: String,
     pub website_url: Option<String>,
     pub api_key: String,
+    /// Signs the return URLs sent back to the business's site. Only ever
+    /// shown to the owner, like the API key.
+    pub return_secret: String,
     pub verified: bool,
-    pub balance: f64,
+    pub balance: Money,
     pub created_at: DateTime<Utc>,
 }
 
@@ -32,12 +37,14 @@ pub struct Transaction {
     pub from_user_id: Option<Uuid>,
     pub to_user_id: Option<Uuid>,
     pub business_id: Option<Uuid>,
-    pub amount: f64,
-    pub platform_fee: f64,
+    pub amount: Money,
+    pub platform_fee: Money,
     pub status: TransactionStatus,
     pub description: String,
     pub created_at: DateTime<Utc>,
     pub completed_at: Option<DateTime<Utc>>,
+    /// Total refunded so far; only business payments can have refunds.
+    pub refunded_amount: Money,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,6 +55,7 @@ pub enum TransactionType {
     CodeRedemption,
     PlatformFee,
     Welcome,
+    Refund,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -63,7 +71,7 @@ pub enum TransactionStatus {
 pub struct RedemptionCode {
     pub id: Uuid,
     pub code: String,
-    pub amount: f64,
+    pub amount: Money,
     pub max_uses: Option<i32>,
     pub current_uses: i32,
     pub created_by: Uuid,
@@ -72,44 +80,101 @@ pub struct RedemptionCode {
     pub active: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct CodeRedemption {
-    pub id: Uuid,
-    pub code_id: Uuid,
-    pub user_id: Uuid,
-    pub amount_received: f64,
-    pub redeemed_at: DateTime<Utc>,
+// Request/Response DTOs
+#[derive(Debug, Deserialize)]
+pub struct SendMoneyRequest {
+    pub to_address: String,
+    pub amount: Money,
+    pub description: String,
+    pub two_factor_code: Option<String>, // Needed above the user's step-up threshold
 }
 
-// Request/Response DTOs
 #[derive(Debug, Deserialize)]
-pub struct AuthVerifyRequest {
-    pub token: String,
+pub struct RequestMoneyRequest {
+    pub from_address: String,
+    pub amount: Money,
+    pub description: String,
 }
 
-#[derive(Debug, Serialize)]
-pub struct AuthVerifyResponse {
-    pub user: User,
-    pub session_token: String,
+#[derive(Debug, Deserialize)]
+pub struct RefreshSessionRequest {
+    pub refresh_token: String,
 }
 
+/// Second login step for accounts with two-factor enabled.
 #[derive(Debug, Deserialize)]
-pub struct UserRegisterRequest {
-    pub arsonflare_token: String,
+pub struct LoginTwoFactorRequest {
+    pub challenge_token: String,
+    pub code: String, // Authenticator code or a recovery code
 }
 
 #[derive(Debug, Deserialize)]
-pub struct SendMoneyRequest {
+pub struct TwoFactorCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetSendThresholdRequest {
+    pub code: String,
+    pub threshold: Option<Money>, // None stops asking for a code on sends
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevokeSessionRequest {
+    pub session_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockAccountRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssuePasswordResetRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub reset_token: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateScheduledTransferRequest {
     pub to_address: String,
-    pub amount: f64,
+    pub amount: Money,
     pub description: String,
+    pub interval: String, // "day", "week", "month" or "year"
+    pub interval_count: Option<i32>, // Default 1
+    pub start_at: Option<DateTime<Utc>>, // First run; defaults to now
+    pub max_runs: Option<i32>, // Runs forever by default
+    pub two_factor_code: Option<String>, // Needed when each run is above the user's step-up threshold
 }
 
+/// Body of the pause, resume and cancel scheduled transfer endpoints.
 #[derive(Debug, Deserialize)]
-pub struct RequestMoneyRequest {
-    pub from_address: String,
-    pub amount: f64,
-    pub description: String,
+pub struct ScheduledTransferActionRequest {
+    pub scheduled_transfer_id: Uuid,
+}
+
+/// Body of the pay, decline and cancel money request endpoints.
+#[derive(Debug, Deserialize)]
+pub struct RespondMoneyRequest {
+    pub request_id: Uuid,
+    pub two_factor_code: Option<String>, // Needed to pay above the user's step-up threshold
+}
+
+/// Body of `/api/invoice/pay/*`, which can be left empty.
+#[derive(Debug, Default, Deserialize)]
+pub struct PayInvoiceRequest {
+    pub two_factor_code: Option<String>, // Needed above the user's step-up threshold
 }
 
 #[derive(Debug, Deserialize)]
@@ -125,15 +190,38 @@ pub struct RedeemCodeRequest {
 
 #[derive(Debug, Deserialize)]
 pub struct CreateCodeRequest {
-    pub amount: f64,
+    pub amount: Money,
     pub max_uses: Option<i32>,
     pub expires_in_hours: Option<i32>,
 }
 
+#[derive(Debug, Default, Deserialize)]
+pub struct ReconcileRequest {
+    #[serde(default)]
+    pub repair: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetTierLimitsRequest {
+    pub name: String,
+    pub per_transaction: Option<Money>, // Each limit is unlimited when left out
+    pub daily: Option<Money>,
+    pub monthly: Option<Money>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetUserLimitsRequest {
+    pub user_id: Uuid,
+    pub tier: Option<String>, // Defaults to the standard tier
+    pub per_transaction: Option<Money>, // Each limit falls back to the tier's when left out
+    pub daily: Option<Money>,
+    pub monthly: Option<Money>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct BusinessTransferRequest {
     pub business_id: String,
-    pub amount: f64,
+    pub amount: Money,
     pub direction: String, // "deposit" or "withdraw"
     pub description: String,
 }
@@ -142,13 +230,64 @@ pub struct BusinessTransferRequest {
 pub struct Invoice {
     pub id: Uuid,
     pub business_id: Uuid,
-    pub amount: f64,
+    pub amount: Money,
     pub description: String,
     pub customer_name: Option<String>,
     pub status: InvoiceStatus,
     pub paid_at: Option<DateTime<Utc>>,
+    pub transaction_id: Option<Uuid>,
     pub created_at: DateTime<Utc>,
     pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub line_items: Vec<InvoiceLineItem>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    pub success_url: Option<String>,
+    pub cancel_url: Option<String>,
+    /// Set when the invoice was opened from a reusable payment link.
+    pub payment_link_id: Option<Uuid>,
+}
+
+/// Everything the business decides about an invoice before it is created.
+#[derive(Debug, Clone)]
+pub struct NewInvoice {
+    pub amount: Money,
+    pub description: String,
+    pub customer_name: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub line_items: Vec<InvoiceLineItem>,
+    pub metadata: BTreeMap<String, String>,
+    pub success_url: Option<String>,
+    pub cancel_url: Option<String>,
+    pub payment_link_id: Option<Uuid>,
+}
+
+/// One line of an invoice. Tax and discount lines are flat amounts in
+/// `unit_price`; a discount is subtracted from the total.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InvoiceLineItem {
+    pub name: String,
+    #[serde(default)]
+    pub kind: LineItemKind,
+    #[serde(default = "default_quantity")]
+    pub quantity: i64,
+    pub unit_price: Money,
+    /// quantity × unit_price, filled in by the server.
+    #[serde(default)]
+    pub amount: Money,
+}
+
+fn default_quantity() -> i64 {
+    1
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LineItemKind {
+    #[default]
+    Item,
+    Tax,
+    Discount,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -160,63 +299,125 @@ pub enum InvoiceStatus {
     Cancelled,
 }
 
+/// Outcome of trying to pay an invoice; only `Paid` moved any money.
+#[derive(Debug)]
+pub enum InvoicePayment {
+    Paid(Transaction),
+    AlreadyPaid,
+    NotPayable(InvoiceStatus),
+    NotFound,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateInvoiceRequest {
-    pub amount: f64,
-    pub description: String,
+    pub amount: Option<Money>, // Required without line items; must match their total with them
+    pub description: Option<String>, // Defaults to the item names when there are line items
     pub customer_name: Option<String>,
     pub expires_in_hours: Option<i32>, // Default 24 hours
+    #[serde(default)]
+    pub line_items: Vec<InvoiceLineItem>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    pub success_url: Option<String>, // Must be on the business website_url's domain
+    pub cancel_url: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct CreateInvoiceResponse {
     pub invoice_id: Uuid,
     pub payment_url: String,
-    pub amount: f64,
+    pub amount: Money,
     pub description: String,
     pub status: InvoiceStatus,
     pub expires_at: Option<DateTime<Utc>>,
+    pub line_items: Vec<InvoiceLineItem>,
+    pub metadata: BTreeMap<String, String>,
+    pub success_url: Option<String>,
+    pub cancel_url: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
-pub struct CreateCodeResponse {
-    pub code: String,
-    pub amount: f64,
-    pub max_uses: Option<i32>,
-    pub expires_at: Option<DateTime<Utc>>,
+/// Query string of `/api/invoice/list`. Every filter is optional.
+#[derive(Debug, Deserialize)]
+pub struct InvoiceListQuery {
+    pub status: Option<InvoiceStatus>,
+    pub created_from: Option<DateTime<Utc>>,
+    pub created_to: Option<DateTime<Utc>>,
+    pub customer_name: Option<String>, // Case-insensitive substring match
+    pub min_amount: Option<Money>,
+    pub max_amount: Option<Money>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
 }
 
-#[derive(Debug, Serialize)]
-pub struct WalletInfo {
-    pub balance: f64,
-    pub address: String,
-    pub total_sent: f64,
-    pub total_received: f64,
+#[derive(Debug, Deserialize)]
+pub struct RefundRequest {
+    pub transaction_id: Uuid,
+    pub amount: Option<Money>, // Defaults to everything not yet refunded
+    pub reason: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
-pub struct TransactionSummary {
-    pub id: Uuid,
-    pub transaction_type: TransactionType,
-    pub amount: f64,
+#[derive(Debug, Deserialize)]
+pub struct CaptureRequest {
+    pub authorization_id: Uuid,
+    pub amount: Option<Money>, // Defaults to the full authorized amount
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VoidRequest {
+    pub authorization_id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreatePlanRequest {
+    pub name: String,
+    pub amount: Money,
+    pub interval: String, // "day", "week", "month" or "year"
+    pub interval_count: Option<i32>, // Default 1
+    pub trial_days: Option<i32>, // Default 0
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeactivatePlanRequest {
+    pub plan_id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubscribeRequest {
+    pub plan_id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CancelSubscriptionRequest {
+    pub subscription_id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreatePaymentLinkRequest {
     pub description: String,
-    pub status: TransactionStatus,
-    pub created_at: DateTime<Utc>,
-    pub other_party: Option<String>, // username or business name
+    pub amount: Option<Money>, // Leave out to let each customer choose the amount
+    pub max_uses: Option<i32>, // Paid invoices allowed; unlimited by default
+    pub expires_in_hours: Option<i32>, // Never expires by default
 }
 
-// ArsonFlare OAuth response
 #[derive(Debug, Deserialize)]
-pub struct ArsonFlareUser {
-    pub user_id: String,
-    pub username: String,
+pub struct DeactivatePaymentLinkRequest {
+    pub link_id: Uuid,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct UserSession {
-    pub id: Uuid,
-    pub user_id: Uuid,
-    pub session_token: String,
-    pub created_at: DateTime<Utc>,
-    pub expires_at: DateTime<Utc>,
+#[derive(Debug, Default, Deserialize)]
+pub struct OpenPaymentLinkRequest {
+    pub amount: Option<Money>, // Required when the link has no fixed amount
+    pub customer_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<String>, // Empty subscribes to every event
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteWebhookRequest {
+    pub endpoint_id: Uuid,
 }
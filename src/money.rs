@@ -0,0 +1,275 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// An amount of GC held as integer minor units (centi-GC), so ledger math
+/// never goes through floating point.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(i64);
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum MoneyError {
+    #[error("invalid amount: {0}")]
+    InvalidFormat(String),
+    #[error("amounts support at most {} decimal places", Money::DECIMALS)]
+    TooPrecise,
+    #[error("amount out of range")]
+    Overflow,
+}
+
+impl Money {
+    pub const DECIMALS: u32 = 2;
+    pub const MINOR_PER_MAJOR: i64 = 10i64.pow(Self::DECIMALS);
+    pub const ZERO: Money = Money(0);
+
+    pub const fn from_minor(minor: i64) -> Self {
+        Money(minor)
+    }
+
+    /// Whole GC. Only meant for constants; panics on overflow.
+    pub const fn from_major(major: i64) -> Self {
+        Money(major * Self::MINOR_PER_MAJOR)
+    }
+
+    pub const fn minor(self) -> i64 {
+        self.0
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        self.0.checked_add(other.0).map(Money)
+    }
+
+    pub fn checked_sub(self, other: Money) -> Option<Money> {
+        self.0.checked_sub(other.0).map(Money)
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        let scale = Self::MINOR_PER_MAJOR as u64;
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            abs / scale,
+            abs % scale,
+            width = Self::DECIMALS as usize
+        )
+    }
+}
+
+impl FromStr for Money {
+    type Err = MoneyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let (negative, digits) = match trimmed.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, trimmed),
+        };
+
+        let (whole, frac) = match digits.split_once('.') {
+            Some((whole, frac)) => (whole, frac),
+            None => (digits, ""),
+        };
+
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if (whole.is_empty() && frac.is_empty()) || !is_digits(whole) || !is_digits(frac)
+            || (digits.contains('.') && frac.is_empty())
+        {
+            return Err(MoneyError::InvalidFormat(s.to_string()));
+        }
+
+        let frac = frac.trim_end_matches('0');
+        if frac.len() > Self::DECIMALS as usize {
+            return Err(MoneyError::TooPrecise);
+        }
+
+        let whole: i64 = if whole.is_empty() {
+            0
+        } else {
+            whole.parse().map_err(|_| MoneyError::Overflow)?
+        };
+        let frac_minor: i64 = if frac.is_empty() {
+            0
+        } else {
+            let padded = format!("{:0<width$}", frac, width = Self::DECIMALS as usize);
+            padded.parse().map_err(|_| MoneyError::InvalidFormat(s.to_string()))?
+        };
+
+        let minor = whole
+            .checked_mul(Self::MINOR_PER_MAJOR)
+            .and_then(|m| m.checked_add(frac_minor))
+            .ok_or(MoneyError::Overflow)?;
+
+        Ok(Money(if negative { -minor } else { minor }))
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(MoneyVisitor)
+    }
+}
+
+struct MoneyVisitor;
+
+impl de::Visitor<'_> for MoneyVisitor {
+    type Value = Money;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a decimal amount with at most {} decimal places", Money::DECIMALS)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Money, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Money, E> {
+        v.checked_mul(Money::MINOR_PER_MAJOR)
+            .map(Money)
+            .ok_or_else(|| E::custom(MoneyError::Overflow))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Money, E> {
+        i64::try_from(v)
+            .map_err(|_| E::custom(MoneyError::Overflow))
+            .and_then(|v| self.visit_i64(v))
+    }
+
+    // JSON clients (the Lua frontend included) still send bare numbers. Going
+    // through the shortest round-trip representation keeps 0.1 as exactly 10
+    // minor units instead of whatever the nearest double happens to be.
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Money, E> {
+        if !v.is_finite() {
+            return Err(E::custom(MoneyError::InvalidFormat(v.to_string())));
+        }
+        v.to_string().parse().map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Money, MoneyError> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_decimal_strings() {
+        assert_eq!(parse("12.34"), Ok(Money::from_minor(1234)));
+        assert_eq!(parse("5"), Ok(Money::from_minor(500)));
+        assert_eq!(parse("0.1"), Ok(Money::from_minor(10)));
+        assert_eq!(parse(".5"), Ok(Money::from_minor(50)));
+        assert_eq!(parse(" 7.00 "), Ok(Money::from_minor(700)));
+        assert_eq!(parse("1.230"), Ok(Money::from_minor(123)));
+    }
+
+    #[test]
+    fn parses_negative_amounts() {
+        assert_eq!(parse("-1.50"), Ok(Money::from_minor(-150)));
+        assert_eq!(parse("-0.01"), Ok(Money::from_minor(-1)));
+        assert!(parse("-3").unwrap().is_negative());
+        assert!(!parse("-3").unwrap().is_positive());
+        assert_eq!(parse("--1"), Err(MoneyError::InvalidFormat("--1".to_string())));
+    }
+
+    #[test]
+    fn rejects_malformed_strings() {
+        for input in ["", ".", "1.", "abc", "1.2.3", "+1", "1e3", "1,00", "- 1"] {
+            assert_eq!(parse(input), Err(MoneyError::InvalidFormat(input.to_string())), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_more_than_two_decimal_places() {
+        assert_eq!(parse("0.001"), Err(MoneyError::TooPrecise));
+        assert_eq!(parse("19.999"), Err(MoneyError::TooPrecise));
+        assert_eq!(parse("-0.005"), Err(MoneyError::TooPrecise));
+    }
+
+    #[test]
+    fn rejects_overflow() {
+        assert_eq!(parse("92233720368547758.07"), Ok(Money::from_minor(i64::MAX)));
+        assert_eq!(parse("92233720368547758.08"), Err(MoneyError::Overflow));
+        assert_eq!(parse("99999999999999999999"), Err(MoneyError::Overflow));
+        assert_eq!(Money::from_minor(i64::MAX).checked_add(Money::from_minor(1)), None);
+        assert_eq!(Money::from_minor(i64::MIN).checked_sub(Money::from_minor(1)), None);
+    }
+
+    #[test]
+    fn checked_sub_can_go_negative() {
+        let left = Money::from_minor(500).checked_sub(Money::from_minor(750)).unwrap();
+        assert_eq!(left, Money::from_minor(-250));
+        assert!(left.is_negative());
+    }
+
+    #[test]
+    fn displays_two_decimal_places() {
+        assert_eq!(Money::from_minor(1234).to_string(), "12.34");
+        assert_eq!(Money::from_minor(5).to_string(), "0.05");
+        assert_eq!(Money::ZERO.to_string(), "0.00");
+        assert_eq!(Money::from_minor(-150).to_string(), "-1.50");
+        assert_eq!(Money::from_minor(-5).to_string(), "-0.05");
+        assert_eq!(Money::from_minor(i64::MIN).to_string(), "-92233720368547758.08");
+    }
+
+    #[test]
+    fn display_round_trips_through_from_str() {
+        for minor in [0, 1, 99, 100, 123_456_789, -42, i64::MAX, i64::MIN + 1] {
+            let money = Money::from_minor(minor);
+            assert_eq!(parse(&money.to_string()), Ok(money), "{}", minor);
+        }
+    }
+
+    #[test]
+    fn serializes_as_a_decimal_string() {
+        assert_eq!(serde_json::to_string(&Money::from_minor(1999)).unwrap(), "\"19.99\"");
+        for minor in [0, 1, 1999, -250, i64::MAX] {
+            let money = Money::from_minor(minor);
+            let json = serde_json::to_string(&money).unwrap();
+            assert_eq!(serde_json::from_str::<Money>(&json).unwrap(), money);
+        }
+    }
+
+    #[test]
+    fn deserializes_strings_and_integers() {
+        assert_eq!(serde_json::from_str::<Money>("\"12.50\"").unwrap(), Money::from_minor(1250));
+        assert_eq!(serde_json::from_str::<Money>("12").unwrap(), Money::from_minor(1200));
+        assert_eq!(serde_json::from_str::<Money>("-3").unwrap(), Money::from_minor(-300));
+        assert!(serde_json::from_str::<Money>("\"1.005\"").is_err());
+        assert!(serde_json::from_str::<Money>("92233720368547759").is_err());
+        assert!(serde_json::from_str::<Money>("18446744073709551615").is_err());
+        assert!(serde_json::from_str::<Money>("null").is_err());
+    }
+
+    #[test]
+    fn deserializes_floats_by_their_shortest_representation() {
+        assert_eq!(serde_json::from_str::<Money>("0.1").unwrap(), Money::from_minor(10));
+        assert_eq!(serde_json::from_str::<Money>("19.99").unwrap(), Money::from_minor(1999));
+        assert_eq!(serde_json::from_str::<Money>("1.1").unwrap(), Money::from_minor(110));
+        // 0.1 + 0.2 in binary floating point is 0.30000000000000004
+        let sum = serde_json::to_string(&(0.1f64 + 0.2f64)).unwrap();
+        assert_eq!(sum, "0.30000000000000004");
+        let err = serde_json::from_str::<Money>(&sum).unwrap_err();
+        assert!(err.to_string().starts_with(&MoneyError::TooPrecise.to_string()), "{}", err);
+        assert!(serde_json::from_str::<Money>("1.005").is_err());
+    }
+}
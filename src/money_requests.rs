@@ -0,0 +1,380 @@
+use crate::database::record_transfer;
+use crate::error::{ApiError, ApiResult};
+use crate::models::Transaction;
+use crate::money::Money;
+use chrono::{DateTime, Utc};
+use gurtlib::{GurtError, Result};
+use serde::Serialize;
+use sqlx::{AnyPool, Row};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// A request nobody answered is expired this long after it was made.
+pub const EXPIRY_DAYS: i64 = 7;
+const LIST_LIMIT: i64 = 50;
+const SWEEP_INTERVAL_SECS: u64 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestStatus {
+    Pending,
+    Paid,
+    Declined,
+    Cancelled,
+    Expired,
+}
+
+impl RequestStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RequestStatus::Pending => "pending",
+            RequestStatus::Paid => "paid",
+            RequestStatus::Declined => "declined",
+            RequestStatus::Cancelled => "cancelled",
+            RequestStatus::Expired => "expired",
+        }
+    }
+
+    fn parse(s: &str) -> Option<RequestStatus> {
+        match s {
+            "pending" => Some(RequestStatus::Pending),
+            "paid" => Some(RequestStatus::Paid),
+            "declined" => Some(RequestStatus::Declined),
+            "cancelled" => Some(RequestStatus::Cancelled),
+            "expired" => Some(RequestStatus::Expired),
+            _ => None,
+        }
+    }
+}
+
+/// One user asking another for money. `from_*` is who is asked to pay and
+/// `to_*` is who asked and would be paid.
+#[derive(Debug, Clone, Serialize)]
+pub struct MoneyRequest {
+    pub id: Uuid,
+    pub from_user_id: Uuid,
+    pub from_username: String,
+    pub from_address: String,
+    pub to_user_id: Uuid,
+    pub to_username: String,
+    pub to_address: String,
+    pub amount: Money,
+    pub description: String,
+    pub status: RequestStatus,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub responded_at: Option<DateTime<Utc>>,
+    pub transaction_id: Option<Uuid>,
+}
+
+pub enum Direction {
+    /// Requests the user has been asked to pay.
+    Incoming,
+    /// Requests the user has made of others.
+    Outgoing,
+}
+
+pub async fn list(pool: &AnyPool, user_id: Uuid, direction: Direction) -> Result<Vec<MoneyRequest>> {
+    let column = match direction {
+        Direction::Incoming => "from_user_id",
+        Direction::Outgoing => "to_user_id",
+    };
+    let rows = sqlx::query(&format!("{} WHERE r.{} = $1 ORDER BY r.created_at DESC LIMIT $2", SELECT_REQUEST, column))
+        .bind(user_id.to_string())
+        .bind(LIST_LIMIT)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to list money requests: {}", e)))?;
+    rows.iter().map(request_from_row).collect()
+}
+
+/// A request the user has been asked to pay.
+pub async fn get_incoming(pool: &AnyPool, user_id: Uuid, request_id: Uuid) -> ApiResult<MoneyRequest> {
+    get_for(pool, "from_user_id", user_id, request_id).await
+}
+
+/// Pays a pending request from the payer's wallet. The status flip and the
+/// transfer share one database transaction, so a request is never marked paid
+/// without the money moving, and insufficient funds leaves it pending.
+pub async fn pay(pool: &AnyPool, user_id: Uuid, request_id: Uuid) -> ApiResult<(MoneyRequest, Transaction)> {
+    let request = get_for(pool, "from_user_id", user_id, request_id).await?;
+    let transaction_id = Uuid::new_v4();
+    let now = Utc::now();
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    let claimed = sqlx::query(
+        "UPDATE money_requests SET status = 'paid', responded_at = $1, transaction_id = $2 \
+         WHERE id = $3 AND from_user_id = $4 AND status = 'pending' AND created_at > $5"
+    )
+    .bind(now.to_rfc3339())
+    .bind(transaction_id.to_string())
+    .bind(request_id.to_string())
+    .bind(user_id.to_string())
+    .bind(expiry_cutoff(now).to_rfc3339())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to claim money request: {}", e)))?;
+    if claimed.rows_affected() == 0 {
+        drop(tx);
+        return Err(not_pending(pool, &request).await);
+    }
+
+    let description = format!("Money request: {}", request.description);
+    let transaction = record_transfer(&mut tx, transaction_id, &user_id, &request.to_user_id, request.amount, &description).await?;
+
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit money request payment: {}", e)))?;
+
+    Ok((MoneyRequest {
+        status: RequestStatus::Paid,
+        responded_at: Some(now),
+        transaction_id: Some(transaction_id),
+        ..request
+    }, transaction))
+}
+
+/// The payer turns the request down.
+pub async fn decline(pool: &AnyPool, user_id: Uuid, request_id: Uuid) -> ApiResult<MoneyRequest> {
+    close(pool, "from_user_id", user_id, request_id, RequestStatus::Declined).await
+}
+
+/// The requester withdraws the request.
+pub async fn cancel(pool: &AnyPool, user_id: Uuid, request_id: Uuid) -> ApiResult<MoneyRequest> {
+    close(pool, "to_user_id", user_id, request_id, RequestStatus::Cancelled).await
+}
+
+/// Marks pending requests older than `EXPIRY_DAYS` as expired. Returns how
+/// many were expired.
+pub async fn expire_due(pool: &AnyPool) -> Result<u64> {
+    let now = Utc::now();
+    let result = sqlx::query("UPDATE money_requests SET status = 'expired', responded_at = $1 WHERE status = 'pending' AND created_at <= $2")
+        .bind(now.to_rfc3339())
+        .bind(expiry_cutoff(now).to_rfc3339())
+        .execute(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to expire money requests: {}", e)))?;
+    Ok(result.rows_affected())
+}
+
+/// Starts the background task that expires unanswered requests.
+pub fn spawn_expiry_sweeper(pool: AnyPool) {
+    tokio::spawn(async move {
+        loop {
+            match expire_due(&pool).await {
+                Ok(0) => {}
+                Ok(count) => tracing::info!("Expired {} money requests", count),
+                Err(e) => tracing::warn!("Money request expiry sweep failed: {}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(SWEEP_INTERVAL_SECS)).await;
+        }
+    });
+}
+
+async fn close(pool: &AnyPool, owner_column: &str, user_id: Uuid, request_id: Uuid, status: RequestStatus) -> ApiResult<MoneyRequest> {
+    let request = get_for(pool, owner_column, user_id, request_id).await?;
+    let now = Utc::now();
+
+    let closed = sqlx::query(&format!(
+        "UPDATE money_requests SET status = $1, responded_at = $2 WHERE id = $3 AND {} = $4 AND status = 'pending' AND created_at > $5",
+        owner_column
+    ))
+    .bind(status.as_str())
+    .bind(now.to_rfc3339())
+    .bind(request_id.to_string())
+    .bind(user_id.to_string())
+    .bind(expiry_cutoff(now).to_rfc3339())
+    .execute(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to update money request: {}", e)))?;
+    if closed.rows_affected() == 0 {
+        return Err(not_pending(pool, &request).await);
+    }
+
+    Ok(MoneyRequest { status, responded_at: Some(now), ..request })
+}
+
+/// Loads a request the user is on the given side of; anyone else gets 404.
+async fn get_for(pool: &AnyPool, owner_column: &str, user_id: Uuid, request_id: Uuid) -> ApiResult<MoneyRequest> {
+    let row = sqlx::query(&format!("{} WHERE r.id = $1 AND r.{} = $2", SELECT_REQUEST, owner_column))
+        .bind(request_id.to_string())
+        .bind(user_id.to_string())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to get money request: {}", e)))?
+        .ok_or_else(|| ApiError::not_found("Money request not found"))?;
+    Ok(request_from_row(&row)?)
+}
+
+/// Explains why a request could not be answered, expiring it first if it was
+/// only still pending because the sweeper hasn't reached it.
+async fn not_pending(pool: &AnyPool, request: &MoneyRequest) -> ApiError {
+    let status = sqlx::query("SELECT COALESCE(status, 'pending') AS status FROM money_requests WHERE id = $1")
+        .bind(request.id.to_string())
+        .fetch_one(pool)
+        .await
+        .map(|row| row.get::<String, _>("status"));
+    let status = match status {
+        Ok(status) => RequestStatus::parse(&status),
+        Err(e) => return ApiError::internal(format!("Failed to get money request: {}", e)),
+    };
+    match status.unwrap_or(RequestStatus::Pending) {
+        RequestStatus::Pending => {
+            if let Err(e) = expire_due(pool).await {
+                return e.into();
+            }
+            ApiError::conflict("Money request has expired")
+        }
+        RequestStatus::Paid => ApiError::conflict("Money request is already paid"),
+        RequestStatus::Declined => ApiError::conflict("Money request was declined"),
+        RequestStatus::Cancelled => ApiError::conflict("Money request was cancelled"),
+        RequestStatus::Expired => ApiError::conflict("Money request has expired"),
+    }
+}
+
+fn expiry_cutoff(now: DateTime<Utc>) -> DateTime<Utc> {
+    now - chrono::Duration::days(EXPIRY_DAYS)
+}
+
+const SELECT_REQUEST: &str =
+    "SELECT r.id, r.from_user_id, r.to_user_id, r.amount, r.description, COALESCE(r.status, 'pending') AS status, \
+            r.created_at, r.responded_at, r.transaction_id, \
+            f.username AS from_username, f.wallet_address AS from_address, \
+            t.username AS to_username, t.wallet_address AS to_address \
+     FROM money_requests r \
+     JOIN users f ON f.id = r.from_user_id \
+     JOIN users t ON t.id = r.to_user_id";
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| GurtError::invalid_message(format!("Invalid timestamp {}: {}", value, e)))
+}
+
+fn request_from_row(row: &sqlx::any::AnyRow) -> Result<MoneyRequest> {
+    let parse_uuid = |column: &str| {
+        Uuid::parse_str(&row.get::<String, _>(column))
+            .map_err(|e| GurtError::invalid_message(format!("Invalid {}: {}", column, e)))
+    };
+    let status = row.get::<String, _>("status");
+    let created_at = parse_time(&row.get::<String, _>("created_at"))?;
+    Ok(MoneyRequest {
+        id: parse_uuid("id")?,
+        from_user_id: parse_uuid("from_user_id")?,
+        from_username: row.get("from_username"),
+        from_address: row.get("from_address"),
+        to_user_id: parse_uuid("to_user_id")?,
+        to_username: row.get("to_username"),
+        to_address: row.get("to_address"),
+        amount: Money::from_minor(row.get("amount")),
+        description: row.get("description"),
+        status: RequestStatus::parse(&status)
+            .ok_or_else(|| GurtError::invalid_message(format!("Unknown money request status: {}", status)))?,
+        created_at,
+        expires_at: created_at + chrono::Duration::days(EXPIRY_DAYS),
+        responded_at: row.get::<Option<String>, _>("responded_at").as_deref().map(parse_time).transpose()?,
+        transaction_id: row.get::<Option<String>, _>("transaction_id").and_then(|s| Uuid::parse_str(&s).ok()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{create_user_with_password, get_user_by_id, test_pool, WELCOME_BONUS};
+
+    async fn user(pool: &AnyPool) -> Uuid {
+        create_user_with_password(pool, &format!("requests-{}", Uuid::new_v4()), "unused").await.unwrap().id
+    }
+
+    /// `requester` asking `payer` for `amount`, made at `created_at`.
+    async fn request(pool: &AnyPool, payer: Uuid, requester: Uuid, amount: Money, created_at: DateTime<Utc>) -> Uuid {
+        let id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO money_requests (id, from_user_id, to_user_id, amount, description, status, created_at) \
+             VALUES ($1, $2, $3, $4, 'Lunch', 'pending', $5)"
+        )
+        .bind(id.to_string())
+        .bind(payer.to_string())
+        .bind(requester.to_string())
+        .bind(amount.minor())
+        .bind(created_at.to_rfc3339())
+        .execute(pool)
+        .await
+        .unwrap();
+        id
+    }
+
+    async fn status(pool: &AnyPool, payer: Uuid) -> RequestStatus {
+        list(pool, payer, Direction::Incoming).await.unwrap().remove(0).status
+    }
+
+    async fn wallet(pool: &AnyPool, user_id: Uuid) -> Money {
+        get_user_by_id(pool, user_id).await.unwrap().unwrap().wallet_balance
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn paying_racing_a_decline_answers_once() {
+        let pool = test_pool().await;
+        let (payer, requester) = (user(&pool).await, user(&pool).await);
+        let id = request(&pool, payer, requester, Money::from_major(12), Utc::now()).await;
+
+        let (paid, declined) = tokio::join!(
+            tokio::spawn({
+                let pool = pool.clone();
+                async move { pay(&pool, payer, id).await }
+            }),
+            tokio::spawn({
+                let pool = pool.clone();
+                async move { decline(&pool, payer, id).await }
+            }),
+        );
+        let (paid, declined) = (paid.unwrap(), declined.unwrap());
+
+        assert_ne!(paid.is_ok(), declined.is_ok());
+        assert!(matches!(paid, Ok(_) | Err(ApiError::Conflict(_))));
+        assert!(matches!(declined, Ok(_) | Err(ApiError::Conflict(_))));
+        let moved = if paid.is_ok() { Money::from_major(12) } else { Money::ZERO };
+        assert_eq!(wallet(&pool, payer).await, WELCOME_BONUS.checked_sub(moved).unwrap());
+        assert_eq!(wallet(&pool, requester).await, WELCOME_BONUS.checked_add(moved).unwrap());
+        let expected = if paid.is_ok() { RequestStatus::Paid } else { RequestStatus::Declined };
+        assert_eq!(status(&pool, payer).await, expected);
+    }
+
+    #[tokio::test]
+    async fn insufficient_funds_leaves_the_request_pending() {
+        let pool = test_pool().await;
+        let (payer, requester) = (user(&pool).await, user(&pool).await);
+        let id = request(&pool, payer, requester, WELCOME_BONUS.checked_add(Money::from_minor(1)).unwrap(), Utc::now()).await;
+
+        assert!(matches!(pay(&pool, payer, id).await, Err(ApiError::InsufficientFunds(_))));
+        assert_eq!(status(&pool, payer).await, RequestStatus::Pending);
+        assert_eq!(wallet(&pool, payer).await, WELCOME_BONUS);
+        assert!(decline(&pool, payer, id).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn expired_requests_cant_be_paid() {
+        let pool = test_pool().await;
+        let (payer, requester) = (user(&pool).await, user(&pool).await);
+        let made = Utc::now() - chrono::Duration::days(EXPIRY_DAYS) - chrono::Duration::minutes(1);
+        let id = request(&pool, payer, requester, Money::from_major(12), made).await;
+
+        let result = pay(&pool, payer, id).await;
+
+        assert!(matches!(result, Err(ApiError::Conflict(message)) if message == "Money request has expired"));
+        assert_eq!(status(&pool, payer).await, RequestStatus::Expired);
+        assert_eq!(wallet(&pool, payer).await, WELCOME_BONUS);
+    }
+
+    #[tokio::test]
+    async fn only_the_payer_can_pay() {
+        let pool = test_pool().await;
+        let (payer, requester) = (user(&pool).await, user(&pool).await);
+        let id = request(&pool, payer, requester, Money::from_major(12), Utc::now()).await;
+
+        assert!(matches!(pay(&pool, requester, id).await, Err(ApiError::NotFound(_))));
+        assert!(matches!(decline(&pool, requester, id).await, Err(ApiError::NotFound(_))));
+        assert_eq!(status(&pool, payer).await, RequestStatus::Pending);
+    }
+}
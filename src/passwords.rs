@@ -0,0 +1,265 @@
+use crate::auth::{hash_password, revoke_sessions_on};
+use crate::database::{record_audit, set_user_password_hash};
+use crate::error::{ApiError, ApiResult};
+use chrono::{DateTime, Utc};
+use gurtlib::{GurtError, Result};
+use rand::Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{AnyPool, Row};
+use uuid::Uuid;
+
+const MIN_LENGTH: usize = 6;
+/// Long enough for an admin to pass the token on by hand.
+const RESET_TOKEN_HOURS: i64 = 24;
+
+/// A one-time token an admin hands to a user who can't sign in.
+#[derive(Debug, Serialize)]
+pub struct ResetToken {
+    pub reset_token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub fn validate(password: &str) -> ApiResult<()> {
+    if password.len() < MIN_LENGTH {
+        return Err(ApiError::validation(format!("Password must be at least {} characters", MIN_LENGTH)));
+    }
+    Ok(())
+}
+
+/// Replaces the password of a user who proved they know the current one, and
+/// logs out every session but the one making the change. Returns how many
+/// sessions were revoked.
+pub async fn change(pool: &AnyPool, user_id: Uuid, current_session_id: &str, new_password: &str) -> ApiResult<u64> {
+    validate(new_password)?;
+    let password_hash = hash_password(new_password)?;
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    set_user_password_hash(&mut tx, &user_id, &password_hash).await?;
+    let revoked = revoke_sessions_on(&mut tx, user_id, Some(current_session_id)).await?;
+    record_audit(&mut tx, Some(user_id), "password_changed", &serde_json::json!({
+        "sessions_revoked": revoked,
+    })).await?;
+
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit password change: {}", e)))?;
+    Ok(revoked)
+}
+
+/// Issues a reset token for the user, replacing any earlier one they haven't
+/// used. Only the hash is stored, so the token is shown this once.
+pub async fn issue_reset_token(pool: &AnyPool, actor: Uuid, user_id: Uuid) -> Result<ResetToken> {
+    let now = Utc::now();
+    let reset_token = format!("pr_{}", random_string(40));
+    let expires_at = now + chrono::Duration::hours(RESET_TOKEN_HOURS);
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    sqlx::query("DELETE FROM password_resets WHERE user_id = $1 AND used_at IS NULL")
+        .bind(user_id.to_string())
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to clear reset tokens: {}", e)))?;
+
+    sqlx::query(
+        "INSERT INTO password_resets (id, user_id, token_hash, issued_by, expires_at, created_at) VALUES ($1, $2, $3, $4, $5, $6)"
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(user_id.to_string())
+    .bind(hash_token(&reset_token))
+    .bind(actor.to_string())
+    .bind(expires_at.to_rfc3339())
+    .bind(now.to_rfc3339())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to create reset token: {}", e)))?;
+
+    record_audit(&mut tx, Some(actor), "password_reset_issued", &serde_json::json!({
+        "user_id": user_id,
+        "expires_at": expires_at.to_rfc3339(),
+    })).await?;
+
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit reset token: {}", e)))?;
+    Ok(ResetToken { reset_token, expires_at })
+}
+
+/// Uses up a reset token to set a new password, and logs out all of the user's
+/// sessions. Two-factor stays on, so it is still needed to sign in. Returns
+/// the user whose password was reset.
+pub async fn reset(pool: &AnyPool, reset_token: &str, new_password: &str) -> ApiResult<Uuid> {
+    validate(new_password)?;
+    let password_hash = hash_password(new_password)?;
+    let now = Utc::now();
+    let token_hash = hash_token(reset_token);
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    let row = sqlx::query("SELECT id, user_id FROM password_resets WHERE token_hash = $1 AND used_at IS NULL AND expires_at > $2")
+        .bind(&token_hash)
+        .bind(now.to_rfc3339())
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to get reset token: {}", e)))?
+        .ok_or_else(|| ApiError::unauthorized("Reset token is invalid, used or expired"))?;
+    let reset_id: String = row.get("id");
+    let user_id = Uuid::parse_str(&row.get::<String, _>("user_id"))
+        .map_err(|e| GurtError::invalid_message(format!("Invalid user_id: {}", e)))?;
+
+    // Only one of two concurrent uses gets to mark it
+    let used = sqlx::query("UPDATE password_resets SET used_at = $1 WHERE id = $2 AND used_at IS NULL")
+        .bind(now.to_rfc3339())
+        .bind(&reset_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to use reset token: {}", e)))?;
+    if used.rows_affected() == 0 {
+        return Err(ApiError::unauthorized("Reset token is invalid, used or expired"));
+    }
+
+    set_user_password_hash(&mut tx, &user_id, &password_hash).await?;
+    let revoked = revoke_sessions_on(&mut tx, user_id, None).await?;
+    record_audit(&mut tx, Some(user_id), "password_reset", &serde_json::json!({
+        "reset_id": reset_id,
+        "sessions_revoked": revoked,
+    })).await?;
+
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit password reset: {}", e)))?;
+    Ok(user_id)
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::verify_password;
+    use crate::database::{create_user_with_password, get_password_hash, test_pool};
+
+    /// Opens a session row for the user without signing a token for it.
+    async fn open_session(pool: &AnyPool, user_id: Uuid) -> String {
+        let session_id = Uuid::new_v4().to_string();
+        let now = Utc::now();
+        sqlx::query(
+            "INSERT INTO user_sessions (id, user_id, jwt_token, refresh_token_hash, created_at, expires_at, last_used_at, active) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, TRUE)"
+        )
+        .bind(&session_id)
+        .bind(user_id.to_string())
+        .bind(format!("jwt-{}", session_id))
+        .bind(hash_token(&session_id))
+        .bind(now.to_rfc3339())
+        .bind((now + chrono::Duration::days(1)).to_rfc3339())
+        .bind(now.to_rfc3339())
+        .execute(pool)
+        .await
+        .unwrap();
+        session_id
+    }
+
+    async fn is_active(pool: &AnyPool, session_id: &str) -> bool {
+        sqlx::query("SELECT CASE WHEN active THEN 1 ELSE 0 END AS active FROM user_sessions WHERE id = $1")
+            .bind(session_id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+            .get::<i32, _>("active") == 1
+    }
+
+    async fn has_password(pool: &AnyPool, user_id: Uuid, password: &str) -> bool {
+        let stored = get_password_hash(pool, &user_id).await.unwrap().unwrap();
+        verify_password(password, &stored).unwrap()
+    }
+
+    async fn user(pool: &AnyPool) -> Uuid {
+        let password_hash = hash_password("original").unwrap();
+        create_user_with_password(pool, &format!("pw-{}", Uuid::new_v4()), &password_hash).await.unwrap().id
+    }
+
+    #[test]
+    fn validate_wants_a_minimum_length() {
+        assert!(matches!(validate("12345"), Err(ApiError::Validation(_))));
+        validate("123456").unwrap();
+    }
+
+    #[tokio::test]
+    async fn change_logs_out_the_other_sessions() {
+        let pool = test_pool().await;
+        let user_id = user(&pool).await;
+        let current = open_session(&pool, user_id).await;
+        let others = [open_session(&pool, user_id).await, open_session(&pool, user_id).await];
+        let bystander = open_session(&pool, user(&pool).await).await;
+
+        assert!(matches!(change(&pool, user_id, &current, "short").await, Err(ApiError::Validation(_))));
+        assert!(has_password(&pool, user_id, "original").await);
+
+        assert_eq!(change(&pool, user_id, &current, "replacement").await.unwrap(), 2);
+        assert!(has_password(&pool, user_id, "replacement").await);
+        assert!(is_active(&pool, &current).await);
+        for session_id in &others {
+            assert!(!is_active(&pool, session_id).await);
+        }
+        assert!(is_active(&pool, &bystander).await);
+    }
+
+    #[tokio::test]
+    async fn reset_tokens_work_once() {
+        let pool = test_pool().await;
+        let user_id = user(&pool).await;
+        let session = open_session(&pool, user_id).await;
+        let token = issue_reset_token(&pool, user_id, user_id).await.unwrap();
+
+        assert_eq!(reset(&pool, &token.reset_token, "replacement").await.unwrap(), user_id);
+        assert!(has_password(&pool, user_id, "replacement").await);
+        assert!(!is_active(&pool, &session).await);
+
+        let again = reset(&pool, &token.reset_token, "another one").await;
+        assert!(matches!(again, Err(ApiError::Unauthorized(_))));
+        assert!(has_password(&pool, user_id, "replacement").await);
+    }
+
+    #[tokio::test]
+    async fn reset_tokens_expire() {
+        let pool = test_pool().await;
+        let user_id = user(&pool).await;
+        let token = issue_reset_token(&pool, user_id, user_id).await.unwrap();
+        sqlx::query("UPDATE password_resets SET expires_at = $1 WHERE user_id = $2")
+            .bind((Utc::now() - chrono::Duration::seconds(1)).to_rfc3339())
+            .bind(user_id.to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let expired = reset(&pool, &token.reset_token, "replacement").await;
+        assert!(matches!(expired, Err(ApiError::Unauthorized(_))));
+        assert!(has_password(&pool, user_id, "original").await);
+    }
+
+    #[tokio::test]
+    async fn a_new_reset_token_replaces_the_old_one() {
+        let pool = test_pool().await;
+        let user_id = user(&pool).await;
+        let first = issue_reset_token(&pool, user_id, user_id).await.unwrap();
+        let second = issue_reset_token(&pool, user_id, user_id).await.unwrap();
+
+        let replaced = reset(&pool, &first.reset_token, "replacement").await;
+        assert!(matches!(replaced, Err(ApiError::Unauthorized(_))));
+        reset(&pool, &second.reset_token, "replacement").await.unwrap();
+        assert!(has_password(&pool, user_id, "replacement").await);
+    }
+}
@@ -0,0 +1,286 @@
+use crate::database::create_invoice;
+use crate::error::{ApiError, ApiResult};
+use crate::models::{CreatePaymentLinkRequest, Invoice, NewInvoice, OpenPaymentLinkRequest};
+use crate::money::Money;
+use chrono::{DateTime, Utc};
+use gurtlib::{GurtError, Result};
+use serde::Serialize;
+use sqlx::{AnyConnection, AnyPool, Row};
+use uuid::Uuid;
+
+/// How long a customer has to pay the invoice a link opens for them.
+const LINK_INVOICE_EXPIRY_HOURS: i64 = 1;
+const MAX_DESCRIPTION_LEN: usize = 500;
+const MAX_CUSTOMER_NAME_LEN: usize = 100;
+
+/// A reusable checkout a business can post anywhere. Each payer gets their own
+/// invoice; `use_count` counts the ones that were paid.
+#[derive(Debug, Clone, Serialize)]
+pub struct PaymentLink {
+    pub id: Uuid,
+    pub business_id: Uuid,
+    pub description: String,
+    /// `None` lets the customer choose how much to pay.
+    pub amount: Option<Money>,
+    pub max_uses: Option<i32>,
+    pub use_count: i32,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl PaymentLink {
+    /// Whether a new customer can still open an invoice from the link.
+    pub fn is_open(&self) -> bool {
+        self.active
+            && self.expires_at.is_none_or(|t| t > Utc::now())
+            && self.max_uses.is_none_or(|max| self.use_count < max)
+    }
+}
+
+pub async fn create(pool: &AnyPool, business_id: Uuid, request: &CreatePaymentLinkRequest) -> ApiResult<PaymentLink> {
+    let description = request.description.trim();
+    if description.is_empty() || description.len() > MAX_DESCRIPTION_LEN {
+        return Err(ApiError::validation(format!("Description must be 1 to {} characters", MAX_DESCRIPTION_LEN)));
+    }
+    if request.amount.is_some_and(|amount| !amount.is_positive()) {
+        return Err(ApiError::validation("Amount must be greater than 0"));
+    }
+    if request.max_uses.is_some_and(|max| max < 1) {
+        return Err(ApiError::validation("max_uses must be at least 1"));
+    }
+    if request.expires_in_hours.is_some_and(|hours| hours < 1) {
+        return Err(ApiError::validation("expires_in_hours must be at least 1"));
+    }
+
+    let now = Utc::now();
+    let link = PaymentLink {
+        id: Uuid::new_v4(),
+        business_id,
+        description: description.to_string(),
+        amount: request.amount,
+        max_uses: request.max_uses,
+        use_count: 0,
+        expires_at: request.expires_in_hours.map(|hours| now + chrono::Duration::hours(hours as i64)),
+        active: true,
+        created_at: now,
+    };
+
+    sqlx::query(
+        "INSERT INTO payment_links (id, business_id, description, amount, max_uses, use_count, expires_at, active, created_at) \
+         VALUES ($1, $2, $3, $4, $5, 0, $6, TRUE, $7)"
+    )
+    .bind(link.id.to_string())
+    .bind(business_id.to_string())
+    .bind(&link.description)
+    .bind(link.amount.map(|amount| amount.minor()))
+    .bind(link.max_uses)
+    .bind(link.expires_at.map(|t| t.to_rfc3339()))
+    .bind(now.to_rfc3339())
+    .execute(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to create payment link: {}", e)))?;
+
+    Ok(link)
+}
+
+pub async fn get(pool: &AnyPool, link_id: Uuid) -> Result<Option<PaymentLink>> {
+    let row = sqlx::query(&format!("{} WHERE id = $1", SELECT_LINK))
+        .bind(link_id.to_string())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to get payment link: {}", e)))?;
+    row.as_ref().map(link_from_row).transpose()
+}
+
+pub async fn list(pool: &AnyPool, business_id: Uuid) -> Result<Vec<PaymentLink>> {
+    let rows = sqlx::query(&format!("{} WHERE business_id = $1 ORDER BY created_at DESC", SELECT_LINK))
+        .bind(business_id.to_string())
+        .fetch_all(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to list payment links: {}", e)))?;
+    rows.iter().map(link_from_row).collect()
+}
+
+/// Turns the link off. Invoices already opened from it can no longer be paid.
+pub async fn deactivate(pool: &AnyPool, business_id: Uuid, link_id: Uuid) -> ApiResult<()> {
+    let result = sqlx::query("UPDATE payment_links SET active = FALSE WHERE id = $1 AND business_id = $2")
+        .bind(link_id.to_string())
+        .bind(business_id.to_string())
+        .execute(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to deactivate payment link: {}", e)))?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::not_found("Payment link not found"));
+    }
+    Ok(())
+}
+
+/// Opens a fresh invoice for one payer, which they then pay on the usual
+/// `/pay/*` page.
+pub async fn open_invoice(pool: &AnyPool, link_id: Uuid, request: OpenPaymentLinkRequest) -> ApiResult<Invoice> {
+    let link = get(pool, link_id).await?
+        .ok_or_else(|| ApiError::not_found("Payment link not found"))?;
+    if !link.is_open() {
+        return Err(ApiError::conflict("This payment link is no longer available"));
+    }
+
+    let amount = match (link.amount, request.amount) {
+        (Some(fixed), Some(chosen)) if fixed != chosen => {
+            return Err(ApiError::validation("This payment link has a fixed amount"));
+        }
+        (Some(fixed), _) => fixed,
+        (None, Some(chosen)) if chosen.is_positive() => chosen,
+        (None, Some(_)) => return Err(ApiError::validation("Amount must be greater than 0")),
+        (None, None) => return Err(ApiError::validation("Missing amount")),
+    };
+
+    let customer_name = request.customer_name
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty());
+    if customer_name.as_ref().is_some_and(|name| name.len() > MAX_CUSTOMER_NAME_LEN) {
+        return Err(ApiError::validation(format!("Customer name must be at most {} characters", MAX_CUSTOMER_NAME_LEN)));
+    }
+
+    let mut expires_at = Utc::now() + chrono::Duration::hours(LINK_INVOICE_EXPIRY_HOURS);
+    if let Some(link_expiry) = link.expires_at {
+        expires_at = expires_at.min(link_expiry);
+    }
+
+    Ok(create_invoice(pool, link.business_id, NewInvoice {
+        amount,
+        description: link.description,
+        customer_name,
+        expires_at: Some(expires_at),
+        line_items: Vec::new(),
+        metadata: Default::default(),
+        success_url: None,
+        cancel_url: None,
+        payment_link_id: Some(link.id),
+    }).await?)
+}
+
+/// Counts a paid invoice against its link, inside the payment's transaction.
+/// Fails if the link was turned off or ran out of uses since the invoice was
+/// opened, which rolls the payment back.
+pub(crate) async fn record_use(conn: &mut AnyConnection, link_id: &str) -> ApiResult<()> {
+    let result = sqlx::query(
+        "UPDATE payment_links SET use_count = use_count + 1 \
+         WHERE id = $1 AND active = TRUE AND (max_uses IS NULL OR use_count < max_uses)"
+    )
+    .bind(link_id)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to update payment link: {}", e)))?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::conflict("This payment link is no longer available"));
+    }
+    Ok(())
+}
+
+const SELECT_LINK: &str =
+    "SELECT id, business_id, description, amount, max_uses, use_count, expires_at, \
+            CASE WHEN active THEN 1 ELSE 0 END AS active, created_at \
+     FROM payment_links";
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| GurtError::invalid_message(format!("Invalid timestamp {}: {}", value, e)))
+}
+
+fn link_from_row(row: &sqlx::any::AnyRow) -> Result<PaymentLink> {
+    let parse_uuid = |column: &str| {
+        Uuid::parse_str(&row.get::<String, _>(column))
+            .map_err(|e| GurtError::invalid_message(format!("Invalid {}: {}", column, e)))
+    };
+    Ok(PaymentLink {
+        id: parse_uuid("id")?,
+        business_id: parse_uuid("business_id")?,
+        description: row.get("description"),
+        amount: row.get::<Option<i64>, _>("amount").map(Money::from_minor),
+        max_uses: row.get::<Option<i32>, _>("max_uses"),
+        use_count: row.get::<i32, _>("use_count"),
+        expires_at: row.get::<Option<String>, _>("expires_at").as_deref().map(parse_time).transpose()?,
+        active: row.get::<i64, _>("active") != 0,
+        created_at: parse_time(&row.get::<String, _>("created_at"))?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{create_user_with_password, get_business_by_id, get_invoice, get_user_by_id, pay_invoice, test_business, test_pool, WELCOME_BONUS};
+    use crate::models::{InvoicePayment, InvoiceStatus};
+
+    async fn link(pool: &AnyPool, business_id: Uuid, max_uses: Option<i32>) -> PaymentLink {
+        create(pool, business_id, &CreatePaymentLinkRequest {
+            description: "Donation".to_string(),
+            amount: Some(Money::from_major(25)),
+            max_uses,
+            expires_in_hours: None,
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn opened(pool: &AnyPool, link_id: Uuid) -> Invoice {
+        open_invoice(pool, link_id, OpenPaymentLinkRequest { amount: None, customer_name: None }).await.unwrap()
+    }
+
+    async fn payer(pool: &AnyPool) -> Uuid {
+        create_user_with_password(pool, &format!("donor-{}", Uuid::new_v4()), "unused").await.unwrap().id
+    }
+
+    #[tokio::test]
+    async fn single_use_links_take_one_payment() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let link = link(&pool, business_id, Some(1)).await;
+        let (first, second) = (opened(&pool, link.id).await, opened(&pool, link.id).await);
+        let (first_payer, second_payer) = (payer(&pool).await, payer(&pool).await);
+
+        assert!(matches!(pay_invoice(&pool, first.id, first_payer).await.unwrap(), InvoicePayment::Paid(_)));
+        let late = pay_invoice(&pool, second.id, second_payer).await;
+
+        assert!(matches!(late, Err(ApiError::Conflict(_))));
+        assert!(matches!(get_invoice(&pool, second.id).await.unwrap().unwrap().status, InvoiceStatus::Pending));
+        assert_eq!(get_user_by_id(&pool, second_payer).await.unwrap().unwrap().wallet_balance, WELCOME_BONUS);
+        assert_eq!(get_business_by_id(&pool, business_id).await.unwrap().unwrap().balance, Money::from_major(25));
+        assert_eq!(get(&pool, link.id).await.unwrap().unwrap().use_count, 1);
+        assert!(matches!(
+            open_invoice(&pool, link.id, OpenPaymentLinkRequest { amount: None, customer_name: None }).await,
+            Err(ApiError::Conflict(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn deactivating_blocks_invoices_already_opened() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let link = link(&pool, business_id, None).await;
+        let invoice = opened(&pool, link.id).await;
+        let user_id = payer(&pool).await;
+
+        deactivate(&pool, business_id, link.id).await.unwrap();
+        let result = pay_invoice(&pool, invoice.id, user_id).await;
+
+        assert!(matches!(result, Err(ApiError::Conflict(_))));
+        assert!(matches!(get_invoice(&pool, invoice.id).await.unwrap().unwrap().status, InvoiceStatus::Pending));
+        assert_eq!(get_user_by_id(&pool, user_id).await.unwrap().unwrap().wallet_balance, WELCOME_BONUS);
+        assert_eq!(get(&pool, link.id).await.unwrap().unwrap().use_count, 0);
+    }
+
+    #[tokio::test]
+    async fn fixed_amounts_cant_be_changed() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let link = link(&pool, business_id, None).await;
+
+        let request = OpenPaymentLinkRequest { amount: Some(Money::from_major(1)), customer_name: None };
+        assert!(matches!(open_invoice(&pool, link.id, request).await, Err(ApiError::Validation(_))));
+        assert_eq!(opened(&pool, link.id).await.amount, Money::from_major(25));
+    }
+}
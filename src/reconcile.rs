@@ -0,0 +1,206 @@
+use crate::database::record_audit;
+use crate::ledger::{self, Account};
+use crate::money::Money;
+use gurtlib::{GurtError, Result};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{AnyConnection, AnyPool, Row};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// One account whose cached balance disagrees with its books.
+#[derive(Debug, Clone, Serialize)]
+pub struct Discrepancy {
+    pub account_type: &'static str,
+    pub account_id: Uuid,
+    /// What `wallet_balance`/`balance` currently says.
+    pub cached: Money,
+    /// Sum of the account's ledger postings; this is what a repair writes back.
+    pub ledger: Money,
+    /// Net of the completed rows in `transactions`. Only differs from `ledger`
+    /// if an entry was lost, which a repair can't fix, so it is reported as-is.
+    pub history: Money,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReconciliationReport {
+    pub accounts_checked: usize,
+    pub discrepancies: Vec<Discrepancy>,
+    pub repaired: bool,
+    pub audit_id: Option<Uuid>,
+}
+
+/// Compares every user wallet and business balance against the ledger and the
+/// transaction history. With `repair` set, cached balances that drifted from
+/// the ledger are overwritten with the ledger figure and an audit entry listing
+/// the changes is written, all in one database transaction.
+pub async fn reconcile(pool: &AnyPool, repair: bool, actor: Option<Uuid>) -> Result<ReconciliationReport> {
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    let cached = cached_balances(&mut tx).await?;
+    let ledger = ledger::balances(&mut tx).await?;
+    let history = history_balances(&mut tx).await?;
+
+    let mut discrepancies: Vec<Discrepancy> = cached.iter()
+        .filter_map(|(account, cached)| {
+            let account_id = account.owner_id()?;
+            let ledger = ledger.get(account).copied().unwrap_or_default();
+            let history = history.get(account).copied().unwrap_or_default();
+            (*cached != ledger || ledger != history).then_some(Discrepancy {
+                account_type: account.kind(),
+                account_id,
+                cached: *cached,
+                ledger,
+                history,
+            })
+        })
+        .collect();
+    discrepancies.sort_by_key(|d| (d.account_type, d.account_id));
+
+    let drifted: Vec<&Discrepancy> = discrepancies.iter().filter(|d| d.cached != d.ledger).collect();
+    let mut audit_id = None;
+    if repair && !drifted.is_empty() {
+        for d in &drifted {
+            let sql = match d.account_type {
+                "business" => "UPDATE businesses SET balance = $1 WHERE id = $2",
+                _ => "UPDATE users SET wallet_balance = $1 WHERE id = $2",
+            };
+            sqlx::query(sql)
+                .bind(d.ledger.minor())
+                .bind(d.account_id.to_string())
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| GurtError::invalid_message(format!("Failed to repair {} balance: {}", d.account_type, e)))?;
+        }
+
+        let details = json!({ "repaired": drifted });
+        audit_id = Some(record_audit(&mut tx, actor, "balance_reconciliation_repair", &details).await?);
+    }
+
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit reconciliation: {}", e)))?;
+
+    Ok(ReconciliationReport {
+        accounts_checked: cached.len(),
+        repaired: audit_id.is_some(),
+        discrepancies,
+        audit_id,
+    })
+}
+
+async fn cached_balances(conn: &mut AnyConnection) -> Result<HashMap<Account, Money>> {
+    let mut balances = HashMap::new();
+
+    let users = sqlx::query("SELECT id, wallet_balance FROM users")
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to read wallet balances: {}", e)))?;
+    for row in users {
+        if let Ok(id) = Uuid::parse_str(&row.get::<String, _>("id")) {
+            balances.insert(Account::UserWallet(id), Money::from_minor(row.get("wallet_balance")));
+        }
+    }
+
+    let businesses = sqlx::query("SELECT id, balance FROM businesses")
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to read business balances: {}", e)))?;
+    for row in businesses {
+        if let Ok(id) = Uuid::parse_str(&row.get::<String, _>("id")) {
+            balances.insert(Account::Business(id), Money::from_minor(row.get("balance")));
+        }
+    }
+
+    Ok(balances)
+}
+
+async fn history_balances(conn: &mut AnyConnection) -> Result<HashMap<Account, Money>> {
+    let rows = sqlx::query(
+        "SELECT from_user_id, to_user_id, business_id, amount FROM transactions WHERE status = 'completed'"
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to read transaction history: {}", e)))?;
+
+    let parse_id = |value: Option<String>| value.and_then(|s| Uuid::parse_str(&s).ok());
+    let mut balances: HashMap<Account, Money> = HashMap::new();
+    for row in rows {
+        let amount = Money::from_minor(row.get("amount"));
+        let accounts = ledger::legacy_accounts(
+            parse_id(row.get("from_user_id")),
+            parse_id(row.get("to_user_id")),
+            parse_id(row.get("business_id")),
+        );
+        let Some((from, to)) = accounts else { continue };
+
+        for (account, delta) in [(from, Money::from_minor(-amount.minor())), (to, amount)] {
+            let balance = balances.entry(account).or_default();
+            *balance = balance.checked_add(delta)
+                .ok_or_else(|| GurtError::invalid_message("Transaction history total out of range"))?;
+        }
+    }
+    Ok(balances)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{create_user_with_password, get_user_by_id, test_pool, WELCOME_BONUS};
+
+    #[tokio::test]
+    async fn reports_and_repairs_drifted_balances() {
+        let pool = test_pool().await;
+        let steady = create_user_with_password(&pool, "steady", "unused").await.unwrap();
+        let drifted = create_user_with_password(&pool, "drifted", "unused").await.unwrap();
+
+        let clean = reconcile(&pool, false, None).await.unwrap();
+        assert_eq!(clean.accounts_checked, 2);
+        assert!(clean.discrepancies.is_empty());
+
+        sqlx::query("UPDATE users SET wallet_balance = wallet_balance + 1234 WHERE id = $1")
+            .bind(drifted.id.to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let report = reconcile(&pool, false, None).await.unwrap();
+        assert!(!report.repaired);
+        assert_eq!(report.discrepancies.len(), 1);
+        let found = &report.discrepancies[0];
+        assert_eq!((found.account_type, found.account_id), ("user_wallet", drifted.id));
+        assert_eq!(found.cached, WELCOME_BONUS.checked_add(Money::from_minor(1234)).unwrap());
+        assert_eq!(found.ledger, WELCOME_BONUS);
+        assert_eq!(found.history, WELCOME_BONUS);
+        // Only reporting leaves it alone
+        assert_eq!(get_user_by_id(&pool, drifted.id).await.unwrap().unwrap().wallet_balance, found.cached);
+
+        let admin = Uuid::new_v4();
+        let repaired = reconcile(&pool, true, Some(admin)).await.unwrap();
+        assert!(repaired.repaired);
+        assert_eq!(get_user_by_id(&pool, drifted.id).await.unwrap().unwrap().wallet_balance, WELCOME_BONUS);
+        assert_eq!(get_user_by_id(&pool, steady.id).await.unwrap().unwrap().wallet_balance, WELCOME_BONUS);
+
+        let audit = sqlx::query("SELECT actor_user_id, action, details FROM audit_log WHERE id = $1")
+            .bind(repaired.audit_id.unwrap().to_string())
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(audit.get::<String, _>("action"), "balance_reconciliation_repair");
+        assert_eq!(audit.get::<Option<String>, _>("actor_user_id"), Some(admin.to_string()));
+        let details: serde_json::Value = serde_json::from_str(&audit.get::<String, _>("details")).unwrap();
+        assert_eq!(details["repaired"][0]["account_id"], drifted.id.to_string());
+
+        assert!(reconcile(&pool, false, None).await.unwrap().discrepancies.is_empty());
+    }
+
+    #[tokio::test]
+    async fn repair_with_nothing_drifted_writes_no_audit() {
+        let pool = test_pool().await;
+        create_user_with_password(&pool, "steady", "unused").await.unwrap();
+
+        let report = reconcile(&pool, true, None).await.unwrap();
+        assert!(!report.repaired);
+        assert!(report.audit_id.is_none());
+    }
+}
@@ -0,0 +1,663 @@
+use crate::database::{get_user_by_wallet_address, record_transfer};
+use crate::error::{ApiError, ApiResult};
+use crate::models::CreateScheduledTransferRequest;
+use crate::money::Money;
+use crate::subscriptions::BillingInterval;
+use chrono::{DateTime, Utc};
+use gurtlib::{GurtError, Result};
+use serde::Serialize;
+use sqlx::{AnyPool, Row};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// A schedule whose runs fail this many times in a row is paused. Errors on our
+/// side count too, so one that never clears up can't be retried forever.
+const MAX_FAILED_ATTEMPTS: i32 = 3;
+const MAX_INTERVAL_COUNT: i32 = 12;
+const MAX_DESCRIPTION_LEN: usize = 500;
+const RUNS_LIST_LIMIT: i64 = 50;
+const WORKER_INTERVAL_SECS: u64 = 60;
+const BATCH_SIZE: i64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleStatus {
+    Active,
+    Paused,
+    Cancelled,
+    /// Made all of its `max_runs` transfers.
+    Completed,
+}
+
+impl ScheduleStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ScheduleStatus::Active => "active",
+            ScheduleStatus::Paused => "paused",
+            ScheduleStatus::Cancelled => "cancelled",
+            ScheduleStatus::Completed => "completed",
+        }
+    }
+
+    fn parse(s: &str) -> Option<ScheduleStatus> {
+        match s {
+            "active" => Some(ScheduleStatus::Active),
+            "paused" => Some(ScheduleStatus::Paused),
+            "cancelled" => Some(ScheduleStatus::Cancelled),
+            "completed" => Some(ScheduleStatus::Completed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Succeeded,
+    Failed,
+}
+
+impl RunStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RunStatus::Succeeded => "succeeded",
+            RunStatus::Failed => "failed",
+        }
+    }
+
+    fn parse(s: &str) -> Option<RunStatus> {
+        match s {
+            "succeeded" => Some(RunStatus::Succeeded),
+            "failed" => Some(RunStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// A transfer the user has asked to repeat, e.g. 100 GC to a friend every
+/// week starting on a Friday.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledTransfer {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub to_user_id: Uuid,
+    pub to_username: String,
+    pub to_address: String,
+    pub amount: Money,
+    pub description: String,
+    pub interval: BillingInterval,
+    pub interval_count: i32,
+    /// `None` once the schedule is cancelled or completed.
+    pub next_run_at: Option<DateTime<Utc>>,
+    /// Transfers left to make; `None` repeats until cancelled.
+    pub remaining_runs: Option<i32>,
+    pub status: ScheduleStatus,
+    pub failed_attempts: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// One attempt at a scheduled transfer.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledTransferRun {
+    pub id: Uuid,
+    pub scheduled_transfer_id: Uuid,
+    pub scheduled_for: DateTime<Utc>,
+    pub status: RunStatus,
+    pub transaction_id: Option<Uuid>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn create(pool: &AnyPool, user_id: Uuid, request: &CreateScheduledTransferRequest) -> ApiResult<ScheduledTransfer> {
+    if !request.amount.is_positive() {
+        return Err(ApiError::validation("Amount must be greater than 0"));
+    }
+    let description = request.description.trim();
+    if description.len() > MAX_DESCRIPTION_LEN {
+        return Err(ApiError::validation(format!("Description must be at most {} characters", MAX_DESCRIPTION_LEN)));
+    }
+    let interval = BillingInterval::parse(&request.interval)
+        .ok_or_else(|| ApiError::validation("interval must be one of day, week, month or year"))?;
+    let interval_count = request.interval_count.unwrap_or(1);
+    if !(1..=MAX_INTERVAL_COUNT).contains(&interval_count) {
+        return Err(ApiError::validation(format!("interval_count must be between 1 and {}", MAX_INTERVAL_COUNT)));
+    }
+    if request.max_runs.is_some_and(|max| max < 1) {
+        return Err(ApiError::validation("max_runs must be at least 1"));
+    }
+    let now = Utc::now();
+    if request.start_at.is_some_and(|start| start < now) {
+        return Err(ApiError::validation("start_at must not be in the past"));
+    }
+
+    let recipient = get_user_by_wallet_address(pool, &request.to_address).await?
+        .ok_or_else(|| ApiError::not_found("Recipient wallet address not found"))?;
+    if recipient.id == user_id {
+        return Err(ApiError::validation("Cannot send money to yourself"));
+    }
+
+    let schedule = ScheduledTransfer {
+        id: Uuid::new_v4(),
+        user_id,
+        to_user_id: recipient.id,
+        to_username: recipient.username,
+        to_address: recipient.wallet_address,
+        amount: request.amount,
+        description: description.to_string(),
+        interval,
+        interval_count,
+        next_run_at: Some(request.start_at.unwrap_or(now)),
+        remaining_runs: request.max_runs,
+        status: ScheduleStatus::Active,
+        failed_attempts: 0,
+        created_at: now,
+    };
+
+    sqlx::query(
+        "INSERT INTO scheduled_transfers (id, user_id, to_user_id, amount, description, schedule_interval, interval_count, \
+                                          next_run_at, remaining_runs, status, failed_attempts, created_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, 'active', 0, $10)"
+    )
+    .bind(schedule.id.to_string())
+    .bind(user_id.to_string())
+    .bind(schedule.to_user_id.to_string())
+    .bind(schedule.amount.minor())
+    .bind(&schedule.description)
+    .bind(interval.as_str())
+    .bind(interval_count)
+    .bind(schedule.next_run_at.map(|t| t.to_rfc3339()))
+    .bind(schedule.remaining_runs)
+    .bind(now.to_rfc3339())
+    .execute(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to create scheduled transfer: {}", e)))?;
+
+    Ok(schedule)
+}
+
+pub async fn list(pool: &AnyPool, user_id: Uuid) -> Result<Vec<ScheduledTransfer>> {
+    let rows = sqlx::query(&format!("{} WHERE s.user_id = $1 ORDER BY s.created_at DESC", SELECT_SCHEDULE))
+        .bind(user_id.to_string())
+        .fetch_all(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to list scheduled transfers: {}", e)))?;
+    rows.iter().map(schedule_from_row).collect()
+}
+
+/// The schedule's most recent runs, newest first.
+pub async fn list_runs(pool: &AnyPool, user_id: Uuid, schedule_id: Uuid) -> ApiResult<Vec<ScheduledTransferRun>> {
+    get_for(pool, user_id, schedule_id).await?;
+
+    let rows = sqlx::query(
+        "SELECT id, scheduled_transfer_id, scheduled_for, status, transaction_id, error, created_at \
+         FROM scheduled_transfer_runs WHERE scheduled_transfer_id = $1 ORDER BY scheduled_for DESC LIMIT $2"
+    )
+    .bind(schedule_id.to_string())
+    .bind(RUNS_LIST_LIMIT)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to list scheduled transfer runs: {}", e)))?;
+    Ok(rows.iter().map(run_from_row).collect::<Result<_>>()?)
+}
+
+pub async fn pause(pool: &AnyPool, user_id: Uuid, schedule_id: Uuid) -> ApiResult<ScheduledTransfer> {
+    let schedule = get_for(pool, user_id, schedule_id).await?;
+    if schedule.status != ScheduleStatus::Active {
+        return Err(ApiError::conflict(format!("Scheduled transfer is {}", schedule.status.as_str())));
+    }
+    let next_run_at = schedule.next_run_at;
+    transition(pool, schedule, ScheduleStatus::Paused, next_run_at).await
+}
+
+/// Starts a paused schedule again from its next run that is still ahead, and
+/// forgets earlier failures. Runs missed while paused are skipped.
+pub async fn resume(pool: &AnyPool, user_id: Uuid, schedule_id: Uuid) -> ApiResult<ScheduledTransfer> {
+    let schedule = get_for(pool, user_id, schedule_id).await?;
+    if schedule.status != ScheduleStatus::Paused {
+        return Err(ApiError::conflict(format!("Scheduled transfer is {}", schedule.status.as_str())));
+    }
+    let now = Utc::now();
+    let next_run_at = match schedule.next_run_at {
+        Some(next) if next > now => next,
+        Some(next) => next_run_after(&schedule, next, now)?,
+        None => now,
+    };
+    transition(pool, schedule, ScheduleStatus::Active, Some(next_run_at)).await
+}
+
+pub async fn cancel(pool: &AnyPool, user_id: Uuid, schedule_id: Uuid) -> ApiResult<ScheduledTransfer> {
+    let schedule = get_for(pool, user_id, schedule_id).await?;
+    if !matches!(schedule.status, ScheduleStatus::Active | ScheduleStatus::Paused) {
+        return Err(ApiError::conflict(format!("Scheduled transfer is already {}", schedule.status.as_str())));
+    }
+    transition(pool, schedule, ScheduleStatus::Cancelled, None).await
+}
+
+/// Makes every transfer that is due. Returns how many went through.
+pub async fn run_due(pool: &AnyPool) -> Result<u64> {
+    let rows = sqlx::query(&format!(
+        "{} WHERE s.status = 'active' AND s.next_run_at <= $1 ORDER BY s.next_run_at LIMIT $2",
+        SELECT_SCHEDULE
+    ))
+    .bind(Utc::now().to_rfc3339())
+    .bind(BATCH_SIZE)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to find due scheduled transfers: {}", e)))?;
+
+    let mut executed = 0;
+    for row in &rows {
+        let schedule = schedule_from_row(row)?;
+        match execute(pool, &schedule).await {
+            Ok(true) => executed += 1,
+            Ok(false) => {}
+            Err(e) => tracing::warn!("Running scheduled transfer {} failed: {}", schedule.id, e),
+        }
+    }
+    Ok(executed)
+}
+
+/// Starts the background task that makes due scheduled transfers.
+pub fn spawn_worker(pool: AnyPool) {
+    tokio::spawn(async move {
+        loop {
+            match run_due(&pool).await {
+                Ok(0) => {}
+                Ok(count) => tracing::info!("Made {} scheduled transfers", count),
+                Err(e) => tracing::warn!("Scheduled transfer run failed: {}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(WORKER_INTERVAL_SECS)).await;
+        }
+    });
+}
+
+/// Makes one due transfer. If the server was down through several due times,
+/// only one transfer is made and the schedule moves on to its next future run.
+async fn execute(pool: &AnyPool, schedule: &ScheduledTransfer) -> Result<bool> {
+    let Some(due_at) = schedule.next_run_at else { return Ok(false) };
+    let next_run_at = next_run_after(schedule, due_at, Utc::now())?;
+    let remaining_runs = schedule.remaining_runs.map(|runs| runs - 1);
+    let finished = remaining_runs.is_some_and(|runs| runs <= 0);
+    let status = if finished { ScheduleStatus::Completed } else { ScheduleStatus::Active };
+    let transaction_id = Uuid::new_v4();
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    // Compare-and-set on the due time, so two workers can't both send it
+    let claimed = sqlx::query(
+        "UPDATE scheduled_transfers SET next_run_at = $1, remaining_runs = $2, status = $3, failed_attempts = 0 \
+         WHERE id = $4 AND next_run_at = $5 AND status = 'active'"
+    )
+    .bind((!finished).then(|| next_run_at.to_rfc3339()))
+    .bind(remaining_runs)
+    .bind(status.as_str())
+    .bind(schedule.id.to_string())
+    .bind(due_at.to_rfc3339())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to claim scheduled transfer: {}", e)))?;
+    if claimed.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    let description = if schedule.description.is_empty() {
+        "Scheduled transfer".to_string()
+    } else {
+        format!("Scheduled transfer: {}", schedule.description)
+    };
+    match record_transfer(&mut tx, transaction_id, &schedule.user_id, &schedule.to_user_id, schedule.amount, &description).await {
+        Ok(_) => {
+            insert_run(&mut tx, schedule.id, due_at, RunStatus::Succeeded, Some(transaction_id), None).await?;
+            tx.commit().await
+                .map_err(|e| GurtError::invalid_message(format!("Failed to commit scheduled transfer: {}", e)))?;
+            Ok(true)
+        }
+        Err(e) => {
+            drop(tx);
+            record_failed_run(pool, schedule, due_at, next_run_at, Failure::of(&e), &e.to_string()).await?;
+            Ok(false)
+        }
+    }
+}
+
+/// Why a due transfer didn't go through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    /// The user couldn't cover it this time; later runs may still go through.
+    Declined,
+    /// Something failed on our side, which may clear up if the run is retried.
+    Errored,
+    /// The transfer can't go through as scheduled, e.g. the recipient is gone.
+    Rejected,
+}
+
+impl Failure {
+    fn of(error: &ApiError) -> Failure {
+        match error {
+            ApiError::InsufficientFunds(_) | ApiError::LimitExceeded(_) => Failure::Declined,
+            ApiError::Internal(_) => Failure::Errored,
+            _ => Failure::Rejected,
+        }
+    }
+}
+
+/// Records a run that didn't go through. A declined run is skipped in favour
+/// of the next one, an errored run is retried on the next pass, and a
+/// rejected one pauses the schedule straight away. Either of the first two
+/// also pauses it once too many runs in a row have failed. An errored run is
+/// only written to the run log once it stops being retried.
+async fn record_failed_run(
+    pool: &AnyPool,
+    schedule: &ScheduledTransfer,
+    due_at: DateTime<Utc>,
+    next_run_at: DateTime<Utc>,
+    failure: Failure,
+    error: &str,
+) -> Result<()> {
+    let attempts = schedule.failed_attempts + 1;
+    let status = if failure == Failure::Rejected || attempts >= MAX_FAILED_ATTEMPTS {
+        ScheduleStatus::Paused
+    } else {
+        ScheduleStatus::Active
+    };
+    let next_run_at = if failure == Failure::Errored { due_at } else { next_run_at };
+    let log_run = failure != Failure::Errored || status == ScheduleStatus::Paused;
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    let updated = sqlx::query(
+        "UPDATE scheduled_transfers SET next_run_at = $1, status = $2, failed_attempts = $3 \
+         WHERE id = $4 AND next_run_at = $5 AND status = 'active'"
+    )
+    .bind(next_run_at.to_rfc3339())
+    .bind(status.as_str())
+    .bind(attempts)
+    .bind(schedule.id.to_string())
+    .bind(due_at.to_rfc3339())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to update scheduled transfer: {}", e)))?;
+    if updated.rows_affected() == 0 {
+        return Ok(());
+    }
+
+    if log_run {
+        insert_run(&mut tx, schedule.id, due_at, RunStatus::Failed, None, Some(error)).await?;
+    }
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit failed scheduled transfer: {}", e)))?;
+
+    if failure == Failure::Errored {
+        tracing::warn!("Running scheduled transfer {} failed: {}", schedule.id, error);
+    }
+    if status == ScheduleStatus::Paused {
+        tracing::info!("Paused scheduled transfer {} after {} failed runs", schedule.id, attempts);
+    }
+    Ok(())
+}
+
+async fn insert_run(
+    conn: &mut sqlx::AnyConnection,
+    schedule_id: Uuid,
+    scheduled_for: DateTime<Utc>,
+    status: RunStatus,
+    transaction_id: Option<Uuid>,
+    error: Option<&str>,
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO scheduled_transfer_runs (id, scheduled_transfer_id, scheduled_for, status, transaction_id, error, created_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7)"
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(schedule_id.to_string())
+    .bind(scheduled_for.to_rfc3339())
+    .bind(status.as_str())
+    .bind(transaction_id.map(|id| id.to_string()))
+    .bind(error)
+    .bind(Utc::now().to_rfc3339())
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to record scheduled transfer run: {}", e)))?;
+    Ok(())
+}
+
+/// The first run after `from` that is later than `now`.
+fn next_run_after(schedule: &ScheduledTransfer, from: DateTime<Utc>, now: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    let mut next = from;
+    while next <= now {
+        next = schedule.interval.advance(next, schedule.interval_count)
+            .ok_or_else(|| GurtError::invalid_message("Scheduled transfer run out of range"))?;
+    }
+    Ok(next)
+}
+
+async fn transition(pool: &AnyPool, schedule: ScheduledTransfer, status: ScheduleStatus, next_run_at: Option<DateTime<Utc>>) -> ApiResult<ScheduledTransfer> {
+    let updated = sqlx::query(
+        "UPDATE scheduled_transfers SET status = $1, next_run_at = $2, failed_attempts = 0 \
+         WHERE id = $3 AND user_id = $4 AND status = $5"
+    )
+    .bind(status.as_str())
+    .bind(next_run_at.map(|t| t.to_rfc3339()))
+    .bind(schedule.id.to_string())
+    .bind(schedule.user_id.to_string())
+    .bind(schedule.status.as_str())
+    .execute(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to update scheduled transfer: {}", e)))?;
+
+    if updated.rows_affected() == 0 {
+        return Err(ApiError::conflict("Scheduled transfer changed while updating it; try again"));
+    }
+    Ok(ScheduledTransfer { status, next_run_at, failed_attempts: 0, ..schedule })
+}
+
+/// Loads one of the user's schedules; anyone else's is a 404.
+async fn get_for(pool: &AnyPool, user_id: Uuid, schedule_id: Uuid) -> ApiResult<ScheduledTransfer> {
+    let row = sqlx::query(&format!("{} WHERE s.id = $1 AND s.user_id = $2", SELECT_SCHEDULE))
+        .bind(schedule_id.to_string())
+        .bind(user_id.to_string())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to get scheduled transfer: {}", e)))?
+        .ok_or_else(|| ApiError::not_found("Scheduled transfer not found"))?;
+    Ok(schedule_from_row(&row)?)
+}
+
+const SELECT_SCHEDULE: &str =
+    "SELECT s.id, s.user_id, s.to_user_id, s.amount, s.description, s.schedule_interval, s.interval_count, \
+            s.next_run_at, s.remaining_runs, s.status, s.failed_attempts, s.created_at, \
+            u.username AS to_username, u.wallet_address AS to_address \
+     FROM scheduled_transfers s \
+     JOIN users u ON u.id = s.to_user_id";
+
+fn parse_uuid(row: &sqlx::any::AnyRow, column: &str) -> Result<Uuid> {
+    Uuid::parse_str(&row.get::<String, _>(column))
+        .map_err(|e| GurtError::invalid_message(format!("Invalid {}: {}", column, e)))
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| GurtError::invalid_message(format!("Invalid timestamp {}: {}", value, e)))
+}
+
+fn schedule_from_row(row: &sqlx::any::AnyRow) -> Result<ScheduledTransfer> {
+    let interval = row.get::<String, _>("schedule_interval");
+    let status = row.get::<String, _>("status");
+    Ok(ScheduledTransfer {
+        id: parse_uuid(row, "id")?,
+        user_id: parse_uuid(row, "user_id")?,
+        to_user_id: parse_uuid(row, "to_user_id")?,
+        to_username: row.get("to_username"),
+        to_address: row.get("to_address"),
+        amount: Money::from_minor(row.get("amount")),
+        description: row.get("description"),
+        interval: BillingInterval::parse(&interval)
+            .ok_or_else(|| GurtError::invalid_message(format!("Unknown schedule interval: {}", interval)))?,
+        interval_count: row.get::<i32, _>("interval_count"),
+        next_run_at: row.get::<Option<String>, _>("next_run_at").as_deref().map(parse_time).transpose()?,
+        remaining_runs: row.get::<Option<i32>, _>("remaining_runs"),
+        status: ScheduleStatus::parse(&status)
+            .ok_or_else(|| GurtError::invalid_message(format!("Unknown scheduled transfer status: {}", status)))?,
+        failed_attempts: row.get::<i32, _>("failed_attempts"),
+        created_at: parse_time(&row.get::<String, _>("created_at"))?,
+    })
+}
+
+fn run_from_row(row: &sqlx::any::AnyRow) -> Result<ScheduledTransferRun> {
+    let status = row.get::<String, _>("status");
+    Ok(ScheduledTransferRun {
+        id: parse_uuid(row, "id")?,
+        scheduled_transfer_id: parse_uuid(row, "scheduled_transfer_id")?,
+        scheduled_for: parse_time(&row.get::<String, _>("scheduled_for"))?,
+        status: RunStatus::parse(&status)
+            .ok_or_else(|| GurtError::invalid_message(format!("Unknown scheduled transfer run status: {}", status)))?,
+        transaction_id: row.get::<Option<String>, _>("transaction_id").and_then(|s| Uuid::parse_str(&s).ok()),
+        error: row.get("error"),
+        created_at: parse_time(&row.get::<String, _>("created_at"))?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{create_user_with_password, get_user_by_id, test_pool, WELCOME_BONUS};
+
+    /// A daily transfer of `amount` between two new users, due now.
+    async fn due_schedule(pool: &AnyPool, amount: Money) -> ScheduledTransfer {
+        let sender = create_user_with_password(pool, &format!("sender-{}", Uuid::new_v4()), "unused").await.unwrap();
+        let recipient = create_user_with_password(pool, &format!("recipient-{}", Uuid::new_v4()), "unused").await.unwrap();
+        create(pool, sender.id, &CreateScheduledTransferRequest {
+            to_address: recipient.wallet_address,
+            amount,
+            description: "Rent".to_string(),
+            interval: "day".to_string(),
+            interval_count: None,
+            start_at: None,
+            max_runs: None,
+            two_factor_code: None,
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn reload(pool: &AnyPool, schedule: &ScheduledTransfer) -> ScheduledTransfer {
+        get_for(pool, schedule.user_id, schedule.id).await.unwrap()
+    }
+
+    /// Moves the schedule's next run into the past.
+    async fn make_due(pool: &AnyPool, schedule: &ScheduledTransfer) {
+        sqlx::query("UPDATE scheduled_transfers SET next_run_at = $1 WHERE id = $2")
+            .bind((Utc::now() - chrono::Duration::minutes(1)).to_rfc3339())
+            .bind(schedule.id.to_string())
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn runs(pool: &AnyPool, schedule: &ScheduledTransfer) -> Vec<ScheduledTransferRun> {
+        list_runs(pool, schedule.user_id, schedule.id).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn makes_due_transfers_and_moves_to_the_next_run() {
+        let pool = test_pool().await;
+        let schedule = due_schedule(&pool, Money::from_major(10)).await;
+
+        assert_eq!(run_due(&pool).await.unwrap(), 1);
+
+        let recipient = get_user_by_id(&pool, schedule.to_user_id).await.unwrap().unwrap();
+        assert_eq!(recipient.wallet_balance, WELCOME_BONUS.checked_add(Money::from_major(10)).unwrap());
+        let after = reload(&pool, &schedule).await;
+        assert_eq!(after.status, ScheduleStatus::Active);
+        assert!(after.next_run_at.unwrap() > Utc::now());
+        let runs = runs(&pool, &schedule).await;
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].status, RunStatus::Succeeded);
+        assert!(runs[0].transaction_id.is_some());
+    }
+
+    #[tokio::test]
+    async fn skips_declined_runs_and_pauses_after_too_many() {
+        let pool = test_pool().await;
+        let schedule = due_schedule(&pool, WELCOME_BONUS.checked_add(Money::from_major(1)).unwrap()).await;
+
+        assert_eq!(run_due(&pool).await.unwrap(), 0);
+        let after = reload(&pool, &schedule).await;
+        assert_eq!(after.status, ScheduleStatus::Active);
+        assert_eq!(after.failed_attempts, 1);
+        assert!(after.next_run_at.unwrap() > Utc::now());
+        assert_eq!(runs(&pool, &schedule).await[0].status, RunStatus::Failed);
+
+        for _ in 1..MAX_FAILED_ATTEMPTS {
+            make_due(&pool, &schedule).await;
+            run_due(&pool).await.unwrap();
+        }
+        let after = reload(&pool, &schedule).await;
+        assert_eq!(after.status, ScheduleStatus::Paused);
+        assert_eq!(after.failed_attempts, MAX_FAILED_ATTEMPTS);
+        assert_eq!(runs(&pool, &schedule).await.len(), MAX_FAILED_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn retries_errored_runs_then_pauses() {
+        let pool = test_pool().await;
+        let schedule = due_schedule(&pool, Money::from_major(10)).await;
+        // A zero amount fails ledger validation, which surfaces as an internal error
+        sqlx::query("UPDATE scheduled_transfers SET amount = 0 WHERE id = $1")
+            .bind(schedule.id.to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        run_due(&pool).await.unwrap();
+        let after = reload(&pool, &schedule).await;
+        assert_eq!(after.status, ScheduleStatus::Active);
+        assert_eq!(after.failed_attempts, 1);
+        assert_eq!(after.next_run_at, schedule.next_run_at);
+        assert!(runs(&pool, &schedule).await.is_empty());
+
+        for _ in 1..MAX_FAILED_ATTEMPTS {
+            run_due(&pool).await.unwrap();
+        }
+        let after = reload(&pool, &schedule).await;
+        assert_eq!(after.status, ScheduleStatus::Paused);
+        assert_eq!(after.failed_attempts, MAX_FAILED_ATTEMPTS);
+        let runs = runs(&pool, &schedule).await;
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].status, RunStatus::Failed);
+        assert_eq!(Some(runs[0].scheduled_for), schedule.next_run_at);
+
+        // Not picked up again while paused
+        assert_eq!(run_due(&pool).await.unwrap(), 0);
+        assert_eq!(reload(&pool, &schedule).await.failed_attempts, MAX_FAILED_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn pauses_rejected_runs_straight_away() {
+        let pool = test_pool().await;
+        let schedule = due_schedule(&pool, Money::from_major(10)).await;
+        let due_at = schedule.next_run_at.unwrap();
+
+        record_failed_run(&pool, &schedule, due_at, due_at + chrono::Duration::days(1), Failure::Rejected, "Unknown wallet account").await.unwrap();
+
+        let after = reload(&pool, &schedule).await;
+        assert_eq!(after.status, ScheduleStatus::Paused);
+        assert_eq!(after.failed_attempts, 1);
+        let runs = runs(&pool, &schedule).await;
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].error.as_deref(), Some("Unknown wallet account"));
+    }
+
+    #[test]
+    fn classifies_failures() {
+        assert_eq!(Failure::of(&ApiError::insufficient_funds("Insufficient funds")), Failure::Declined);
+        assert_eq!(Failure::of(&ApiError::limit_exceeded("Daily limit")), Failure::Declined);
+        assert_eq!(Failure::of(&ApiError::internal("Journal entry contains a zero posting")), Failure::Errored);
+        assert_eq!(Failure::of(&ApiError::not_found("Unknown wallet account")), Failure::Rejected);
+        assert_eq!(Failure::of(&ApiError::validation("Cannot send money to yourself")), Failure::Rejected);
+        assert_eq!(Failure::of(&ApiError::forbidden("Account is frozen")), Failure::Rejected);
+    }
+}
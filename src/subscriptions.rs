@@ -0,0 +1,788 @@
+use crate::database::record_business_payment;
+use crate::error::{ApiError, ApiResult};
+use crate::limits;
+use crate::models::{CreatePlanRequest, Transaction};
+use crate::money::Money;
+use crate::webhooks::{self, Event};
+use chrono::{DateTime, Months, Utc};
+use gurtlib::{GurtError, Result};
+use serde::Serialize;
+use sqlx::{AnyConnection, AnyPool, Row};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// A renewal that still can't be paid after this many tries cancels the subscription.
+const MAX_FAILED_ATTEMPTS: i32 = 3;
+/// Wait between retries of a past-due renewal.
+const RETRY_DELAY_HOURS: i64 = 24;
+const MAX_INTERVAL_COUNT: i32 = 12;
+const MAX_TRIAL_DAYS: i32 = 365;
+const SCHEDULER_INTERVAL_SECS: u64 = 60;
+const BATCH_SIZE: i64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BillingInterval {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl BillingInterval {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            BillingInterval::Day => "day",
+            BillingInterval::Week => "week",
+            BillingInterval::Month => "month",
+            BillingInterval::Year => "year",
+        }
+    }
+
+    pub(crate) fn parse(s: &str) -> Option<BillingInterval> {
+        match s {
+            "day" => Some(BillingInterval::Day),
+            "week" => Some(BillingInterval::Week),
+            "month" => Some(BillingInterval::Month),
+            "year" => Some(BillingInterval::Year),
+            _ => None,
+        }
+    }
+
+    /// `count` intervals after `from`. Month steps land on the same day of the
+    /// month, or the last day of a shorter month.
+    pub(crate) fn advance(&self, from: DateTime<Utc>, count: i32) -> Option<DateTime<Utc>> {
+        match self {
+            BillingInterval::Day => from.checked_add_signed(chrono::Duration::days(count as i64)),
+            BillingInterval::Week => from.checked_add_signed(chrono::Duration::weeks(count as i64)),
+            BillingInterval::Month => from.checked_add_months(Months::new(count as u32)),
+            BillingInterval::Year => from.checked_add_months(Months::new(count as u32 * 12)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionStatus {
+    Trialing,
+    Active,
+    PastDue,
+    Cancelled,
+}
+
+impl SubscriptionStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SubscriptionStatus::Trialing => "trialing",
+            SubscriptionStatus::Active => "active",
+            SubscriptionStatus::PastDue => "past_due",
+            SubscriptionStatus::Cancelled => "cancelled",
+        }
+    }
+
+    fn parse(s: &str) -> Option<SubscriptionStatus> {
+        match s {
+            "trialing" => Some(SubscriptionStatus::Trialing),
+            "active" => Some(SubscriptionStatus::Active),
+            "past_due" => Some(SubscriptionStatus::PastDue),
+            "cancelled" => Some(SubscriptionStatus::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+/// What a business sells on repeat: `amount` every `interval_count` intervals,
+/// after an optional free trial.
+#[derive(Debug, Clone, Serialize)]
+pub struct Plan {
+    pub id: Uuid,
+    pub business_id: Uuid,
+    pub name: String,
+    pub amount: Money,
+    pub interval: BillingInterval,
+    pub interval_count: i32,
+    pub trial_days: i32,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A user's standing approval for a business to charge a plan to their wallet.
+/// `current_period_*` is the stretch already paid for (or the trial), and
+/// `next_charge_at` is when the scheduler next tries to bill.
+#[derive(Debug, Clone, Serialize)]
+pub struct Subscription {
+    pub id: Uuid,
+    pub plan_id: Uuid,
+    pub business_id: Uuid,
+    pub user_id: Uuid,
+    pub plan_name: String,
+    pub amount: Money,
+    pub interval: BillingInterval,
+    pub interval_count: i32,
+    pub status: SubscriptionStatus,
+    pub current_period_start: DateTime<Utc>,
+    pub current_period_end: DateTime<Utc>,
+    pub next_charge_at: Option<DateTime<Utc>>,
+    pub failed_attempts: i32,
+    pub created_at: DateTime<Utc>,
+    pub cancelled_at: Option<DateTime<Utc>>,
+}
+
+/// Who is cancelling, which decides whose subscriptions they can see.
+pub enum Owner {
+    User(Uuid),
+    Business(Uuid),
+}
+
+pub async fn create_plan(pool: &AnyPool, business_id: Uuid, request: &CreatePlanRequest) -> ApiResult<Plan> {
+    let name = request.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::validation("Plan name is required"));
+    }
+    if !request.amount.is_positive() {
+        return Err(ApiError::validation("Amount must be greater than 0"));
+    }
+    let interval = BillingInterval::parse(&request.interval)
+        .ok_or_else(|| ApiError::validation("interval must be one of day, week, month or year"))?;
+    let interval_count = request.interval_count.unwrap_or(1);
+    if !(1..=MAX_INTERVAL_COUNT).contains(&interval_count) {
+        return Err(ApiError::validation(format!("interval_count must be between 1 and {}", MAX_INTERVAL_COUNT)));
+    }
+    let trial_days = request.trial_days.unwrap_or(0);
+    if !(0..=MAX_TRIAL_DAYS).contains(&trial_days) {
+        return Err(ApiError::validation(format!("trial_days must be between 0 and {}", MAX_TRIAL_DAYS)));
+    }
+
+    let plan = Plan {
+        id: Uuid::new_v4(),
+        business_id,
+        name: name.to_string(),
+        amount: request.amount,
+        interval,
+        interval_count,
+        trial_days,
+        active: true,
+        created_at: Utc::now(),
+    };
+
+    sqlx::query(
+        "INSERT INTO subscription_plans (id, business_id, name, amount, billing_interval, interval_count, trial_days, active, created_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, TRUE, $8)"
+    )
+    .bind(plan.id.to_string())
+    .bind(business_id.to_string())
+    .bind(&plan.name)
+    .bind(plan.amount.minor())
+    .bind(interval.as_str())
+    .bind(interval_count)
+    .bind(trial_days)
+    .bind(plan.created_at.to_rfc3339())
+    .execute(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to create plan: {}", e)))?;
+
+    Ok(plan)
+}
+
+pub async fn get_plan(pool: &AnyPool, plan_id: Uuid) -> Result<Option<Plan>> {
+    let row = sqlx::query(&format!("{} WHERE id = $1", SELECT_PLAN))
+        .bind(plan_id.to_string())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to get plan: {}", e)))?;
+    row.as_ref().map(plan_from_row).transpose()
+}
+
+pub async fn list_plans(pool: &AnyPool, business_id: Uuid) -> Result<Vec<Plan>> {
+    let rows = sqlx::query(&format!("{} WHERE business_id = $1 ORDER BY created_at DESC", SELECT_PLAN))
+        .bind(business_id.to_string())
+        .fetch_all(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to list plans: {}", e)))?;
+    rows.iter().map(plan_from_row).collect()
+}
+
+/// Stops new sign-ups to the plan. Existing subscriptions keep renewing.
+pub async fn deactivate_plan(pool: &AnyPool, business_id: Uuid, plan_id: Uuid) -> ApiResult<()> {
+    let result = sqlx::query("UPDATE subscription_plans SET active = FALSE WHERE id = $1 AND business_id = $2")
+        .bind(plan_id.to_string())
+        .bind(business_id.to_string())
+        .execute(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to deactivate plan: {}", e)))?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::not_found("Plan not found"));
+    }
+    Ok(())
+}
+
+/// Signs the user up to the plan. Without a trial the first period is charged
+/// straight away, and the subscription is only created if that succeeds.
+pub async fn subscribe(pool: &AnyPool, user_id: Uuid, plan_id: Uuid) -> ApiResult<Subscription> {
+    let plan = get_plan(pool, plan_id).await?
+        .ok_or_else(|| ApiError::not_found("Plan not found"))?;
+    if !plan.active {
+        return Err(ApiError::conflict("Plan is no longer available"));
+    }
+
+    let existing = sqlx::query("SELECT id FROM subscriptions WHERE user_id = $1 AND plan_id = $2 AND status <> 'cancelled'")
+        .bind(user_id.to_string())
+        .bind(plan_id.to_string())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to check subscriptions: {}", e)))?;
+    if existing.is_some() {
+        return Err(ApiError::conflict("You are already subscribed to this plan"));
+    }
+
+    let now = Utc::now();
+    let (status, period_end) = if plan.trial_days > 0 {
+        (SubscriptionStatus::Trialing, now + chrono::Duration::days(plan.trial_days as i64))
+    } else {
+        let end = plan.interval.advance(now, plan.interval_count)
+            .ok_or_else(|| ApiError::internal("Billing period out of range"))?;
+        (SubscriptionStatus::Active, end)
+    };
+
+    let subscription = Subscription {
+        id: Uuid::new_v4(),
+        plan_id,
+        business_id: plan.business_id,
+        user_id,
+        plan_name: plan.name,
+        amount: plan.amount,
+        interval: plan.interval,
+        interval_count: plan.interval_count,
+        status,
+        current_period_start: now,
+        current_period_end: period_end,
+        next_charge_at: Some(period_end),
+        failed_attempts: 0,
+        created_at: now,
+        cancelled_at: None,
+    };
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    sqlx::query(
+        "INSERT INTO subscriptions (id, plan_id, business_id, user_id, status, current_period_start, current_period_end, next_charge_at, failed_attempts, created_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, 0, $9)"
+    )
+    .bind(subscription.id.to_string())
+    .bind(plan_id.to_string())
+    .bind(subscription.business_id.to_string())
+    .bind(user_id.to_string())
+    .bind(status.as_str())
+    .bind(now.to_rfc3339())
+    .bind(period_end.to_rfc3339())
+    .bind(period_end.to_rfc3339())
+    .bind(now.to_rfc3339())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| match e.as_database_error() {
+        // A concurrent sign-up got past the check above first
+        Some(db) if db.is_unique_violation() => ApiError::conflict("You are already subscribed to this plan"),
+        _ => GurtError::invalid_message(format!("Failed to create subscription: {}", e)).into(),
+    })?;
+
+    if status == SubscriptionStatus::Active {
+        charge(&mut tx, &subscription, now, period_end).await?;
+    }
+
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit subscription: {}", e)))?;
+
+    Ok(subscription)
+}
+
+/// Ends the subscription at once; nothing more is charged. Users can cancel
+/// their own subscriptions and businesses the ones to their plans.
+pub async fn cancel(pool: &AnyPool, owner: Owner, subscription_id: Uuid) -> ApiResult<Subscription> {
+    let (owner_column, owner_id) = match owner {
+        Owner::User(id) => ("user_id", id),
+        Owner::Business(id) => ("business_id", id),
+    };
+    let row = sqlx::query(&format!("{} WHERE s.id = $1 AND s.{} = $2", SELECT_SUBSCRIPTION, owner_column))
+        .bind(subscription_id.to_string())
+        .bind(owner_id.to_string())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to get subscription: {}", e)))?
+        .ok_or_else(|| ApiError::not_found("Subscription not found"))?;
+    let mut subscription = subscription_from_row(&row)?;
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+    if !end_subscription(&mut tx, &subscription, "cancelled").await? {
+        return Err(ApiError::conflict("Subscription is already cancelled"));
+    }
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit cancellation: {}", e)))?;
+
+    subscription.status = SubscriptionStatus::Cancelled;
+    subscription.next_charge_at = None;
+    subscription.cancelled_at = Some(Utc::now());
+    Ok(subscription)
+}
+
+pub async fn list_for_user(pool: &AnyPool, user_id: Uuid) -> Result<Vec<Subscription>> {
+    let rows = sqlx::query(&format!("{} WHERE s.user_id = $1 ORDER BY s.created_at DESC", SELECT_SUBSCRIPTION))
+        .bind(user_id.to_string())
+        .fetch_all(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to list subscriptions: {}", e)))?;
+    rows.iter().map(subscription_from_row).collect()
+}
+
+pub async fn list_for_business(pool: &AnyPool, business_id: Uuid) -> Result<Vec<Subscription>> {
+    let rows = sqlx::query(&format!("{} WHERE s.business_id = $1 ORDER BY s.created_at DESC", SELECT_SUBSCRIPTION))
+        .bind(business_id.to_string())
+        .fetch_all(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to list subscriptions: {}", e)))?;
+    rows.iter().map(subscription_from_row).collect()
+}
+
+/// Bills every subscription whose next charge is due. Returns how many
+/// renewals were paid.
+pub async fn renew_due(pool: &AnyPool) -> Result<u64> {
+    let rows = sqlx::query(&format!(
+        "{} WHERE s.status IN ('trialing', 'active', 'past_due') AND s.next_charge_at <= $1 ORDER BY s.next_charge_at LIMIT $2",
+        SELECT_SUBSCRIPTION
+    ))
+    .bind(Utc::now().to_rfc3339())
+    .bind(BATCH_SIZE)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to find due subscriptions: {}", e)))?;
+
+    let mut renewed = 0;
+    for row in &rows {
+        let subscription = subscription_from_row(row)?;
+        match renew(pool, &subscription).await {
+            Ok(true) => renewed += 1,
+            Ok(false) => {}
+            Err(e) => tracing::warn!("Renewing subscription {} failed: {}", subscription.id, e),
+        }
+    }
+    Ok(renewed)
+}
+
+/// Starts the background task that bills due subscriptions.
+pub fn spawn_billing_scheduler(pool: AnyPool) {
+    tokio::spawn(async move {
+        loop {
+            match renew_due(&pool).await {
+                Ok(0) => {}
+                Ok(count) => tracing::info!("Renewed {} subscriptions", count),
+                Err(e) => tracing::warn!("Subscription billing run failed: {}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(SCHEDULER_INTERVAL_SECS)).await;
+        }
+    });
+}
+
+/// Charges the period after the one already paid for. On insufficient funds,
+/// or a charge over the user's transfer limits, the subscription goes past due
+/// and is retried, and is cancelled after `MAX_FAILED_ATTEMPTS`. Returns
+/// whether a charge went through.
+async fn renew(pool: &AnyPool, subscription: &Subscription) -> Result<bool> {
+    let Some(due_at) = subscription.next_charge_at else { return Ok(false) };
+    let period_start = subscription.current_period_end;
+    let period_end = subscription.interval.advance(period_start, subscription.interval_count)
+        .ok_or_else(|| GurtError::invalid_message("Billing period out of range"))?;
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    // Compare-and-set on the due time, so two schedulers can't both bill it
+    let claimed = sqlx::query(
+        "UPDATE subscriptions SET status = 'active', current_period_start = $1, current_period_end = $2, next_charge_at = $3, failed_attempts = 0 \
+         WHERE id = $4 AND next_charge_at = $5 AND status IN ('trialing', 'active', 'past_due')"
+    )
+    .bind(period_start.to_rfc3339())
+    .bind(period_end.to_rfc3339())
+    .bind(period_end.to_rfc3339())
+    .bind(subscription.id.to_string())
+    .bind(due_at.to_rfc3339())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to claim subscription: {}", e)))?;
+    if claimed.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    match charge(&mut tx, subscription, period_start, period_end).await {
+        Ok(_) => {
+            tx.commit().await
+                .map_err(|e| GurtError::invalid_message(format!("Failed to commit renewal: {}", e)))?;
+            Ok(true)
+        }
+        Err(ApiError::InsufficientFunds(_) | ApiError::LimitExceeded(_)) => {
+            drop(tx);
+            record_failed_renewal(pool, subscription, due_at).await?;
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn record_failed_renewal(pool: &AnyPool, subscription: &Subscription, due_at: DateTime<Utc>) -> Result<()> {
+    let attempts = subscription.failed_attempts + 1;
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+
+    if attempts >= MAX_FAILED_ATTEMPTS {
+        end_subscription(&mut tx, subscription, "payment_failed").await?;
+    } else {
+        let retry_at = Utc::now() + chrono::Duration::hours(RETRY_DELAY_HOURS);
+        let updated = sqlx::query(
+            "UPDATE subscriptions SET status = 'past_due', failed_attempts = $1, next_charge_at = $2 \
+             WHERE id = $3 AND next_charge_at = $4 AND status IN ('trialing', 'active', 'past_due')"
+        )
+        .bind(attempts)
+        .bind(retry_at.to_rfc3339())
+        .bind(subscription.id.to_string())
+        .bind(due_at.to_rfc3339())
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to mark subscription past due: {}", e)))?;
+
+        if updated.rows_affected() > 0 && subscription.status != SubscriptionStatus::PastDue {
+            webhooks::enqueue(&mut tx, subscription.business_id, Event::SubscriptionPastDue, serde_json::json!({
+                "subscription_id": subscription.id,
+                "plan_id": subscription.plan_id,
+                "user_id": subscription.user_id,
+                "amount": subscription.amount,
+                "next_attempt_at": retry_at.to_rfc3339(),
+            })).await?;
+        }
+    }
+
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit failed renewal: {}", e)))?;
+    Ok(())
+}
+
+/// Takes one period's payment from the user to the business. The charge row's
+/// unique (subscription, period start) pair is a second guard against billing
+/// the same period twice.
+async fn charge(conn: &mut AnyConnection, subscription: &Subscription, period_start: DateTime<Utc>, period_end: DateTime<Utc>) -> ApiResult<Transaction> {
+    let transaction_id = Uuid::new_v4();
+    let now = Utc::now();
+
+    sqlx::query(
+        "INSERT INTO subscription_charges (id, subscription_id, transaction_id, amount, period_start, period_end, created_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7)"
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(subscription.id.to_string())
+    .bind(transaction_id.to_string())
+    .bind(subscription.amount.minor())
+    .bind(period_start.to_rfc3339())
+    .bind(period_end.to_rfc3339())
+    .bind(now.to_rfc3339())
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to record subscription charge: {}", e)))?;
+
+    let description = format!("Subscription: {}", subscription.plan_name);
+    let transaction = record_business_payment(conn, transaction_id, &subscription.user_id, &subscription.business_id, subscription.amount, &description).await?;
+    limits::enforce(conn, subscription.user_id, subscription.amount, Some(transaction_id)).await?;
+
+    webhooks::enqueue(conn, subscription.business_id, Event::PaymentSucceeded, serde_json::json!({
+        "transaction_id": transaction.id,
+        "subscription_id": subscription.id,
+        "amount": subscription.amount,
+        "description": description,
+        "period_start": period_start.to_rfc3339(),
+        "period_end": period_end.to_rfc3339(),
+        "created_at": transaction.created_at.to_rfc3339(),
+    })).await?;
+
+    Ok(transaction)
+}
+
+/// Cancels a live subscription and tells the business why. Returns false if it
+/// was already cancelled.
+async fn end_subscription(conn: &mut AnyConnection, subscription: &Subscription, reason: &str) -> Result<bool> {
+    let now = Utc::now();
+    let ended = sqlx::query(
+        "UPDATE subscriptions SET status = 'cancelled', next_charge_at = NULL, cancelled_at = $1 \
+         WHERE id = $2 AND status <> 'cancelled'"
+    )
+    .bind(now.to_rfc3339())
+    .bind(subscription.id.to_string())
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to cancel subscription: {}", e)))?;
+    if ended.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    webhooks::enqueue(conn, subscription.business_id, Event::SubscriptionCancelled, serde_json::json!({
+        "subscription_id": subscription.id,
+        "plan_id": subscription.plan_id,
+        "user_id": subscription.user_id,
+        "reason": reason,
+        "cancelled_at": now.to_rfc3339(),
+    })).await?;
+    Ok(true)
+}
+
+const SELECT_PLAN: &str =
+    "SELECT id, business_id, name, amount, billing_interval, interval_count, trial_days, \
+            CASE WHEN active THEN 1 ELSE 0 END AS active, created_at \
+     FROM subscription_plans";
+
+const SELECT_SUBSCRIPTION: &str =
+    "SELECT s.id, s.plan_id, s.business_id, s.user_id, s.status, s.current_period_start, s.current_period_end, \
+            s.next_charge_at, s.failed_attempts, s.created_at, s.cancelled_at, \
+            p.name, p.amount, p.billing_interval, p.interval_count \
+     FROM subscriptions s JOIN subscription_plans p ON p.id = s.plan_id";
+
+fn parse_uuid(row: &sqlx::any::AnyRow, column: &str) -> Result<Uuid> {
+    Uuid::parse_str(&row.get::<String, _>(column))
+        .map_err(|e| GurtError::invalid_message(format!("Invalid {}: {}", column, e)))
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| GurtError::invalid_message(format!("Invalid timestamp {}: {}", value, e)))
+}
+
+fn parse_interval(row: &sqlx::any::AnyRow) -> Result<BillingInterval> {
+    let interval = row.get::<String, _>("billing_interval");
+    BillingInterval::parse(&interval)
+        .ok_or_else(|| GurtError::invalid_message(format!("Unknown billing interval: {}", interval)))
+}
+
+fn plan_from_row(row: &sqlx::any::AnyRow) -> Result<Plan> {
+    Ok(Plan {
+        id: parse_uuid(row, "id")?,
+        business_id: parse_uuid(row, "business_id")?,
+        name: row.get("name"),
+        amount: Money::from_minor(row.get("amount")),
+        interval: parse_interval(row)?,
+        interval_count: row.get::<i32, _>("interval_count"),
+        trial_days: row.get::<i32, _>("trial_days"),
+        active: row.get::<i64, _>("active") != 0,
+        created_at: parse_time(&row.get::<String, _>("created_at"))?,
+    })
+}
+
+fn subscription_from_row(row: &sqlx::any::AnyRow) -> Result<Subscription> {
+    let status = row.get::<String, _>("status");
+    Ok(Subscription {
+        id: parse_uuid(row, "id")?,
+        plan_id: parse_uuid(row, "plan_id")?,
+        business_id: parse_uuid(row, "business_id")?,
+        user_id: parse_uuid(row, "user_id")?,
+        plan_name: row.get("name"),
+        amount: Money::from_minor(row.get("amount")),
+        interval: parse_interval(row)?,
+        interval_count: row.get::<i32, _>("interval_count"),
+        status: SubscriptionStatus::parse(&status)
+            .ok_or_else(|| GurtError::invalid_message(format!("Unknown subscription status: {}", status)))?,
+        current_period_start: parse_time(&row.get::<String, _>("current_period_start"))?,
+        current_period_end: parse_time(&row.get::<String, _>("current_period_end"))?,
+        next_charge_at: row.get::<Option<String>, _>("next_charge_at").as_deref().map(parse_time).transpose()?,
+        failed_attempts: row.get::<i32, _>("failed_attempts"),
+        created_at: parse_time(&row.get::<String, _>("created_at"))?,
+        cancelled_at: row.get::<Option<String>, _>("cancelled_at").as_deref().map(parse_time).transpose()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{create_user_with_password, get_business_by_id, get_user_by_id, test_business, test_pool, WELCOME_BONUS};
+
+    async fn plan(pool: &AnyPool, business_id: Uuid, amount: Money, trial_days: i32) -> Plan {
+        create_plan(pool, business_id, &CreatePlanRequest {
+            name: "Pro".to_string(),
+            amount,
+            interval: "month".to_string(),
+            interval_count: None,
+            trial_days: Some(trial_days),
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn subscriber(pool: &AnyPool) -> Uuid {
+        create_user_with_password(pool, &format!("subscriber-{}", Uuid::new_v4()), "unused").await.unwrap().id
+    }
+
+    async fn current(pool: &AnyPool, user_id: Uuid) -> Subscription {
+        list_for_user(pool, user_id).await.unwrap().remove(0)
+    }
+
+    /// Moves the end of the current period, and so the next charge, into the past.
+    async fn make_due(pool: &AnyPool, subscription_id: Uuid) {
+        let due = (Utc::now() - chrono::Duration::minutes(1)).to_rfc3339();
+        sqlx::query("UPDATE subscriptions SET current_period_end = $1, next_charge_at = $2 WHERE id = $3")
+            .bind(&due)
+            .bind(&due)
+            .bind(subscription_id.to_string())
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn charges(pool: &AnyPool, subscription_id: Uuid) -> i64 {
+        sqlx::query("SELECT COUNT(*) AS n FROM subscription_charges WHERE subscription_id = $1")
+            .bind(subscription_id.to_string())
+            .fetch_one(pool)
+            .await
+            .unwrap()
+            .get("n")
+    }
+
+    #[tokio::test]
+    async fn trials_renew_into_a_paid_period() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let plan = plan(&pool, business_id, Money::from_major(30), 7).await;
+        let user_id = subscriber(&pool).await;
+
+        let subscription = subscribe(&pool, user_id, plan.id).await.unwrap();
+        assert_eq!(subscription.status, SubscriptionStatus::Trialing);
+        assert_eq!(charges(&pool, subscription.id).await, 0);
+
+        make_due(&pool, subscription.id).await;
+        assert_eq!(renew_due(&pool).await.unwrap(), 1);
+
+        let renewed = current(&pool, user_id).await;
+        assert_eq!(renewed.status, SubscriptionStatus::Active);
+        assert!(renewed.next_charge_at.unwrap() > Utc::now());
+        assert_eq!(charges(&pool, subscription.id).await, 1);
+        assert_eq!(get_user_by_id(&pool, user_id).await.unwrap().unwrap().wallet_balance, Money::from_major(4970));
+        assert_eq!(get_business_by_id(&pool, business_id).await.unwrap().unwrap().balance, Money::from_major(30));
+    }
+
+    #[tokio::test]
+    async fn unpaid_renewals_retry_then_cancel() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let plan = plan(&pool, business_id, WELCOME_BONUS.checked_add(Money::from_major(1)).unwrap(), 7).await;
+        let user_id = subscriber(&pool).await;
+        let subscription = subscribe(&pool, user_id, plan.id).await.unwrap();
+
+        for attempt in 1..MAX_FAILED_ATTEMPTS {
+            make_due(&pool, subscription.id).await;
+            assert_eq!(renew_due(&pool).await.unwrap(), 0);
+            let past_due = current(&pool, user_id).await;
+            assert_eq!(past_due.status, SubscriptionStatus::PastDue);
+            assert_eq!(past_due.failed_attempts, attempt);
+            assert!(past_due.next_charge_at.unwrap() > Utc::now());
+        }
+
+        make_due(&pool, subscription.id).await;
+        assert_eq!(renew_due(&pool).await.unwrap(), 0);
+        let cancelled = current(&pool, user_id).await;
+        assert_eq!(cancelled.status, SubscriptionStatus::Cancelled);
+        assert!(cancelled.next_charge_at.is_none());
+        assert_eq!(charges(&pool, subscription.id).await, 0);
+        assert_eq!(get_user_by_id(&pool, user_id).await.unwrap().unwrap().wallet_balance, WELCOME_BONUS);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_renewals_bill_once() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let plan = plan(&pool, business_id, Money::from_major(30), 7).await;
+        let user_id = subscriber(&pool).await;
+        let subscription = subscribe(&pool, user_id, plan.id).await.unwrap();
+        make_due(&pool, subscription.id).await;
+        let due = current(&pool, user_id).await;
+
+        let (first, second) = tokio::join!(
+            tokio::spawn({
+                let (pool, due) = (pool.clone(), due.clone());
+                async move { renew(&pool, &due).await }
+            }),
+            tokio::spawn({
+                let (pool, due) = (pool.clone(), due.clone());
+                async move { renew(&pool, &due).await }
+            }),
+        );
+
+        assert_ne!(first.unwrap().unwrap(), second.unwrap().unwrap());
+        assert_eq!(charges(&pool, subscription.id).await, 1);
+        assert_eq!(get_user_by_id(&pool, user_id).await.unwrap().unwrap().wallet_balance, Money::from_major(4970));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_sign_ups_subscribe_once() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let plan = plan(&pool, business_id, Money::from_major(30), 0).await;
+        let user_id = subscriber(&pool).await;
+
+        let (first, second) = tokio::join!(
+            tokio::spawn({
+                let pool = pool.clone();
+                async move { subscribe(&pool, user_id, plan.id).await }
+            }),
+            tokio::spawn({
+                let pool = pool.clone();
+                async move { subscribe(&pool, user_id, plan.id).await }
+            }),
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+
+        assert_ne!(first.is_ok(), second.is_ok());
+        assert!(matches!(first.as_ref().err().or(second.as_ref().err()), Some(ApiError::Conflict(_))));
+        assert_eq!(list_for_user(&pool, user_id).await.unwrap().len(), 1);
+        assert_eq!(get_user_by_id(&pool, user_id).await.unwrap().unwrap().wallet_balance, Money::from_major(4970));
+    }
+
+    #[tokio::test]
+    async fn the_same_plan_can_be_taken_again_after_cancelling() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let plan = plan(&pool, business_id, Money::from_major(30), 7).await;
+        let user_id = subscriber(&pool).await;
+        let first = subscribe(&pool, user_id, plan.id).await.unwrap();
+        cancel(&pool, Owner::User(user_id), first.id).await.unwrap();
+
+        let second = subscribe(&pool, user_id, plan.id).await.unwrap();
+
+        assert_ne!(first.id, second.id);
+        assert!(matches!(subscribe(&pool, user_id, plan.id).await, Err(ApiError::Conflict(_))));
+    }
+
+    #[tokio::test]
+    async fn charges_over_the_transfer_limit_are_refused() {
+        let pool = test_pool().await;
+        let business_id = test_business(&pool).await;
+        let user_id = subscriber(&pool).await;
+        crate::limits::set_user(&pool, Uuid::new_v4(), &crate::models::SetUserLimitsRequest {
+            user_id,
+            tier: None,
+            per_transaction: Some(Money::from_major(20)),
+            daily: None,
+            monthly: None,
+        })
+        .await
+        .unwrap();
+
+        let immediate = plan(&pool, business_id, Money::from_major(30), 0).await;
+        assert!(matches!(subscribe(&pool, user_id, immediate.id).await, Err(ApiError::LimitExceeded(_))));
+        assert!(list_for_user(&pool, user_id).await.unwrap().is_empty());
+
+        let trial = plan(&pool, business_id, Money::from_major(30), 7).await;
+        let subscription = subscribe(&pool, user_id, trial.id).await.unwrap();
+        make_due(&pool, subscription.id).await;
+        assert_eq!(renew_due(&pool).await.unwrap(), 0);
+
+        let past_due = current(&pool, user_id).await;
+        assert_eq!(past_due.status, SubscriptionStatus::PastDue);
+        assert_eq!(past_due.failed_attempts, 1);
+        assert_eq!(get_user_by_id(&pool, user_id).await.unwrap().unwrap().wallet_balance, WELCOME_BONUS);
+    }
+}
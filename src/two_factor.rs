@@ -0,0 +1,562 @@
+use crate::error::{ApiError, ApiResult};
+use crate::lockout;
+use crate::models::User;
+use crate::money::Money;
+use chrono::{DateTime, Utc};
+use gurtlib::{GurtError, Result};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::Serialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use sqlx::{AnyConnection, AnyPool, Row};
+use uuid::Uuid;
+
+const ISSUER: &str = "GurtPay";
+/// RFC 6238 defaults, which is what authenticator apps assume.
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Codes from one step either side of now are accepted, for clock drift.
+const SKEW_STEPS: i64 = 1;
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+/// How long the second login step stays open after the password was accepted.
+const CHALLENGE_MINUTES: i64 = 5;
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+
+/// What an authenticator app needs to add the account.
+#[derive(Debug, Serialize)]
+pub struct Enrollment {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub enabled: bool,
+    /// Sends above this need a code. `None` never asks.
+    pub send_threshold: Option<Money>,
+    pub recovery_codes_remaining: i64,
+}
+
+/// Handed out instead of a session when the password was right but the
+/// account has two-factor enabled.
+#[derive(Debug, Serialize)]
+pub struct LoginChallenge {
+    pub challenge_token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub async fn status(pool: &AnyPool, user_id: Uuid) -> Result<Status> {
+    let row = sqlx::query(
+        "SELECT CASE WHEN enabled THEN 1 ELSE 0 END AS enabled, send_threshold FROM user_two_factor WHERE user_id = $1"
+    )
+    .bind(user_id.to_string())
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to get two-factor settings: {}", e)))?;
+
+    let remaining = sqlx::query("SELECT COUNT(*) AS remaining FROM two_factor_recovery_codes WHERE user_id = $1 AND used_at IS NULL")
+        .bind(user_id.to_string())
+        .fetch_one(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to count recovery codes: {}", e)))?
+        .get::<i64, _>("remaining");
+
+    Ok(Status {
+        enabled: row.as_ref().is_some_and(|row| row.get::<i64, _>("enabled") != 0),
+        send_threshold: row.and_then(|row| row.get::<Option<i64>, _>("send_threshold")).map(Money::from_minor),
+        recovery_codes_remaining: remaining,
+    })
+}
+
+pub async fn is_enabled(pool: &AnyPool, user_id: Uuid) -> Result<bool> {
+    Ok(status(pool, user_id).await?.enabled)
+}
+
+/// Generates a new secret for the user to add to their authenticator app.
+/// Nothing changes for their logins until `enable` confirms a code from it.
+pub async fn begin_setup(pool: &AnyPool, user: &User) -> ApiResult<Enrollment> {
+    if is_enabled(pool, user.id).await? {
+        return Err(ApiError::conflict("Two-factor authentication is already enabled"));
+    }
+
+    let secret_bytes: Vec<u8> = (0..SECRET_BYTES).map(|_| rand::thread_rng().gen()).collect();
+    let secret = base32::encode(base32::Alphabet::Rfc4648 { padding: false }, &secret_bytes);
+
+    sqlx::query(
+        "INSERT INTO user_two_factor (user_id, secret, enabled, created_at) VALUES ($1, $2, FALSE, $3) \
+         ON CONFLICT (user_id) DO UPDATE SET secret = excluded.secret, enabled = FALSE, last_used_step = NULL, created_at = excluded.created_at"
+    )
+    .bind(user.id.to_string())
+    .bind(&secret)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to save two-factor secret: {}", e)))?;
+
+    let label: String = url::form_urlencoded::byte_serialize(format!("{}:{}", ISSUER, user.username).as_bytes()).collect();
+    let provisioning_uri = format!(
+        "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        label, secret, ISSUER, DIGITS, STEP_SECS
+    );
+    Ok(Enrollment { secret, provisioning_uri })
+}
+
+/// Turns two-factor on once the user proves their app produces the right
+/// codes. Returns recovery codes, which are only ever shown this once.
+pub async fn enable(pool: &AnyPool, user_id: Uuid, code: &str) -> ApiResult<Vec<String>> {
+    let row = sqlx::query("SELECT secret, CASE WHEN enabled THEN 1 ELSE 0 END AS enabled FROM user_two_factor WHERE user_id = $1")
+        .bind(user_id.to_string())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to get two-factor settings: {}", e)))?
+        .ok_or_else(|| ApiError::conflict("Start two-factor setup first"))?;
+    if row.get::<i64, _>("enabled") != 0 {
+        return Err(ApiError::conflict("Two-factor authentication is already enabled"));
+    }
+    verify_totp(pool, user_id, &row.get::<String, _>("secret"), code).await?;
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+    sqlx::query("UPDATE user_two_factor SET enabled = TRUE, enabled_at = $1 WHERE user_id = $2")
+        .bind(Utc::now().to_rfc3339())
+        .bind(user_id.to_string())
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to enable two-factor: {}", e)))?;
+    let codes = replace_recovery_codes(&mut tx, user_id).await?;
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit two-factor setup: {}", e)))?;
+
+    Ok(codes)
+}
+
+/// Turns two-factor off. Takes a current code or an unused recovery code.
+pub async fn disable(pool: &AnyPool, user_id: Uuid, code: &str) -> ApiResult<()> {
+    verify(pool, user_id, code).await?;
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+    sqlx::query("DELETE FROM two_factor_recovery_codes WHERE user_id = $1")
+        .bind(user_id.to_string())
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to delete recovery codes: {}", e)))?;
+    sqlx::query("DELETE FROM user_two_factor WHERE user_id = $1")
+        .bind(user_id.to_string())
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to disable two-factor: {}", e)))?;
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit two-factor removal: {}", e)))?;
+
+    Ok(())
+}
+
+/// Replaces every recovery code with a fresh set.
+pub async fn regenerate_recovery_codes(pool: &AnyPool, user_id: Uuid, code: &str) -> ApiResult<Vec<String>> {
+    verify(pool, user_id, code).await?;
+
+    let mut tx = pool.begin().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to start transaction: {}", e)))?;
+    let codes = replace_recovery_codes(&mut tx, user_id).await?;
+    tx.commit().await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to commit recovery codes: {}", e)))?;
+    Ok(codes)
+}
+
+/// Sets the amount above which sending money needs a code, or `None` to never
+/// ask. Changing it takes a code too, so a stolen session can't lift it.
+pub async fn set_send_threshold(pool: &AnyPool, user_id: Uuid, code: &str, threshold: Option<Money>) -> ApiResult<()> {
+    if threshold.is_some_and(Money::is_negative) {
+        return Err(ApiError::validation("Threshold can't be negative"));
+    }
+    verify(pool, user_id, code).await?;
+
+    sqlx::query("UPDATE user_two_factor SET send_threshold = $1 WHERE user_id = $2")
+        .bind(threshold.map(Money::minor))
+        .bind(user_id.to_string())
+        .execute(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to save send threshold: {}", e)))?;
+    Ok(())
+}
+
+/// Step-up check for sending `amount`: passes when the user has no threshold or
+/// the amount is within it, and otherwise needs a valid code.
+pub async fn require_for_send(pool: &AnyPool, user_id: Uuid, amount: Money, code: Option<&str>) -> ApiResult<()> {
+    let status = status(pool, user_id).await?;
+    let Some(threshold) = status.send_threshold.filter(|_| status.enabled) else { return Ok(()) };
+    if amount <= threshold {
+        return Ok(());
+    }
+
+    let Some(code) = code else {
+        return Err(ApiError::two_factor_required(format!("Sending more than {} GC needs a two-factor code", threshold)));
+    };
+    verify(pool, user_id, code).await.map_err(|e| match e {
+        ApiError::Unauthorized(message) => ApiError::TwoFactorRequired(message),
+        other => other,
+    })
+}
+
+/// Opens the second login step for a user whose password was accepted.
+pub async fn start_login(pool: &AnyPool, user_id: Uuid) -> Result<LoginChallenge> {
+    let now = Utc::now();
+    sqlx::query("DELETE FROM login_challenges WHERE expires_at < $1")
+        .bind(now.to_rfc3339())
+        .execute(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to clear login challenges: {}", e)))?;
+
+    let challenge_token = format!("lc_{}", random_string(40));
+    let expires_at = now + chrono::Duration::minutes(CHALLENGE_MINUTES);
+    sqlx::query(
+        "INSERT INTO login_challenges (id, user_id, token_hash, attempts, expires_at, created_at) VALUES ($1, $2, $3, 0, $4, $5)"
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(user_id.to_string())
+    .bind(hash_secret(&challenge_token))
+    .bind(expires_at.to_rfc3339())
+    .bind(now.to_rfc3339())
+    .execute(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to create login challenge: {}", e)))?;
+
+    Ok(LoginChallenge { challenge_token, expires_at })
+}
+
+/// Checks the code for an open login challenge and closes it. Returns the user
+/// to issue a session for.
+pub async fn complete_login(pool: &AnyPool, challenge_token: &str, code: &str) -> ApiResult<Uuid> {
+    let token_hash = hash_secret(challenge_token);
+    let row = sqlx::query("SELECT id, user_id, attempts FROM login_challenges WHERE token_hash = $1 AND expires_at > $2")
+        .bind(&token_hash)
+        .bind(Utc::now().to_rfc3339())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to get login challenge: {}", e)))?
+        .ok_or_else(|| ApiError::unauthorized("Login challenge is invalid or expired; sign in again"))?;
+    let challenge_id: String = row.get("id");
+    let user_id = Uuid::parse_str(&row.get::<String, _>("user_id"))
+        .map_err(|e| GurtError::invalid_message(format!("Invalid user_id: {}", e)))?;
+
+    if let Err(e) = verify(pool, user_id, code).await {
+        let attempts = row.get::<i32, _>("attempts") + 1;
+        let query = if attempts >= MAX_CHALLENGE_ATTEMPTS {
+            sqlx::query("DELETE FROM login_challenges WHERE id = $1").bind(&challenge_id)
+        } else {
+            sqlx::query("UPDATE login_challenges SET attempts = attempts + 1 WHERE id = $1").bind(&challenge_id)
+        };
+        query.execute(pool)
+            .await
+            .map_err(|e| GurtError::invalid_message(format!("Failed to update login challenge: {}", e)))?;
+        return Err(e);
+    }
+
+    // Deleting is the claim: a challenge can only be completed once
+    let claimed = sqlx::query("DELETE FROM login_challenges WHERE id = $1")
+        .bind(&challenge_id)
+        .execute(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to close login challenge: {}", e)))?;
+    if claimed.rows_affected() == 0 {
+        return Err(ApiError::unauthorized("Login challenge is invalid or expired; sign in again"));
+    }
+    Ok(user_id)
+}
+
+/// Accepts a current authenticator code, or uses up one of the recovery codes.
+/// Wrong codes count towards a lockout on the user, as wrong passwords do.
+pub async fn verify(pool: &AnyPool, user_id: Uuid, code: &str) -> ApiResult<()> {
+    let keys = [lockout::Key::two_factor(user_id)];
+    lockout::check(pool, &keys).await?;
+
+    match check_code(pool, user_id, code).await {
+        Ok(()) => {
+            lockout::clear(pool, &keys[0]).await?;
+            Ok(())
+        }
+        Err(e @ ApiError::Unauthorized(_)) => {
+            lockout::record_failure(pool, &keys).await?;
+            Err(e)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn check_code(pool: &AnyPool, user_id: Uuid, code: &str) -> ApiResult<()> {
+    let secret = sqlx::query("SELECT secret FROM user_two_factor WHERE user_id = $1 AND enabled = TRUE")
+        .bind(user_id.to_string())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to get two-factor settings: {}", e)))?
+        .map(|row| row.get::<String, _>("secret"))
+        .ok_or_else(|| ApiError::conflict("Two-factor authentication is not enabled"))?;
+
+    let code = code.trim();
+    if code.len() == DIGITS as usize && code.chars().all(|c| c.is_ascii_digit()) {
+        return verify_totp(pool, user_id, &secret, code).await;
+    }
+
+    let used = sqlx::query(
+        "UPDATE two_factor_recovery_codes SET used_at = $1 WHERE user_id = $2 AND code_hash = $3 AND used_at IS NULL"
+    )
+    .bind(Utc::now().to_rfc3339())
+    .bind(user_id.to_string())
+    .bind(hash_secret(&normalize_recovery_code(code)))
+    .execute(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to use recovery code: {}", e)))?;
+    if used.rows_affected() == 0 {
+        return Err(ApiError::unauthorized("Invalid two-factor code"));
+    }
+    Ok(())
+}
+
+/// Checks `code` against the steps around now and records the step it matched,
+/// so a code can't be replayed.
+async fn verify_totp(pool: &AnyPool, user_id: Uuid, secret: &str, code: &str) -> ApiResult<()> {
+    verify_totp_at(pool, user_id, secret, code, Utc::now()).await
+}
+
+async fn verify_totp_at(pool: &AnyPool, user_id: Uuid, secret: &str, code: &str, now: DateTime<Utc>) -> ApiResult<()> {
+    let key = base32::decode(base32::Alphabet::Rfc4648 { padding: false }, secret)
+        .ok_or_else(|| GurtError::invalid_message("Invalid two-factor secret"))?;
+    let code = code.trim();
+    let now_step = now.timestamp() / STEP_SECS;
+
+    let matched = (now_step - SKEW_STEPS..=now_step + SKEW_STEPS)
+        .find(|&step| format!("{:0width$}", totp_at(&key, step), width = DIGITS as usize) == code)
+        .ok_or_else(|| ApiError::unauthorized("Invalid two-factor code"))?;
+
+    let recorded = sqlx::query(
+        "UPDATE user_two_factor SET last_used_step = $1 WHERE user_id = $2 AND (last_used_step IS NULL OR last_used_step < $3)"
+    )
+    .bind(matched)
+    .bind(user_id.to_string())
+    .bind(matched)
+    .execute(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to record two-factor code: {}", e)))?;
+    if recorded.rows_affected() == 0 {
+        return Err(ApiError::unauthorized("Two-factor code was already used; wait for the next one"));
+    }
+    Ok(())
+}
+
+/// The RFC 4226 HOTP value for one time step.
+fn totp_at(key: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&(step as u64).to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]) & 0x7fff_ffff;
+    binary % 10u32.pow(DIGITS)
+}
+
+async fn replace_recovery_codes(conn: &mut AnyConnection, user_id: Uuid) -> Result<Vec<String>> {
+    sqlx::query("DELETE FROM two_factor_recovery_codes WHERE user_id = $1")
+        .bind(user_id.to_string())
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to delete recovery codes: {}", e)))?;
+
+    let now = Utc::now().to_rfc3339();
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let raw = random_string(RECOVERY_CODE_LEN).to_lowercase();
+        let code = format!("{}-{}", &raw[..RECOVERY_CODE_LEN / 2], &raw[RECOVERY_CODE_LEN / 2..]);
+        sqlx::query("INSERT INTO two_factor_recovery_codes (id, user_id, code_hash, created_at) VALUES ($1, $2, $3, $4)")
+            .bind(Uuid::new_v4().to_string())
+            .bind(user_id.to_string())
+            .bind(hash_secret(&normalize_recovery_code(&code)))
+            .bind(&now)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| GurtError::invalid_message(format!("Failed to store recovery code: {}", e)))?;
+        codes.push(code);
+    }
+    Ok(codes)
+}
+
+/// Recovery codes are accepted with or without the dash and in any case.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase()
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn hash_secret(value: &str) -> String {
+    format!("{:x}", Sha256::digest(value.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{create_user_with_password, test_pool};
+
+    /// The SHA-1 seed from RFC 6238 Appendix B, "12345678901234567890".
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    /// RFC 6238 Appendix B SHA-1 vectors. The RFC prints 8 digits; a 6 digit
+    /// code is the same value with the top two dropped.
+    const RFC_VECTORS: [(i64, &str); 6] = [
+        (59, "94287082"),
+        (1111111109, "07081804"),
+        (1111111111, "14050471"),
+        (1234567890, "89005924"),
+        (2000000000, "69279037"),
+        (20000000000, "65353130"),
+    ];
+
+    fn at(timestamp: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(timestamp, 0).unwrap()
+    }
+
+    fn code_at(secret: &str, timestamp: i64) -> String {
+        let key = base32::decode(base32::Alphabet::Rfc4648 { padding: false }, secret).unwrap();
+        format!("{:06}", totp_at(&key, timestamp / STEP_SECS))
+    }
+
+    /// A user with two-factor enabled on the RFC secret.
+    async fn enrolled_user(pool: &AnyPool) -> Uuid {
+        let user = create_user_with_password(pool, &format!("totp-{}", Uuid::new_v4()), "unused").await.unwrap();
+        sqlx::query("INSERT INTO user_two_factor (user_id, secret, enabled, created_at) VALUES ($1, $2, TRUE, $3)")
+            .bind(user.id.to_string())
+            .bind(RFC_SECRET)
+            .bind(Utc::now().to_rfc3339())
+            .execute(pool)
+            .await
+            .unwrap();
+        user.id
+    }
+
+    #[test]
+    fn totp_at_matches_rfc_6238_vectors() {
+        let key = b"12345678901234567890";
+        for (timestamp, expected) in RFC_VECTORS {
+            assert_eq!(format!("{:06}", totp_at(key, timestamp / STEP_SECS)), expected[2..], "T = {}", timestamp);
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_totp_accepts_rfc_6238_vectors() {
+        let pool = test_pool().await;
+        for (timestamp, expected) in RFC_VECTORS {
+            let user_id = enrolled_user(&pool).await;
+            verify_totp_at(&pool, user_id, RFC_SECRET, &expected[2..], at(timestamp)).await
+                .unwrap_or_else(|e| panic!("T = {}: {}", timestamp, e));
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_totp_allows_one_step_of_drift() {
+        let pool = test_pool().await;
+        let now = 1111111111;
+        let code = code_at(RFC_SECRET, now);
+
+        verify_totp_at(&pool, enrolled_user(&pool).await, RFC_SECRET, &code, at(now - STEP_SECS)).await.unwrap();
+        verify_totp_at(&pool, enrolled_user(&pool).await, RFC_SECRET, &code, at(now + STEP_SECS)).await.unwrap();
+        let late = verify_totp_at(&pool, enrolled_user(&pool).await, RFC_SECRET, &code, at(now + 2 * STEP_SECS)).await;
+        assert!(matches!(late, Err(ApiError::Unauthorized(_))));
+        let wrong = verify_totp_at(&pool, enrolled_user(&pool).await, RFC_SECRET, "000000", at(now)).await;
+        assert!(matches!(wrong, Err(ApiError::Unauthorized(_))));
+    }
+
+    #[tokio::test]
+    async fn verify_totp_rejects_a_reused_step() {
+        let pool = test_pool().await;
+        let user_id = enrolled_user(&pool).await;
+        let now = 1234567890;
+        let code = code_at(RFC_SECRET, now);
+
+        verify_totp_at(&pool, user_id, RFC_SECRET, &code, at(now)).await.unwrap();
+        let replayed = verify_totp_at(&pool, user_id, RFC_SECRET, &code, at(now)).await;
+        assert!(matches!(replayed, Err(ApiError::Unauthorized(_))));
+
+        // Once a step is used, the one before it is closed too
+        let earlier = code_at(RFC_SECRET, now - STEP_SECS);
+        let stale = verify_totp_at(&pool, user_id, RFC_SECRET, &earlier, at(now)).await;
+        assert!(matches!(stale, Err(ApiError::Unauthorized(_))));
+
+        let next = code_at(RFC_SECRET, now + STEP_SECS);
+        verify_totp_at(&pool, user_id, RFC_SECRET, &next, at(now + STEP_SECS)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_work_once() {
+        let pool = test_pool().await;
+        let user = create_user_with_password(&pool, &format!("totp-{}", Uuid::new_v4()), "unused").await.unwrap();
+        let enrollment = begin_setup(&pool, &user).await.unwrap();
+        let codes = enable(&pool, user.id, &code_at(&enrollment.secret, Utc::now().timestamp())).await.unwrap();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert_eq!(status(&pool, user.id).await.unwrap().recovery_codes_remaining, RECOVERY_CODE_COUNT as i64);
+
+        verify(&pool, user.id, &codes[0]).await.unwrap();
+        assert!(matches!(verify(&pool, user.id, &codes[0]).await, Err(ApiError::Unauthorized(_))));
+
+        // Dash and case don't matter, but it is still the same code
+        let shouted = codes[1].replace('-', "").to_uppercase();
+        verify(&pool, user.id, &shouted).await.unwrap();
+        assert!(matches!(verify(&pool, user.id, &codes[1]).await, Err(ApiError::Unauthorized(_))));
+
+        assert_eq!(status(&pool, user.id).await.unwrap().recovery_codes_remaining, RECOVERY_CODE_COUNT as i64 - 2);
+    }
+
+    #[tokio::test]
+    async fn wrong_codes_lock_the_user_out() {
+        let pool = test_pool().await;
+        let user_id = enrolled_user(&pool).await;
+        for _ in 0..lockout::FREE_ATTEMPTS {
+            assert!(matches!(verify(&pool, user_id, "000000").await, Err(ApiError::Unauthorized(_))));
+        }
+
+        // Even the right code has to wait
+        let code = code_at(RFC_SECRET, Utc::now().timestamp());
+        assert!(matches!(verify(&pool, user_id, &code).await, Err(ApiError::TooManyAttempts(_))));
+
+        // Another user's codes are not held up
+        let other = enrolled_user(&pool).await;
+        verify(&pool, other, &code).await.unwrap();
+
+        let user = crate::database::get_user_by_id(&pool, user_id).await.unwrap().unwrap();
+        lockout::unlock_account(&pool, other, &user.username).await.unwrap();
+        verify(&pool, user_id, &code).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn require_for_send_asks_for_a_code_above_the_threshold() {
+        let pool = test_pool().await;
+        let user_id = enrolled_user(&pool).await;
+        let threshold = Money::from_major(100);
+
+        // No threshold set, nothing is asked
+        require_for_send(&pool, user_id, Money::from_major(1000), None).await.unwrap();
+
+        sqlx::query("UPDATE user_two_factor SET send_threshold = $1 WHERE user_id = $2")
+            .bind(threshold.minor())
+            .bind(user_id.to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+        require_for_send(&pool, user_id, threshold, None).await.unwrap();
+
+        let above = threshold.checked_add(Money::from_minor(1)).unwrap();
+        let missing = require_for_send(&pool, user_id, above, None).await;
+        assert!(matches!(missing, Err(ApiError::TwoFactorRequired(_))));
+        let wrong = require_for_send(&pool, user_id, above, Some("000000")).await;
+        assert!(matches!(wrong, Err(ApiError::TwoFactorRequired(_))));
+
+        let code = code_at(RFC_SECRET, Utc::now().timestamp());
+        require_for_send(&pool, user_id, above, Some(&code)).await.unwrap();
+    }
+}
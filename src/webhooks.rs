@@ -0,0 +1,590 @@
+use crate::error::{ApiError, ApiResult};
+use chrono::{DateTime, Utc};
+use gurtlib::{GurtError, Result};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::{AnyConnection, AnyPool, Row};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// A delivery that still hasn't been accepted after this many tries is given up on.
+const MAX_ATTEMPTS: i32 = 10;
+/// First retry delay; doubles with every failed attempt up to `MAX_BACKOFF_SECS`.
+const BASE_BACKOFF_SECS: i64 = 30;
+const MAX_BACKOFF_SECS: i64 = 3600;
+/// How long a claimed delivery is hidden from other dispatchers. Longer than the
+/// request timeout, so a dispatcher that dies mid-send only delays the retry.
+const CLAIM_LEASE_SECS: i64 = 60;
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+const POLL_INTERVAL_SECS: u64 = 5;
+const BATCH_SIZE: i64 = 50;
+const MAX_ENDPOINTS_PER_BUSINESS: i64 = 10;
+const DELIVERY_LOG_LIMIT: i64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    InvoicePaid,
+    InvoiceExpired,
+    PaymentSucceeded,
+    RefundCreated,
+    SubscriptionPastDue,
+    SubscriptionCancelled,
+}
+
+impl Event {
+    pub const ALL: [Event; 6] = [
+        Event::InvoicePaid,
+        Event::InvoiceExpired,
+        Event::PaymentSucceeded,
+        Event::RefundCreated,
+        Event::SubscriptionPastDue,
+        Event::SubscriptionCancelled,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Event::InvoicePaid => "invoice.paid",
+            Event::InvoiceExpired => "invoice.expired",
+            Event::PaymentSucceeded => "payment.succeeded",
+            Event::RefundCreated => "refund.created",
+            Event::SubscriptionPastDue => "subscription.past_due",
+            Event::SubscriptionCancelled => "subscription.cancelled",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Event> {
+        Event::ALL.into_iter().find(|event| event.as_str() == name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookEndpoint {
+    pub id: Uuid,
+    pub url: String,
+    pub events: Vec<String>,
+    pub active: bool,
+    /// Only returned when the endpoint is created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub endpoint_id: Uuid,
+    pub event_id: Uuid,
+    pub event_type: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_status_code: Option<i32>,
+    pub last_error: Option<String>,
+    pub next_attempt_at: Option<String>,
+    pub created_at: String,
+    pub delivered_at: Option<String>,
+}
+
+/// Registers a new endpoint for the business. An empty `events` list subscribes
+/// it to every event. The signing secret is generated here and only ever
+/// returned from this call.
+pub async fn create_endpoint(pool: &AnyPool, business_id: Uuid, url: &str, events: &[String]) -> ApiResult<WebhookEndpoint> {
+    let url = url.trim();
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        return Err(ApiError::validation("Webhook URL must start with http:// or https://"));
+    }
+    for name in events {
+        if Event::parse(name).is_none() {
+            return Err(ApiError::validation(format!("Unknown webhook event: {}", name)));
+        }
+    }
+
+    let count = sqlx::query("SELECT COUNT(*) AS count FROM webhook_endpoints WHERE business_id = $1 AND active = TRUE")
+        .bind(business_id.to_string())
+        .fetch_one(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to count webhook endpoints: {}", e)))?;
+    if count.get::<i64, _>("count") >= MAX_ENDPOINTS_PER_BUSINESS {
+        return Err(ApiError::validation(format!("A business can have at most {} webhook endpoints", MAX_ENDPOINTS_PER_BUSINESS)));
+    }
+
+    let events: Vec<String> = if events.is_empty() {
+        Event::ALL.iter().map(|event| event.as_str().to_string()).collect()
+    } else {
+        events.to_vec()
+    };
+    let endpoint = WebhookEndpoint {
+        id: Uuid::new_v4(),
+        url: url.to_string(),
+        events,
+        active: true,
+        secret: Some(generate_secret()),
+        created_at: Utc::now(),
+    };
+
+    sqlx::query(
+        "INSERT INTO webhook_endpoints (id, business_id, url, secret, events, active, created_at) \
+         VALUES ($1, $2, $3, $4, $5, TRUE, $6)"
+    )
+    .bind(endpoint.id.to_string())
+    .bind(business_id.to_string())
+    .bind(&endpoint.url)
+    .bind(&endpoint.secret)
+    .bind(endpoint.events.join(","))
+    .bind(endpoint.created_at.to_rfc3339())
+    .execute(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to create webhook endpoint: {}", e)))?;
+
+    Ok(endpoint)
+}
+
+pub async fn list_endpoints(pool: &AnyPool, business_id: Uuid) -> Result<Vec<WebhookEndpoint>> {
+    let rows = sqlx::query(
+        "SELECT id, url, events, CASE WHEN active THEN 1 ELSE 0 END AS active, created_at \
+         FROM webhook_endpoints WHERE business_id = $1 ORDER BY created_at DESC"
+    )
+    .bind(business_id.to_string())
+    .fetch_all(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to list webhook endpoints: {}", e)))?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| WebhookEndpoint {
+            id: Uuid::parse_str(&row.get::<String, _>("id")).unwrap(),
+            url: row.get("url"),
+            events: row.get::<String, _>("events").split(',').map(str::to_string).collect(),
+            active: row.get::<i64, _>("active") != 0,
+            secret: None,
+            created_at: DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at")).unwrap().with_timezone(&Utc),
+        })
+        .collect())
+}
+
+/// Stops sending to the endpoint. Deliveries still queued for it are marked
+/// failed the next time the dispatcher picks them up.
+pub async fn disable_endpoint(pool: &AnyPool, business_id: Uuid, endpoint_id: Uuid) -> ApiResult<()> {
+    let result = sqlx::query("UPDATE webhook_endpoints SET active = FALSE WHERE id = $1 AND business_id = $2")
+        .bind(endpoint_id.to_string())
+        .bind(business_id.to_string())
+        .execute(pool)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to disable webhook endpoint: {}", e)))?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::not_found("Webhook endpoint not found"));
+    }
+    Ok(())
+}
+
+/// Most recent deliveries for the business, newest first.
+pub async fn list_deliveries(pool: &AnyPool, business_id: Uuid) -> Result<Vec<WebhookDelivery>> {
+    let rows = sqlx::query(
+        "SELECT id, endpoint_id, event_id, event_type, status, attempts, last_status_code, last_error, \
+                next_attempt_at, created_at, delivered_at \
+         FROM webhook_deliveries WHERE business_id = $1 ORDER BY created_at DESC LIMIT $2"
+    )
+    .bind(business_id.to_string())
+    .bind(DELIVERY_LOG_LIMIT)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to list webhook deliveries: {}", e)))?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let status: String = row.get("status");
+            WebhookDelivery {
+                id: Uuid::parse_str(&row.get::<String, _>("id")).unwrap(),
+                endpoint_id: Uuid::parse_str(&row.get::<String, _>("endpoint_id")).unwrap(),
+                event_id: Uuid::parse_str(&row.get::<String, _>("event_id")).unwrap(),
+                event_type: row.get("event_type"),
+                attempts: row.get("attempts"),
+                last_status_code: row.get("last_status_code"),
+                last_error: row.get("last_error"),
+                next_attempt_at: (status == "pending").then(|| row.get("next_attempt_at")),
+                created_at: row.get("created_at"),
+                delivered_at: row.get("delivered_at"),
+                status,
+            }
+        })
+        .collect())
+}
+
+/// Queues `event` for every active endpoint of the business subscribed to it.
+/// Takes the caller's connection so the deliveries commit or roll back with the
+/// change that caused them; nothing is sent until the dispatcher picks them up.
+pub async fn enqueue(conn: &mut AnyConnection, business_id: Uuid, event: Event, data: serde_json::Value) -> Result<()> {
+    let endpoints = sqlx::query("SELECT id, events FROM webhook_endpoints WHERE business_id = $1 AND active = TRUE")
+        .bind(business_id.to_string())
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to look up webhook endpoints: {}", e)))?;
+
+    let endpoint_ids: Vec<String> = endpoints
+        .into_iter()
+        .filter(|row| row.get::<String, _>("events").split(',').any(|name| name == event.as_str()))
+        .map(|row| row.get("id"))
+        .collect();
+    if endpoint_ids.is_empty() {
+        return Ok(());
+    }
+
+    let event_id = Uuid::new_v4();
+    let now = Utc::now().to_rfc3339();
+    let payload = json!({
+        "id": event_id,
+        "type": event.as_str(),
+        "created_at": now,
+        "data": data,
+    })
+    .to_string();
+
+    for endpoint_id in endpoint_ids {
+        sqlx::query(
+            "INSERT INTO webhook_deliveries (id, endpoint_id, business_id, event_id, event_type, payload, status, attempts, next_attempt_at, created_at) \
+             VALUES ($1, $2, $3, $4, $5, $6, 'pending', 0, $7, $8)"
+        )
+        .bind(Uuid::new_v4().to_string())
+        .bind(endpoint_id)
+        .bind(business_id.to_string())
+        .bind(event_id.to_string())
+        .bind(event.as_str())
+        .bind(&payload)
+        .bind(&now)
+        .bind(&now)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| GurtError::invalid_message(format!("Failed to queue webhook delivery: {}", e)))?;
+    }
+    Ok(())
+}
+
+/// Value of the `gurtpay-signature` header: the send time and an HMAC-SHA256 of
+/// `"{timestamp}.{body}"` under the endpoint secret, hex encoded. Receivers
+/// recompute it and reject stale timestamps to stop replays.
+pub fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("t={},v1={:x}", timestamp, mac.finalize().into_bytes())
+}
+
+/// Starts the background task that sends queued deliveries.
+pub fn spawn_dispatcher(pool: AnyPool) {
+    tokio::spawn(async move {
+        let client = match reqwest::Client::builder()
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+        {
+            Ok(client) => client,
+            Err(e) => {
+                tracing::error!("Webhook dispatcher not started: {}", e);
+                return;
+            }
+        };
+        loop {
+            if let Err(e) = dispatch_due(&pool, &client).await {
+                tracing::warn!("Webhook dispatch failed: {}", e);
+            }
+            tokio::time::sleep(Duration::from_secs(POLL_INTERVAL_SECS)).await;
+        }
+    });
+}
+
+async fn dispatch_due(pool: &AnyPool, client: &reqwest::Client) -> Result<()> {
+    let now = Utc::now();
+    let due = sqlx::query(
+        "SELECT d.id, d.event_type, d.payload, d.attempts, d.next_attempt_at, e.url, e.secret, \
+                CASE WHEN e.active THEN 1 ELSE 0 END AS endpoint_active \
+         FROM webhook_deliveries d JOIN webhook_endpoints e ON e.id = d.endpoint_id \
+         WHERE d.status = 'pending' AND d.next_attempt_at <= $1 \
+         ORDER BY d.next_attempt_at LIMIT $2"
+    )
+    .bind(now.to_rfc3339())
+    .bind(BATCH_SIZE)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to load due webhook deliveries: {}", e)))?;
+
+    for row in due {
+        let id: String = row.get("id");
+
+        // Claim the delivery by pushing it out by the lease; if another
+        // dispatcher got there first the compare fails and we move on.
+        let claimed = sqlx::query("UPDATE webhook_deliveries SET next_attempt_at = $1 WHERE id = $2 AND status = 'pending' AND next_attempt_at = $3")
+            .bind((now + chrono::Duration::seconds(CLAIM_LEASE_SECS)).to_rfc3339())
+            .bind(&id)
+            .bind(row.get::<String, _>("next_attempt_at"))
+            .execute(pool)
+            .await
+            .map_err(|e| GurtError::invalid_message(format!("Failed to claim webhook delivery: {}", e)))?;
+        if claimed.rows_affected() == 0 {
+            continue;
+        }
+
+        let attempts = row.get::<i32, _>("attempts") + 1;
+        if row.get::<i64, _>("endpoint_active") == 0 {
+            record_attempt(pool, &id, attempts - 1, Outcome::Failed, None, Some("Endpoint disabled")).await?;
+            continue;
+        }
+
+        let payload: String = row.get("payload");
+        let secret: String = row.get("secret");
+        let result = client
+            .post(row.get::<String, _>("url"))
+            .header("content-type", "application/json")
+            .header("gurtpay-event", row.get::<String, _>("event_type"))
+            .header("gurtpay-delivery", &id)
+            .header("gurtpay-signature", signature(&secret, Utc::now().timestamp(), &payload))
+            .body(payload)
+            .send()
+            .await;
+
+        let (status_code, error) = match result {
+            Ok(response) if response.status().is_success() => (Some(response.status().as_u16() as i32), None),
+            Ok(response) => (Some(response.status().as_u16() as i32), Some(format!("Endpoint responded with {}", response.status()))),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        let outcome = match error {
+            None => Outcome::Delivered,
+            Some(_) if attempts >= MAX_ATTEMPTS => Outcome::Failed,
+            Some(_) => Outcome::Retry(Utc::now() + backoff(attempts)),
+        };
+        record_attempt(pool, &id, attempts, outcome, status_code, error.as_deref()).await?;
+    }
+    Ok(())
+}
+
+enum Outcome {
+    Delivered,
+    Retry(DateTime<Utc>),
+    Failed,
+}
+
+async fn record_attempt(pool: &AnyPool, id: &str, attempts: i32, outcome: Outcome, status_code: Option<i32>, error: Option<&str>) -> Result<()> {
+    let now = Utc::now().to_rfc3339();
+    let (status, next_attempt_at, delivered_at) = match outcome {
+        Outcome::Delivered => ("delivered", now.clone(), Some(now)),
+        Outcome::Retry(at) => ("pending", at.to_rfc3339(), None),
+        Outcome::Failed => ("failed", now, None),
+    };
+    let error = error.map(|e| e.chars().take(500).collect::<String>());
+
+    sqlx::query(
+        "UPDATE webhook_deliveries SET status = $1, attempts = $2, next_attempt_at = $3, delivered_at = $4, \
+         last_status_code = $5, last_error = $6 WHERE id = $7"
+    )
+    .bind(status)
+    .bind(attempts)
+    .bind(next_attempt_at)
+    .bind(delivered_at)
+    .bind(status_code)
+    .bind(error)
+    .bind(id)
+    .execute(pool)
+    .await
+    .map_err(|e| GurtError::invalid_message(format!("Failed to record webhook attempt: {}", e)))?;
+    Ok(())
+}
+
+/// Delay before retrying after the given number of failed attempts.
+fn backoff(attempts: i32) -> chrono::Duration {
+    let exponent = (attempts - 1).clamp(0, 20) as u32;
+    chrono::Duration::seconds((BASE_BACKOFF_SECS << exponent).min(MAX_BACKOFF_SECS))
+}
+
+fn generate_secret() -> String {
+    use rand::Rng;
+    let secret: String = rand::thread_rng()
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    format!("whsec_{}", secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{test_business, test_pool};
+    use std::collections::HashMap;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    struct Received {
+        headers: HashMap<String, String>,
+        body: String,
+    }
+
+    /// A stand-in receiver that answers every request with `status` and
+    /// passes on what it was sent.
+    async fn receiver(status: u16) -> (String, mpsc::UnboundedReceiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks", listener.local_addr().unwrap());
+        let (sender, received) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = BufReader::new(stream);
+                let mut headers = HashMap::new();
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                loop {
+                    line.clear();
+                    stream.read_line(&mut line).await.unwrap();
+                    let Some((name, value)) = line.trim_end().split_once(':') else { break };
+                    headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+                }
+                let length = headers.get("content-length").map_or(0, |length| length.parse().unwrap());
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).await.unwrap();
+
+                let response = format!("HTTP/1.1 {} Test\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+                stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+                let _ = sender.send(Received { headers, body: String::from_utf8(body).unwrap() });
+            }
+        });
+        (url, received)
+    }
+
+    /// An endpoint at `url` with one `payment.succeeded` delivery queued for it.
+    async fn queued(pool: &AnyPool, url: &str) -> (WebhookEndpoint, Uuid) {
+        let business_id = test_business(pool).await;
+        let endpoint = create_endpoint(pool, business_id, url, &[]).await.unwrap();
+        let mut conn = pool.acquire().await.unwrap();
+        enqueue(&mut conn, business_id, Event::PaymentSucceeded, json!({ "amount": "12.50" })).await.unwrap();
+        (endpoint, business_id)
+    }
+
+    async fn only_delivery(pool: &AnyPool, business_id: Uuid) -> WebhookDelivery {
+        let mut deliveries = list_deliveries(pool, business_id).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        deliveries.remove(0)
+    }
+
+    fn client() -> reqwest::Client {
+        reqwest::Client::builder().timeout(Duration::from_secs(5)).build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn delivers_a_signed_payload() {
+        let pool = test_pool().await;
+        let (url, mut received) = receiver(200).await;
+        let (endpoint, business_id) = queued(&pool, &url).await;
+
+        dispatch_due(&pool, &client()).await.unwrap();
+
+        let request = received.recv().await.unwrap();
+        let delivery = only_delivery(&pool, business_id).await;
+        assert_eq!(request.headers["gurtpay-event"], "payment.succeeded");
+        assert_eq!(request.headers["gurtpay-delivery"], delivery.id.to_string());
+
+        let signed = &request.headers["gurtpay-signature"];
+        let timestamp: i64 = signed.strip_prefix("t=").unwrap().split(',').next().unwrap().parse().unwrap();
+        assert_eq!(signed, &signature(endpoint.secret.as_deref().unwrap(), timestamp, &request.body));
+        assert!((Utc::now().timestamp() - timestamp).abs() < 60);
+
+        let payload: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(payload["type"], "payment.succeeded");
+        assert_eq!(payload["data"]["amount"], "12.50");
+
+        assert_eq!(delivery.status, "delivered");
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.last_status_code, Some(200));
+        assert!(delivery.delivered_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn reschedules_after_a_non_2xx_response() {
+        let pool = test_pool().await;
+        let (url, mut received) = receiver(503).await;
+        let (_, business_id) = queued(&pool, &url).await;
+
+        let before = Utc::now();
+        dispatch_due(&pool, &client()).await.unwrap();
+        let after = Utc::now();
+        received.recv().await.unwrap();
+
+        let delivery = only_delivery(&pool, business_id).await;
+        assert_eq!(delivery.status, "pending");
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.last_status_code, Some(503));
+        assert!(delivery.last_error.unwrap().contains("503"));
+
+        let next_attempt_at = DateTime::parse_from_rfc3339(&delivery.next_attempt_at.unwrap()).unwrap();
+        assert!(next_attempt_at >= before + backoff(1));
+        assert!(next_attempt_at <= after + backoff(1));
+
+        // Not due yet, so a second pass leaves it alone
+        dispatch_due(&pool, &client()).await.unwrap();
+        assert_eq!(only_delivery(&pool, business_id).await.attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let pool = test_pool().await;
+        let (url, mut received) = receiver(500).await;
+        let (_, business_id) = queued(&pool, &url).await;
+        sqlx::query("UPDATE webhook_deliveries SET attempts = $1 WHERE business_id = $2")
+            .bind(MAX_ATTEMPTS - 1)
+            .bind(business_id.to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        dispatch_due(&pool, &client()).await.unwrap();
+        received.recv().await.unwrap();
+
+        let delivery = only_delivery(&pool, business_id).await;
+        assert_eq!(delivery.status, "failed");
+        assert_eq!(delivery.attempts, MAX_ATTEMPTS);
+        assert_eq!(delivery.last_status_code, Some(500));
+        assert!(delivery.next_attempt_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn fails_deliveries_to_a_disabled_endpoint_without_sending() {
+        let pool = test_pool().await;
+        let (url, mut received) = receiver(200).await;
+        let (endpoint, business_id) = queued(&pool, &url).await;
+        disable_endpoint(&pool, business_id, endpoint.id).await.unwrap();
+
+        dispatch_due(&pool, &client()).await.unwrap();
+
+        assert!(received.try_recv().is_err());
+        let delivery = only_delivery(&pool, business_id).await;
+        assert_eq!(delivery.status, "failed");
+        assert_eq!(delivery.attempts, 0);
+        assert_eq!(delivery.last_error.as_deref(), Some("Endpoint disabled"));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(backoff(0), chrono::Duration::seconds(BASE_BACKOFF_SECS));
+        assert_eq!(backoff(1), chrono::Duration::seconds(30));
+        assert_eq!(backoff(2), chrono::Duration::seconds(60));
+        assert_eq!(backoff(3), chrono::Duration::seconds(120));
+        assert_eq!(backoff(7), chrono::Duration::seconds(1920));
+        assert_eq!(backoff(8), chrono::Duration::seconds(MAX_BACKOFF_SECS));
+        assert_eq!(backoff(MAX_ATTEMPTS), chrono::Duration::seconds(MAX_BACKOFF_SECS));
+        assert_eq!(backoff(i32::MAX), chrono::Duration::seconds(MAX_BACKOFF_SECS));
+    }
+
+    #[test]
+    fn parses_every_event_name() {
+        for event in Event::ALL {
+            assert_eq!(Event::parse(event.as_str()), Some(event));
+        }
+        assert_eq!(Event::parse("invoice.paid"), Some(Event::InvoicePaid));
+        assert_eq!(Event::parse("Invoice.Paid"), None);
+        assert_eq!(Event::parse("invoice"), None);
+        assert_eq!(Event::parse(""), None);
+    }
+}